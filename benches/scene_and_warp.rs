@@ -0,0 +1,58 @@
+//! Scene coordinates and the UV warp for a dense grid, the stages parallelized with rayon.
+//! Run with `cargo bench --no-default-features` to leave out opencv.
+
+use criterion::{criterion_group, criterion_main, Criterion, black_box};
+use glm::*;
+use aligner::{Resolution, ImagePointGrid, VirtualCamera, pipeline};
+use aligner::surfaces::{SurfaceType, CameraModel, CameraIntrinsics};
+
+const COLS: i32 = 100;
+const ROWS: i32 = 100;
+
+/// A fisheye looking up into a dome
+fn dome_camera() -> (SurfaceType, CameraModel) {
+    let camera = CameraModel {
+        position: vec3(0., 0., 0.),
+        look_at: vec3(0., 1., 0.),
+        up_dir: vec3(0., 0., -1.),
+        intrinsics: CameraIntrinsics {fov: 180., image_width: 2000, image_height: 2000},
+    };
+    (SurfaceType::HemisphericalDome {radius: 5.}, camera)
+}
+
+/// 10k corners spread over the front half of the fisheye image
+fn image_points() -> ImagePointGrid {
+    let mut points = vec![];
+    for j in 0..ROWS {
+        for i in 0..COLS {
+            points.push(vec2(
+                600. + 800. * i as f32 / (COLS - 1) as f32,
+                1050. + 400. * j as f32 / (ROWS - 1) as f32
+            ));
+        }
+    }
+    ImagePointGrid::new(COLS, ROWS, points)
+}
+
+fn scene_and_warp(c: &mut Criterion) {
+    let (surface, camera) = dome_camera();
+    let image_points = image_points();
+    let projector_res = Resolution {width: 1920, height: 1080};
+
+    c.bench_function("locate_scene_coords 10k", |b| {
+        b.iter(|| pipeline::locate_scene_coords(black_box(&surface), &camera, &image_points).unwrap())
+    });
+
+    let scene = pipeline::locate_scene_coords(&surface, &camera, &image_points).unwrap();
+    let look_at = pipeline::calculate_look_at(&surface, &image_points, &camera).unwrap();
+    c.bench_function("generate_uv_warp_and_fov 10k", |b| {
+        b.iter(|| {
+            let mut virtual_camera = VirtualCamera::new(vec3(0., 0., 0.));
+            virtual_camera.look_at = Some(look_at);
+            pipeline::generate_uv_warp_and_fov(black_box(&scene), &mut virtual_camera, projector_res).unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, scene_and_warp);
+criterion_main!(benches);
@@ -0,0 +1,320 @@
+//! Async versions of the calibration entry points, for embedding in an async service.
+//!
+//! The calibration itself runs on tokio's blocking pool, so the opencv stages never hold up
+//! the executor. Every pattern, photo and operator prompt it needs is handed back to the
+//! calling task and done there with the async HTTP client. Dropping the future stops the
+//! run at its next pattern or photo and blanks the projector.
+
+use log::{debug, info, warn};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use opencv::prelude::*;
+use super::{CalibrationOptions, CalibrationResult, EyePositionSource, GridSpec, Error, PatternDisplay, Resolution};
+use super::{images, network, photo, surfaces};
+use super::control::{ControlProtocol, RawPostProtocol};
+use super::images::ImageEncoding;
+use super::network::{CommandResponse, NetworkError};
+use super::prompt::{OperatorPrompt, PromptError};
+
+/// What the async traits return, boxed so they can be used as trait objects
+pub type IoFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// `OperatorPrompt` for async hosts, e.g. a dialog in a web UI
+pub trait AsyncOperatorPrompt: Send + Sync {
+    /// Show the message and finish once the operator confirms it's done
+    fn wait<'a>(&'a self, message: &'a str) -> IoFuture<'a, Result<(), PromptError>>;
+}
+
+/// Print to stderr and wait for enter on stdin without blocking the executor
+pub struct AsyncStdinPrompt;
+
+impl AsyncOperatorPrompt for AsyncStdinPrompt {
+    fn wait<'a>(&'a self, message: &'a str) -> IoFuture<'a, Result<(), PromptError>> {
+        Box::pin(async move {
+            eprintln!("Please {} and press enter", message);
+            let mut line = String::new();
+            match BufReader::new(tokio::io::stdin()).read_line(&mut line).await {
+                Ok(0) => Err(PromptError::Closed),
+                Ok(_) => Ok(()),
+                Err(err) => Err(PromptError::Io(err)),
+            }
+        })
+    }
+}
+
+/// `ControlProtocol` for async hosts
+pub trait AsyncControlProtocol: Send + Sync {
+    /// Show an encoded image full screen. format is "png", "jpeg" etc
+    fn display_image<'a>(&'a self, image_bytes: &'a [u8], format: &'a str) -> IoFuture<'a, Result<(), NetworkError>>;
+    /// How patterns are encoded for `display_image`
+    fn pattern_encoding(&self) -> ImageEncoding {
+        ImageEncoding::PATTERN_DEFAULT
+    }
+    /// Show black
+    fn blank<'a>(&'a self) -> IoFuture<'a, Result<(), NetworkError>>;
+    /// Hand over the finished calibration JSON document
+    fn send_calibration<'a>(&'a self, json: &'a str) -> IoFuture<'a, Result<CommandResponse, NetworkError>>;
+}
+
+/// The original raw POST protocol over the async client
+pub struct AsyncRawPostProtocol(pub RawPostProtocol);
+
+impl AsyncControlProtocol for AsyncRawPostProtocol {
+    fn display_image<'a>(&'a self, image_bytes: &'a [u8], format: &'a str) -> IoFuture<'a, Result<(), NetworkError>> {
+        Box::pin(async move {
+            let url = format!("{}/{}", self.0.url, self.0.image_endpoint);
+            network::post_image_async(&self.0.config, &url, image_bytes, format).await?;
+            Ok(())
+        })
+    }
+
+    fn pattern_encoding(&self) -> ImageEncoding {
+        self.0.pattern_encoding
+    }
+
+    fn blank<'a>(&'a self) -> IoFuture<'a, Result<(), NetworkError>> {
+        let black = images::pixel_png(0, 0, 0).to_vec();
+        Box::pin(async move { self.display_image(&black, "png").await })
+    }
+
+    fn send_calibration<'a>(&'a self, json: &'a str) -> IoFuture<'a, Result<CommandResponse, NetworkError>> {
+        Box::pin(network::send_command_async(&self.0.config, &self.0.url, &self.0.calibration_endpoint, json))
+    }
+}
+
+/// Any of the blocking protocols, run on the blocking pool
+pub struct BlockingControl(pub Arc<dyn ControlProtocol + Send + Sync>);
+
+impl AsyncControlProtocol for BlockingControl {
+    fn display_image<'a>(&'a self, image_bytes: &'a [u8], format: &'a str) -> IoFuture<'a, Result<(), NetworkError>> {
+        let (protocol, image_bytes, format) = (self.0.clone(), image_bytes.to_vec(), format.to_string());
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || protocol.display_image(&image_bytes, &format)).await.expect("control protocol panicked")
+        })
+    }
+
+    fn pattern_encoding(&self) -> ImageEncoding {
+        self.0.pattern_encoding()
+    }
+
+    fn blank<'a>(&'a self) -> IoFuture<'a, Result<(), NetworkError>> {
+        let protocol = self.0.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || protocol.blank()).await.expect("control protocol panicked")
+        })
+    }
+
+    fn send_calibration<'a>(&'a self, json: &'a str) -> IoFuture<'a, Result<CommandResponse, NetworkError>> {
+        let (protocol, json) = (self.0.clone(), json.to_string());
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || protocol.send_calibration(&json)).await.expect("control protocol panicked")
+        })
+    }
+}
+
+/// How patterns get onto the projector in an async calibration. Fullscreen local windows
+/// need the blocking API.
+#[derive(Clone)]
+pub enum AsyncPatternDisplay {
+    Control(Arc<dyn AsyncControlProtocol>),
+    Manual(Arc<dyn AsyncOperatorPrompt>),
+}
+
+/// `produce_calibration` for async code. camera is the same argument as there, remote
+/// cameras are fetched with the async client and warmed up as `options.camera_warm_up`
+/// says. `options.post_to` is still called from the blocking pool, send the returned
+/// result with `AsyncControlProtocol::send_calibration` to keep that async too.
+pub async fn produce_calibration_async(surface: surfaces::SurfaceType, camera_cal_fname: String, display: AsyncPatternDisplay, camera: Option<String>, eye: EyePositionSource, grid: GridSpec, projector_res: Resolution, options: CalibrationOptions) -> Result<CalibrationResult, Error> {
+    let camera_type = photo::CameraType::from_arg(camera.as_deref()).with_warm_up(&options.camera_warm_up);
+    let (requests, mut incoming) = unbounded_channel();
+    let encoding = match &display {
+        AsyncPatternDisplay::Control(protocol) => protocol.pattern_encoding(),
+        AsyncPatternDisplay::Manual(_) => ImageEncoding::PATTERN_DEFAULT,
+    };
+    let bridge = Bridge(requests, encoding);
+    let photo_bridge = bridge.clone();
+    let supplied = photo::CameraType::Supplied {
+        meta: camera_type.meta(),
+        capture: Arc::new(move || photo_bridge.photo()),
+    };
+    let manual = match display { AsyncPatternDisplay::Manual(_) => true, _ => false };
+    let calibration = tokio::task::spawn_blocking(move || {
+        let blocking_display = if manual {
+            PatternDisplay::Manual(Box::new(bridge))
+        } else {
+            PatternDisplay::Control(Box::new(bridge))
+        };
+        super::produce_calibration_with_camera(surface, &camera_cal_fname, blocking_display, supplied, eye, grid, projector_res, options)
+    });
+
+    let mut cancel_guard = BlankOnDrop {display: Some(display.clone())};
+    // the bridges are dropped with the blocking run, which ends this loop
+    while let Some(request) = incoming.recv().await {
+        serve(request, &display, &camera_type).await;
+    }
+    cancel_guard.display = None;
+    calibration.await.expect("calibration panicked")
+}
+
+/// What the blocking calibration asks the calling task to do. The answer goes back on done.
+enum IoRequest {
+    Display {image: Vec<u8>, format: String, done: mpsc::Sender<Result<(), NetworkError>>},
+    Blank {done: mpsc::Sender<Result<(), NetworkError>>},
+    Calibration {json: String, done: mpsc::Sender<Result<CommandResponse, NetworkError>>},
+    Prompt {message: String, done: mpsc::Sender<Result<(), PromptError>>},
+    Photo {done: mpsc::Sender<Vec<u8>>},
+}
+
+/// Stands in for the control server, operator and camera in the blocking calibration,
+/// passing each request to the async task. None once the async side has gone. Patterns are
+/// encoded for the async control server.
+#[derive(Clone)]
+struct Bridge(UnboundedSender<IoRequest>, ImageEncoding);
+
+impl Bridge {
+    fn ask<T>(&self, request: impl FnOnce(mpsc::Sender<T>) -> IoRequest) -> Option<T> {
+        let (done, answer) = mpsc::channel();
+        self.0.send(request(done)).ok()?;
+        answer.recv().ok()
+    }
+
+    fn photo(&self) -> Vec<u8> {
+        self.ask(|done| IoRequest::Photo {done: done}).expect("calibration cancelled while waiting for a photo")
+    }
+}
+
+impl ControlProtocol for Bridge {
+    fn display_image(&self, image_bytes: &[u8], format: &str) -> Result<(), NetworkError> {
+        self.ask(|done| IoRequest::Display {image: image_bytes.to_vec(), format: format.to_string(), done: done})
+            .unwrap_or(Err(NetworkError::Cancelled))
+    }
+
+    fn pattern_encoding(&self) -> ImageEncoding {
+        self.1
+    }
+
+    fn blank(&self) -> Result<(), NetworkError> {
+        self.ask(|done| IoRequest::Blank {done: done}).unwrap_or(Err(NetworkError::Cancelled))
+    }
+
+    fn send_calibration(&self, json: &str) -> Result<CommandResponse, NetworkError> {
+        self.ask(|done| IoRequest::Calibration {json: json.to_string(), done: done}).unwrap_or(Err(NetworkError::Cancelled))
+    }
+}
+
+impl OperatorPrompt for Bridge {
+    fn wait(&self, message: &str) -> Result<(), PromptError> {
+        self.ask(|done| IoRequest::Prompt {message: message.to_string(), done: done}).unwrap_or(Err(PromptError::Cancelled))
+    }
+}
+
+/// Do what the blocking calibration asked. A dropped answer means the run has already
+/// failed and is ignored.
+async fn serve(request: IoRequest, display: &AsyncPatternDisplay, camera: &photo::CameraType) {
+    let no_control = || NetworkError::Config("the calibration has no control server".to_string());
+    match (request, display) {
+        (IoRequest::Display {image, format, done}, AsyncPatternDisplay::Control(protocol)) => {
+            let _ = done.send(protocol.display_image(&image, &format).await);
+        },
+        (IoRequest::Blank {done}, AsyncPatternDisplay::Control(protocol)) => {
+            let _ = done.send(protocol.blank().await);
+        },
+        (IoRequest::Calibration {json, done}, AsyncPatternDisplay::Control(protocol)) => {
+            let _ = done.send(protocol.send_calibration(&json).await);
+        },
+        (IoRequest::Display {done, ..}, _) | (IoRequest::Blank {done}, _) => {
+            let _ = done.send(Err(no_control()));
+        },
+        (IoRequest::Calibration {done, ..}, _) => {
+            let _ = done.send(Err(no_control()));
+        },
+        (IoRequest::Prompt {message, done}, AsyncPatternDisplay::Manual(prompt)) => {
+            let _ = done.send(prompt.wait(&message).await);
+        },
+        (IoRequest::Prompt {message, done}, _) => {
+            let _ = done.send(Err(PromptError::NotInteractive(message)));
+        },
+        (IoRequest::Photo {done}, _) => {
+            let _ = done.send(capture_photo(camera).await);
+        },
+    }
+}
+
+/// Fetch remote camera photos with the async client, other cameras on the blocking pool
+async fn capture_photo(camera: &photo::CameraType) -> Vec<u8> {
+    match camera {
+        photo::CameraType::RemoteHttpCamera {url, warm_up} => fetch_settled_photo(url, warm_up).await,
+        other => {
+            let other = other.clone();
+            tokio::task::spawn_blocking(move || photo::capture_photo(other).data_typed::<u8>().unwrap().to_vec())
+                .await.expect("camera capture panicked")
+        }
+    }
+}
+
+/// `photo::fetch_settled_photo` with the async client, waiting without blocking the executor
+async fn fetch_settled_photo(url: &str, warm_up: &photo::WarmUp) -> Vec<u8> {
+    let started = Instant::now();
+    tokio::time::delay_for(Duration::from_secs_f32(warm_up.settle_seconds)).await;
+    for _ in 0..warm_up.discard_frames {
+        fetch_photo(url).await;
+    }
+    let mut photo = fetch_photo(url).await;
+    let mut frames = warm_up.discard_frames + 1;
+    if let Some(check) = warm_up.stability {
+        loop {
+            tokio::time::delay_for(Duration::from_secs_f32(check.interval_seconds)).await;
+            let next = fetch_photo(url).await;
+            frames += 1;
+            let difference = photo::frame_difference(&Mat::from_slice(&photo).unwrap(), &Mat::from_slice(&next).unwrap());
+            photo = next;
+            match difference {
+                Some(difference) if difference < check.threshold => break,
+                Some(difference) => debug!("remote camera frames differ by {:.1}, waiting", difference),
+                None => debug!("remote camera frames couldn't be compared, waiting"),
+            }
+            if started.elapsed().as_secs_f32() > check.timeout_seconds {
+                warn!("remote camera frames still changing after {:.1}s, using the latest", started.elapsed().as_secs_f32());
+                break;
+            }
+        }
+    }
+    if *warm_up != photo::WarmUp::default() {
+        info!("remote camera settled after {:.2}s and {} frames", started.elapsed().as_secs_f32(), frames);
+    }
+    photo
+}
+
+async fn fetch_photo(url: &str) -> Vec<u8> {
+    info!("fetching camera photo from {}", &url);
+    let res = reqwest::get(url).await.expect("failed to request from remote camera URL");
+    res.bytes().await.expect("response didn't contain image data").to_vec()
+}
+
+/// Blanks the projector, best effort, if the calibration future is dropped before it
+/// finishes so the control server isn't left showing a chessboard
+struct BlankOnDrop {
+    display: Option<AsyncPatternDisplay>,
+}
+
+impl Drop for BlankOnDrop {
+    fn drop(&mut self) {
+        if let Some(AsyncPatternDisplay::Control(protocol)) = self.display.take() {
+            warn!("calibration cancelled, blanking the projector");
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    runtime.spawn(async move {
+                        if let Err(err) = protocol.blank().await {
+                            warn!("couldn't blank the projector after cancelling: {}", err);
+                        }
+                    });
+                },
+                Err(_) => warn!("no tokio runtime left to blank the projector with"),
+            }
+        }
+    }
+}
@@ -2,8 +2,11 @@
 use opencv::prelude::*;
 use opencv::core::*;
 use xmltree::Element;
-use std::fs::File;
-use log::{info};
+use std::fs::{self, File};
+use log::{info, warn};
+use super::output::{CalibrationFileMeta, IntrinsicsMeta, fnv1a_hex};
+use super::Error;
+use super::defects::CameraDefects;
 
 pub struct Calibration {
     pub camera_matrix: Matx33d,
@@ -11,39 +14,235 @@ pub struct Calibration {
     pub fov: f32,
     pub image_width: i32,
     pub image_height: i32,
+    /// sensor and lens defects corrected in each photo before it's undistorted
+    pub defects: Option<CameraDefects>,
+    /// photos must be exactly the calibrated size, rather than a uniform scale of it
+    pub strict_photo_size: bool,
 }
 
-pub fn load_calibration_file(fname: &str) -> Option<Calibration> {
-    // Load the camera calibration from file
-    let mut root_elm = Element::parse(File::open(fname).unwrap()).unwrap();
-    let node = root_elm.get_mut_child("camera_matrix").expect("can't find camera_matrix element");
-    let data = node.get_mut_child("data").expect("can't find camera_matrix data");
-    let values = data.get_text().unwrap();
-    let mut floats = [0_f64; 9];
-    for (i, word) in values.split_ascii_whitespace().enumerate() {
-        floats[i] = word.parse().unwrap();
+/// Distortion coefficient counts opencv understands: k1 k2 p1 p2 [k3 [k4 k5 k6 [s1 s2 s3 s4 [tx ty]]]]
+pub const DISTORTION_COEFFICIENT_COUNTS: &[usize] = &[4, 5, 8, 12, 14];
+
+impl Calibration {
+    /// Build a calibration from intrinsics known at runtime, e.g. from a camera SDK, rather
+    /// than a calibration XML. Distortion coefficients are in opencv's order.
+    pub fn from_intrinsics(fx: f64, fy: f64, cx: f64, cy: f64, distortion_coefficients: &[f64], image_width: i32, image_height: i32) -> Result<Calibration, Error> {
+        if image_width <= 0 || image_height <= 0 {
+            return Err(Error::Config(format!("camera image size {}x{} must be positive", image_width, image_height)));
+        }
+        if fx <= 0. || fy <= 0. {
+            return Err(Error::Config(format!("camera focal lengths {}, {} must be positive", fx, fy)));
+        }
+        if !DISTORTION_COEFFICIENT_COUNTS.contains(&distortion_coefficients.len()) {
+            return Err(Error::Config(format!(
+                "{} distortion coefficients given, opencv needs {:?}",
+                distortion_coefficients.len(), DISTORTION_COEFFICIENT_COUNTS
+            )));
+        }
+        let camera_matrix = Matx33d::from([
+            fx, 0., cx,
+            0., fy, cy,
+            0., 0., 1.
+        ]);
+        Ok(from_parts(camera_matrix, Mat::from_slice(distortion_coefficients)?, image_width, image_height))
+    }
+
+    /// focal length in pixels along x
+    pub fn fx(&self) -> f64 {
+        *self.camera_matrix.get((0, 0)).unwrap()
     }
-    let camera_matrix = Matx33d::from(floats);
 
-    let node = root_elm.get_mut_child("distortion_coefficients").expect("can't find distortion_coefficients element");
-    let data = node.get_mut_child("data").expect("can't find distortion_coefficients data");
-    let values = data.get_text().unwrap();
-    let floats: Vec<f64> = values.split_ascii_whitespace().map(|word| word.parse().unwrap()).collect();
-    let distortion_coefficients = Mat::from_slice(&floats).unwrap();
+    pub fn fy(&self) -> f64 {
+        *self.camera_matrix.get((1, 1)).unwrap()
+    }
 
-    let node = root_elm.get_mut_child("image_height").expect("can't find image_height element");
-    let image_height: i32 = node.get_text().as_deref().unwrap().parse().unwrap();
+    /// principal point x in pixels
+    pub fn cx(&self) -> f64 {
+        *self.camera_matrix.get((0, 2)).unwrap()
+    }
 
-    let node = root_elm.get_mut_child("image_width").expect("can't find image_width element");
-    let image_width: i32 = node.get_text().as_deref().unwrap().parse().unwrap();
+    pub fn cy(&self) -> f64 {
+        *self.camera_matrix.get((1, 2)).unwrap()
+    }
+
+    pub fn distortion(&self) -> Vec<f64> {
+        distortion_values(self)
+    }
+
+    /// The calibration for photos of width x height. None when that's the calibrated size,
+    /// otherwise the camera matrix scaled to it; the distortion coefficients don't depend on
+    /// the scale. Err when the photo isn't a uniform scale of the calibrated size, or isn't
+    /// the calibrated size at all with `strict_photo_size`. Defects measured at the
+    /// calibrated size aren't carried over.
+    pub fn for_photo(&self, width: i32, height: i32) -> Result<Option<Calibration>, Error> {
+        if width == self.image_width && height == self.image_height {
+            return Ok(None);
+        }
+        let mismatch = Error::PhotoSize {photo: (width, height), calibration: (self.image_width, self.image_height), strict: self.strict_photo_size};
+        if self.strict_photo_size || width <= 0 || height <= 0 {
+            return Err(mismatch);
+        }
+        let (sx, sy) = (width as f64 / self.image_width as f64, height as f64 / self.image_height as f64);
+        // within a pixel of the same scale both ways, so e.g. a third of 4000x3000 rounded to
+        // 1333x1000 still counts
+        if (height as f64 - self.image_height as f64 * sx).abs() > 1. || (width as f64 - self.image_width as f64 * sy).abs() > 1. {
+            return Err(mismatch);
+        }
+        info!(
+            "the photo is {}x{}, {} of the {}x{} the camera was calibrated at, scaling the camera matrix to match",
+            width, height, describe_scale(sx), self.image_width, self.image_height
+        );
+        if self.defects.is_some() {
+            warn!("the camera defects were measured at the calibrated size, they aren't corrected in scaled photos");
+        }
+        // scaled about the pixels' corners rather than their centers
+        let camera_matrix = Matx33d::from([
+            self.fx() * sx, 0., (self.cx() + 0.5) * sx - 0.5,
+            0., self.fy() * sy, (self.cy() + 0.5) * sy - 0.5,
+            0., 0., 1.
+        ]);
+        let mut scaled = from_parts(camera_matrix, Mat::from_slice(&self.distortion())?, width, height);
+        scaled.strict_photo_size = self.strict_photo_size;
+        Ok(Some(scaled))
+    }
+}
 
+/// A scale as a simple fraction when it's close to one, e.g. "1/2"
+fn describe_scale(scale: f64) -> String {
+    for denominator in 1..=16 {
+        let numerator = (scale * denominator as f64).round();
+        if numerator > 0. && (numerator / denominator as f64 - scale).abs() < 1e-3 {
+            return if denominator == 1 { format!("{}x", numerator) } else { format!("{}/{}", numerator, denominator) };
+        }
+    }
+    format!("{:.4}x", scale)
+}
+
+/// Load a camera calibration XML, as written by opencv's calibration tools
+pub fn load_calibration_file(fname: &str) -> Result<Calibration, Error> {
+    let invalid = |what: &str| Error::Config(format!("camera calibration {} has no valid {}", fname, what));
+    let file = File::open(fname).map_err(|err| Error::Config(format!("can't open camera calibration {}: {}", fname, err)))?;
+    let root_elm = Element::parse(file).map_err(|err| Error::Config(format!("camera calibration {} isn't valid XML: {}", fname, err)))?;
+    let text = |name: &str, child: Option<&str>| -> Option<String> {
+        let node = root_elm.get_child(name)?;
+        let node = match child { Some(child) => node.get_child(child)?, None => node };
+        node.get_text().map(|text| text.into_owned())
+    };
+    let numbers = |name: &str| -> Result<Vec<f64>, Error> {
+        let values = text(name, Some("data")).ok_or_else(|| invalid(name))?;
+        values.split_ascii_whitespace().map(|word| word.parse().map_err(|_| invalid(name))).collect()
+    };
+
+    let floats = numbers("camera_matrix")?;
+    if floats.len() != 9 {
+        return Err(invalid("camera_matrix"));
+    }
+    let mut matrix = [0_f64; 9];
+    matrix.copy_from_slice(&floats);
+    let camera_matrix = Matx33d::from(matrix);
+
+    let floats = numbers("distortion_coefficients")?;
+    let distortion_coefficients = Mat::from_slice(&floats)?;
+
+    let size = |name: &str| -> Result<i32, Error> {
+        text(name, None).and_then(|text| text.trim().parse().ok()).ok_or_else(|| invalid(name))
+    };
+    let image_height = size("image_height")?;
+    let image_width = size("image_width")?;
+
+    info!("camera matrix and distortion coefficients loaded from {}", &fname);
+    Ok(from_parts(camera_matrix, distortion_coefficients, image_width, image_height))
+}
+
+/// Build a calibration from an intrinsic matrix and distortion coefficients
+pub fn from_parts(camera_matrix: Matx33d, distortion_coefficients: Mat, image_width: i32, image_height: i32) -> Calibration {
     // get the camera FOV from the intrinsic camera matrix
     let fy: f32 = camera_matrix.get((1, 1)).unwrap().clone() as f32;
     let _aa = (image_height as f32).atan2(2. * fy);
     let fov = (2. * _aa).to_degrees();
 
-    info!("camera matrix and distortion coefficients loaded from {}", &fname);
     info!("physical camera field of view calculated as {} degrees", fov);
 
-    Some(Calibration {camera_matrix: camera_matrix, distortion_coefficients: distortion_coefficients, fov: fov, image_width: image_width, image_height: image_height})
+    Calibration {camera_matrix: camera_matrix, distortion_coefficients: distortion_coefficients, fov: fov, image_width: image_width, image_height: image_height, defects: None, strict_photo_size: false}
+}
+
+/// The intrinsic matrix as 9 values, row by row
+pub fn camera_matrix_values(calibration: &Calibration) -> [f64; 9] {
+    let mut values = [0_f64; 9];
+    for (i, value) in values.iter_mut().enumerate() {
+        *value = *calibration.camera_matrix.get((i as i32 / 3, i as i32 % 3)).unwrap();
+    }
+    values
+}
+
+/// The distortion coefficients as a plain list
+pub fn distortion_values(calibration: &Calibration) -> Vec<f64> {
+    calibration.distortion_coefficients.data_typed::<f64>().map(|d| d.to_vec()).unwrap_or_default()
+}
+
+/// Summary of the intrinsics for the output metadata
+pub fn intrinsics_meta(calibration: &Calibration) -> IntrinsicsMeta {
+    let m = camera_matrix_values(calibration);
+    let distortion = distortion_values(calibration);
+    let model = match distortion.len() {
+        0 => "none",
+        4 | 5 => "radialTangential",
+        8 => "rational",
+        12 => "thinPrism",
+        14 => "tilted",
+        _ => "unknown",
+    };
+    IntrinsicsMeta {
+        focal_length: [m[0], m[4]],
+        principal_point: [m[2], m[5]],
+        image_width: calibration.image_width,
+        image_height: calibration.image_height,
+        fov: calibration.fov,
+        distortion_model: model.to_string(),
+        distortion_coefficients: distortion,
+    }
+}
+
+/// Path and content hash of a calibration file, recorded in the output metadata
+pub fn file_identity(fname: &str) -> Result<CalibrationFileMeta, Error> {
+    let bytes = fs::read(fname).map_err(|err| Error::Config(format!("can't read camera calibration {}: {}", fname, err)))?;
+    Ok(CalibrationFileMeta {path: fname.to_string(), hash: fnv1a_hex(&bytes)})
+}
+
+/// A distortion free calibration with the principal point at the image center
+pub fn ideal_calibration(fov: f32, image_width: i32, image_height: i32) -> Calibration {
+    let fy = image_height as f64 / (2. * (fov as f64 / 2.).to_radians().tan());
+    let camera_matrix = Matx33d::from([
+        fy, 0., image_width as f64 / 2.,
+        0., fy, image_height as f64 / 2.,
+        0., 0., 1.
+    ]);
+    let distortion_coefficients = Mat::from_slice(&[0_f64; 5]).unwrap();
+    Calibration {camera_matrix: camera_matrix, distortion_coefficients: distortion_coefficients, fov: fov, image_width: image_width, image_height: image_height, defects: None, strict_photo_size: false}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_identified_by_their_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a.xml"), dir.path().join("b.xml"));
+        fs::write(&a, "<opencv_storage/>").unwrap();
+        fs::write(&b, "<opencv_storage></opencv_storage>").unwrap();
+        let identity = |path: &std::path::Path| file_identity(path.to_str().unwrap()).unwrap();
+        assert_eq!(identity(&a).path, a.to_str().unwrap());
+        assert_eq!(identity(&a).hash, fnv1a_hex(b"<opencv_storage/>"));
+        assert_ne!(identity(&a).hash, identity(&b).hash);
+    }
+
+    #[test]
+    fn unreadable_files_have_no_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        match file_identity(dir.path().join("missing.xml").to_str().unwrap()) {
+            Err(Error::Config(message)) => assert!(message.contains("missing.xml"), "{}", message),
+            other => panic!("a missing file gave {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,292 @@
+//! How much a calibration changed between two runs, for checking that a tweak did what was
+//! expected or for spotting rig drift between shows.
+
+#[cfg(feature = "opencv")]
+use opencv::core::Mat;
+use glm::*;
+use serde::{Serialize, Deserialize};
+use super::{CalibrationResult, GridSpec, Error};
+use super::pipeline;
+use super::surfaces::SurfaceType;
+#[cfg(feature = "opencv")]
+use super::images;
+
+/// Summary of per corner displacements
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DisplacementStats {
+    pub mean: f32,
+    pub rms: f32,
+    pub max: f32,
+    /// grid index (row by row) of the corner that moved most
+    pub worst_index: Option<usize>,
+}
+
+/// The differences between calibration a and calibration b, b minus a
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CalibrationDiff {
+    /// the grid the calibrations were compared on
+    pub warp_res_x: i32,
+    pub warp_res_y: i32,
+    /// "a" or "b", whichever was resampled to the other's grid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resampled: Option<String>,
+    /// corners neither calibration filled in from neighbours
+    pub compared_corners: usize,
+    /// warp displacement, 0-1 across the image
+    pub uv: DisplacementStats,
+    /// scene displacement in scene units
+    pub scene: DisplacementStats,
+    /// per corner, row by row. None for corners that weren't compared.
+    pub uv_displacement: Vec<Option<f32>>,
+    pub scene_displacement: Vec<Option<f32>>,
+    pub fov_delta: f32,
+    pub eye_delta: [f32; 3],
+    pub look_at_delta: [f32; 3],
+    pub up_delta: [f32; 3],
+}
+
+impl CalibrationDiff {
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// Compare two calibrations, whatever output conventions they were written with. When
+/// their grids differ the smaller one is resampled to the larger, or it's an error if
+/// resample is false.
+pub fn compare_calibrations(a: &CalibrationResult, b: &CalibrationResult, resample: bool) -> Result<CalibrationDiff, Error> {
+    let (a, grid_a) = native(a)?;
+    let (b, grid_b) = native(b)?;
+    let (grid, resampled) = if grid_a == grid_b {
+        (grid_a, None)
+    } else if !resample {
+        return Err(Error::Config(format!("the calibrations have different grids ({} and {}) and resampling is off", grid_a, grid_b)));
+    } else if grid_a.len() < grid_b.len() {
+        (grid_b, Some("a".to_string()))
+    } else {
+        (grid_a, Some("b".to_string()))
+    };
+    let (warp_a, scene_a, valid_a) = on_grid(&a, grid_a, grid);
+    let (warp_b, scene_b, valid_b) = on_grid(&b, grid_b, grid);
+
+    let mut uv_displacement = vec![];
+    let mut scene_displacement = vec![];
+    for i in 0..grid.len() {
+        if valid_a[i] && valid_b[i] {
+            uv_displacement.push(Some(length(warp_b[i] - warp_a[i])));
+            scene_displacement.push(Some(length(scene_b[i] - scene_a[i])));
+        } else {
+            uv_displacement.push(None);
+            scene_displacement.push(None);
+        }
+    }
+    let compared = uv_displacement.iter().filter(|d| d.is_some()).count();
+    if compared == 0 {
+        return Err(Error::Config("the calibrations have no valid corners in common".to_string()));
+    }
+
+    Ok(CalibrationDiff {
+        warp_res_x: grid.cols,
+        warp_res_y: grid.rows,
+        resampled: resampled,
+        compared_corners: compared,
+        uv: stats(&uv_displacement),
+        scene: stats(&scene_displacement),
+        uv_displacement: uv_displacement,
+        scene_displacement: scene_displacement,
+        fov_delta: b.fov - a.fov,
+        eye_delta: *(b.eye - a.eye).as_array(),
+        look_at_delta: *(b.look_at - a.look_at).as_array(),
+        up_delta: *(b.up - a.up).as_array(),
+    })
+}
+
+/// A copy of result in the native conventions, with the grid its arrays are checked to
+/// match
+fn native(result: &CalibrationResult) -> Result<(CalibrationResult, GridSpec), Error> {
+    let grid = GridSpec {cols: result.warp_res_x, rows: result.warp_res_y};
+    if result.scene.len() != grid.len() || result.warp.len() != grid.len() {
+        return Err(Error::Config(format!(
+            "calibration claims a {} grid but has {} scene and {} warp points",
+            grid, result.scene.len(), result.warp.len()
+        )));
+    }
+    let native = match &result.meta {
+        Some(meta) => meta.output_conventions.revert(result, meta.projector_resolution),
+        None => result.clone(),
+    };
+    Ok((native, grid))
+}
+
+/// Warp, scene and validity of a native result resampled to grid `to`
+fn on_grid(result: &CalibrationResult, from: GridSpec, to: GridSpec) -> (Vec<glm::Vec2>, Vec<glm::Vec3>, Vec<bool>) {
+    let surface = result.meta.as_ref().map(|meta| meta.surface).unwrap_or(SurfaceType::Wall);
+    let valid = result.valid.clone().unwrap_or(vec![true; from.len()]);
+    (
+        pipeline::resample_warp(&result.warp, from, to),
+        pipeline::resample_scene(&surface, &result.scene, from, to),
+        pipeline::resample_valid(&valid, from, to)
+    )
+}
+
+fn stats(displacements: &[Option<f32>]) -> DisplacementStats {
+    let mut out = DisplacementStats::default();
+    let (mut sum, mut sum_sq, mut n) = (0_f32, 0_f32, 0);
+    for (i, d) in displacements.iter().enumerate() {
+        if let Some(d) = d {
+            sum += d;
+            sum_sq += d * d;
+            n += 1;
+            if out.worst_index.is_none() || *d > out.max {
+                out.max = *d;
+                out.worst_index = Some(i);
+            }
+        }
+    }
+    if n > 0 {
+        out.mean = sum / n as f32;
+        out.rms = (sum_sq / n as f32).sqrt();
+    }
+    out
+}
+
+/// The uv displacement of each corner as a PNG, one cell_size pixel square per corner
+/// colored from blue (no movement) to red (max_displacement, the diff's largest when None).
+/// Corners that weren't compared are grey.
+#[cfg(feature = "opencv")]
+pub fn uv_heatmap_png(diff: &CalibrationDiff, max_displacement: Option<f32>, cell_size: i32) -> Result<Vec<u8>, Error> {
+    let max = max_displacement.unwrap_or(diff.uv.max);
+    let heatmap: Mat = images::heatmap(&diff.uv_displacement, diff.warp_res_x, max, cell_size)?;
+    Ok(images::encode_image(&heatmap, ".png").to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Resolution;
+    use crate::output::{AxisConvention, CameraSourceMeta, Meta, OutputConventions, OutputTransform, PhysicalCameraMeta, WarpOrder, WarpUnits};
+
+    const PROJECTOR: Resolution = Resolution {width: 1920, height: 1080};
+
+    /// A wall calibration whose warp and scene are linear across the grid, so any grid size
+    /// samples the same calibration
+    fn calibration(grid: GridSpec) -> CalibrationResult {
+        let mut warp = vec![];
+        let mut scene = vec![];
+        for row in 0..grid.rows {
+            for col in 0..grid.cols {
+                let t = vec2(col as f32 / (grid.cols - 1) as f32, row as f32 / (grid.rows - 1) as f32);
+                warp.push(vec2(0.1 + 0.8 * t.x, 0.2 + 0.6 * t.y));
+                scene.push(vec3(4. * t.x - 2., 3. * t.y - 1.5, 0.));
+            }
+        }
+        let camera = PhysicalCameraMeta {position: [0., 0., 5.], look_at: [0., 0., 0.], up: [0., 1., 0.]};
+        CalibrationResult {
+            format_version: crate::CALIBRATION_FORMAT_VERSION,
+            eye_name: None,
+            fov: 60.,
+            eye: vec3(0., 0., 4.),
+            look_at: vec3(0., 0., 0.),
+            up: vec3(0., 1., 0.),
+            projector_optics: None,
+            view_matrix: None,
+            projection_matrix: None,
+            matrix_layout: None,
+            warp_res_x: grid.cols,
+            warp_res_y: grid.rows,
+            warp: warp,
+            scene: scene,
+            valid: None,
+            confidence: None,
+            meta: Some(Meta::new(SurfaceType::Wall, camera, None, grid, PROJECTOR, CameraSourceMeta::Simulated)),
+            diagnostics: None,
+        }
+    }
+
+    const GRID: GridSpec = GridSpec {cols: 9, rows: 6};
+
+    #[test]
+    fn a_calibration_matches_itself() {
+        let a = calibration(GRID);
+        let diff = compare_calibrations(&a, &a, false).unwrap();
+        assert_eq!((diff.warp_res_x, diff.warp_res_y, diff.compared_corners), (9, 6, 54));
+        assert_eq!(diff.resampled, None);
+        assert_eq!((diff.uv.max, diff.scene.max, diff.fov_delta), (0., 0., 0.));
+        assert!(diff.uv_displacement.iter().all(|d| *d == Some(0.)));
+    }
+
+    #[test]
+    fn output_conventions_are_undone() {
+        let a = calibration(GRID);
+        let conventions = OutputConventions {
+            flip_y: true,
+            units: WarpUnits::Pixels,
+            order: WarpOrder::ColumnMajor,
+            transform: OutputTransform {scale: 0.001, axes: AxisConvention::ZUpRightHanded},
+        };
+        let b = conventions.apply(&a, PROJECTOR);
+        let diff = compare_calibrations(&a, &b, false).unwrap();
+        assert_eq!(diff.compared_corners, 54);
+        assert!(diff.uv.max < 1e-5, "{:?}", diff.uv);
+        assert!(diff.scene.max < 1e-4, "{:?}", diff.scene);
+        assert!(length(vec3(diff.eye_delta[0], diff.eye_delta[1], diff.eye_delta[2])) < 1e-4);
+    }
+
+    #[test]
+    fn displacements_are_b_minus_a() {
+        let a = calibration(GRID);
+        let mut b = a.clone();
+        b.warp[7] = b.warp[7] + vec2(0.03, 0.04);
+        b.scene[7] = b.scene[7] + vec3(0., 0., 0.5);
+        b.fov = 62.;
+        b.eye = vec3(0., 0.25, 4.);
+        let diff = compare_calibrations(&a, &b, false).unwrap();
+        assert!((diff.uv.max - 0.05).abs() < 1e-6);
+        assert_eq!(diff.uv.worst_index, Some(7));
+        assert!((diff.uv.mean - 0.05 / 54.).abs() < 1e-6);
+        assert!((diff.uv.rms - 0.05 / (54_f32).sqrt()).abs() < 1e-6);
+        assert!((diff.scene.max - 0.5).abs() < 1e-6);
+        assert_eq!(diff.scene.worst_index, Some(7));
+        assert!((diff.fov_delta - 2.).abs() < 1e-6);
+        assert_eq!(diff.eye_delta, [0., 0.25, 0.]);
+    }
+
+    #[test]
+    fn unseen_corners_arent_compared() {
+        let a = calibration(GRID);
+        let mut b = a.clone();
+        let mut valid = vec![true; GRID.len()];
+        valid[3] = false;
+        b.valid = Some(valid);
+        b.warp[3] = vec2(0.9, 0.9);
+        let diff = compare_calibrations(&a, &b, false).unwrap();
+        assert_eq!(diff.compared_corners, 53);
+        assert_eq!(diff.uv_displacement[3], None);
+        assert_eq!(diff.uv.max, 0.);
+
+        b.valid = Some(vec![false; GRID.len()]);
+        assert!(compare_calibrations(&a, &b, false).is_err());
+    }
+
+    #[test]
+    fn the_smaller_grid_is_resampled() {
+        let (small, large) = (calibration(GRID), calibration(GridSpec {cols: 17, rows: 11}));
+        assert!(compare_calibrations(&small, &large, false).is_err());
+        for (a, b, resampled) in [(&small, &large, "a"), (&large, &small, "b")].iter() {
+            let diff = compare_calibrations(a, b, true).unwrap();
+            assert_eq!(diff.resampled.as_deref(), Some(*resampled));
+            assert_eq!((diff.warp_res_x, diff.warp_res_y, diff.compared_corners), (17, 11, 187));
+            assert!(diff.uv.max < 1e-5, "{:?}", diff.uv);
+            assert!(diff.scene.max < 1e-5, "{:?}", diff.scene);
+        }
+    }
+
+    #[test]
+    fn arrays_must_match_the_grid() {
+        let mut a = calibration(GRID);
+        a.warp.pop();
+        assert!(compare_calibrations(&a, &calibration(GRID), true).is_err());
+    }
+}
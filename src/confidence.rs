@@ -0,0 +1,41 @@
+//! How far each corner of the warp can be trusted, 0-1. A measured corner starts at 1 and
+//! loses confidence for each sign it's noisy: low contrast around it, a large subpixel
+//! correction (the first estimate was poor), and distance from the principal point where
+//! what's left of the lens distortion is worst. Warp corners interpolated between measured
+//! ones or extrapolated beyond them get less again.
+
+/// Contrast (of the photo's white level) around a corner at and above which it's fully trusted
+pub const GOOD_CONTRAST: f32 = 0.5;
+/// Subpixel correction in photo pixels that halves a corner's confidence
+pub const SUBPIXEL_HALF: f32 = 1.5;
+/// Confidence at the corners of the photo from the distance to the principal point
+pub const EDGE: f32 = 0.5;
+/// Factor for warp corners interpolated between measured ones
+pub const INTERPOLATED: f32 = 0.5;
+/// Factor for warp corners extrapolated or filled in from their neighbours
+pub const EXTRAPOLATED: f32 = 0.25;
+/// Factor for corners refined over camera defects, see `defects`
+pub const MASKED: f32 = 0.25;
+
+/// What the photo says about one detected corner
+#[derive(Clone, Copy, Debug)]
+pub struct CornerEvidence {
+    /// max - min around the corner, 0-1 of the white level
+    pub contrast: f32,
+    /// how far corner_sub_pix moved it, photo pixels
+    pub subpixel_shift: f32,
+    /// distance from the principal point, 0 at it and 1 at the photo's corners
+    pub radius: f32,
+    /// the subpixel window covered masked camera defects
+    pub masked: bool,
+}
+
+pub fn corner_confidence(evidence: &CornerEvidence) -> f32 {
+    let contrast = (evidence.contrast / GOOD_CONTRAST).max(0.).min(1.);
+    let shift = evidence.subpixel_shift / SUBPIXEL_HALF;
+    let subpixel = 1. / (1. + shift * shift);
+    let radius = evidence.radius.max(0.).min(1.);
+    let edge = 1. - (1. - EDGE) * radius * radius;
+    let masked = if evidence.masked { MASKED } else { 1. };
+    contrast * subpixel * edge * masked
+}
@@ -0,0 +1,341 @@
+use super::network::{self, NetworkConfig, NetworkError, CommandResponse};
+use super::images::{self, ImageEncoding};
+use super::output::fnv1a_hex;
+use super::transport;
+use log::warn;
+use std::sync::Mutex;
+use serde_json::json;
+
+/// The contract between the aligner and whatever is driving the projector(s).
+/// Implement this to integrate with a control server that speaks a different protocol.
+pub trait ControlProtocol {
+    /// Show an encoded image full screen. format is "png", "jpeg" etc
+    fn display_image(&self, image_bytes: &[u8], format: &str) -> Result<(), NetworkError>;
+    /// How patterns are encoded for `display_image`
+    fn pattern_encoding(&self) -> ImageEncoding {
+        ImageEncoding::PATTERN_DEFAULT
+    }
+    /// Show black
+    fn blank(&self) -> Result<(), NetworkError>;
+    /// Hand over the finished calibration JSON document
+    fn send_calibration(&self, json: &str) -> Result<CommandResponse, NetworkError>;
+    /// Hand over one of several calibrations made for different eye positions. The document
+    /// carries the name as `eyeName` too, by default it's sent like any other calibration.
+    fn send_eye_calibration(&self, _eye_name: &str, json: &str) -> Result<CommandResponse, NetworkError> {
+        self.send_calibration(json)
+    }
+    /// Called before the first pattern of a run. Servers that can report what they're
+    /// showing save it here so `end_session` can put it back.
+    fn begin_session(&self) -> Result<(), NetworkError> {
+        Ok(())
+    }
+    /// Called after the last pattern, whether the run succeeded or not. By default the
+    /// projector is blanked so the chessboard isn't left on screen.
+    fn end_session(&self) -> Result<(), NetworkError> {
+        self.blank()
+    }
+}
+
+/// Commands bracketing a run, for servers that can save and restore what they were showing.
+/// They're endpoints for the raw and multipart protocols and command names for the JSON one.
+#[derive(Default)]
+pub struct SessionCommands {
+    /// asked for the current state before the first pattern, its reply is kept
+    pub save_state: Option<String>,
+    /// sent the kept reply after the last pattern. The projector is blanked instead when None.
+    pub restore: Option<String>,
+    saved: Mutex<Option<String>>,
+}
+
+impl SessionCommands {
+    pub fn new(save_state: Option<String>, restore: Option<String>) -> SessionCommands {
+        SessionCommands {save_state: save_state, restore: restore, saved: Mutex::new(None)}
+    }
+
+    fn keep(&self, reply: String) {
+        *self.saved.lock().unwrap() = Some(reply);
+    }
+
+    fn take(&self) -> Option<String> {
+        self.saved.lock().unwrap().take()
+    }
+}
+
+/// Brackets a run on the control servers: `begin` starts a session on the measured projector
+/// and on the others, then blanks the others so they don't light the surface. Dropping the
+/// guard ends every session, on the error path too. Errors while ending are only logged.
+pub struct SessionGuard<'a> {
+    started: Vec<&'a dyn ControlProtocol>,
+}
+
+impl<'a> SessionGuard<'a> {
+    pub fn begin(measured: Option<&'a dyn ControlProtocol>, others: Vec<&'a dyn ControlProtocol>) -> Result<SessionGuard<'a>, NetworkError> {
+        let mut guard = SessionGuard {started: vec![]};
+        for protocol in measured.into_iter().chain(others.iter().cloned()) {
+            protocol.begin_session()?;
+            guard.started.push(protocol);
+        }
+        for other in others {
+            other.blank()?;
+        }
+        Ok(guard)
+    }
+}
+
+impl<'a> Drop for SessionGuard<'a> {
+    fn drop(&mut self) {
+        for protocol in self.started.iter().rev() {
+            if let Err(err) = protocol.end_session() {
+                warn!("couldn't restore the control server after the run: {}", err);
+            }
+        }
+    }
+}
+
+/// Blank every projector except the one at index `active`, so only it lights the surface
+pub fn blank_all_except(projectors: &[&dyn ControlProtocol], active: usize) -> Result<(), NetworkError> {
+    for (i, projector) in projectors.iter().enumerate() {
+        if i != active {
+            projector.blank()?;
+        }
+    }
+    Ok(())
+}
+
+/// The original protocol: raw image bytes POSTed to `{url}/show_image` and the calibration
+/// JSON POSTed to `{url}/set_calibration` (`?eye={name}` for per-eye calibrations)
+pub struct RawPostProtocol {
+    pub url: String,
+    pub config: NetworkConfig,
+    pub image_endpoint: String,
+    pub calibration_endpoint: String,
+    pub session: SessionCommands,
+    pub pattern_encoding: ImageEncoding,
+}
+
+impl RawPostProtocol {
+    pub fn new(url: &str, config: NetworkConfig) -> RawPostProtocol {
+        RawPostProtocol {
+            url: url.to_string(),
+            config: config,
+            image_endpoint: "show_image".to_string(),
+            calibration_endpoint: "set_calibration".to_string(),
+            session: SessionCommands::default(),
+            pattern_encoding: ImageEncoding::PATTERN_DEFAULT,
+        }
+    }
+}
+
+impl ControlProtocol for RawPostProtocol {
+    fn display_image(&self, image_bytes: &[u8], format: &str) -> Result<(), NetworkError> {
+        let url = format!("{}/{}", self.url, self.image_endpoint);
+        network::post_image(&self.config, &url, image_bytes, format)?;
+        Ok(())
+    }
+
+    fn pattern_encoding(&self) -> ImageEncoding {
+        self.pattern_encoding
+    }
+
+    fn blank(&self) -> Result<(), NetworkError> {
+        self.display_image(images::pixel_png(0, 0, 0).to_slice(), "png")
+    }
+
+    fn send_calibration(&self, json: &str) -> Result<CommandResponse, NetworkError> {
+        network::send_command(&self.config, &self.url, &self.calibration_endpoint, json)
+    }
+
+    fn send_eye_calibration(&self, eye_name: &str, json: &str) -> Result<CommandResponse, NetworkError> {
+        network::send_command_with_query(&self.config, &self.url, &self.calibration_endpoint, &[("eye", eye_name)], json)
+    }
+
+    fn begin_session(&self) -> Result<(), NetworkError> {
+        begin_endpoint_session(&self.config, &self.url, &self.session)
+    }
+
+    fn end_session(&self) -> Result<(), NetworkError> {
+        end_endpoint_session(&self.config, &self.url, &self.session, self)
+    }
+}
+
+/// The save endpoint is POSTed an empty JSON object and its reply body is kept
+fn begin_endpoint_session(config: &NetworkConfig, url: &str, session: &SessionCommands) -> Result<(), NetworkError> {
+    if let Some(endpoint) = &session.save_state {
+        let reply = network::send_command(config, url, endpoint, "{}")?;
+        session.keep(reply.body);
+    }
+    Ok(())
+}
+
+/// The restore endpoint is POSTed the kept reply, or an empty JSON object when nothing was saved
+fn end_endpoint_session(config: &NetworkConfig, url: &str, session: &SessionCommands, protocol: &dyn ControlProtocol) -> Result<(), NetworkError> {
+    match &session.restore {
+        Some(endpoint) => {
+            let saved = session.take().unwrap_or_else(|| "{}".to_string());
+            network::send_command(config, url, endpoint, &saved)?;
+            Ok(())
+        },
+        None => protocol.blank()
+    }
+}
+
+/// Pattern images uploaded as a multipart form (e.g. to `/api/v1/pattern`), calibration
+/// posted as JSON
+pub struct MultipartProtocol {
+    pub url: String,
+    pub config: NetworkConfig,
+    pub image_endpoint: String,
+    /// name of the form field carrying the image
+    pub field_name: String,
+    pub calibration_endpoint: String,
+    pub session: SessionCommands,
+    pub pattern_encoding: ImageEncoding,
+}
+
+impl MultipartProtocol {
+    pub fn new(url: &str, config: NetworkConfig) -> MultipartProtocol {
+        MultipartProtocol {
+            url: url.to_string(),
+            config: config,
+            image_endpoint: "api/v1/pattern".to_string(),
+            field_name: "image".to_string(),
+            calibration_endpoint: "api/v1/calibration".to_string(),
+            session: SessionCommands::default(),
+            pattern_encoding: ImageEncoding::PATTERN_DEFAULT,
+        }
+    }
+}
+
+impl ControlProtocol for MultipartProtocol {
+    fn display_image(&self, image_bytes: &[u8], format: &str) -> Result<(), NetworkError> {
+        let url = format!("{}/{}", self.url, self.image_endpoint);
+        // built by hand so it can go through a transport, and with a boundary taken from the
+        // image so a replayed upload is byte for byte the recorded one
+        let boundary = format!("aligner-{}", fnv1a_hex(image_bytes));
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"pattern.{}\"\r\nContent-Type: image/{}\r\n\r\n",
+            boundary, self.field_name, format, format).into_bytes();
+        body.extend_from_slice(image_bytes);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        let request = self.config.request(&url, &format!("multipart/form-data; boundary={}", boundary), body);
+        transport::send_command_request(&self.config, &request)?;
+        Ok(())
+    }
+
+    fn pattern_encoding(&self) -> ImageEncoding {
+        self.pattern_encoding
+    }
+
+    fn blank(&self) -> Result<(), NetworkError> {
+        self.display_image(images::pixel_png(0, 0, 0).to_slice(), "png")
+    }
+
+    fn send_calibration(&self, json: &str) -> Result<CommandResponse, NetworkError> {
+        network::send_command(&self.config, &self.url, &self.calibration_endpoint, json)
+    }
+
+    fn send_eye_calibration(&self, eye_name: &str, json: &str) -> Result<CommandResponse, NetworkError> {
+        network::send_command_with_query(&self.config, &self.url, &self.calibration_endpoint, &[("eye", eye_name)], json)
+    }
+
+    fn begin_session(&self) -> Result<(), NetworkError> {
+        begin_endpoint_session(&self.config, &self.url, &self.session)
+    }
+
+    fn end_session(&self) -> Result<(), NetworkError> {
+        end_endpoint_session(&self.config, &self.url, &self.session, self)
+    }
+}
+
+/// Every operation is a JSON command `{"command": ..., ...}` POSTed to a single endpoint,
+/// with images embedded as base64
+pub struct JsonCommandProtocol {
+    pub url: String,
+    pub config: NetworkConfig,
+    pub endpoint: String,
+    pub show_image_command: String,
+    pub blank_command: String,
+    pub calibration_command: String,
+    /// the save command's reply is sent back as `state` with the restore command
+    pub session: SessionCommands,
+    pub pattern_encoding: ImageEncoding,
+}
+
+impl JsonCommandProtocol {
+    pub fn new(url: &str, config: NetworkConfig) -> JsonCommandProtocol {
+        JsonCommandProtocol {
+            url: url.to_string(),
+            config: config,
+            endpoint: "command".to_string(),
+            show_image_command: "show_image".to_string(),
+            blank_command: "blank".to_string(),
+            calibration_command: "set_calibration".to_string(),
+            session: SessionCommands::default(),
+            pattern_encoding: ImageEncoding::PATTERN_DEFAULT,
+        }
+    }
+
+    fn command(&self, body: serde_json::Value) -> Result<CommandResponse, NetworkError> {
+        network::send_command(&self.config, &self.url, &self.endpoint, &body.to_string())
+    }
+}
+
+impl ControlProtocol for JsonCommandProtocol {
+    fn display_image(&self, image_bytes: &[u8], format: &str) -> Result<(), NetworkError> {
+        self.command(json!({
+            "command": self.show_image_command,
+            "format": format,
+            "data": base64::encode(image_bytes)
+        }))?;
+        Ok(())
+    }
+
+    fn pattern_encoding(&self) -> ImageEncoding {
+        self.pattern_encoding
+    }
+
+    fn blank(&self) -> Result<(), NetworkError> {
+        self.command(json!({"command": self.blank_command}))?;
+        Ok(())
+    }
+
+    fn send_calibration(&self, json: &str) -> Result<CommandResponse, NetworkError> {
+        let calibration: serde_json::Value = serde_json::from_str(json)
+            .map_err(|err| NetworkError::InvalidPayload(err.to_string()))?;
+        self.command(json!({
+            "command": self.calibration_command,
+            "calibration": calibration
+        }))
+    }
+
+    fn send_eye_calibration(&self, eye_name: &str, json: &str) -> Result<CommandResponse, NetworkError> {
+        let calibration: serde_json::Value = serde_json::from_str(json)
+            .map_err(|err| NetworkError::InvalidPayload(err.to_string()))?;
+        self.command(json!({
+            "command": self.calibration_command,
+            "eye": eye_name,
+            "calibration": calibration
+        }))
+    }
+
+    fn begin_session(&self) -> Result<(), NetworkError> {
+        if let Some(command) = &self.session.save_state {
+            let reply = self.command(json!({"command": command}))?;
+            self.session.keep(reply.json.map(|state| state.to_string()).unwrap_or(reply.body));
+        }
+        Ok(())
+    }
+
+    fn end_session(&self) -> Result<(), NetworkError> {
+        match &self.session.restore {
+            Some(command) => {
+                let state = self.session.take()
+                    .map(|saved| serde_json::from_str(&saved).unwrap_or(serde_json::Value::String(saved)))
+                    .unwrap_or(serde_json::Value::Null);
+                self.command(json!({"command": command, "state": state}))?;
+                Ok(())
+            },
+            None => self.blank()
+        }
+    }
+}
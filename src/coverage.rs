@@ -0,0 +1,196 @@
+//! How much of the camera frame and the projector raster the detected chessboard covers. A
+//! board that only fills part of either gives a calibration that's only good for that part.
+
+use glm::*;
+use serde::{Serialize, Deserialize};
+
+/// The operator is warned when the board covers less of the camera frame than this
+pub const CAMERA_FRACTION_WARNING: f32 = 0.3;
+/// The operator is warned when the board's centroid is further than this from the center
+/// of the camera frame, as a fraction of the half frame
+pub const CENTROID_OFFSET_WARNING: f32 = 0.35;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Coverage {
+    /// area of the convex hull of the detected corners over the area of the camera frame
+    pub camera_fraction: f32,
+    /// centroid of that hull relative to the center of the frame, -1 to 1 across it
+    pub camera_centroid: [f32; 2],
+    /// area of the hull of the detected corners' positions in the pattern over the whole
+    /// projector raster, when the pattern layout is known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub projector_fraction: Option<f32>,
+    /// guidance logged for the operator
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+/// Measure the coverage of corners detected in a frame_width x frame_height photo.
+/// projector_points are the same corners' positions in the projected pattern, 0-1 across it.
+pub fn measure(camera_points: &[glm::Vec2], frame_width: i32, frame_height: i32, projector_points: Option<&[glm::Vec2]>) -> Coverage {
+    let (w, h) = (frame_width.max(1) as f32, frame_height.max(1) as f32);
+    let hull = convex_hull(camera_points);
+    let camera_fraction = area(&hull) / (w * h);
+    let center = centroid(&hull);
+    let offset = vec2((center.x / w) * 2. - 1., (center.y / h) * 2. - 1.);
+
+    let mut warnings = vec![];
+    if camera_fraction < CAMERA_FRACTION_WARNING {
+        warnings.push(format!(
+            "chessboard occupies only {:.0}% of the camera frame; move the camera closer or zoom in",
+            camera_fraction * 100.
+        ));
+    }
+    if length(offset) > CENTROID_OFFSET_WARNING {
+        let horizontal = if offset.x < -CENTROID_OFFSET_WARNING / 2. { "left" } else if offset.x > CENTROID_OFFSET_WARNING / 2. { "right" } else { "" };
+        let vertical = if offset.y < -CENTROID_OFFSET_WARNING / 2. { "top" } else if offset.y > CENTROID_OFFSET_WARNING / 2. { "bottom" } else { "" };
+        let side = if horizontal.is_empty() || vertical.is_empty() {
+            format!("{}{}", vertical, horizontal)
+        } else {
+            format!("{} {}", vertical, horizontal)
+        };
+        warnings.push(format!(
+            "chessboard is off center, towards the {} of the camera frame; aim the camera at the middle of the projection",
+            side
+        ));
+    }
+
+    Coverage {
+        camera_fraction: camera_fraction,
+        camera_centroid: [offset.x, offset.y],
+        projector_fraction: projector_points.map(|points| area(&convex_hull(points))),
+        warnings: warnings,
+    }
+}
+
+/// Convex hull of points, anticlockwise in a y-down image (monotone chain)
+fn convex_hull(points: &[glm::Vec2]) -> Vec<glm::Vec2> {
+    let mut sorted: Vec<glm::Vec2> = points.to_vec();
+    sorted.sort_by(|a, b| a.x.partial_cmp(&b.x).unwrap().then(a.y.partial_cmp(&b.y).unwrap()));
+    if sorted.len() < 3 {
+        return sorted;
+    }
+    let cross = |o: glm::Vec2, a: glm::Vec2, b: glm::Vec2| (a.x - o.x) * (b.y - o.y) - (a.y - o.y) * (b.x - o.x);
+    let mut hull: Vec<glm::Vec2> = vec![];
+    for pass in 0..2 {
+        let start = hull.len();
+        let ordered: Box<dyn Iterator<Item = &glm::Vec2>> = if pass == 0 { Box::new(sorted.iter()) } else { Box::new(sorted.iter().rev()) };
+        for p in ordered {
+            while hull.len() >= start + 2 && cross(hull[hull.len() - 2], hull[hull.len() - 1], *p) <= 0. {
+                hull.pop();
+            }
+            hull.push(*p);
+        }
+        // the last point of each half starts the other
+        hull.pop();
+    }
+    hull
+}
+
+/// Area of a polygon (shoelace formula)
+fn area(polygon: &[glm::Vec2]) -> f32 {
+    if polygon.len() < 3 {
+        return 0.;
+    }
+    let mut sum = 0.;
+    for i in 0..polygon.len() {
+        let (a, b) = (polygon[i], polygon[(i + 1) % polygon.len()]);
+        sum += a.x * b.y - b.x * a.y;
+    }
+    sum.abs() / 2.
+}
+
+/// Centroid of a polygon, the mean of its points when it has no area
+fn centroid(polygon: &[glm::Vec2]) -> glm::Vec2 {
+    let signed_area: f32 = (0..polygon.len()).map(|i| {
+        let (a, b) = (polygon[i], polygon[(i + 1) % polygon.len()]);
+        a.x * b.y - b.x * a.y
+    }).sum::<f32>() / 2.;
+    if signed_area.abs() < 1e-6 {
+        let mut sum = vec2(0., 0.);
+        for p in polygon { sum = sum + *p; }
+        return sum / polygon.len().max(1) as f32;
+    }
+    let mut c = vec2(0., 0.);
+    for i in 0..polygon.len() {
+        let (a, b) = (polygon[i], polygon[(i + 1) % polygon.len()]);
+        let cross = a.x * b.y - b.x * a.y;
+        c = c + (a + b) * cross;
+    }
+    c / (6. * signed_area)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// cols x rows points evenly spread from min to max
+    fn grid_points(cols: i32, rows: i32, min: glm::Vec2, max: glm::Vec2) -> Vec<glm::Vec2> {
+        let mut points = vec![];
+        for row in 0..rows {
+            for col in 0..cols {
+                let t = vec2(col as f32 / (cols - 1) as f32, row as f32 / (rows - 1) as f32);
+                points.push(min + (max - min) * t);
+            }
+        }
+        points
+    }
+
+    #[test]
+    fn hull_of_a_grid_is_its_outer_corners() {
+        let hull = convex_hull(&grid_points(5, 4, vec2(10., 20.), vec2(50., 80.)));
+        assert_eq!(hull.len(), 4);
+        for corner in [vec2(10., 20.), vec2(50., 20.), vec2(10., 80.), vec2(50., 80.)].iter() {
+            assert!(hull.contains(corner), "{:?} isn't in {:?}", corner, hull);
+        }
+        assert_eq!(convex_hull(&[vec2(1., 1.), vec2(0., 0.)]), vec![vec2(0., 0.), vec2(1., 1.)]);
+    }
+
+    #[test]
+    fn polygon_area_and_centroid() {
+        let rectangle = [vec2(10., 10.), vec2(20., 10.), vec2(20., 30.), vec2(10., 30.)];
+        assert!((area(&rectangle) - 200.).abs() < 1e-4);
+        assert!(length(centroid(&rectangle) - vec2(15., 20.)) < 1e-4);
+        // either winding
+        let mut reversed = rectangle.to_vec();
+        reversed.reverse();
+        assert!((area(&reversed) - 200.).abs() < 1e-4);
+        assert!(length(centroid(&reversed) - vec2(15., 20.)) < 1e-4);
+
+        let triangle = [vec2(0., 0.), vec2(6., 0.), vec2(0., 6.)];
+        assert!((area(&triangle) - 18.).abs() < 1e-4);
+        assert!(length(centroid(&triangle) - vec2(2., 2.)) < 1e-4);
+
+        // no area, the mean of the points
+        let line = [vec2(0., 0.), vec2(4., 2.)];
+        assert_eq!(area(&line), 0.);
+        assert!(length(centroid(&line) - vec2(2., 1.)) < 1e-4);
+    }
+
+    #[test]
+    fn centered_board_filling_the_frame_has_no_warnings() {
+        let coverage = measure(&grid_points(9, 6, vec2(100., 100.), vec2(900., 900.)), 1000, 1000, Some(&grid_points(9, 6, vec2(0., 0.), vec2(1., 1.))));
+        assert!((coverage.camera_fraction - 0.64).abs() < 1e-4);
+        assert!(coverage.camera_centroid[0].abs() < 1e-4 && coverage.camera_centroid[1].abs() < 1e-4);
+        assert!((coverage.projector_fraction.unwrap() - 1.).abs() < 1e-4);
+        assert!(coverage.warnings.is_empty(), "{:?}", coverage.warnings);
+    }
+
+    #[test]
+    fn small_and_off_center_boards_are_warned_about() {
+        let small = measure(&grid_points(9, 6, vec2(375., 375.), vec2(625., 625.)), 1000, 1000, None);
+        assert!((small.camera_fraction - 0.0625).abs() < 1e-4);
+        assert_eq!(small.projector_fraction, None);
+        assert_eq!(small.warnings.len(), 1);
+        assert!(small.warnings[0].contains("only 6%"), "{}", small.warnings[0]);
+
+        let corner = measure(&grid_points(9, 6, vec2(0., 0.), vec2(600., 600.)), 1000, 1000, None);
+        assert!((corner.camera_centroid[0] + 0.4).abs() < 1e-4 && (corner.camera_centroid[1] + 0.4).abs() < 1e-4);
+        assert_eq!(corner.warnings.len(), 1);
+        assert!(corner.warnings[0].contains("towards the top left"), "{}", corner.warnings[0]);
+
+        let right = measure(&grid_points(9, 6, vec2(500., 100.), vec2(1000., 900.)), 1000, 1000, None);
+        assert!(right.warnings.iter().any(|warning| warning.contains("towards the right")), "{:?}", right.warnings);
+    }
+}
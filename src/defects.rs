@@ -0,0 +1,258 @@
+//! Camera sensor and lens defects, measured from a dark frame (lens covered) and a flat frame
+//! (camera evenly lit, e.g. through a diffuser) and corrected in every photo before it's
+//! undistorted. The flat frame divides out the vignetting. Hot and dead pixels and lens dirt
+//! are masked and filled in from their neighbours, and the corners refined over them are
+//! trusted less (see `confidence::MASKED`).
+//!
+//! The derived mask and gain are cached as PNGs named after the camera calibration file's
+//! hash, so later runs with the same calibration don't need the frames again.
+
+use opencv::prelude::*;
+use opencv::core::{self, Mat, Rect, Size, Scalar, StsError, CV_8U, CV_16U, CV_32F};
+use opencv::imgcodecs;
+use opencv::imgproc::{cvt_color, median_blur, resize, COLOR_BGR2GRAY, COLOR_BGRA2GRAY, INTER_AREA, INTER_LINEAR};
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use super::{Error, camera_calibration, photo, pipeline};
+use super::camera_calibration::Calibration;
+use super::prompt::OperatorPrompt;
+
+/// Dark frame level (of white) above which a pixel is hot
+pub const HOT_LEVEL: f32 = 0.1;
+/// How far a pixel of the flat frame may be from its smoothed surroundings before it's masked
+pub const DEFECT_DEVIATION: f32 = 0.15;
+/// Largest correction of the vignetting, either way
+pub const MAX_GAIN: f32 = 4.;
+/// Flat frames dimmer than this (of white) once the dark frame is taken off are rejected
+const MIN_FLAT_LEVEL: f64 = 0.05;
+/// Width the flat frame is shrunk to to smooth out everything but the vignetting
+const SMOOTH_WIDTH: i32 = 64;
+/// Gain is cached as a 16-bit PNG of gain times this
+const GAIN_SCALE: f64 = 16000.;
+
+/// Where the dark and flat frames come from
+#[derive(Clone, Debug)]
+pub enum DefectFrames {
+    /// image files, at the camera's resolution
+    Files {dark: String, flat: String},
+    /// ask the operator to cover and then evenly light the camera and take a photo of each
+    Capture,
+}
+
+/// How camera defects are found, see `prepare`
+#[derive(Clone, Debug, Default)]
+pub struct DefectOptions {
+    pub frames: Option<DefectFrames>,
+    /// directory the derived mask and gain are cached in
+    pub cache_dir: Option<String>,
+}
+
+/// What's corrected in each photo from one camera
+pub struct CameraDefects {
+    /// 255 for pixels filled in from their neighbours, in photo pixels, CV_8U
+    pub mask: Mat,
+    /// the mask after undistortion, for judging corners found in undistorted photos
+    pub undistorted_mask: Mat,
+    /// what each pixel is multiplied by to even out the vignetting, CV_32F
+    pub gain: Mat,
+    /// pixels in mask
+    pub masked: usize,
+}
+
+impl CameraDefects {
+    fn new(mask: Mat, gain: Mat, calibration: &Calibration) -> opencv::Result<CameraDefects> {
+        // anything the interpolation touches counts
+        let mut undistorted_mask = Mat::default()?;
+        opencv::calib3d::undistort(&mask, &mut undistorted_mask, &calibration.camera_matrix, &calibration.distortion_coefficients, &calibration.camera_matrix)?;
+        let masked = core::count_non_zero(&mask)? as usize;
+        Ok(CameraDefects {mask: mask, undistorted_mask: undistorted_mask, gain: gain, masked: masked})
+    }
+
+    /// Derive the defects from a dark and a flat frame, decoded photos at the calibration's
+    /// resolution
+    pub fn from_frames(dark: &Mat, flat: &Mat, calibration: &Calibration) -> Result<CameraDefects, Error> {
+        let (w, h) = (calibration.image_width, calibration.image_height);
+        for (name, frame) in &[("dark", dark), ("flat", flat)] {
+            if frame.cols() != w || frame.rows() != h {
+                return Err(Error::Config(format!("the {} frame is {}x{} but the camera calibration is {}x{}", name, frame.cols(), frame.rows(), w, h)));
+            }
+        }
+        let dark = normalized_greyscale(dark)?;
+        let flat = normalized_greyscale(flat)?;
+        let dark_values = dark.data_typed::<f32>()?;
+        let lit: Vec<f32> = flat.data_typed::<f32>()?.iter().zip(dark_values.iter()).map(|(f, d)| f - d).collect();
+        let mean = lit.iter().map(|v| *v as f64).sum::<f64>() / lit.len() as f64;
+        if mean < MIN_FLAT_LEVEL {
+            return Err(Error::Config(format!(
+                "the flat frame is only {:.1}% of white once the dark frame is taken off, light the camera more brightly",
+                mean * 100.
+            )));
+        }
+
+        // the vignetting is what's left once the frame is shrunk and blown up again
+        let mut small = Mat::default()?;
+        let small_height = ((SMOOTH_WIDTH * h) as f32 / w as f32).round().max(1.) as i32;
+        resize(&filled_mat(&lit, w, h, CV_32F)?, &mut small, Size::new(SMOOTH_WIDTH, small_height), 0., 0., INTER_AREA)?;
+        let mut smooth = Mat::default()?;
+        resize(&small, &mut smooth, Size::new(w, h), 0., 0., INTER_LINEAR)?;
+        let smooth = smooth.data_typed::<f32>()?;
+
+        let mut bad = vec![false; lit.len()];
+        let mut gain = vec![1_f32; lit.len()];
+        for i in 0..lit.len() {
+            let ratio = if smooth[i] > 1e-6 { lit[i] / smooth[i] } else { 0. };
+            bad[i] = dark_values[i] > HOT_LEVEL || (ratio - 1.).abs() > DEFECT_DEVIATION;
+            gain[i] = if smooth[i] > 1e-6 { (mean as f32 / smooth[i]).max(1. / MAX_GAIN).min(MAX_GAIN) } else { MAX_GAIN };
+        }
+        // grow the mask by a pixel, defects bleed into their neighbours
+        let mut mask = vec![0_u8; lit.len()];
+        for y in 0..h {
+            for x in 0..w {
+                let near = (-1..=1).any(|dy| (-1..=1).any(|dx| {
+                    let (nx, ny) = (x + dx, y + dy);
+                    nx >= 0 && ny >= 0 && nx < w && ny < h && bad[(ny * w + nx) as usize]
+                }));
+                if near {
+                    mask[(y * w + x) as usize] = 255;
+                }
+            }
+        }
+        Ok(CameraDefects::new(filled_mat(&mask, w, h, CV_8U)?, filled_mat(&gain, w, h, CV_32F)?, calibration)?)
+    }
+
+    /// The photo with the vignetting evened out and the masked pixels filled in, at its own
+    /// depth and channels
+    pub fn correct(&self, photo: &Mat) -> opencv::Result<Mat> {
+        if photo.cols() != self.gain.cols() || photo.rows() != self.gain.rows() {
+            return Err(opencv::Error::new(StsError, format!(
+                "the photo is {}x{} but the camera defects were measured at {}x{}",
+                photo.cols(), photo.rows(), self.gain.cols(), self.gain.rows()
+            )));
+        }
+        let channels = photo.channels()? as usize;
+        let mut values = Mat::default()?;
+        photo.convert_to(&mut values, CV_32F, 1., 0.)?;
+        {
+            let mut single = values.reshape(1, 0)?;
+            let gain = self.gain.data_typed::<f32>()?;
+            for (i, value) in single.data_typed_mut::<f32>()?.iter_mut().enumerate() {
+                *value *= gain[i / channels];
+            }
+        }
+        let mut corrected = Mat::default()?;
+        values.convert_to(&mut corrected, photo.depth()?, 1., 0.)?;
+        let mut filled = Mat::default()?;
+        median_blur(&corrected, &mut filled, 5)?;
+        filled.copy_to_masked(&mut corrected, &self.mask)?;
+        Ok(corrected)
+    }
+
+    /// Any masked pixel in rect of the undistorted photo
+    pub fn masks_undistorted(&self, rect: Rect) -> opencv::Result<bool> {
+        let (x0, y0) = (rect.x.max(0), rect.y.max(0));
+        let x1 = (rect.x + rect.width).min(self.undistorted_mask.cols());
+        let y1 = (rect.y + rect.height).min(self.undistorted_mask.rows());
+        if x1 <= x0 || y1 <= y0 {
+            return Ok(false);
+        }
+        Ok(core::count_non_zero(&Mat::roi(&self.undistorted_mask, Rect::new(x0, y0, x1 - x0, y1 - y0))?)? > 0)
+    }
+
+    /// Write the mask and gain into dir, named after the calibration file
+    pub fn save(&self, dir: &str, calibration_fname: &str) -> Result<(), Error> {
+        std::fs::create_dir_all(dir)?;
+        let (mask_path, gain_path) = cache_paths(dir, calibration_fname)?;
+        let mut gain = Mat::default()?;
+        self.gain.convert_to(&mut gain, CV_16U, GAIN_SCALE, 0.)?;
+        imgcodecs::imwrite(&mask_path.to_string_lossy(), &self.mask, &opencv::types::VectorOfi32::new())?;
+        imgcodecs::imwrite(&gain_path.to_string_lossy(), &gain, &opencv::types::VectorOfi32::new())?;
+        Ok(())
+    }
+
+    /// The defects saved in dir for the calibration file, None when there aren't any
+    pub fn load(dir: &str, calibration_fname: &str, calibration: &Calibration) -> Result<Option<CameraDefects>, Error> {
+        let (mask_path, gain_path) = cache_paths(dir, calibration_fname)?;
+        if !mask_path.exists() || !gain_path.exists() {
+            return Ok(None);
+        }
+        let mask = imgcodecs::imread(&mask_path.to_string_lossy(), imgcodecs::IMREAD_GRAYSCALE)?;
+        let stored = imgcodecs::imread(&gain_path.to_string_lossy(), imgcodecs::IMREAD_ANYDEPTH)?;
+        if mask.cols() != calibration.image_width || mask.rows() != calibration.image_height || stored.size()? != mask.size()? {
+            warn!("the cached camera defects in {} don't match the camera's resolution, ignoring them", dir);
+            return Ok(None);
+        }
+        let mut gain = Mat::default()?;
+        stored.convert_to(&mut gain, CV_32F, 1. / GAIN_SCALE, 0.)?;
+        Ok(Some(CameraDefects::new(mask, gain, calibration)?))
+    }
+}
+
+/// The camera's defects as options say: from the cache when it has them, unless frame files
+/// are given, otherwise derived from the frames and cached. None when there's nothing to
+/// derive them from. Frames are captured with camera_type after asking through prompt.
+pub fn prepare(options: &DefectOptions, calibration_fname: &str, calibration: &Calibration, camera_type: photo::CameraType, prompt: &dyn OperatorPrompt) -> Result<Option<CameraDefects>, Error> {
+    if let Some(dir) = &options.cache_dir {
+        let files_given = match options.frames { Some(DefectFrames::Files {..}) => true, _ => false };
+        if !files_given {
+            if let Some(defects) = CameraDefects::load(dir, calibration_fname, calibration)? {
+                info!("camera defects loaded from {}, {} pixels masked", dir, defects.masked);
+                return Ok(Some(defects));
+            }
+        }
+    }
+    let (dark, flat) = match &options.frames {
+        Some(DefectFrames::Files {dark, flat}) => (read_frame(dark)?, read_frame(flat)?),
+        Some(DefectFrames::Capture) => {
+            prompt.wait("cover the camera lens for the dark frame")?;
+            let dark = pipeline::decode_photo(&photo::capture_photo(camera_type.clone()))?;
+            prompt.wait("light the camera evenly for the flat frame, e.g. with a diffuser held over the lens")?;
+            let flat = pipeline::decode_photo(&photo::capture_photo(camera_type))?;
+            (dark, flat)
+        },
+        None => {
+            if let Some(dir) = &options.cache_dir {
+                warn!("no camera defects are cached in {} for {}, the photos are used as they are", dir, calibration_fname);
+            }
+            return Ok(None);
+        }
+    };
+    let defects = CameraDefects::from_frames(&dark, &flat, calibration)?;
+    info!("{} camera pixels masked as defects", defects.masked);
+    if let Some(dir) = &options.cache_dir {
+        defects.save(dir, calibration_fname)?;
+    }
+    Ok(Some(defects))
+}
+
+fn cache_paths(dir: &str, calibration_fname: &str) -> Result<(PathBuf, PathBuf), Error> {
+    let hash = camera_calibration::file_identity(calibration_fname)?.hash;
+    let dir = Path::new(dir);
+    Ok((dir.join(format!("defects-{}-mask.png", hash)), dir.join(format!("defects-{}-gain.png", hash))))
+}
+
+fn read_frame(path: &str) -> Result<Mat, Error> {
+    let frame = imgcodecs::imread(path, imgcodecs::IMREAD_ANYDEPTH | imgcodecs::IMREAD_ANYCOLOR)?;
+    if frame.empty()? {
+        return Err(Error::Config(format!("can't read the defect frame {}", path)));
+    }
+    Ok(frame)
+}
+
+/// A single channel CV_32F copy of a frame, 0-1 of its white level
+fn normalized_greyscale(frame: &Mat) -> opencv::Result<Mat> {
+    let mut grey = Mat::default()?;
+    match frame.channels()? {
+        1 => frame.copy_to(&mut grey)?,
+        4 => cvt_color(frame, &mut grey, COLOR_BGRA2GRAY, 1)?,
+        _ => cvt_color(frame, &mut grey, COLOR_BGR2GRAY, 1)?,
+    }
+    let mut out = Mat::default()?;
+    grey.convert_to(&mut out, CV_32F, 1. / pipeline::white_level(frame)?, 0.)?;
+    Ok(out)
+}
+
+fn filled_mat<T: core::DataType>(values: &[T], width: i32, height: i32, typ: i32) -> opencv::Result<Mat> {
+    let mut mat = Mat::new_rows_cols_with_default(height, width, typ, Scalar::all(0.))?;
+    mat.data_typed_mut::<T>()?.copy_from_slice(values);
+    Ok(mat)
+}
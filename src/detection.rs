@@ -0,0 +1,207 @@
+//! Preprocessing variants tried when the chessboard isn't found in the photo as is. Dark or
+//! high gain surfaces often need the contrast stretched or a different threshold before
+//! opencv sees the squares.
+
+#[cfg(feature = "opencv")]
+use opencv::{prelude::*, core::{self, Mat, Size}, imgproc::*};
+use glm::vec2;
+use serde::{Serialize, Deserialize};
+use std::time::Duration;
+
+/// How the detection image is adjusted before looking for corners
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Preprocess {
+    None,
+    /// global histogram equalization
+    Equalize,
+    /// contrast limited adaptive histogram equalization
+    #[serde(rename_all = "camelCase")]
+    Clahe {clip_limit: f64},
+    /// below 1 brightens the shadows, above 1 darkens them
+    Gamma {gamma: f32},
+    /// binarize with a local mean threshold over block_size pixels (odd) first
+    #[serde(rename_all = "camelCase")]
+    AdaptiveThreshold {block_size: i32},
+}
+
+/// Which way round the chessboard's squares are looked for
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Polarity {
+    /// detect on the inverted greyscale photo, which suits the chessboard on a dark surface
+    Invert,
+    /// detect on the greyscale photo as is, often better on light grey screens
+    NoInvert,
+    /// try inverted first then as is, keeping whichever finds the board
+    Auto,
+}
+
+impl Default for Polarity {
+    fn default() -> Polarity {
+        Polarity::Invert
+    }
+}
+
+impl Polarity {
+    pub fn parse(input: &str) -> Result<Polarity, &'static str> {
+        match input {
+            "invert" => Ok(Polarity::Invert),
+            "no-invert" => Ok(Polarity::NoInvert),
+            "auto" => Ok(Polarity::Auto),
+            _ => Err("polarity must be invert, no-invert or auto")
+        }
+    }
+
+    fn inverts(&self) -> &'static [bool] {
+        match self {
+            Polarity::Invert => &[true],
+            Polarity::NoInvert => &[false],
+            Polarity::Auto => &[true, false],
+        }
+    }
+}
+
+/// The part of the undistorted photo the chessboard is looked for in. Everything outside
+/// is set to mid grey before detection, so other lit structures in view can't be mistaken
+/// for the board.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum DetectionRoi {
+    /// a rectangle in photo pixels
+    Rect {x: f32, y: f32, width: f32, height: f32},
+    /// the corners of a polygon in photo pixels, in order round its edge
+    Polygon {points: Vec<[f32; 2]>},
+    /// wherever a photo of the chessboard differs from a photo of a black frame by more
+    /// than threshold (of 255). Costs an extra frame.
+    Auto {threshold: f64},
+}
+
+impl DetectionRoi {
+    /// The polygon given by the user, None for Auto
+    pub fn polygon(&self) -> Option<Vec<glm::Vec2>> {
+        match self {
+            DetectionRoi::Rect {x, y, width, height} => Some(vec![
+                vec2(*x, *y), vec2(x + width, *y), vec2(x + width, y + height), vec2(*x, y + height)
+            ]),
+            DetectionRoi::Polygon {points} => Some(points.iter().map(|p| vec2(p[0], p[1])).collect()),
+            DetectionRoi::Auto {..} => None,
+        }
+    }
+}
+
+/// One way of preparing the photo for corner detection
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DetectionVariant {
+    /// the photo was inverted before detection, see `Polarity`
+    pub invert: bool,
+    pub preprocess: Preprocess,
+}
+
+impl Default for DetectionVariant {
+    fn default() -> DetectionVariant {
+        DetectionVariant {invert: true, preprocess: Preprocess::None}
+    }
+}
+
+/// How hard to try to find the chessboard
+#[derive(Clone, Debug)]
+pub struct DetectionOptions {
+    /// times the pattern is shown and photographed before giving up
+    pub attempts: u32,
+    /// always use this variant, e.g. the one a previous run's diagnostics reported
+    pub variant: Option<DetectionVariant>,
+    /// which polarities to try, ignored when variant is given
+    pub polarity: Polarity,
+    /// try the other preprocessing of `SWEEP` when the first one fails
+    pub sweep: bool,
+    /// no new variants are started once this much time has been spent on a photo
+    pub time_budget: Duration,
+    /// after detection show a frame lighting one quadrant of the chessboard, and reverse the
+    /// corners if they came back starting from the wrong end
+    pub orientation_check: bool,
+    /// only look for the chessboard here, None for the whole photo
+    pub roi: Option<DetectionRoi>,
+    /// fail when the detected chessboard covers less than this fraction of the photo,
+    /// otherwise small boards only get a warning
+    pub min_camera_coverage: Option<f32>,
+    /// warn about boards that cover too little or sit off center. Off for the tiles of a
+    /// tiled capture, only their stitched grid is checked.
+    pub coverage_warnings: bool,
+}
+
+impl Default for DetectionOptions {
+    fn default() -> DetectionOptions {
+        DetectionOptions {
+            attempts: 3,
+            variant: None,
+            polarity: Polarity::default(),
+            sweep: true,
+            time_budget: Duration::from_secs(20),
+            orientation_check: true,
+            roi: None,
+            min_camera_coverage: None,
+            coverage_warnings: true,
+        }
+    }
+}
+
+impl DetectionOptions {
+    /// The variants to try on each photo, in order. With Auto polarity both polarities of
+    /// each preprocessing are tried before moving on to the next.
+    pub fn variants(&self) -> Vec<DetectionVariant> {
+        if let Some(variant) = self.variant {
+            return vec![variant];
+        }
+        let preprocessing = if self.sweep { SWEEP } else { &[Preprocess::None] };
+        let mut variants = vec![];
+        for preprocess in preprocessing {
+            for invert in self.polarity.inverts() {
+                variants.push(DetectionVariant {invert: *invert, preprocess: *preprocess});
+            }
+        }
+        variants
+    }
+}
+
+/// Preprocessing tried in order, cheapest and most likely first
+pub const SWEEP: &[Preprocess] = &[
+    Preprocess::None,
+    Preprocess::Equalize,
+    Preprocess::Clahe {clip_limit: 3.},
+    Preprocess::Gamma {gamma: 0.5},
+    Preprocess::Gamma {gamma: 2.},
+    Preprocess::AdaptiveThreshold {block_size: 31},
+    Preprocess::AdaptiveThreshold {block_size: 101},
+];
+
+#[cfg(feature = "opencv")]
+impl DetectionVariant {
+    /// Apply to the greyscale photo `take_undistorted_photo` produces, once it's 8-bit
+    pub fn apply(&self, greyscale: &Mat) -> opencv::Result<Mat> {
+        let mut image = Mat::default()?;
+        if self.invert {
+            core::bitwise_not(greyscale, &mut image, &Mat::default()?)?;
+        } else {
+            greyscale.copy_to(&mut image)?;
+        }
+        let mut out = Mat::default()?;
+        match self.preprocess {
+            Preprocess::None => return Ok(image),
+            Preprocess::Equalize => equalize_hist(&image, &mut out)?,
+            Preprocess::Clahe {clip_limit} => {
+                let mut clahe = create_clahe(clip_limit, Size::new(8, 8))?;
+                clahe.apply(&image, &mut out)?;
+            },
+            Preprocess::Gamma {gamma} => {
+                let table: Vec<u8> = (0..256).map(|i| ((i as f32 / 255.).powf(gamma) * 255.).round() as u8).collect();
+                core::lut(&image, &Mat::from_slice(&table)?, &mut out)?;
+            },
+            Preprocess::AdaptiveThreshold {block_size} => {
+                adaptive_threshold(&image, &mut out, 255., ADAPTIVE_THRESH_MEAN_C, THRESH_BINARY, block_size | 1, 0.)?;
+            },
+        }
+        Ok(out)
+    }
+}
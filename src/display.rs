@@ -0,0 +1,120 @@
+use opencv::prelude::*;
+use opencv::core::{Size, Mat};
+use opencv::highgui;
+use opencv::imgproc::{resize, INTER_NEAREST};
+use log::debug;
+use super::{Resolution, Error};
+use super::control::ControlProtocol;
+use super::images::{self, Pattern, ImageEncoding};
+use super::projector::ProjectorOrientation;
+use super::prompt::OperatorPrompt;
+
+const WINDOW_NAME: &str = "aligner pattern";
+
+/// How patterns get onto the projector
+pub enum PatternDisplay {
+    /// post patterns to a control server
+    Control(Box<dyn ControlProtocol + Send>),
+    /// ask the operator to display each pattern
+    Manual(Box<dyn OperatorPrompt>),
+    /// this machine drives the projector, show patterns in a borderless fullscreen window
+    LocalFullscreen(LocalDisplay),
+}
+
+/// A fullscreen window on one of this machine's monitors
+pub struct LocalDisplay {
+    pub monitor: i32,
+    /// top left of the monitor on the virtual desktop. When None the monitors are assumed to
+    /// be arranged left to right, each at the projector resolution.
+    pub origin: Option<(i32, i32)>,
+}
+
+impl PatternDisplay {
+    /// The control server, if patterns are being posted to one
+    pub fn control(&self) -> Option<&dyn ControlProtocol> {
+        match self {
+            PatternDisplay::Control(protocol) => Some(protocol.as_ref()),
+            _ => None
+        }
+    }
+
+    /// How patterns are encoded for the control server, if they're posted to one
+    pub fn pattern_encoding(&self) -> Option<ImageEncoding> {
+        self.control().map(|protocol| protocol.pattern_encoding())
+    }
+
+    /// The operator prompt, if patterns are shown by hand
+    pub fn prompt(&self) -> Option<&dyn OperatorPrompt> {
+        match self {
+            PatternDisplay::Manual(prompt) => Some(prompt.as_ref()),
+            _ => None
+        }
+    }
+
+    /// Show a pattern on the projector, rotated so it appears upright on the surface. Returns
+    /// once the pattern should be visible.
+    pub fn show(&self, pattern: &Pattern, projector_res: Resolution, orientation: ProjectorOrientation) -> Result<(), Error> {
+        self.show_with_message(pattern, projector_res, orientation, &format!("display the {} on the projector", pattern.describe()))
+    }
+
+    /// Show a pattern again after it wasn't detected. The operator is told what went wrong.
+    pub fn show_again(&self, pattern: &Pattern, projector_res: Resolution, orientation: ProjectorOrientation, problem: &str) -> Result<(), Error> {
+        self.show_with_message(pattern, projector_res, orientation, &format!("fix the problem ({}) and display the {} again", problem, pattern.describe()))
+    }
+
+    fn show_with_message(&self, pattern: &Pattern, projector_res: Resolution, orientation: ProjectorOrientation, message: &str) -> Result<(), Error> {
+        match self {
+            PatternDisplay::Control(protocol) => {
+                let encoding = protocol.pattern_encoding();
+                let image = images::encode_with(&render_native(pattern, projector_res, orientation)?, encoding)?;
+                protocol.display_image(&image.to_slice(), encoding.format())?;
+            },
+            PatternDisplay::Manual(prompt) => prompt.wait(message)?,
+            PatternDisplay::LocalFullscreen(local) => local.show(pattern, projector_res, orientation)?,
+        }
+        Ok(())
+    }
+
+    /// Tidy up after the last pattern
+    pub fn close(&self) -> Result<(), Error> {
+        if let PatternDisplay::LocalFullscreen(_) = self {
+            highgui::destroy_window(WINDOW_NAME)?;
+        }
+        Ok(())
+    }
+}
+
+/// Render a pattern upright and rotate it into the projector's native layout
+pub(crate) fn render_native(pattern: &Pattern, projector_res: Resolution, orientation: ProjectorOrientation) -> Result<Mat, Error> {
+    let upright = orientation.effective_resolution(projector_res);
+    Ok(orientation.to_native_image(&pattern.render(upright.width, upright.height)?)?)
+}
+
+impl LocalDisplay {
+    fn show(&self, pattern: &Pattern, projector_res: Resolution, orientation: ProjectorOrientation) -> Result<(), Error> {
+        let (x, y) = self.origin.unwrap_or((self.monitor * projector_res.width, 0));
+        highgui::named_window(WINDOW_NAME, highgui::WINDOW_NORMAL)?;
+        highgui::move_window(WINDOW_NAME, x, y)?;
+        highgui::set_window_property(WINDOW_NAME, highgui::WND_PROP_FULLSCREEN, highgui::WINDOW_FULLSCREEN as f64)?;
+
+        // render at exactly the projector resolution so the window never has to scale it,
+        // nearest neighbour keeps the chessboard edges hard
+        let rendered = render_native(pattern, projector_res, orientation)?;
+        let mut image = Mat::default()?;
+        resize(&rendered, &mut image, Size::new(projector_res.width, projector_res.height), 0., 0., INTER_NEAREST)?;
+        highgui::imshow(WINDOW_NAME, &image)?;
+
+        // give the window system time to map and paint the window
+        highgui::wait_key(500)?;
+
+        let rect = highgui::get_window_image_rect(WINDOW_NAME)?;
+        debug!("pattern window is {}x{} at {},{}", rect.width, rect.height, rect.x, rect.y);
+        if rect.width != projector_res.width || rect.height != projector_res.height {
+            return Err(Error::Display(format!(
+                "fullscreen window on monitor {} is {}x{} but the projector resolution is {}. Check the monitor index and OS display scaling.",
+                self.monitor, rect.width, rect.height, projector_res
+            )));
+        }
+        Ok(())
+    }
+}
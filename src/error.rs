@@ -0,0 +1,84 @@
+use std::fmt;
+use super::network::NetworkError;
+use super::prompt::PromptError;
+
+/// Errors returned by the calibration entry points
+#[derive(Debug)]
+pub enum Error {
+    Network(NetworkError),
+    #[cfg(feature = "opencv")]
+    OpenCv(opencv::Error),
+    /// the pattern couldn't be shown correctly
+    Display(String),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    /// the eye position couldn't be fetched from its source
+    EyePosition(String),
+    /// the arguments or setup files don't make sense together
+    Config(String),
+    /// the chessboard wasn't found in the photo
+    Detection(String),
+    /// the scene can't be seen from the eye
+    Geometry(String),
+    /// the operator couldn't be asked to do something
+    Prompt(PromptError),
+    /// the photo's size isn't the calibration's, or a uniform scale of it when that's allowed
+    PhotoSize {photo: (i32, i32), calibration: (i32, i32), strict: bool},
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Network(err) => write!(f, "{}", err),
+            #[cfg(feature = "opencv")]
+            Error::OpenCv(err) => write!(f, "opencv error: {}", err),
+            Error::Display(msg) => write!(f, "{}", msg),
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Json(err) => write!(f, "invalid JSON: {}", err),
+            Error::EyePosition(msg) => write!(f, "{}", msg),
+            Error::Config(msg) => write!(f, "{}", msg),
+            Error::Detection(msg) => write!(f, "{}", msg),
+            Error::Geometry(msg) => write!(f, "{}", msg),
+            Error::Prompt(err) => write!(f, "{}", err),
+            Error::PhotoSize {photo, calibration, strict} => write!(
+                f,
+                "the photo is {}x{} but the camera was calibrated at {}x{}, {}",
+                photo.0, photo.1, calibration.0, calibration.1,
+                if *strict { "and photos must match the calibration exactly" } else { "which isn't a uniform scale of it" }
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<NetworkError> for Error {
+    fn from(err: NetworkError) -> Error {
+        Error::Network(err)
+    }
+}
+
+#[cfg(feature = "opencv")]
+impl From<opencv::Error> for Error {
+    fn from(err: opencv::Error) -> Error {
+        Error::OpenCv(err)
+    }
+}
+
+impl From<PromptError> for Error {
+    fn from(err: PromptError) -> Error {
+        Error::Prompt(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::Json(err)
+    }
+}
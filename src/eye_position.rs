@@ -0,0 +1,107 @@
+//! Where the virtual camera's eye position comes from. Besides a fixed point it can be read
+//! from a head tracker, either over HTTP or from a JSON file a tracking bridge keeps updated.
+
+use glm::*;
+use glm::ext::*;
+use log::info;
+use std::time::Duration;
+use super::Error;
+use super::output::{EyePositionMeta, EyeSourceMeta};
+
+/// Conversion from the tracker's coordinate system into scene space
+#[derive(Clone, Debug)]
+pub enum EyeTransform {
+    Identity,
+    Matrix(glm::Mat4),
+    /// rotation in degrees about X, then Y, then Z, followed by the translation
+    TranslationRotation {translation: glm::Vec3, rotation: glm::Vec3},
+}
+
+impl EyeTransform {
+    pub fn matrix(&self) -> glm::Mat4 {
+        match self {
+            EyeTransform::Identity => mat4(1., 0., 0., 0., 0., 1., 0., 0., 0., 0., 1., 0., 0., 0., 0., 1.),
+            EyeTransform::Matrix(m) => *m,
+            EyeTransform::TranslationRotation {translation, rotation} => {
+                let identity = EyeTransform::Identity.matrix();
+                let m = translate(&identity, *translation);
+                let m = rotate(&m, radians(rotation.z), vec3(0., 0., 1.));
+                let m = rotate(&m, radians(rotation.y), vec3(0., 1., 0.));
+                rotate(&m, radians(rotation.x), vec3(1., 0., 0.))
+            }
+        }
+    }
+
+    pub fn apply(&self, point: glm::Vec3) -> glm::Vec3 {
+        (self.matrix() * point.extend(1.)).truncate(3)
+    }
+}
+
+/// Supplies the eye position for a calibration run. json_pointer (e.g. "/head/position")
+/// picks out either a [x, y, z] array or an {"x", "y", "z"} object, "" for the whole document.
+#[derive(Clone, Debug)]
+pub enum EyePositionSource {
+    Fixed(glm::Vec3),
+    HttpJson {url: String, json_pointer: String, transform: EyeTransform},
+    File {path: String, json_pointer: String, transform: EyeTransform},
+}
+
+impl From<glm::Vec3> for EyePositionSource {
+    fn from(position: glm::Vec3) -> EyePositionSource {
+        EyePositionSource::Fixed(position)
+    }
+}
+
+impl EyePositionSource {
+    /// Fetch the current eye position in scene space
+    pub fn resolve(&self) -> Result<glm::Vec3, Error> {
+        let position = match self {
+            EyePositionSource::Fixed(position) => *position,
+            EyePositionSource::HttpJson {url, json_pointer, transform} => {
+                let client = reqwest::blocking::Client::builder()
+                    .timeout(Duration::from_secs(5))
+                    .build()
+                    .map_err(|err| Error::EyePosition(format!("can't create HTTP client: {}", err)))?;
+                let body = client.get(url).send()
+                    .and_then(|res| res.error_for_status())
+                    .and_then(|res| res.text())
+                    .map_err(|err| Error::EyePosition(format!("can't fetch eye position from {}: {}", url, err)))?;
+                transform.apply(eye_from_json(&body, json_pointer, url)?)
+            },
+            EyePositionSource::File {path, json_pointer, transform} => {
+                let body = std::fs::read_to_string(path)
+                    .map_err(|err| Error::EyePosition(format!("can't read eye position file {}: {}", path, err)))?;
+                transform.apply(eye_from_json(&body, json_pointer, path)?)
+            }
+        };
+        info!("eye position is {:?}", position);
+        Ok(position)
+    }
+
+    /// Record of the source and the position it resolved to, for the output metadata
+    pub fn meta(&self, position: glm::Vec3) -> EyePositionMeta {
+        let source = match self {
+            EyePositionSource::Fixed(_) => EyeSourceMeta::Fixed,
+            EyePositionSource::HttpJson {url, json_pointer, ..} => EyeSourceMeta::HttpJson {url: url.clone(), json_pointer: json_pointer.clone()},
+            EyePositionSource::File {path, json_pointer, ..} => EyeSourceMeta::File {path: path.clone(), json_pointer: json_pointer.clone()},
+        };
+        EyePositionMeta {source: source, position: [position.x, position.y, position.z]}
+    }
+}
+
+fn eye_from_json(body: &str, json_pointer: &str, origin: &str) -> Result<glm::Vec3, Error> {
+    let json: serde_json::Value = serde_json::from_str(body)
+        .map_err(|err| Error::EyePosition(format!("eye position from {} isn't valid JSON: {}", origin, err)))?;
+    let value = json.pointer(json_pointer)
+        .ok_or_else(|| Error::EyePosition(format!("eye position from {} has nothing at '{}'", origin, json_pointer)))?;
+    let component = |v: Option<&serde_json::Value>| v.and_then(|v| v.as_f64()).map(|v| v as f32);
+    let xyz = if let Some(array) = value.as_array() {
+        if array.len() != 3 { None } else { component(array.get(0)).zip(component(array.get(1))).zip(component(array.get(2))) }
+    } else {
+        component(value.get("x")).zip(component(value.get("y"))).zip(component(value.get("z")))
+    };
+    match xyz {
+        Some(((x, y), z)) => Ok(vec3(x, y, z)),
+        None => Err(Error::EyePosition(format!("eye position from {} at '{}' isn't a [x, y, z] array or {{x, y, z}} object", origin, json_pointer)))
+    }
+}
@@ -0,0 +1,209 @@
+//! Checking the order opencv returns the chessboard corners in. Depending on how the board
+//! sits in the photo they can come back column by column or mirrored, which scrambles the
+//! warp in a way that only shows up on the projector.
+//!
+//! Of the eight ways a flat list can be read into the grid (row or column major, either
+//! direction along each) only the ones that give straight, non-crossing rows and columns
+//! with the board's own handedness are physically possible. A photo can rotate the board
+//! but never mirror it, so corners (col + 1, row) and (col, row + 1) must always turn the
+//! same way as x and y do in the image.
+
+use glm::*;
+use serde::{Serialize, Deserialize};
+use super::GridSpec;
+
+/// Largest angle in degrees between a step along a row (or column) and that row's mean step
+pub const TOLERANCE_DEGREES: f32 = 45.;
+
+/// How the detected list was read into the grid
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CornerOrder {
+    /// the list ran column by column
+    pub transposed: bool,
+    /// each row ran right to left
+    pub reverse_cols: bool,
+    /// the rows ran bottom to top
+    pub reverse_rows: bool,
+}
+
+impl CornerOrder {
+    fn all() -> Vec<CornerOrder> {
+        let mut orders = vec![];
+        for &transposed in &[false, true] {
+            for &(reverse_cols, reverse_rows) in &[(false, false), (true, true), (true, false), (false, true)] {
+                orders.push(CornerOrder {transposed: transposed, reverse_cols: reverse_cols, reverse_rows: reverse_rows});
+            }
+        }
+        orders
+    }
+
+    /// Index into the detected list of grid corner (col, row)
+    fn source(&self, col: i32, row: i32, grid: GridSpec) -> usize {
+        let col = if self.reverse_cols { grid.cols - 1 - col } else { col };
+        let row = if self.reverse_rows { grid.rows - 1 - row } else { row };
+        (if self.transposed { col * grid.rows + row } else { row * grid.cols + col }) as usize
+    }
+
+    /// The detected list read into the grid in this order, row by row
+    pub fn apply<T: Copy>(&self, detected: &[T], grid: GridSpec) -> Vec<T> {
+        let mut out = Vec::with_capacity(detected.len());
+        for row in 0..grid.rows {
+            for col in 0..grid.cols {
+                out.push(detected[self.source(col, row, grid)]);
+            }
+        }
+        out
+    }
+}
+
+/// The order to read detected points (a complete grid in photo pixels) in so they form a
+/// coherent grid with the board's handedness. Of the orders that do, the one whose first row
+/// runs most nearly left to right and first column most nearly top to bottom is chosen, so
+/// the first corner is the top left in the photo whatever order they came in; the
+/// orientation check then settles a board that really is upside down. Err describes why no
+/// order works.
+pub fn canonical_order(detected: &[glm::Vec2], grid: GridSpec) -> Result<CornerOrder, String> {
+    if detected.len() != grid.len() {
+        return Err(format!("{} corners detected for a {} grid", detected.len(), grid));
+    }
+    if grid.cols < 2 || grid.rows < 2 {
+        return Ok(CornerOrder::default());
+    }
+    let mut best: Option<(CornerOrder, f32)> = None;
+    let mut problem = String::new();
+    for order in CornerOrder::all() {
+        let points = order.apply(detected, grid);
+        match handedness(&points, grid) {
+            Ok(sign) if sign > 0. => {
+                // image y is down
+                let rightwards = normalize(points[(grid.cols - 1) as usize] - points[0]).x;
+                let downwards = normalize(points[((grid.rows - 1) * grid.cols) as usize] - points[0]).y;
+                let score = rightwards + downwards;
+                if best.map(|(_, best_score)| score > best_score).unwrap_or(true) {
+                    best = Some((order, score));
+                }
+            },
+            Ok(_) => {},
+            Err(reason) => if order == CornerOrder::default() { problem = reason },
+        }
+    }
+    best.map(|(order, _)| order).ok_or_else(|| {
+        if problem.is_empty() { "the corners don't form a coherent grid in any order".to_string() } else { problem }
+    })
+}
+
+/// 1 when rows and columns turn like the image's x and y, -1 when mirrored. Err when a row or
+/// column bends by more than the tolerance or the turn changes across the grid, i.e. rows cross.
+fn handedness(points: &[glm::Vec2], grid: GridSpec) -> Result<f32, String> {
+    let at = |col: i32, row: i32| points[(row * grid.cols + col) as usize];
+    let min_cos = TOLERANCE_DEGREES.to_radians().cos();
+    let straight = |steps: &[glm::Vec2]| -> bool {
+        let mut mean = vec2(0., 0.);
+        for s in steps { mean = mean + *s; }
+        let mean_length = length(mean);
+        mean_length > 1e-6 && steps.iter().all(|s| {
+            let l = length(*s);
+            l > 1e-6 && dot(*s, mean) / (l * mean_length) >= min_cos
+        })
+    };
+    for row in 0..grid.rows {
+        let steps: Vec<glm::Vec2> = (1..grid.cols).map(|col| at(col, row) - at(col - 1, row)).collect();
+        if !straight(&steps) {
+            return Err(format!("row {} of the detected corners isn't straight", row));
+        }
+    }
+    for col in 0..grid.cols {
+        let steps: Vec<glm::Vec2> = (1..grid.rows).map(|row| at(col, row) - at(col, row - 1)).collect();
+        if !straight(&steps) {
+            return Err(format!("column {} of the detected corners isn't straight", col));
+        }
+    }
+    let mut sign = 0_f32;
+    for row in 0..grid.rows - 1 {
+        for col in 0..grid.cols - 1 {
+            let (along, down) = (at(col + 1, row) - at(col, row), at(col, row + 1) - at(col, row));
+            let turn = (along.x * down.y - along.y * down.x).signum();
+            if sign == 0. {
+                sign = turn;
+            } else if turn != sign {
+                return Err(format!("the detected rows cross near corner {},{}", col, row));
+            }
+        }
+    }
+    Ok(sign)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRID: GridSpec = GridSpec {cols: 9, rows: 6};
+
+    /// The corners of a board turned by degrees in the photo, row by row from its top left
+    fn board(degrees: f32) -> Vec<glm::Vec2> {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let mut points = vec![];
+        for row in 0..GRID.rows {
+            for col in 0..GRID.cols {
+                let (x, y) = (50. * col as f32, 40. * row as f32);
+                points.push(vec2(500. + x * cos - y * sin, 300. + x * sin + y * cos));
+            }
+        }
+        points
+    }
+
+    /// The list order would read back into points
+    fn detected_as(order: CornerOrder, points: &[glm::Vec2]) -> Vec<glm::Vec2> {
+        let mut detected = vec![vec2(0., 0.); points.len()];
+        for row in 0..GRID.rows {
+            for col in 0..GRID.cols {
+                detected[order.source(col, row, GRID)] = points[(row * GRID.cols + col) as usize];
+            }
+        }
+        detected
+    }
+
+    #[test]
+    fn every_ordering_reads_back_from_the_top_left() {
+        let points = board(10.);
+        for order in CornerOrder::all() {
+            let detected = detected_as(order, &points);
+            let found = canonical_order(&detected, GRID).unwrap();
+            assert_eq!(found, order);
+            assert_eq!(found.apply(&detected, GRID), points, "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn transposed_list_is_read_column_by_column() {
+        let points = board(-5.);
+        let mut detected = vec![];
+        for col in 0..GRID.cols {
+            for row in 0..GRID.rows {
+                detected.push(points[(row * GRID.cols + col) as usize]);
+            }
+        }
+        let order = canonical_order(&detected, GRID).unwrap();
+        assert_eq!(order, CornerOrder {transposed: true, reverse_cols: false, reverse_rows: false});
+        assert_eq!(order.apply(&detected, GRID), points);
+    }
+
+    #[test]
+    fn reversed_list_isnt_kept_for_being_coherent() {
+        // a reversed list is still a right handed grid, just starting bottom right
+        let points = board(0.);
+        let detected: Vec<glm::Vec2> = points.iter().rev().cloned().collect();
+        let order = canonical_order(&detected, GRID).unwrap();
+        assert_eq!(order, CornerOrder {transposed: false, reverse_cols: true, reverse_rows: true});
+        assert_eq!(order.apply(&detected, GRID), points);
+    }
+
+    #[test]
+    fn scrambled_lists_have_no_order() {
+        let mut scrambled = board(0.);
+        scrambled.swap(3, 40);
+        assert!(canonical_order(&scrambled, GRID).is_err());
+        assert!(canonical_order(&board(0.)[1..], GRID).is_err());
+    }
+}
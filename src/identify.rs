@@ -0,0 +1,142 @@
+//! Checking which control server drives which projector before calibrating. Every output
+//! shows its own aruco marker at once, with the output's index as the marker id, and one
+//! photo shows which of them the camera can see and where. Venues with several projectors
+//! are easily mislabeled, and otherwise that only shows up after a whole run against the
+//! wrong one.
+
+use opencv::prelude::*;
+use opencv::types::*;
+use opencv::core::{Mat, Point2f};
+use opencv::calib3d::find_homography;
+use log::{debug, info, warn};
+use serde::{Serialize, Deserialize};
+use super::{Resolution, Error, display, images, keystone, photo, pipeline};
+use super::camera_calibration::Calibration;
+use super::control::ControlProtocol;
+use super::detection::DetectionRoi;
+use super::locator::ArucoDictionary;
+use super::projector::ProjectorOrientation;
+
+/// What the photo showed of one output's marker
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputIdentity {
+    /// position in the list of outputs, also the id of its marker
+    pub index: usize,
+    /// what the caller calls the output, e.g. its control URL
+    pub label: String,
+    pub seen: bool,
+    /// the marker's corners in the undistorted photo, clockwise from its top left
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub marker: Option<[[f32; 2]; 4]>,
+    /// where the output's whole raster falls in the undistorted photo, extrapolated from the
+    /// marker as if the surface were flat
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub footprint: Option<[[f32; 2]; 4]>,
+}
+
+/// Which outputs the camera can see
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentificationReport {
+    pub dictionary: ArucoDictionary,
+    pub outputs: Vec<OutputIdentity>,
+    /// markers in view that no output showed, e.g. a printed camera location marker
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unexpected_ids: Vec<i32>,
+}
+
+impl IdentificationReport {
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Indices of the outputs whose marker wasn't in view
+    pub fn unseen(&self) -> Vec<usize> {
+        self.outputs.iter().filter(|output| !output.seen).map(|output| output.index).collect()
+    }
+
+    /// The footprint of output index, for detecting its chessboard in. None when its marker
+    /// wasn't seen.
+    pub fn roi(&self, index: usize) -> Option<DetectionRoi> {
+        self.outputs.get(index)
+            .and_then(|output| output.footprint)
+            .map(|footprint| DetectionRoi::Polygon {points: footprint.to_vec()})
+    }
+}
+
+/// Show marker i of dictionary on outputs[i], every output at once, and report which markers
+/// one photo shows. The markers are left up, the caller's control session puts back what was
+/// showing before.
+pub fn identify_outputs(outputs: &[(String, &dyn ControlProtocol)], calibration: &Calibration, camera_type: photo::CameraType, projector_res: Resolution, orientation: ProjectorOrientation, dictionary: ArucoDictionary, debug_encoding: images::ImageEncoding) -> Result<IdentificationReport, Error> {
+    for (i, (label, output)) in outputs.iter().enumerate() {
+        let pattern = images::Pattern::IdMarker {id: i as i32, dictionary: dictionary};
+        let encoding = output.pattern_encoding();
+        let image = images::encode_with(&display::render_native(&pattern, projector_res, orientation)?, encoding)?;
+        output.display_image(&image.to_slice(), encoding.format())?;
+        debug!("showing identification marker {} on {}", i, label);
+    }
+
+    let photo_data = photo::capture_photo(camera_type);
+    let (_, greyscale) = pipeline::take_undistorted_photo(calibration, &photo_data, debug_encoding)?;
+    let (greyscale, _) = pipeline::to_8bit(&greyscale)?;
+    let mut ids = VectorOfi32::new();
+    let mut corners = VectorOfVectorOfPoint2f::new();
+    let mut rejected = VectorOfVectorOfPoint2f::new();
+    let dict = opencv::aruco::get_predefined_dictionary(dictionary.predefined())?;
+    let params = opencv::aruco::DetectorParameters::create()?;
+    // the photo is already undistorted
+    opencv::aruco::detect_markers(&greyscale, &dict, &mut corners, &mut ids, &params, &mut rejected, &Mat::default()?, &Mat::default()?)?;
+    let detected = ids.to_vec();
+    info!("identification photo shows {} aruco markers {:?}", dictionary.name(), detected);
+
+    // where the marker is drawn in the upright frame, to extrapolate the footprint from
+    let upright = orientation.effective_resolution(projector_res);
+    let rect = images::id_marker_rect(upright.width, upright.height);
+    let (x0, y0, x1, y1) = (rect.x as f32, rect.y as f32, (rect.x + rect.width) as f32, (rect.y + rect.height) as f32);
+    let mut drawn = VectorOfPoint2f::new();
+    for (x, y) in &[(x0, y0), (x1, y0), (x1, y1), (x0, y1)] {
+        drawn.push(Point2f::new(*x, *y));
+    }
+
+    let mut identities = vec![];
+    for (i, (label, _)) in outputs.iter().enumerate() {
+        let found: Vec<usize> = detected.iter().enumerate().filter(|(_, id)| **id == i as i32).map(|(j, _)| j).collect();
+        if found.len() > 1 {
+            warn!("{}'s identification marker is in view {} times, using the first", label, found.len());
+        }
+        let identity = match found.first() {
+            Some(j) => {
+                let seen = corners.get(*j)?;
+                let mut marker = [[0_f32; 2]; 4];
+                for (k, corner) in seen.iter().take(4).enumerate() {
+                    marker[k] = [corner.x, corner.y];
+                }
+                let mut mask = Mat::default()?;
+                let h = find_homography(&drawn, &seen, 0, 3., &mut mask, 2000, 0.995)?;
+                let mut m = [0_f64; 9];
+                for r in 0..3 {
+                    for c in 0..3 {
+                        m[(r * 3 + c) as usize] = *h.at_2d::<f64>(r, c)?;
+                    }
+                }
+                let (w, h) = (upright.width as f64, upright.height as f64);
+                let mut footprint = [[0_f32; 2]; 4];
+                for (k, (x, y)) in [(0., 0.), (w, 0.), (w, h), (0., h)].iter().enumerate() {
+                    let (px, py) = keystone::apply(&m, *x, *y);
+                    footprint[k] = [px as f32, py as f32];
+                }
+                OutputIdentity {index: i, label: label.clone(), seen: true, marker: Some(marker), footprint: Some(footprint)}
+            },
+            None => {
+                warn!("{}'s identification marker isn't in view", label);
+                OutputIdentity {index: i, label: label.clone(), seen: false, marker: None, footprint: None}
+            }
+        };
+        identities.push(identity);
+    }
+    let mut unexpected_ids: Vec<i32> = detected.iter().cloned().filter(|id| *id < 0 || *id as usize >= outputs.len()).collect();
+    unexpected_ids.sort();
+    unexpected_ids.dedup();
+    Ok(IdentificationReport {dictionary: dictionary, outputs: identities, unexpected_ids: unexpected_ids})
+}
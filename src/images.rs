@@ -2,21 +2,146 @@ use opencv::prelude::*;
 use opencv::types::*;
 use opencv::core::*;
 use opencv::imgcodecs;
+use opencv::imgproc::{put_text, get_text_size, apply_color_map, resize, cvt_color, FONT_HERSHEY_SIMPLEX, LINE_AA, COLORMAP_JET, INTER_NEAREST, COLOR_GRAY2BGR, COLOR_BGR2GRAY};
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use super::{GridSpec, Error};
+use super::locator::ArucoDictionary;
+use super::projector::PatternPlacement;
+use super::structured_light::{self, StripeAxis};
 
-/// Produce a chessboard calibration pattern with the given dimentions. The dimentions refer
-/// to the internal "corners" on the board where four corners meet.
-pub fn chessboard(nx: i32, ny: i32) -> Mat {
+/// Size in pixels of each chessboard square
+const SQUARE_SIZE: i32 = 50;
+
+/// A rectangular block of a chessboard's inner corners, starting at corner (col, row)
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct GridRegion {
+    pub col: i32,
+    pub row: i32,
+    pub cols: i32,
+    pub rows: i32,
+}
+
+/// Something that can be put up on a projector between captures
+pub enum Pattern {
+    Chessboard {grid: GridSpec},
+    /// only the squares surrounding a region of the chessboard's inner corners, the rest black
+    ChessboardRegion {grid: GridSpec, region: GridRegion},
+    SolidColor {r: u8, g: u8, b: u8},
+    /// the name rendered large, for identifying which projector is which
+    IdSlate {name: String},
+    /// marker id of dictionary as large as fits on white, for telling which output lights
+    /// which part of the surface
+    IdMarker {id: i32, dictionary: ArucoDictionary},
+    /// the quadrant of the chessboard (or its region) around inner corner (0, 0) white, the
+    /// rest black, for telling which way round the detected corners are
+    OrientationCue {grid: GridSpec, region: Option<GridRegion>},
+    /// another pattern drawn inside part of the frame, the rest black
+    Placed {pattern: Box<Pattern>, placement: PatternPlacement},
+    /// one bit of every column's or row's Gray code, see `structured_light`
+    GrayCode {axis: StripeAxis, bit: u32, inverted: bool},
+}
+
+impl Pattern {
+    pub fn black() -> Pattern {
+        Pattern::SolidColor {r: 0, g: 0, b: 0}
+    }
+
+    pub fn white() -> Pattern {
+        Pattern::SolidColor {r: 255, g: 255, b: 255}
+    }
+
+    /// This pattern drawn in placement, or as it is without one
+    pub fn placed(self, placement: Option<&PatternPlacement>) -> Pattern {
+        match placement {
+            Some(placement) => Pattern::Placed {pattern: Box::new(self), placement: *placement},
+            None => self
+        }
+    }
+
+    /// Render the pattern. Solid colors, slates, markers, stripes and placed patterns are
+    /// rendered at width x height, chessboards at their own size.
+    pub fn render(&self, width: i32, height: i32) -> Result<Mat, Error> {
+        Ok(match self {
+            Pattern::Chessboard {grid} => chessboard(*grid)?,
+            Pattern::ChessboardRegion {grid, region} => chessboard_region(*grid, *region)?,
+            Pattern::SolidColor {r, g, b} => solid_color(width, height, *r, *g, *b),
+            Pattern::IdSlate {name} => id_slate(width, height, name),
+            Pattern::IdMarker {id, dictionary} => id_marker(width, height, *id, *dictionary),
+            Pattern::OrientationCue {grid, region} => orientation_cue(*grid, *region),
+            Pattern::Placed {pattern, placement} => placed(pattern, placement, width, height)?,
+            Pattern::GrayCode {axis, bit, inverted} => gray_code_stripes(width, height, *axis, *bit, *inverted),
+        })
+    }
+
+    /// Where each inner corner of a chessboard lies in the pattern, 0-1 across it, row by row
+    pub fn corner_positions(&self) -> Option<Vec<glm::Vec2>> {
+        if let Pattern::Placed {pattern, placement} = self {
+            return pattern.corner_positions().map(|positions| positions.iter().map(|p| placement.to_raster(*p)).collect());
+        }
+        let (grid, region) = match self {
+            Pattern::Chessboard {grid} => (*grid, GridRegion {col: 0, row: 0, cols: grid.cols, rows: grid.rows}),
+            Pattern::ChessboardRegion {grid, region} => (*grid, *region),
+            _ => return None
+        };
+        let mut positions = vec![];
+        for row in region.row..region.row + region.rows {
+            for col in region.col..region.col + region.cols {
+                positions.push(glm::vec2((col + 1) as f32 / (grid.cols + 1) as f32, (row + 1) as f32 / (grid.rows + 1) as f32));
+            }
+        }
+        Some(positions)
+    }
+
+    /// The orientation cue to show after this chessboard has been detected
+    pub fn orientation_cue(&self) -> Option<Pattern> {
+        match self {
+            Pattern::Chessboard {grid} => Some(Pattern::OrientationCue {grid: *grid, region: None}),
+            Pattern::ChessboardRegion {grid, region} => Some(Pattern::OrientationCue {grid: *grid, region: Some(*region)}),
+            Pattern::Placed {pattern, placement} => pattern.orientation_cue().map(|cue| Pattern::Placed {pattern: Box::new(cue), placement: *placement}),
+            _ => None
+        }
+    }
+
+    /// Human readable description used when asking the operator to show the pattern
+    pub fn describe(&self) -> String {
+        match self {
+            Pattern::Chessboard {grid} => format!("full-screen chessboard pattern with {} inner corners", grid),
+            Pattern::ChessboardRegion {grid, region} => format!(
+                "full-screen chessboard pattern with {} inner corners showing only the {}x{} corners from {},{}",
+                grid, region.cols, region.rows, region.col, region.row
+            ),
+            Pattern::SolidColor {r: 0, g: 0, b: 0} => "full-screen black frame".to_string(),
+            Pattern::SolidColor {r: 255, g: 255, b: 255} => "full-screen white frame".to_string(),
+            Pattern::SolidColor {r, g, b} => format!("full-screen solid color frame (rgb {}, {}, {})", r, g, b),
+            Pattern::IdSlate {name} => format!("identification slate for \"{}\"", name),
+            Pattern::IdMarker {id, dictionary} => format!("full-screen identification marker {} from the {} aruco dictionary", id, dictionary.name()),
+            Pattern::OrientationCue {..} => "orientation frame (top left quarter of the chessboard white)".to_string(),
+            Pattern::Placed {pattern, placement} => format!(
+                "{} drawn in the {:.0}% x {:.0}% of the frame from {:.0}%, {:.0}%",
+                pattern.describe().trim_start_matches("full-screen "), placement.width * 100., placement.height * 100., placement.x * 100., placement.y * 100.
+            ),
+            Pattern::GrayCode {axis, bit, inverted} => format!(
+                "full-screen Gray code {} bit {}{}",
+                match axis { StripeAxis::Columns => "column", StripeAxis::Rows => "row" }, bit, if *inverted { " inverted" } else { "" }
+            ),
+        }
+    }
+}
+
+/// Produce a chessboard calibration pattern with grid.cols x grid.rows inner corners (where
+/// four squares meet), so (cols + 1) x (rows + 1) squares. It must be odd x even, see
+/// `GridSpec::new`.
+pub fn chessboard(grid: GridSpec) -> Result<Mat, Error> {
+    GridSpec::new(grid.cols, grid.rows).map_err(|err| Error::Config(format!("can't draw a {} chessboard: {}", grid, err)))?;
     let mut inverted = Mat::default().unwrap();
-    let square_size = 50;
+    let square_size = SQUARE_SIZE;
+    let (nx, ny) = (grid.cols, grid.rows);
     let image_width = square_size * (nx + 1);
     let image_height = square_size * (ny + 1);
     let mat = Mat::new_size_with_default(Size::new(image_width, image_height), CV_8UC3, Scalar::all(0.)).unwrap();
     let mut color = 0u8;
     
-    if nx % 2 == 0 || ny % 2 == 1 {
-        panic!("chessboard width must be odd, height even");
-    }
-    
     // starts black on top-left corner (then inverted)
     let mut i = 0;
     for _ in 0..nx+1 {
@@ -33,13 +158,101 @@ pub fn chessboard(nx: i32, ny: i32) -> Mat {
         i += square_size;
     }
     bitwise_not(&mat, &mut inverted, &Mat::default().unwrap()).unwrap();
-    inverted
+    Ok(inverted)
+}
+
+/// Produce the grid chessboard with everything outside the squares surrounding the region's
+/// inner corners black, so a camera that can only see part of the surface sees a complete
+/// (smaller) chessboard. The region should be odd x even like the full board.
+pub fn chessboard_region(grid: GridSpec, region: GridRegion) -> Result<Mat, Error> {
+    let board = chessboard(grid)?;
+    let out = Mat::new_size_with_default(board.size().unwrap(), CV_8UC3, Scalar::all(0.)).unwrap();
+    let rect = Rect::new(
+        region.col * SQUARE_SIZE,
+        region.row * SQUARE_SIZE,
+        (region.cols + 1) * SQUARE_SIZE,
+        (region.rows + 1) * SQUARE_SIZE
+    );
+    let src = Mat::roi(&board, rect).unwrap();
+    let mut dst = Mat::roi(&out, rect).unwrap();
+    src.copy_to(&mut dst).unwrap();
+    Ok(out)
+}
+
+/// Produce a black frame the size of the grid chessboard with the top left quadrant of the
+/// region's squares (the whole board without a region) white
+pub fn orientation_cue(grid: GridSpec, region: Option<GridRegion>) -> Mat {
+    let region = region.unwrap_or(GridRegion {col: 0, row: 0, cols: grid.cols, rows: grid.rows});
+    let size = Size::new((grid.cols + 1) * SQUARE_SIZE, (grid.rows + 1) * SQUARE_SIZE);
+    let out = Mat::new_size_with_default(size, CV_8UC3, Scalar::all(0.)).unwrap();
+    let rect = Rect::new(
+        region.col * SQUARE_SIZE,
+        region.row * SQUARE_SIZE,
+        (region.cols + 1) * SQUARE_SIZE / 2,
+        (region.rows + 1) * SQUARE_SIZE / 2
+    );
+    let mut quadrant = Mat::roi(&out, rect).unwrap();
+    quadrant.set(Scalar::all(255.)).unwrap();
+    out
+}
+
+/// Produce a width x height black frame with pattern stretched over the placement's part of it
+fn placed(pattern: &Pattern, placement: &PatternPlacement, width: i32, height: i32) -> Result<Mat, Error> {
+    let out = Mat::new_size_with_default(Size::new(width, height), CV_8UC3, Scalar::all(0.)).unwrap();
+    let x = (placement.x * width as f32).round() as i32;
+    let y = (placement.y * height as f32).round() as i32;
+    let rect = Rect::new(x, y, ((placement.width * width as f32).round() as i32).min(width - x).max(1), ((placement.height * height as f32).round() as i32).min(height - y).max(1));
+    // nearest neighbour keeps the chessboard edges hard
+    let mut scaled = Mat::default().unwrap();
+    resize(&pattern.render(rect.width, rect.height)?, &mut scaled, rect.size(), 0., 0., INTER_NEAREST).unwrap();
+    let mut dst = Mat::roi(&out, rect).unwrap();
+    scaled.copy_to(&mut dst).unwrap();
+    Ok(out)
+}
+
+/// Produce bit of each column's (or row's) Gray code, white where it's 1 and black where it's
+/// 0, or the other way round when inverted
+pub fn gray_code_stripes(width: i32, height: i32, axis: StripeAxis, bit: u32, inverted: bool) -> Mat {
+    let length = match axis { StripeAxis::Columns => width, StripeAxis::Rows => height };
+    let bits = structured_light::code_bits(length);
+    let line: Vec<u8> = (0..length)
+        .map(|i| if structured_light::stripe_on(i as u32, bits, bit) != inverted { 255 } else { 0 })
+        .collect();
+    // one row or column stretched over the frame
+    let line = Mat::from_slice(&line).unwrap();
+    let line = match axis { StripeAxis::Columns => line.reshape(1, 1).unwrap(), StripeAxis::Rows => line.reshape(1, length).unwrap() };
+    let mut stripes = Mat::default().unwrap();
+    resize(&line, &mut stripes, Size::new(width, height), 0., 0., INTER_NEAREST).unwrap();
+    let mut out = Mat::default().unwrap();
+    cvt_color(&stripes, &mut out, COLOR_GRAY2BGR, 0).unwrap();
+    out
 }
 
 /// Produce a chessboard pattern and encode in the given image format.
-pub fn chessboard_image(nx: i32, ny: i32, format: &str) -> VectorOfu8 {
-  let data = chessboard(nx, ny);
-  encode_image(&data, format)
+pub fn chessboard_image(grid: GridSpec, format: &str) -> Result<VectorOfu8, Error> {
+  let data = chessboard(grid)?;
+  Ok(encode_image(&data, format))
+}
+
+/// A grid of values, cols wide and row by row, drawn as one cell_size pixel square each
+/// colored from blue (0) to red (max and above). None values are grey.
+pub fn heatmap(values: &[Option<f32>], cols: i32, max: f32, cell_size: i32) -> opencv::Result<Mat> {
+    let max = max.max(1e-9);
+    let levels: Vec<u8> = values.iter()
+        .map(|v| v.map(|v| ((v / max).min(1.) * 255.).round() as u8).unwrap_or(0))
+        .collect();
+    let rows = values.len() as i32 / cols;
+    let small = Mat::from_slice(&levels)?.reshape(1, rows)?;
+    let mut colored = Mat::default()?;
+    apply_color_map(&small, &mut colored, COLORMAP_JET)?;
+    for (i, v) in values.iter().enumerate() {
+        if v.is_none() {
+            *colored.at_2d_mut::<Vec3b>(i as i32 / cols, i as i32 % cols)? = Vec3b::from([128, 128, 128]);
+        }
+    }
+    let mut heatmap = Mat::default()?;
+    resize(&colored, &mut heatmap, Size::new(cols * cell_size, rows * cell_size), 0., 0., INTER_NEAREST)?;
+    Ok(heatmap)
 }
 
 pub fn encode_image(data: &Mat, format: &str) -> VectorOfu8 {
@@ -48,7 +261,200 @@ pub fn encode_image(data: &Mat, format: &str) -> VectorOfu8 {
   encoded
 }
 
-#[allow(dead_code)]
+/// An image format and its quality or compression setting, as imencode and imwrite take them
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", tag = "format")]
+pub enum ImageEncoding {
+    /// lossless, compression 0 (fastest) to 9 (smallest). Black and white images are written
+    /// 1 bit per pixel.
+    Png {compression: i32},
+    /// quality 0-100
+    Jpeg {quality: i32},
+    Bmp,
+    /// quality 1-100, above 100 is lossless
+    WebP {quality: i32},
+}
+
+impl ImageEncoding {
+    /// Patterns are mostly black and white, which PNG at full compression makes tiny
+    pub const PATTERN_DEFAULT: ImageEncoding = ImageEncoding::Png {compression: 9};
+    /// Lossless so corners can be inspected, at a compression that's quick for full photos
+    pub const DEBUG_DEFAULT: ImageEncoding = ImageEncoding::Png {compression: 1};
+
+    /// "FORMAT" or "FORMAT:SETTING", e.g. "png:9", "jpeg:95", "bmp" or "webp:101"
+    pub fn parse(input: &str) -> Result<ImageEncoding, &'static str> {
+        let mut parts = input.splitn(2, ':');
+        let format = parts.next().unwrap_or("").to_lowercase();
+        let setting = match parts.next() {
+            Some(setting) => Some(setting.parse::<i32>().map_err(|_| "the encoding setting must be a whole number, e.g. \"jpeg:95\"")?),
+            None => None
+        };
+        match format.as_str() {
+            "png" => match setting.unwrap_or(9) {
+                c if c >= 0 && c <= 9 => Ok(ImageEncoding::Png {compression: c}),
+                _ => Err("PNG compression must be 0-9")
+            },
+            "jpeg" | "jpg" => match setting.unwrap_or(95) {
+                q if q >= 0 && q <= 100 => Ok(ImageEncoding::Jpeg {quality: q}),
+                _ => Err("JPEG quality must be 0-100")
+            },
+            "bmp" => match setting {
+                None => Ok(ImageEncoding::Bmp),
+                Some(_) => Err("BMP has no quality setting")
+            },
+            "webp" => match setting.unwrap_or(101) {
+                q if q >= 1 => Ok(ImageEncoding::WebP {quality: q}),
+                _ => Err("WebP quality must be 1-100, or above 100 for lossless")
+            },
+            _ => Err("the image format must be png, jpeg, bmp or webp")
+        }
+    }
+
+    /// The format as in an image/... content type, and as the control protocols take it
+    pub fn format(&self) -> &'static str {
+        match self {
+            ImageEncoding::Png {..} => "png",
+            ImageEncoding::Jpeg {..} => "jpeg",
+            ImageEncoding::Bmp => "bmp",
+            ImageEncoding::WebP {..} => "webp",
+        }
+    }
+
+    /// File name extension, without the dot
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageEncoding::Jpeg {..} => "jpg",
+            _ => self.format()
+        }
+    }
+
+    /// The parameter vector imencode and imwrite take
+    fn params(&self, bilevel: bool) -> VectorOfi32 {
+        let mut params = VectorOfi32::new();
+        match self {
+            ImageEncoding::Png {compression} => {
+                params.push(imgcodecs::IMWRITE_PNG_COMPRESSION);
+                params.push(*compression);
+                if bilevel {
+                    params.push(imgcodecs::IMWRITE_PNG_BILEVEL);
+                    params.push(1);
+                }
+            },
+            ImageEncoding::Jpeg {quality} => {
+                params.push(imgcodecs::IMWRITE_JPEG_QUALITY);
+                params.push(*quality);
+            },
+            ImageEncoding::Bmp => {},
+            ImageEncoding::WebP {quality} => {
+                params.push(imgcodecs::IMWRITE_WEBP_QUALITY);
+                params.push(*quality);
+            },
+        }
+        params
+    }
+
+    /// The image to hand the encoder and whether it's black and white. PNG writes those 1 bit
+    /// per pixel, which needs them as one 8-bit channel.
+    fn prepare(&self, data: &Mat) -> opencv::Result<(Mat, bool)> {
+        let mut image = Mat::default()?;
+        if let ImageEncoding::Png {..} = self {
+            if data.depth()? == CV_8U && is_black_and_white(data)? {
+                match data.channels()? {
+                    1 => data.copy_to(&mut image)?,
+                    _ => cvt_color(data, &mut image, COLOR_BGR2GRAY, 0)?,
+                }
+                return Ok((image, true));
+            }
+        }
+        data.copy_to(&mut image)?;
+        Ok((image, false))
+    }
+}
+
+impl fmt::Display for ImageEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageEncoding::Png {compression} => write!(f, "PNG, compression {}", compression),
+            ImageEncoding::Jpeg {quality} => write!(f, "JPEG, quality {}", quality),
+            ImageEncoding::Bmp => write!(f, "BMP"),
+            ImageEncoding::WebP {quality} if *quality > 100 => write!(f, "lossless WebP"),
+            ImageEncoding::WebP {quality} => write!(f, "WebP, quality {}", quality),
+        }
+    }
+}
+
+/// Every pixel of an 8-bit image is black or white
+fn is_black_and_white(data: &Mat) -> opencv::Result<bool> {
+    let mut gray = Mat::default()?;
+    match data.channels()? {
+        1 => data.copy_to(&mut gray)?,
+        3 => cvt_color(data, &mut gray, COLOR_BGR2GRAY, 0)?,
+        _ => return Ok(false),
+    }
+    // a color made of full channels is never 0 or 255 once it's grey
+    Ok(gray.data_typed::<u8>()?.iter().all(|v| *v == 0 || *v == 255))
+}
+
+/// Encode an image with encoding
+pub fn encode_with(data: &Mat, encoding: ImageEncoding) -> opencv::Result<VectorOfu8> {
+    let (image, bilevel) = encoding.prepare(data)?;
+    let mut encoded = VectorOfu8::new();
+    imgcodecs::imencode(&format!(".{}", encoding.extension()), &image, &mut encoded, &encoding.params(bilevel))?;
+    Ok(encoded)
+}
+
+/// Write a debug artifact to name plus encoding's extension. Returns the file name.
+pub fn write_debug(name: &str, image: &Mat, encoding: ImageEncoding) -> opencv::Result<String> {
+    let fname = format!("{}.{}", name, encoding.extension());
+    let (image, bilevel) = encoding.prepare(image)?;
+    imgcodecs::imwrite(&fname, &image, &encoding.params(bilevel))?;
+    Ok(fname)
+}
+
+/// Produce a frame filled with a single color
+pub fn solid_color(width: i32, height: i32, r: u8, g: u8, b: u8) -> Mat {
+    // opencv channel order is BGR
+    Mat::new_size_with_default(Size::new(width, height), CV_8UC3, Scalar::new(b as f64, g as f64, r as f64, 0.)).unwrap()
+}
+
+/// Produce a black frame with the given name rendered as large as will fit, white on black
+pub fn id_slate(width: i32, height: i32, name: &str) -> Mat {
+    let mut mat = solid_color(width, height, 0, 0, 0);
+    let thickness = (height / 40).max(2);
+    let mut baseline = 0;
+    let unit = get_text_size(name, FONT_HERSHEY_SIMPLEX, 1., thickness, &mut baseline).unwrap();
+
+    // scale to fill 80% of the width or half the height, whichever is smaller
+    let scale = (0.8 * width as f64 / unit.width as f64).min(0.5 * height as f64 / unit.height as f64);
+    let size = get_text_size(name, FONT_HERSHEY_SIMPLEX, scale, thickness, &mut baseline).unwrap();
+    let origin = Point::new((width - size.width) / 2, (height + size.height) / 2);
+    put_text(&mut mat, name, origin, FONT_HERSHEY_SIMPLEX, scale, Scalar::all(255.), thickness, LINE_AA, false).unwrap();
+    mat
+}
+
+/// Where `id_marker` draws the marker in a width x height frame, the largest square that
+/// leaves a tenth of the shorter side white all round
+pub fn id_marker_rect(width: i32, height: i32) -> Rect {
+    let margin = width.min(height) / 10;
+    let side = width.min(height) - 2 * margin;
+    Rect::new((width - side) / 2, (height - side) / 2, side, side)
+}
+
+/// Produce a white frame with marker id of dictionary in `id_marker_rect`, the white around
+/// it is the quiet zone the detector needs
+pub fn id_marker(width: i32, height: i32, id: i32, dictionary: ArucoDictionary) -> Mat {
+    let out = solid_color(width, height, 255, 255, 255);
+    let rect = id_marker_rect(width, height);
+    let dict = opencv::aruco::get_predefined_dictionary(dictionary.predefined()).unwrap();
+    let mut marker = Mat::default().unwrap();
+    opencv::aruco::draw_marker(&dict, id, rect.width, &mut marker, 1).expect("marker id isn't in the dictionary");
+    let mut color = Mat::default().unwrap();
+    cvt_color(&marker, &mut color, COLOR_GRAY2BGR, 0).unwrap();
+    let mut dst = Mat::roi(&out, rect).unwrap();
+    color.copy_to(&mut dst).unwrap();
+    out
+}
+
 pub fn pixel_png(r: u8, g: u8, b: u8) -> VectorOfu8 {
     let mat = Mat::new_size_with_default(Size::new(1, 1), CV_8UC3, Scalar::new(r as f64, g as f64, b as f64, 255.)).unwrap();
     encode_image(&mat, ".png")
@@ -0,0 +1,222 @@
+//! Re-running the math of a calibration many times against one capture. The detected corners
+//! are kept in memory along with everything derived from them, and each change only re-runs
+//! the stages downstream of it:
+//!
+//! image points -> (surface, camera pose, warp grid) -> scene points and look_at ->
+//! (eye, virtual camera options) -> result -> output conventions
+
+#[cfg(feature = "opencv")]
+use opencv::{prelude::*, core::{Mat, Matx33d}};
+use glm::*;
+use log::debug;
+use super::{Resolution, GridSpec, Error, CalibrationResult, PhysicalCameraPose};
+use super::{output, pipeline, progress};
+use super::output::{OutputConventions, EyePositionMeta, EyeSourceMeta};
+use super::pipeline::{ImagePointGrid, VirtualCamera, LookAtMethod};
+use super::projector::ProjectorOptics;
+use super::surfaces::{SurfaceType, CameraModel};
+#[cfg(feature = "opencv")]
+use super::{camera_calibration, images, session, DetectionOptions};
+
+/// One capture's detected corners and what's been computed from them
+pub struct CalibrationSession {
+    /// the photo as captured, when it's known
+    pub photo: Option<Vec<u8>>,
+    surface: SurfaceType,
+    camera: CameraModel,
+    image_points: ImagePointGrid,
+    warp_grid: GridSpec,
+    eye: glm::Vec3,
+    projector_optics: Option<ProjectorOptics>,
+    virtual_up: glm::Vec3,
+    clip_planes: Option<(f32, f32)>,
+    look_at_method: LookAtMethod,
+    projector_res: Resolution,
+    output_conventions: OutputConventions,
+    meta: output::Meta,
+    /// scene points on the warp grid and look_at, None once the surface or camera changes
+    scene: Option<(Vec<glm::Vec3>, glm::Vec3)>,
+    /// in the default conventions, None once anything upstream changes
+    result: Option<CalibrationResult>,
+}
+
+impl CalibrationSession {
+    /// image_points must be complete. The projector orientation and pattern placement come
+    /// from meta.
+    pub fn new(surface: SurfaceType, camera: CameraModel, image_points: ImagePointGrid, eye: glm::Vec3, warp_grid: GridSpec, projector_res: Resolution, meta: output::Meta) -> CalibrationSession {
+        CalibrationSession {
+            photo: None,
+            surface: surface,
+            camera: camera,
+            image_points: image_points,
+            warp_grid: warp_grid,
+            eye: eye,
+            projector_optics: None,
+            virtual_up: vec3(0., 1., 0.),
+            clip_planes: None,
+            look_at_method: LookAtMethod::default(),
+            projector_res: projector_res,
+            output_conventions: OutputConventions::default(),
+            meta: meta,
+            scene: None,
+            result: None,
+        }
+    }
+
+    /// A session saved by `produce_calibration`. With redetect the corners are detected again
+    /// from the saved photo rather than using the saved image points, writing debug artifacts
+    /// in debug_encoding.
+    #[cfg(feature = "opencv")]
+    pub fn load(session_dir: &str, redetect: bool, debug_encoding: images::ImageEncoding) -> Result<CalibrationSession, Error> {
+        let record = session::load_session(session_dir)?;
+        let intrinsics = &record.intrinsics;
+        let calibration = camera_calibration::from_parts(
+            Matx33d::from(intrinsics.camera_matrix),
+            Mat::from_slice(&intrinsics.distortion_coefficients)?,
+            intrinsics.image_width,
+            intrinsics.image_height
+        );
+        let physical_camera = super::PhysicalCamera {
+            position: vec3(record.physical_camera.position[0], record.physical_camera.position[1], record.physical_camera.position[2]),
+            look_at: vec3(record.physical_camera.look_at[0], record.physical_camera.look_at[1], record.physical_camera.look_at[2]),
+            up_dir: vec3(record.physical_camera.up[0], record.physical_camera.up[1], record.physical_camera.up[2]),
+            calibration: calibration,
+        };
+        let photo = std::fs::read(session::photo_path(session_dir, &record))?;
+        if redetect && record.tiling.is_some() {
+            return Err(Error::Config("the session was captured in tiles and only the first tile's photo is saved, so it can't be redetected".to_string()));
+        }
+        if redetect && record.structured_light {
+            return Err(Error::Config("the session was captured with structured light and only its white frame is saved, so it can't be redetected".to_string()));
+        }
+        let image_points = if redetect {
+            let (_, undistorted) = pipeline::take_undistorted_photo(&physical_camera.calibration, &Mat::from_slice(&photo)?, debug_encoding)?;
+            pipeline::locate_chessboard_corners(&undistorted, record.warp_resolution, &DetectionOptions::default(), debug_encoding)?.0
+        } else {
+            let mut image_points = ImagePointGrid::new(record.warp_resolution.cols, record.warp_resolution.rows, record.image_points.clone());
+            if let Some(confidence) = record.image_confidence.as_ref().filter(|c| c.len() == image_points.points.len()) {
+                image_points.confidence = confidence.clone();
+            }
+            image_points
+        };
+
+        let mut meta = output::Meta::new(
+            record.surface,
+            record.physical_camera.clone(),
+            record.camera_calibration.clone(),
+            record.warp_resolution,
+            record.projector_resolution,
+            output::CameraSourceMeta::Session {path: session_dir.to_string()}
+        );
+        meta.projector_orientation = record.projector_orientation;
+        meta.pattern_placement = record.pattern_placement;
+        meta.tiling = record.tiling;
+        meta.structured_light = record.structured_light;
+        meta.camera_intrinsics = Some(camera_calibration::intrinsics_meta(&physical_camera.calibration));
+        let mut session = CalibrationSession::new(
+            record.surface,
+            physical_camera.model(),
+            image_points,
+            record.eye_position,
+            record.warp_grid.unwrap_or(record.warp_resolution),
+            record.projector_resolution,
+            meta
+        );
+        session.photo = Some(photo);
+        session.projector_optics = record.projector_optics;
+        session.look_at_method = record.look_at_method.unwrap_or(LookAtMethod::ImageCenter);
+        session.virtual_up = record.virtual_up;
+        session.clip_planes = record.clip_planes.map(|[near, far]| (near, far));
+        Ok(session)
+    }
+
+    /// The calibration with the current settings, in the default output conventions
+    pub fn result(&mut self) -> Result<CalibrationResult, Error> {
+        if self.scene.is_none() {
+            debug!("recomputing scene coordinates");
+            let scene = pipeline::locate_scene_coords(&self.surface, &self.camera, &self.image_points)?;
+            let scene = pipeline::resample_placed_scene(&self.surface, &scene, GridSpec {cols: self.image_points.cols, rows: self.image_points.rows}, self.warp_grid, self.meta.pattern_placement.as_ref());
+            let look_at = pipeline::calculate_look_at(&self.surface, &self.image_points, &self.camera)?;
+            self.scene = Some((scene, look_at));
+        }
+        if self.result.is_none() {
+            let (scene, look_at) = self.scene.as_ref().unwrap();
+            let mut virtual_camera = VirtualCamera::new(self.eye);
+            virtual_camera.optics = self.projector_optics;
+            virtual_camera.up_dir = self.virtual_up;
+            virtual_camera.clip_planes = self.clip_planes;
+            virtual_camera.look_at_method = self.look_at_method;
+            let orientation = self.meta.projector_orientation;
+            let detected = GridSpec {cols: self.image_points.cols, rows: self.image_points.rows};
+            let confidence = pipeline::resample_confidence(&self.image_points.confidence, detected, self.warp_grid, self.meta.pattern_placement.as_ref());
+            let mut result = pipeline::compute_calibration_from_scene(scene, Some(&confidence), *look_at, &mut virtual_camera, self.warp_grid, self.projector_res, orientation, self.meta.clone(), &mut progress::NoProgress, &mut None)?;
+            result.valid = pipeline::placed_valid(detected, self.warp_grid, self.meta.pattern_placement.as_ref());
+            result.confidence = Some(confidence);
+            self.result = Some(result);
+        }
+        Ok(self.result.clone().unwrap())
+    }
+
+    /// The calibration for another eye position. The scene points are reused.
+    pub fn recompute_with_eye(&mut self, eye: glm::Vec3) -> Result<CalibrationResult, Error> {
+        self.eye = eye;
+        self.meta.eye_position = Some(EyePositionMeta {source: EyeSourceMeta::Fixed, position: *eye.as_array()});
+        self.result = None;
+        self.result()
+    }
+
+    /// The calibration with another projector frustum, virtual up vector, clip planes or way
+    /// of choosing look_at. The scene points are reused.
+    pub fn recompute_with_virtual_camera(&mut self, projector_optics: Option<ProjectorOptics>, virtual_up: glm::Vec3, clip_planes: Option<(f32, f32)>, look_at_method: LookAtMethod) -> Result<CalibrationResult, Error> {
+        self.projector_optics = projector_optics;
+        self.virtual_up = virtual_up;
+        self.clip_planes = clip_planes;
+        self.look_at_method = look_at_method;
+        self.result = None;
+        self.result()
+    }
+
+    /// The calibration on another surface. The image points are reused, the scene points
+    /// and look_at are recomputed.
+    pub fn recompute_with_surface(&mut self, surface: SurfaceType) -> Result<CalibrationResult, Error> {
+        self.surface = surface;
+        self.meta.surface = surface;
+        self.invalidate_scene();
+        self.result()
+    }
+
+    /// The calibration with the physical camera somewhere else. The image points are reused,
+    /// the scene points and look_at are recomputed.
+    pub fn recompute_with_camera_pose(&mut self, pose: PhysicalCameraPose) -> Result<CalibrationResult, Error> {
+        self.camera.position = pose.position;
+        self.camera.look_at = pose.look_at;
+        self.camera.up_dir = pose.up_dir;
+        self.meta.physical_camera = output::PhysicalCameraMeta {
+            position: *pose.position.as_array(),
+            look_at: *pose.look_at.as_array(),
+            up: *pose.up_dir.as_array(),
+        };
+        self.invalidate_scene();
+        self.result()
+    }
+
+    /// The calibration interpolated to another warp grid. The scene points are resampled.
+    pub fn recompute_with_warp_grid(&mut self, warp_grid: GridSpec) -> Result<CalibrationResult, Error> {
+        self.warp_grid = warp_grid;
+        self.invalidate_scene();
+        self.result()
+    }
+
+    /// The calibration in other output conventions, as it would be posted. Nothing upstream
+    /// is recomputed unless it's stale.
+    pub fn recompute_output(&mut self, conventions: OutputConventions) -> Result<CalibrationResult, Error> {
+        self.output_conventions = conventions;
+        let result = self.result()?;
+        Ok(self.output_conventions.apply(&result, self.projector_res))
+    }
+
+    fn invalidate_scene(&mut self) {
+        self.scene = None;
+        self.result = None;
+    }
+}
@@ -0,0 +1,134 @@
+//! Quick keystone correction for flat screens. A single homography between the chessboard
+//! as rendered and as photographed stands in for the surface and eye position model.
+
+use opencv::prelude::*;
+use opencv::types::*;
+use opencv::core::*;
+use opencv::calib3d::{find_homography, RANSAC};
+use serde::{Serialize, Deserialize};
+use log::info;
+use super::{Resolution, GridSpec, CALIBRATION_FORMAT_VERSION};
+use super::pipeline::ImagePointGrid;
+
+/// Which representation of the correction to emit
+#[derive(Clone, Copy, Debug)]
+pub enum KeystoneOutput {
+    Homography,
+    CornerPin,
+}
+
+/// The keystone JSON document
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct KeystoneResult {
+    pub format_version: u32,
+    pub projector_resolution: Resolution,
+    /// 3x3 matrix, row by row, mapping projector pixels to camera photo pixels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub homography: Option<[f64; 9]>,
+    /// normalized (0-1, top left origin) positions to pin the projector's top left, top right,
+    /// bottom right and bottom left corners to so the image appears rectangular, assuming
+    /// the camera faces the screen squarely
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub corner_pin: Option<[[f32; 2]; 4]>,
+    /// camera pixels between the detected corners and the chessboard mapped through the
+    /// homography. A few pixels or more means the screen probably isn't flat.
+    pub reprojection_rms: f64,
+    pub inliers: usize,
+    pub corners: usize,
+}
+
+impl KeystoneResult {
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// Inner corner positions of the chessboard rendered full screen, in projector pixels
+pub fn ideal_grid(grid: GridSpec, projector_res: Resolution) -> Vec<Point2f> {
+    let mut points = vec![];
+    for j in 0..grid.rows {
+        for i in 0..grid.cols {
+            points.push(Point2f::new(
+                (i + 1) as f32 / (grid.cols + 1) as f32 * projector_res.width as f32,
+                (j + 1) as f32 / (grid.rows + 1) as f32 * projector_res.height as f32
+            ));
+        }
+    }
+    points
+}
+
+/// Fit the homography and build the result
+pub fn fit_keystone(image_points: &ImagePointGrid, grid: GridSpec, projector_res: Resolution, output: KeystoneOutput) -> opencv::Result<KeystoneResult> {
+    let ideal = ideal_grid(grid, projector_res);
+    let mut src = VectorOfPoint2f::new();
+    let mut dst = VectorOfPoint2f::new();
+    for (i, point) in image_points.points.iter().enumerate() {
+        if image_points.valid[i] {
+            src.push(ideal[i]);
+            dst.push(Point2f::new(point.x, point.y));
+        }
+    }
+
+    let mut mask = Mat::default()?;
+    let h = find_homography(&src, &dst, RANSAC, 3., &mut mask, 2000, 0.995)?;
+    let mut m = [0_f64; 9];
+    for r in 0..3 {
+        for c in 0..3 {
+            m[(r * 3 + c) as usize] = *h.at_2d::<f64>(r, c)?;
+        }
+    }
+    let inliers = (0..mask.rows()).filter(|i| *mask.at::<u8>(*i).unwrap_or(&0) > 0).count();
+
+    let mut sum_sq = 0_f64;
+    for (s, d) in src.iter().zip(dst.iter()) {
+        let (x, y) = apply(&m, s.x as f64, s.y as f64);
+        sum_sq += (x - d.x as f64).powi(2) + (y - d.y as f64).powi(2);
+    }
+    let rms = (sum_sq / src.len().max(1) as f64).sqrt();
+    info!("keystone homography reprojection error is {} pixels rms ({} of {} corners inliers)", rms, inliers, src.len());
+
+    Ok(KeystoneResult {
+        format_version: CALIBRATION_FORMAT_VERSION,
+        projector_resolution: projector_res,
+        homography: match output { KeystoneOutput::Homography => Some(m), _ => None },
+        corner_pin: match output { KeystoneOutput::CornerPin => Some(corner_pin(&m, projector_res)), _ => None },
+        reprojection_rms: rms,
+        inliers: inliers,
+        corners: src.len(),
+    })
+}
+
+/// Largest axis aligned rectangle inside the projector's footprint in the photo, mapped back
+/// into normalized projector space
+fn corner_pin(m: &[f64; 9], projector_res: Resolution) -> [[f32; 2]; 4] {
+    let (w, h) = (projector_res.width as f64, projector_res.height as f64);
+    let quad: Vec<(f64, f64)> = [(0., 0.), (w, 0.), (w, h), (0., h)].iter().map(|(x, y)| apply(m, *x, *y)).collect();
+    let left = quad[0].0.max(quad[3].0);
+    let right = quad[1].0.min(quad[2].0);
+    let top = quad[0].1.max(quad[1].1);
+    let bottom = quad[2].1.min(quad[3].1);
+
+    let inverse = invert(m);
+    let mut pin = [[0_f32; 2]; 4];
+    for (i, (x, y)) in [(left, top), (right, top), (right, bottom), (left, bottom)].iter().enumerate() {
+        let (px, py) = apply(&inverse, *x, *y);
+        pin[i] = [(px / w) as f32, (py / h) as f32];
+    }
+    pin
+}
+
+pub(crate) fn apply(m: &[f64; 9], x: f64, y: f64) -> (f64, f64) {
+    let z = m[6] * x + m[7] * y + m[8];
+    ((m[0] * x + m[1] * y + m[2]) / z, (m[3] * x + m[4] * y + m[5]) / z)
+}
+
+/// 3x3 inverse via the adjugate, a homography is never singular
+fn invert(m: &[f64; 9]) -> [f64; 9] {
+    let det = m[0] * (m[4] * m[8] - m[5] * m[7]) - m[1] * (m[3] * m[8] - m[5] * m[6]) + m[2] * (m[3] * m[7] - m[4] * m[6]);
+    [
+        (m[4] * m[8] - m[5] * m[7]) / det, (m[2] * m[7] - m[1] * m[8]) / det, (m[1] * m[5] - m[2] * m[4]) / det,
+        (m[5] * m[6] - m[3] * m[8]) / det, (m[0] * m[8] - m[2] * m[6]) / det, (m[2] * m[3] - m[0] * m[5]) / det,
+        (m[3] * m[7] - m[4] * m[6]) / det, (m[1] * m[6] - m[0] * m[7]) / det, (m[0] * m[4] - m[1] * m[3]) / det,
+    ]
+}
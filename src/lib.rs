@@ -1,27 +1,106 @@
 
-use opencv::prelude::*;
-use opencv::types::*;
-use opencv::core::*;
-use opencv::imgcodecs;
-use opencv::imgproc::*;
-use opencv::calib3d::*;
+#[cfg(feature = "opencv")]
 use glm::*;
-use glm::ext::*;
-use serde_json::json;
-use std::io::prelude::*;
 use std::fmt;
-use log::{info, warn, debug};
+#[cfg(feature = "opencv")]
+use log::{info, warn};
 use regex::Regex;
 use lazy_static::*;
+use serde::{Serialize, Deserialize};
 
 mod math;
+#[cfg(feature = "opencv")]
 mod photo;
-mod images;
-mod network;
+#[cfg(feature = "opencv")]
+pub mod images;
+pub mod network;
+pub mod transport;
+#[cfg(feature = "opencv")]
+pub mod control;
+#[cfg(feature = "opencv")]
+pub mod display;
+pub mod progress;
+pub mod prompt;
+#[cfg(feature = "opencv")]
 mod locator;
 pub mod surfaces;
-mod camera_calibration;
+#[cfg(feature = "opencv")]
+pub mod camera_calibration;
+pub mod simulation;
+pub mod session;
+pub mod output;
+pub mod pipeline;
+#[cfg(feature = "opencv")]
+pub mod multi_camera;
+pub mod eye_position;
+pub mod projector;
+#[cfg(feature = "opencv")]
+pub mod keystone;
+pub mod detection;
+#[cfg(feature = "opencv")]
+pub mod verify;
+#[cfg(feature = "opencv")]
+pub mod monitor;
+#[cfg(feature = "opencv")]
+pub mod identify;
+#[cfg(feature = "opencv")]
+pub mod defects;
+pub mod timings;
+pub mod compare;
+pub mod coverage;
+pub mod grid_order;
+pub mod confidence;
+pub mod tiling;
+pub mod structured_light;
+pub mod refine;
+pub mod incremental;
+#[cfg(feature = "opencv")]
+pub mod report;
+#[cfg(feature = "async")]
+pub mod async_api;
+mod error;
 
+pub use error::Error;
+#[cfg(feature = "opencv")]
+pub use control::{ControlProtocol, SessionCommands, SessionGuard};
+#[cfg(feature = "opencv")]
+pub use display::{PatternDisplay, LocalDisplay};
+pub use progress::{CalibrationEvent, ProgressSink};
+pub use prompt::{OperatorPrompt, PromptError, StdinPrompt, TimeoutPrompt, NonInteractive};
+pub use output::{CalibrationResult, CALIBRATION_FORMAT_VERSION, OutputConventions, WarpUnits, WarpOrder, OutputTransform, AxisConvention, MatrixLayout};
+pub use pipeline::{VirtualCamera, ImagePointGrid, LookAtMethod};
+pub use eye_position::{EyePositionSource, EyeTransform};
+pub use projector::{ProjectorOrientation, ProjectorOptics, PatternPlacement};
+pub use tiling::Tiling;
+pub use structured_light::{GrayCodeSequence, PatternFrame, FrameKind, StripeAxis, DenseCorrespondence};
+#[cfg(feature = "opencv")]
+pub use keystone::{KeystoneOutput, KeystoneResult};
+pub use detection::{DetectionOptions, DetectionVariant, DetectionRoi, Polarity};
+#[cfg(feature = "opencv")]
+pub use verify::VerificationReport;
+#[cfg(feature = "opencv")]
+pub use monitor::{MonitorOptions, MonitorReport, MonitorStatus, DriftThresholds};
+#[cfg(feature = "opencv")]
+pub use identify::{IdentificationReport, OutputIdentity};
+#[cfg(feature = "opencv")]
+pub use defects::{DefectOptions, DefectFrames, CameraDefects};
+pub use timings::Timings;
+pub use compare::{compare_calibrations, CalibrationDiff, DisplacementStats};
+pub use refine::{SurfaceParameter, SurfaceRefinement};
+pub use incremental::CalibrationSession;
+#[cfg(feature = "opencv")]
+pub use report::{session_report, write_session_report};
+#[cfg(feature = "opencv")]
+pub use locator::{ArucoDictionary, CameraLocation, AlternativePose, MarkerSelection, EulerOrder};
+#[cfg(feature = "opencv")]
+pub use photo::{WarmUp, StabilityCheck};
+use pipeline::compute_calibration;
+#[cfg(feature = "opencv")]
+use pipeline::{Capture, detect_image_points, compute_calibration_from_scene, take_undistorted_photo, locate_chessboard_corners, calibration_json_string};
+#[cfg(feature = "opencv")]
+use timings::Stage;
+
+#[cfg(feature = "opencv")]
 pub struct PhysicalCamera {
     pub position: glm::Vec3,
     pub look_at: glm::Vec3, // TODO rename this to direction
@@ -29,19 +108,65 @@ pub struct PhysicalCamera {
     pub calibration: camera_calibration::Calibration,
 }
 
-struct VirtualCamera {
+/// Where the physical camera is and which way it faces, in scene space
+#[derive(Clone, Copy, Debug)]
+pub struct PhysicalCameraPose {
     pub position: glm::Vec3,
-    pub up_dir: glm::Vec3, // always 0, 1, 0
-    pub look_at: Option<glm::Vec3>, // this is calculated during calibration
-    pub fov: Option<f32> // this is calculated during calibration
+    /// the direction the camera faces, like `PhysicalCamera::look_at`
+    pub look_at: glm::Vec3,
+    pub up_dir: glm::Vec3,
+}
+
+#[cfg(feature = "opencv")]
+impl PhysicalCamera {
+    pub fn set_pose(&mut self, pose: PhysicalCameraPose) {
+        self.position = pose.position;
+        self.look_at = pose.look_at;
+        self.up_dir = pose.up_dir;
+    }
+
+    /// Scale the calibration to photos of width x height, so the surface mapping sees the
+    /// photos' own intrinsics. See `Calibration::for_photo`.
+    pub fn match_photo(&mut self, width: i32, height: i32) -> Result<(), Error> {
+        if let Some(scaled) = self.calibration.for_photo(width, height)? {
+            self.calibration = scaled;
+        }
+        Ok(())
+    }
+
+    /// The pose and field of view the surface mapping uses
+    pub fn model(&self) -> surfaces::CameraModel {
+        surfaces::CameraModel {
+            position: self.position,
+            look_at: self.look_at,
+            up_dir: self.up_dir,
+            intrinsics: surfaces::CameraIntrinsics {
+                fov: self.calibration.fov,
+                image_width: self.calibration.image_width,
+                image_height: self.calibration.image_height,
+            },
+        }
+    }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Resolution {
-    width: i32,
-    height: i32
+    pub width: i32,
+    pub height: i32
 }
 
+/// Named resolutions accepted by `Resolution::parse`, matched case insensitively
+pub const RESOLUTION_PRESETS: &[(&str, Resolution)] = &[
+    ("720p", Resolution {width: 1280, height: 720}),
+    ("1080p", Resolution {width: 1920, height: 1080}),
+    ("1200p", Resolution {width: 1920, height: 1200}),
+    ("wuxga", Resolution {width: 1920, height: 1200}),
+    ("1440p", Resolution {width: 2560, height: 1440}),
+    ("wqxga", Resolution {width: 2560, height: 1600}),
+    ("4k", Resolution {width: 3840, height: 2160}),
+    ("uhd", Resolution {width: 3840, height: 2160}),
+];
+
 impl fmt::Display for Resolution {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}x{}", self.width, self.height)
@@ -49,260 +174,1039 @@ impl fmt::Display for Resolution {
 }
 
 impl Resolution {
+    pub fn new(width: i32, height: i32) -> Result<Resolution, &'static str> {
+        if width <= 0 || height <= 0 {
+            return Err("resolution width and height must be positive");
+        }
+        Ok(Resolution {width: width, height: height})
+    }
+
     /// aspect ratio of projector output as a fraction (width/height)
     fn aspect_ratio(&self) -> f32 {
         self.width as f32 / self.height as f32
     }
 
+    /// "WIDTHxHEIGHT" (spaces and × allowed) or one of `RESOLUTION_PRESETS`
     pub fn parse(input: &str) -> Result<Resolution, &'static str> {
         lazy_static! {
-            static ref RE: Regex = Regex::new(r"(?P<width>\d+)x(?P<height>\d+)").unwrap();
+            static ref RE: Regex = Regex::new(r"^(?P<width>\d+)\s*[xX×]\s*(?P<height>\d+)$").unwrap();
         }
-        let caps = RE.captures(input).expect("Failed to parse resolution input string");
-        Ok(Resolution {
-            width: caps["width"].parse().unwrap(),
-            height: caps["height"].parse().unwrap()
-        })
-    }
-}
-
-/// Output camera location relative to a single 6x6 aruco marker at 0,0,0 facing into the Z axis
-pub fn locate_camera(camera_cal_fname: &str, camera: Option<&str>, marker_size: f32) {
-    let calibration = camera_calibration::load_calibration_file(camera_cal_fname).expect("load of calibration XML failed");
-    let camera_type = match camera {
-        Some(url_or_path) => {
-            if url_or_path.starts_with("http") {
-                photo::CameraType::RemoteHttpCamera {url: url_or_path.to_string()}
-            } else {
-                // TODO check early that file exists
-                photo::CameraType::SingleImageFile {path: url_or_path.to_string()}
-            }
+        let input = input.trim();
+        if let Some((_, res)) = RESOLUTION_PRESETS.iter().find(|(name, _)| name.eq_ignore_ascii_case(input)) {
+            return Ok(*res);
         }
-        None => photo::CameraType::TetheredCamera
-    };
+        let caps = RE.captures(input).ok_or("resolution must be WIDTHxHEIGHT or a preset like 1080p")?;
+        Resolution::new(
+            caps["width"].parse().map_err(|_| "resolution is too large")?,
+            caps["height"].parse().map_err(|_| "resolution is too large")?
+        )
+    }
+}
+
+/// Size of a chessboard, and so of the warp grid, in inner corners: the points where four
+/// squares meet. This is what opencv detects, the board is drawn with (cols + 1) x (rows + 1)
+/// squares. A chessboard must be odd x even so its orientation is unambiguous, warp grids and
+/// tile counts can be any positive size, see `GridSpec::sized`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GridSpec {
+    #[serde(alias = "width")]
+    pub cols: i32,
+    #[serde(alias = "height")]
+    pub rows: i32,
+}
+
+impl fmt::Display for GridSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.cols, self.rows)
+    }
+}
+
+impl GridSpec {
+    /// A chessboard's inner corners
+    pub fn new(cols: i32, rows: i32) -> Result<GridSpec, &'static str> {
+        let grid = GridSpec::sized(cols, rows)?;
+        if cols % 2 == 0 || rows % 2 == 1 {
+            return Err("chessboard size must be an odd number of columns by an even number of rows");
+        }
+        Ok(grid)
+    }
+
+    /// A grid of any positive size
+    pub fn sized(cols: i32, rows: i32) -> Result<GridSpec, &'static str> {
+        if cols < 1 || rows < 1 {
+            return Err("grid size must be at least 1x1");
+        }
+        Ok(GridSpec {cols: cols, rows: rows})
+    }
+
+    /// number of corners
+    pub fn len(&self) -> usize {
+        (self.cols * self.rows) as usize
+    }
+
+    /// "COLSxROWS" inner corners of a chessboard, see `new`
+    pub fn parse(input: &str) -> Result<GridSpec, &'static str> {
+        let grid = GridSpec::parse_sized(input)?;
+        GridSpec::new(grid.cols, grid.rows)
+    }
+
+    /// "COLSxROWS" of any positive size, for warp grids and tile counts
+    pub fn parse_sized(input: &str) -> Result<GridSpec, &'static str> {
+        lazy_static! {
+            static ref RE: Regex = Regex::new(r"^(?P<cols>\d+)x(?P<rows>\d+)$").unwrap();
+        }
+        let caps = RE.captures(input.trim()).ok_or("grid size must be in the form COLSxROWS")?;
+        GridSpec::sized(
+            caps["cols"].parse().map_err(|_| "grid size is too large")?,
+            caps["rows"].parse().map_err(|_| "grid size is too large")?
+        )
+    }
+}
+
+/// Optional settings for `produce_calibration`
+#[cfg(feature = "opencv")]
+pub struct CalibrationOptions {
+    /// JSON file containing the physical camera pose (output of `locate_camera`)
+    pub camera_location_fname: Option<String>,
+    /// the physical camera pose, when it's already known. Takes precedence over
+    /// camera_location_fname (with a warning if both are given).
+    pub camera_pose: Option<PhysicalCameraPose>,
+    /// where to send the finished calibration. When None it's printed to stdout.
+    pub post_to: Option<Box<dyn ControlProtocol + Send>>,
+    pub progress: Box<dyn ProgressSink + Send>,
+    /// save the captured photo, detected points and everything else needed to recompute
+    /// the calibration offline (see `recompute_calibration`) into this directory
+    pub session_dir: Option<String>,
+    /// how the projector is mounted, patterns are rotated to appear upright and the warp is
+    /// given in the projector's native image space
+    pub projector_orientation: ProjectorOrientation,
+    /// the projector's throw ratio and lens shift, when known
+    pub projector_optics: Option<ProjectorOptics>,
+    /// conventions of the warp in the JSON that's posted or printed, the returned result
+    /// always uses the defaults
+    pub output_conventions: OutputConventions,
+    /// size of the output warp grid, interpolated from the detected chessboard. None for the
+    /// chessboard's own grid.
+    pub warp_grid: Option<GridSpec>,
+    /// retries and preprocessing used to find the chessboard in the photos
+    pub detection: DetectionOptions,
+    /// near and far clip distances of the virtual camera, derived from the scene when None
+    pub clip_planes: Option<(f32, f32)>,
+    /// up vector of the virtual camera, for renders that aren't level with the horizon.
+    /// Must not be parallel to the direction from the eye to look_at.
+    pub virtual_up: glm::Vec3,
+    /// record how long each stage takes, logged at the end of the run and returned in the
+    /// diagnostics
+    pub timings: bool,
+    /// how long to wait for a remote camera to catch up with each pattern, for cameras
+    /// without their own in the multi-camera setup
+    pub camera_warm_up: photo::WarmUp,
+    /// control servers of other projectors lighting the surface, blanked for the run so
+    /// they don't spoil the photos and restored afterwards
+    pub other_outputs: Vec<Box<dyn ControlProtocol + Send>>,
+    /// surface parameters to fit to the captured data before the warp is computed, the rest
+    /// are held at their nominal values. Needs several cameras seeing the same corners.
+    pub refine_surface: Vec<SurfaceParameter>,
+    /// show the chessboard in this part of the projector raster rather than all of it. The
+    /// warp still covers the whole raster, extrapolated beyond the chessboard.
+    pub pattern_placement: Option<PatternPlacement>,
+    /// capture the chessboard a tile at a time rather than whole, for boards too dense to
+    /// detect in one photo. Single camera runs only.
+    pub tiling: Option<Tiling>,
+    /// before the chessboard, show a marker from this dictionary on the measured output and
+    /// each of the other outputs to check the camera sees the right projector, see
+    /// `identify`. Needs a control server. Single camera runs only.
+    pub identify_outputs: Option<ArucoDictionary>,
+    /// camera defects to correct in every photo, see `defects`. Multi-camera runs only use
+    /// the cached ones.
+    pub defects: DefectOptions,
+    /// locate the chessboard's corners with a Gray code sequence rather than detecting the
+    /// chessboard, see `structured_light`. Needs a control server or fullscreen window.
+    /// Single camera runs only.
+    pub structured_light: bool,
+    /// fail when a photo isn't exactly the calibrated size, rather than scaling the camera
+    /// matrix to a photo at a uniform scale of it
+    pub strict_photo_size: bool,
+    /// how the virtual camera's look_at is chosen from the scene
+    pub look_at_method: LookAtMethod,
+    /// how debug artifacts (undistorted photos, detected corners) are written
+    pub debug_encoding: images::ImageEncoding,
+}
+
+#[cfg(feature = "opencv")]
+impl Default for CalibrationOptions {
+    fn default() -> CalibrationOptions {
+        CalibrationOptions {
+            camera_location_fname: None,
+            camera_pose: None,
+            post_to: None,
+            progress: Box::new(progress::NoProgress),
+            session_dir: None,
+            projector_orientation: ProjectorOrientation::Landscape,
+            projector_optics: None,
+            output_conventions: OutputConventions::default(),
+            warp_grid: None,
+            detection: DetectionOptions::default(),
+            virtual_up: vec3(0., 1., 0.),
+            clip_planes: None,
+            timings: false,
+            camera_warm_up: photo::WarmUp::default(),
+            other_outputs: vec![],
+            refine_surface: vec![],
+            pattern_placement: None,
+            tiling: None,
+            identify_outputs: None,
+            defects: DefectOptions::default(),
+            structured_light: false,
+            strict_photo_size: false,
+            look_at_method: LookAtMethod::default(),
+            debug_encoding: images::ImageEncoding::DEBUG_DEFAULT,
+        }
+    }
+}
+
+/// Output camera location relative to an aruco marker from dictionary at 0,0,0 facing into
+/// the Z axis. selection picks the marker when the photo has several. The detected markers
+/// are written as a debug artifact in debug_encoding.
+#[cfg(feature = "opencv")]
+pub fn locate_camera(camera_cal_fname: &str, camera: Option<&str>, marker_size: f32, dictionary: ArucoDictionary, selection: MarkerSelection, debug_encoding: images::ImageEncoding) -> Result<CameraLocation, Error> {
+    let calibration = camera_calibration::load_calibration_file(camera_cal_fname)?;
+    let camera_type = photo::CameraType::from_arg(camera);
     let photo = photo::capture_photo(camera_type);
-    let mut decoded = imgcodecs::imdecode(&photo, imgcodecs::IMREAD_COLOR).unwrap();
-    locator::locate_aruco_marker(&calibration, &mut decoded, marker_size);
+    let (mut decoded, _) = pipeline::to_8bit(&pipeline::decode_photo(&photo)?)?;
+    let location = locator::locate_aruco_marker(&calibration, &mut decoded, marker_size, dictionary, selection, debug_encoding)?;
+    println!("{}", location.to_json_string());
+    Ok(location)
 }
 
-pub fn produce_calibration(surface: surfaces::SurfaceType, camera_cal_fname: &str, control_url: Option<&str>, camera: Option<&str>, camera_location_fname: Option<&str>, eye_position: glm::Vec3, warp_res: Resolution, projector_res: Resolution, post_to: Option<&str>) {
-    let calibration = camera_calibration::load_calibration_file(camera_cal_fname).expect("load of calibration XML failed");
+/// The eye is resolved before anything is captured, so a tracker that can't be reached
+/// fails the run straight away.
+#[cfg(feature = "opencv")]
+pub fn produce_calibration(surface: surfaces::SurfaceType, camera_cal_fname: &str, display: PatternDisplay, camera: Option<&str>, eye: EyePositionSource, grid: GridSpec, projector_res: Resolution, options: CalibrationOptions) -> Result<CalibrationResult, Error> {
+    produce_calibration_with_camera(surface, camera_cal_fname, display, photo::CameraType::from_arg(camera), eye, grid, projector_res, options)
+}
+
+/// `produce_calibration` with the camera already chosen
+#[cfg(feature = "opencv")]
+pub(crate) fn produce_calibration_with_camera(surface: surfaces::SurfaceType, camera_cal_fname: &str, display: PatternDisplay, camera_type: photo::CameraType, eye: EyePositionSource, grid: GridSpec, projector_res: Resolution, mut options: CalibrationOptions) -> Result<CalibrationResult, Error> {
+    let eye_position = eye.resolve()?;
+    let mut timings = if options.timings { Some(Timings::default()) } else { None };
+    let (physical_camera, mut meta, capture) = capture_single_camera(surface, camera_cal_fname, display, camera_type, eye_position, grid, projector_res, &mut options, &mut timings)?;
+    meta.eye_position = Some(eye.meta(eye_position));
+    let mut virtual_camera = VirtualCamera::new(eye_position);
+    virtual_camera.optics = options.projector_optics;
+    virtual_camera.up_dir = options.virtual_up;
+    virtual_camera.clip_planes = options.clip_planes;
+    virtual_camera.look_at_method = options.look_at_method;
+    let progress = options.progress.as_mut();
+    let image_points = capture.image_points;
+    let mut result = compute_calibration(&surface, &physical_camera.model(), &image_points, &mut virtual_camera, options.warp_grid.unwrap_or(grid), projector_res, options.projector_orientation, meta, progress, &mut timings)?;
+    if let Some(diagnostics) = result.diagnostics.as_mut() {
+        diagnostics.detection_variant = Some(capture.detection_variant);
+        diagnostics.orientation_flipped = Some(capture.flipped);
+        diagnostics.coverage = Some(capture.coverage.clone());
+    }
+    let json = timings::timed(&mut timings, Stage::Serialization, || calibration_json_string(&result, &options.output_conventions, projector_res));
+    if let Some(protocol) = &options.post_to {
+        progress.event(CalibrationEvent::Posting);
+        protocol.send_calibration(&json)?;
+    } else {
+        println!("{}", json);
+    }
+    finish_timings(std::slice::from_mut(&mut result), timings);
+    progress.event(CalibrationEvent::Done);
+    Ok(result)
+}
+
+/// An eye position and the name its calibration is stored under
+#[derive(Clone, Debug)]
+pub struct NamedEyePosition {
+    pub name: String,
+    pub position: glm::Vec3,
+}
+
+/// Like `produce_calibration` but makes a calibration for each of several eye positions
+/// (e.g. one per row of seating) from a single capture. Each result has its own look_at and
+/// fov and is named with `eyeName`. Each is posted separately with its name, when not
+/// posting they're printed as one document keyed by name. A saved session records the
+/// first eye position.
+#[cfg(feature = "opencv")]
+pub fn produce_eye_calibrations(surface: surfaces::SurfaceType, camera_cal_fname: &str, display: PatternDisplay, camera: Option<&str>, eye_positions: &[NamedEyePosition], grid: GridSpec, projector_res: Resolution, mut options: CalibrationOptions) -> Result<Vec<CalibrationResult>, Error> {
+    if eye_positions.is_empty() {
+        return Err(Error::Config("no eye positions given".to_string()));
+    }
+    let mut timings = if options.timings { Some(Timings::default()) } else { None };
+    let (physical_camera, meta, capture) = capture_single_camera(surface, camera_cal_fname, display, photo::CameraType::from_arg(camera), eye_positions[0].position, grid, projector_res, &mut options, &mut timings)?;
+    let progress = options.progress.as_mut();
+
+    // everything up to the virtual camera is the same for every eye
+    let warp_grid = options.warp_grid.unwrap_or(grid);
+    let (scene_coords, look_at) = timings::timed(&mut timings, Stage::Scene, || {
+        let scene_coords = pipeline::locate_scene_coords(&surface, &physical_camera.model(), &capture.image_points)?;
+        let scene_coords = pipeline::resample_placed_scene(&surface, &scene_coords, grid, warp_grid, options.pattern_placement.as_ref());
+        Ok::<_, Error>((scene_coords, pipeline::calculate_look_at(&surface, &capture.image_points, &physical_camera.model())?))
+    })?;
+    let valid = pipeline::placed_valid(grid, warp_grid, options.pattern_placement.as_ref());
+    let confidence = pipeline::resample_confidence(&capture.image_points.confidence, grid, warp_grid, options.pattern_placement.as_ref());
+    let mut results = vec![];
+    for eye in eye_positions {
+        let mut virtual_camera = VirtualCamera::new(eye.position);
+        virtual_camera.optics = options.projector_optics;
+        virtual_camera.up_dir = options.virtual_up;
+        virtual_camera.clip_planes = options.clip_planes;
+        virtual_camera.look_at_method = options.look_at_method;
+        let mut result = compute_calibration_from_scene(&scene_coords, Some(&confidence), look_at, &mut virtual_camera, warp_grid, projector_res, options.projector_orientation, meta.clone(), progress, &mut timings)?;
+        result.eye_name = Some(eye.name.clone());
+        result.valid = valid.clone();
+        result.confidence = Some(confidence.clone());
+        if let Some(diagnostics) = result.diagnostics.as_mut() {
+            diagnostics.detection_variant = Some(capture.detection_variant);
+            diagnostics.orientation_flipped = Some(capture.flipped);
+            diagnostics.coverage = Some(capture.coverage.clone());
+        }
+        results.push(result);
+    }
+
+    if let Some(protocol) = &options.post_to {
+        progress.event(CalibrationEvent::Posting);
+        for result in results.iter() {
+            let json = timings::timed(&mut timings, Stage::Serialization, || calibration_json_string(result, &options.output_conventions, projector_res));
+            protocol.send_eye_calibration(result.eye_name.as_deref().unwrap(), &json)?;
+        }
+    } else {
+        let conventions = options.output_conventions;
+        let json = timings::timed(&mut timings, Stage::Serialization, || {
+            let converted: Vec<CalibrationResult> = results.iter().map(|result| conventions.apply(result, projector_res)).collect();
+            output::eye_calibrations_json(&converted)
+        });
+        println!("{}", json);
+    }
+    finish_timings(&mut results, timings);
+    progress.event(CalibrationEvent::Done);
+    Ok(results)
+}
+
+/// Show every output's identification marker and print which the camera sees, see
+/// `identify`. display must post to a control server, it's output 0 and others follow. Debug
+/// artifacts are written in debug_encoding.
+#[cfg(feature = "opencv")]
+pub fn identify_projectors(camera_cal_fname: &str, camera: Option<&str>, display: &PatternDisplay, others: &[Box<dyn ControlProtocol + Send>], projector_res: Resolution, orientation: ProjectorOrientation, dictionary: ArucoDictionary, debug_encoding: images::ImageEncoding) -> Result<IdentificationReport, Error> {
+    let calibration = camera_calibration::load_calibration_file(camera_cal_fname)?;
+    let report = identify_in_session(display, others, &calibration, photo::CameraType::from_arg(camera), projector_res, orientation, dictionary, debug_encoding)?;
+    println!("{}", report.to_json_string());
+    Ok(report)
+}
+
+/// `identify::identify_outputs` on display's control server and others, in a control session
+/// so they're put back afterwards
+#[cfg(feature = "opencv")]
+fn identify_in_session(display: &PatternDisplay, others: &[Box<dyn ControlProtocol + Send>], calibration: &camera_calibration::Calibration, camera_type: photo::CameraType, projector_res: Resolution, orientation: ProjectorOrientation, dictionary: ArucoDictionary, debug_encoding: images::ImageEncoding) -> Result<IdentificationReport, Error> {
+    let measured = display.control().ok_or(Error::Config("identifying the outputs needs a control URL".to_string()))?;
+    let _session = control_session(display, others)?;
+    let mut outputs = vec![("measured output".to_string(), measured)];
+    for (i, other) in others.iter().enumerate() {
+        outputs.push((format!("blank output {}", i + 1), other.as_ref() as &dyn ControlProtocol));
+    }
+    identify::identify_outputs(&outputs, calibration, camera_type, projector_res, orientation, dictionary, debug_encoding)
+}
+
+/// Check the camera sees the measured output before its chessboard is shown. When it doesn't
+/// but sees exactly one of the other outputs they were mislabeled, and that one is swapped in
+/// with a warning. Unless a detection ROI was given the measured output's footprint is used.
+#[cfg(feature = "opencv")]
+fn confirm_measured_output(display: &mut PatternDisplay, physical_camera: &PhysicalCamera, camera_type: photo::CameraType, projector_res: Resolution, dictionary: ArucoDictionary, options: &mut CalibrationOptions) -> Result<(), Error> {
+    let report = identify_in_session(display, &options.other_outputs, &physical_camera.calibration, camera_type, projector_res, options.projector_orientation, dictionary, options.debug_encoding)?;
+    let seen_others: Vec<usize> = report.outputs.iter().skip(1).filter(|output| output.seen).map(|output| output.index).collect();
+    let measured = if report.outputs[0].seen {
+        0
+    } else if seen_others.len() == 1 {
+        let index = seen_others[0];
+        warn!(
+            "the measured output's marker isn't in view but blank output {}'s is, the outputs look mislabeled so blank output {} is being calibrated instead",
+            index, index
+        );
+        if let PatternDisplay::Control(control) = display {
+            std::mem::swap(control, &mut options.other_outputs[index - 1]);
+        }
+        index
+    } else if seen_others.is_empty() {
+        return Err(Error::Detection("none of the outputs' identification markers are in view".to_string()));
+    } else {
+        return Err(Error::Detection(format!(
+            "the measured output's marker isn't in view but blank outputs {:?} are, so it isn't clear which to calibrate",
+            seen_others
+        )));
+    };
+    if options.detection.roi.is_none() {
+        options.detection.roi = report.roi(measured);
+    }
+    Ok(())
+}
+
+/// Start the control server session for a run on display, see `SessionGuard`
+#[cfg(feature = "opencv")]
+fn control_session<'a>(display: &'a PatternDisplay, others: &'a [Box<dyn ControlProtocol + Send>]) -> Result<SessionGuard<'a>, Error> {
+    let others = others.iter().map(|other| other.as_ref() as &dyn ControlProtocol).collect();
+    Ok(SessionGuard::begin(display.control(), others)?)
+}
+
+/// Load the camera, project the chessboard and detect its corners, saving a session when
+/// asked to
+#[cfg(feature = "opencv")]
+fn capture_single_camera(surface: surfaces::SurfaceType, camera_cal_fname: &str, mut display: PatternDisplay, camera_type: photo::CameraType, eye_position: glm::Vec3, grid: GridSpec, projector_res: Resolution, options: &mut CalibrationOptions, timings: &mut Option<Timings>) -> Result<(PhysicalCamera, output::Meta, Capture), Error> {
+    if !options.refine_surface.is_empty() {
+        warn!("surface refinement needs several cameras seeing the same corners, using the nominal surface");
+    }
+    let calibration = camera_calibration::load_calibration_file(camera_cal_fname)?;
     let mut physical_camera = PhysicalCamera {    
-        // camera position (should be suppied by user)
+        // camera position, unless one is given in the options
         position: vec3(0., 0., 0.),
         look_at: vec3(0., 1., 0.),
         up_dir: vec3(0., 0., 1.),
         calibration: calibration
     };
-    if let Some(fname) = camera_location_fname {
-        locator::update_physical_camera_location(&mut physical_camera, fname);
-    }
-    let camera_type = match camera {
-        Some(url_or_path) => {
-            if url_or_path.starts_with("http") {
-                photo::CameraType::RemoteHttpCamera {url: url_or_path.to_string()}
-            } else {
-                // TODO check early that file exists
-                photo::CameraType::SingleImageFile {path: url_or_path.to_string()}
+    match (options.camera_pose, &options.camera_location_fname) {
+        (Some(pose), fname) => {
+            if let Some(fname) = fname {
+                warn!("both a camera pose and camera location file {} were given, using the pose", fname);
             }
-        }
-        None => photo::CameraType::TetheredCamera
-    };
-    let mut virtual_camera = VirtualCamera {
-        position: eye_position,
-        look_at: None,
-        up_dir: vec3(0.0, 1.0, 0.0),
-        fov: None,
-    };
+            physical_camera.set_pose(pose);
+        },
+        (None, Some(fname)) => locator::update_physical_camera_location(&mut physical_camera, fname)?,
+        (None, None) => {}
+    }
+    info!("physical camera is at {:?} facing {:?}", physical_camera.position, physical_camera.look_at);
+    let camera_type = camera_type.with_warm_up(&options.camera_warm_up);
+    physical_camera.calibration.strict_photo_size = options.strict_photo_size;
+    let prompt: &dyn OperatorPrompt = display.prompt().unwrap_or(&StdinPrompt);
+    physical_camera.calibration.defects = defects::prepare(&options.defects, camera_cal_fname, &physical_camera.calibration, camera_type.clone(), prompt)?;
 
     info!("projector resolution is {}", projector_res);
 
-    let image_points = detect_image_points(&physical_camera, control_url, camera_type, warp_res);
-    let scene_coords = locate_scene_coords(&surface, &physical_camera, &image_points);
-    virtual_camera.look_at = Some(calculate_look_at(&surface, &image_points, &physical_camera));
-    let uv_coords = generate_uv_warp_and_fov(&scene_coords, &mut virtual_camera, projector_res);
-    let json = calibration_json_string(&scene_coords, &uv_coords, &virtual_camera, warp_res);
-    if let Some(url) = post_to {
-        network::send_command(&url, "set_calibration", &json);
+    let mut meta = output::Meta::new(
+        surface,
+        output::PhysicalCameraMeta {
+            position: *physical_camera.position.as_array(),
+            look_at: *physical_camera.look_at.as_array(),
+            up: *physical_camera.up_dir.as_array(),
+        },
+        Some(camera_calibration::file_identity(camera_cal_fname)?),
+        grid,
+        projector_res,
+        camera_type.meta()
+    );
+    meta.projector_orientation = options.projector_orientation;
+    meta.pattern_placement = options.pattern_placement;
+    meta.tiling = options.tiling;
+    meta.structured_light = options.structured_light;
+    meta.camera_intrinsics = Some(camera_calibration::intrinsics_meta(&physical_camera.calibration));
+    if meta.structured_light && meta.tiling.is_some() {
+        return Err(Error::Config("structured light locates every corner in one sequence, it can't be combined with tiling".to_string()));
+    }
+
+    if let Some(encoding) = display.pattern_encoding() {
+        info!("patterns are posted to the control server as {}", encoding);
+    }
+    if let Some(dictionary) = options.identify_outputs {
+        confirm_measured_output(&mut display, &physical_camera, camera_type.clone(), projector_res, dictionary, options)?;
+    }
+    let _session = control_session(&display, &options.other_outputs)?;
+    let progress = options.progress.as_mut();
+    let capture = detect_grid(&physical_camera, &display, camera_type, grid, &meta, &options.detection, options.debug_encoding, progress, timings)?;
+    display.close()?;
+    physical_camera.match_photo(capture.undistorted.cols(), capture.undistorted.rows())?;
+    meta.camera_intrinsics = Some(camera_calibration::intrinsics_meta(&physical_camera.calibration));
+    if let Some(dir) = &options.session_dir {
+        let mut record = session_record(&surface, &physical_camera, &meta, grid, eye_position, &capture);
+        record.projector_optics = options.projector_optics;
+        record.warp_grid = options.warp_grid;
+        record.look_at_method = Some(options.look_at_method);
+        record.virtual_up = options.virtual_up;
+        record.clip_planes = options.clip_planes.map(|(near, far)| [near, far]);
+        record.pattern_encoding = display.pattern_encoding().map(|encoding| encoding.to_string());
+        let undistorted = images::encode_image(&capture.undistorted, ".png");
+        session::save_session(dir, &record, &capture.photo, &undistorted.to_slice())?;
+    }
+    Ok((physical_camera, meta, capture))
+}
+
+/// Detect the grid chessboard shown as meta describes: its projector, placement, tiling and
+/// whether it's located with structured light
+#[cfg(feature = "opencv")]
+fn detect_grid(physical_camera: &PhysicalCamera, display: &PatternDisplay, camera_type: photo::CameraType, grid: GridSpec, meta: &output::Meta, detection: &DetectionOptions, debug_encoding: images::ImageEncoding, progress: &mut dyn ProgressSink, timings: &mut Option<Timings>) -> Result<Capture, Error> {
+    let (projector_res, orientation, placement) = (meta.projector_resolution, meta.projector_orientation, meta.pattern_placement.as_ref());
+    if meta.structured_light {
+        return structured_light::detect_structured_light(physical_camera, display, camera_type, grid, projector_res, orientation, placement, detection, debug_encoding, progress, timings);
+    }
+    match &meta.tiling {
+        Some(tiling) => tiling::detect_tiled(physical_camera, display, camera_type, grid, tiling, projector_res, orientation, placement, detection, debug_encoding, progress, timings),
+        None => detect_image_points(physical_camera, display, camera_type, grid, projector_res, orientation, placement, detection, debug_encoding, progress, timings)
+    }
+}
+
+/// Produce a calibration from several cameras at known poses, each seeing part of the
+/// surface. See `multi_camera` for how the corners are merged. Sessions aren't saved for
+/// multi-camera runs.
+#[cfg(feature = "opencv")]
+pub fn produce_multi_camera_calibration(surface: surfaces::SurfaceType, cameras: &[multi_camera::CameraSetup], display: PatternDisplay, eye: EyePositionSource, grid: GridSpec, projector_res: Resolution, mut options: CalibrationOptions) -> Result<CalibrationResult, Error> {
+    if cameras.is_empty() {
+        return Err(Error::Config("no cameras given for multi-camera calibration".to_string()));
+    }
+    let eye_position = eye.resolve()?;
+    if options.session_dir.is_some() {
+        warn!("sessions aren't saved for multi-camera calibrations");
+    }
+    if options.tiling.is_some() {
+        return Err(Error::Config("tiled capture isn't supported with several cameras, give each camera a region instead".to_string()));
+    }
+    if options.identify_outputs.is_some() {
+        warn!("the outputs are only identified in single camera runs, skipping it");
+    }
+    if options.structured_light {
+        return Err(Error::Config("structured light isn't supported with several cameras".to_string()));
+    }
+    if options.defects.frames.is_some() {
+        warn!("defect frames are only used in single camera runs, the cameras' cached defects are used instead");
+    }
+    let mut setup = cameras.iter()
+        .map(|camera| multi_camera::SetupCamera::load(camera, grid, &options.camera_warm_up, options.defects.cache_dir.as_deref()))
+        .collect::<Result<Vec<_>, Error>>()?;
+    for camera in setup.iter_mut() {
+        camera.physical_camera.calibration.strict_photo_size = options.strict_photo_size;
+    }
+    let mut virtual_camera = VirtualCamera::new(eye_position);
+    virtual_camera.optics = options.projector_optics;
+    virtual_camera.up_dir = options.virtual_up;
+    virtual_camera.clip_planes = options.clip_planes;
+    virtual_camera.look_at_method = options.look_at_method;
+    let mut timings = if options.timings { Some(Timings::default()) } else { None };
+
+    // meta describes the first camera, the rest are listed in the diagnostics
+    let first = &setup[0];
+    let mut meta = output::Meta::new(
+        surface,
+        output::PhysicalCameraMeta {
+            position: *first.physical_camera.position.as_array(),
+            look_at: *first.physical_camera.look_at.as_array(),
+            up: *first.physical_camera.up_dir.as_array(),
+        },
+        Some(camera_calibration::file_identity(&first.calibration_path)?),
+        grid,
+        projector_res,
+        first.camera_type.meta()
+    );
+    meta.camera_intrinsics = Some(camera_calibration::intrinsics_meta(&first.physical_camera.calibration));
+    meta.eye_position = Some(eye.meta(eye_position));
+    meta.projector_orientation = options.projector_orientation;
+    meta.pattern_placement = options.pattern_placement;
+
+    let session = control_session(&display, &options.other_outputs)?;
+    let progress = options.progress.as_mut();
+    let detected = multi_camera::detect_all(&mut setup, &display, grid, projector_res, options.projector_orientation, options.pattern_placement.as_ref(), &options.detection, options.debug_encoding, progress, &mut timings)?;
+    display.close()?;
+    meta.camera_intrinsics = Some(camera_calibration::intrinsics_meta(&setup[0].physical_camera.calibration));
+    drop(session);
+    let refinement = if options.refine_surface.is_empty() {
+        None
+    } else {
+        let observations = multi_camera::observations(&setup, &detected, grid);
+        Some(refine::refine_surface(&surface, &observations, &options.refine_surface)?)
+    };
+    let surface = refinement.as_ref().map(|refinement| refinement.surface).unwrap_or(surface);
+    meta.surface = surface;
+    let merged = timings::timed(&mut timings, Stage::Scene, || multi_camera::merge_scene_points(&surface, &setup, &detected, grid))?;
+    info!("cross-camera disagreement is {} rms, {} max", merged.rms_disagreement, merged.max_disagreement);
+
+    // look at the middle of everything the cameras saw, corners none of them saw are placeholders
+    let mut look_at = vec3(0., 0., 0.);
+    let mut count = 0;
+    for (p, valid) in merged.scene.iter().zip(merged.valid.iter()) {
+        if *valid { look_at = look_at + *p; count += 1; }
+    }
+    if count == 0 {
+        return Err(Error::Detection("none of the cameras detected any chessboard corners".to_string()));
+    }
+    look_at = look_at / count as f32;
+
+    let warp_grid = options.warp_grid.unwrap_or(grid);
+    let scene = pipeline::resample_placed_scene(&surface, &merged.scene, grid, warp_grid, options.pattern_placement.as_ref());
+    let valid = pipeline::resample_placed_valid(&merged.valid, grid, warp_grid, options.pattern_placement.as_ref());
+    let confidence = pipeline::resample_confidence(&merged.confidence, grid, warp_grid, options.pattern_placement.as_ref());
+    let mut result = compute_calibration_from_scene(&scene, Some(&confidence), look_at, &mut virtual_camera, warp_grid, projector_res, options.projector_orientation, meta, progress, &mut timings)?;
+    let multi_camera_diagnostics = multi_camera::diagnostics(&setup, &detected, &merged);
+    if let Some(diagnostics) = result.diagnostics.as_mut() {
+        diagnostics.detected_corners = merged.valid.iter().filter(|v| **v).count();
+        diagnostics.multi_camera = Some(multi_camera_diagnostics);
+        diagnostics.surface_refinement = refinement;
+    }
+    if valid.iter().any(|v| !*v) {
+        result.valid = Some(valid);
+    }
+    result.confidence = Some(confidence);
+
+    let json = timings::timed(&mut timings, Stage::Serialization, || calibration_json_string(&result, &options.output_conventions, projector_res));
+    if let Some(protocol) = &options.post_to {
+        progress.event(CalibrationEvent::Posting);
+        protocol.send_calibration(&json)?;
     } else {
         println!("{}", json);
     }
+    finish_timings(std::slice::from_mut(&mut result), timings);
+    progress.event(CalibrationEvent::Done);
+    Ok(result)
 }
 
+/// Quick keystone correction for a flat screen: show the chessboard, detect it and fit a
+/// homography between the chessboard as rendered and as photographed. Without a camera
+/// calibration file the photo is used as is, which is only accurate for low distortion lenses.
+/// Debug artifacts are written in debug_encoding.
+#[cfg(feature = "opencv")]
+pub fn produce_keystone(camera_cal_fname: Option<&str>, camera: Option<&str>, display: PatternDisplay, grid: GridSpec, projector_res: Resolution, output: KeystoneOutput, debug_encoding: images::ImageEncoding) -> Result<KeystoneResult, Error> {
+    let camera_type = photo::CameraType::from_arg(camera);
+    let chessboard = images::Pattern::Chessboard {grid: grid};
+    let session = control_session(&display, &[])?;
+    display.show(&chessboard, projector_res, ProjectorOrientation::Landscape)?;
+    let photo_data = photo::capture_photo(camera_type);
+    display.close()?;
+    drop(session);
 
-fn calculate_look_at(surface: &surfaces::SurfaceType, image_points: &Vec<glm::Vec2>, physical_camera: &PhysicalCamera) -> glm::Vec3 {
-    // possibly naively, we just look_at the center of the chessboard
-    let mut avg = vec2(0., 0.);
-    for p in image_points.iter() { avg = avg + *p }
-    avg = avg / image_points.len() as f32;
-    
-    debug!("Projection area center point is {:?}", avg);
+    let photo = match camera_cal_fname {
+        Some(fname) => {
+            let calibration = camera_calibration::load_calibration_file(fname)?;
+            take_undistorted_photo(&calibration, &photo_data, debug_encoding)?.1
+        },
+        None => {
+            warn!("no camera calibration given, assuming the photo has no lens distortion");
+            pipeline::greyscale_image(&pipeline::decode_photo(&photo_data)?)?
+        }
+    };
+    let (corners, _) = locate_chessboard_corners(&photo, grid, &DetectionOptions::default(), debug_encoding)?;
+    Ok(keystone::fit_keystone(&corners, grid, projector_res, output)?)
+}
 
-    surfaces::camera_to_scene(
-        &surface,
-        &physical_camera,
-        avg,
-        physical_camera.calibration.image_width,
-        physical_camera.calibration.image_height
-    ).unwrap()
+/// Check a stored calibration still matches the rig: project the chessboard again, locate
+/// its corners on the surface and compare them with the stored scene points. The surface,
+/// projector resolution and orientation come from the stored meta, the camera pose too
+/// unless camera_location_fname is given. Passes when no corner moved further than
+/// tolerance (scene units). The report is printed as JSON. Debug artifacts are written in
+/// debug_encoding.
+#[cfg(feature = "opencv")]
+pub fn verify_calibration(stored: &CalibrationResult, camera_cal_fname: &str, display: PatternDisplay, camera: Option<&str>, camera_location_fname: Option<&str>, grid: GridSpec, tolerance: f32, debug_encoding: images::ImageEncoding) -> Result<VerificationReport, Error> {
+    let (report, _) = capture_verification(stored, camera_cal_fname, &display, photo::CameraType::from_arg(camera), camera_location_fname, grid, tolerance, None, debug_encoding)?;
+    println!("{}", report.to_json_string());
+    Ok(report)
 }
 
-fn locate_scene_coords(surface: &surfaces::SurfaceType, physical_camera: &PhysicalCamera, image_points: &Vec<glm::Vec2>) -> Vec<glm::Vec3> { 
-    let mut scene_coords = vec![];
+/// The stored calibration's meta and grid, when a grid chessboard capture can be compared
+/// with it
+#[cfg(feature = "opencv")]
+fn verifiable(stored: &CalibrationResult, grid: GridSpec) -> Result<(&output::Meta, GridSpec), Error> {
+    let meta = stored.meta.as_ref().ok_or(Error::Config("the stored calibration has no meta, so its surface and projector aren't known".to_string()))?;
+    if let Some(detection_grid) = meta.detection_grid {
+        if detection_grid != grid {
+            return Err(Error::Config(format!("the stored calibration was detected with a {} chessboard, not {}", detection_grid, grid)));
+        }
+    }
+    Ok((meta, verify::stored_grid(stored)?))
+}
 
-    for point in image_points.iter() {
-        // Convert point in camera space to a point in 3d world space
-        let scene_coord = surfaces::camera_to_scene(
-            &surface,
-            &physical_camera,
-            *point,
-            physical_camera.calibration.image_width,
-            physical_camera.calibration.image_height
-        ).unwrap();
-        scene_coords.push(scene_coord);
+/// Capture and compare for `verify_calibration`, also returning the fresh scene points on
+/// the stored grid. The camera's defects are loaded from defect_cache_dir when it's given.
+#[cfg(feature = "opencv")]
+fn capture_verification(stored: &CalibrationResult, camera_cal_fname: &str, display: &PatternDisplay, camera_type: photo::CameraType, camera_location_fname: Option<&str>, grid: GridSpec, tolerance: f32, defect_cache_dir: Option<&str>, debug_encoding: images::ImageEncoding) -> Result<(VerificationReport, Vec<glm::Vec3>), Error> {
+    let (meta, stored_grid) = verifiable(stored, grid)?;
+
+    let mut calibration = camera_calibration::load_calibration_file(camera_cal_fname)?;
+    if let Some(dir) = defect_cache_dir {
+        calibration.defects = defects::CameraDefects::load(dir, camera_cal_fname, &calibration)?;
+        if calibration.defects.is_none() {
+            warn!("no camera defects are cached in {} for {}, the photos are used as they are", dir, camera_cal_fname);
+        }
+    }
+    let pose = &meta.physical_camera;
+    let mut physical_camera = PhysicalCamera {
+        position: vec3(pose.position[0], pose.position[1], pose.position[2]),
+        look_at: vec3(pose.look_at[0], pose.look_at[1], pose.look_at[2]),
+        up_dir: vec3(pose.up[0], pose.up[1], pose.up[2]),
+        calibration: calibration,
+    };
+    if let Some(fname) = camera_location_fname {
+        locator::update_physical_camera_location(&mut physical_camera, fname)?;
     }
 
-    scene_coords
+    let session = control_session(display, &[])?;
+    let capture = detect_grid(&physical_camera, display, camera_type, grid, meta, &DetectionOptions::default(), debug_encoding, &mut progress::NoProgress, &mut None)?;
+    display.close()?;
+    drop(session);
+    physical_camera.match_photo(capture.undistorted.cols(), capture.undistorted.rows())?;
+    if !capture.image_points.is_complete() {
+        return Err(Error::Display(format!("only {} of {} chessboard corners were detected", capture.image_points.len(), grid.len())));
+    }
+    let scene = pipeline::locate_scene_coords(&meta.surface, &physical_camera.model(), &capture.image_points)?;
+    let scene = pipeline::resample_placed_scene(&meta.surface, &scene, grid, stored_grid, meta.pattern_placement.as_ref());
+
+    let aspect_ratio = meta.projector_orientation.effective_resolution(meta.projector_resolution).aspect_ratio();
+    let mut report = verify::compare(stored, &scene, aspect_ratio, tolerance)?;
+    report.camera_mismatches = verify::camera_mismatches(meta, &physical_camera, &camera_calibration::file_identity(camera_cal_fname)?);
+    for mismatch in report.camera_mismatches.iter() {
+        warn!("{}", mismatch);
+    }
+    info!("verification {}: scene displacement {} rms, {} max", if report.passed { "passed" } else { "failed" }, report.scene_rms, report.scene_max);
+    Ok((report, scene))
 }
 
-fn detect_image_points(physical_camera: &PhysicalCamera, control_url: Option<&str>, camera_type: photo::CameraType, warp_res: Resolution) -> Vec<glm::Vec2> {
-    // show chessboard image on first projector
-    let chessboard = images::chessboard_image(warp_res.width, warp_res.height, ".png");
-    match &control_url {
-        Some(url) => {
-            network::post_image(&url, &chessboard.to_slice(), "png").unwrap();
-        },
-        None => {
-            info!("Please display the full-screen chessboard pattern on the projector and press any key");
-            std::io::stdin().bytes().next();
-            info!("Continuing...");
+/// Re-verify a stored calibration unattended, for running from cron or a service. The
+/// display must be a control server so nobody has to show the patterns. Captures that fail
+/// (an offline camera, the chessboard not found) are retried, and when every attempt fails
+/// the report's status is `MonitorStatus::Failed` rather than a drift. Otherwise the status
+/// follows the largest uv displacement, and a small enough drift can be corrected by posting
+/// the adjusted baseline (see `monitor::corrected_calibration`). The report is written to
+/// the results directory and printed as JSON. Err is only returned for setups that can't be
+/// monitored and reports that can't be written.
+#[cfg(feature = "opencv")]
+pub fn monitor_calibration(stored: &CalibrationResult, camera_cal_fname: &str, display: PatternDisplay, camera: Option<&str>, camera_location_fname: Option<&str>, grid: GridSpec, options: &MonitorOptions) -> Result<MonitorReport, Error> {
+    let protocol = display.control().ok_or(Error::Config("monitoring needs a control server to show the patterns unattended".to_string()))?;
+    let (meta, _) = verifiable(stored, grid)?;
+    let camera_type = photo::CameraType::from_arg(camera);
+    let mut report = MonitorReport::new(options.thresholds);
+    let mut fresh_scene = None;
+    for attempt in 1..=options.attempts.max(1) {
+        if attempt > 1 {
+            std::thread::sleep(std::time::Duration::from_secs_f32(options.retry_seconds));
         }
+        report.attempts = attempt;
+        let capture = capture_verification(stored, camera_cal_fname, &display, camera_type.clone(), camera_location_fname, grid, options.scene_tolerance, options.defect_cache_dir.as_deref(), options.debug_encoding);
+        let failure = match capture {
+            Ok((verification, scene)) => {
+                report.verification = Some(verification);
+                fresh_scene = Some(scene);
+                break;
+            },
+            Err(err) => err.to_string(),
+        };
+        warn!("monitoring capture {} of {} failed: {}", attempt, options.attempts.max(1), failure);
+        report.failures.push(failure);
     }
 
-    let photo = take_undistorted_photo(&physical_camera.calibration, camera_type).expect("failed to take photo");
-    locate_chessboard_corners(&photo, warp_res).expect("failed to locate chessboard corners")
+    if let (Some(verification), Some(scene)) = (&report.verification, &fresh_scene) {
+        report.status = MonitorStatus::classify(verification.uv_max, &options.thresholds);
+        info!("drift is {} rms, {} max: {:?}", verification.uv_rms, verification.uv_max, report.status);
+        let heal = options.self_heal_below.map(|limit| verification.uv_max <= limit).unwrap_or(false);
+        if report.status == MonitorStatus::DriftWarning && heal {
+            let posted = monitor::corrected_calibration(stored, scene).and_then(|corrected| {
+                let json = calibration_json_string(&corrected, &meta.output_conventions, meta.projector_resolution);
+                Ok(protocol.send_calibration(&json)?)
+            });
+            match posted {
+                Ok(_) => {
+                    info!("posted the baseline corrected for the measured drift");
+                    report.corrected = true;
+                },
+                Err(err) => {
+                    warn!("couldn't post the corrected calibration: {}", err);
+                    report.correction_error = Some(err.to_string());
+                }
+            }
+        }
+    }
+    let path = report.write(&options.results_dir)?;
+    info!("monitoring report written to {}", path.display());
+    println!("{}", report.to_json_string());
+    Ok(report)
 }
 
-fn generate_uv_warp_and_fov(scene_coords: &Vec<glm::Vec3>, virtual_camera: &mut VirtualCamera, projector_res: Resolution) -> Vec<glm::Vec2> {
-    let trans = look_at(virtual_camera.position, virtual_camera.look_at.unwrap(), virtual_camera.up_dir);
-    let mut max_rad = -1_f32;
-    
-    for scene_point in scene_coords.iter() {
-        let eye_relative = trans * scene_point.extend(1.);
-        //let rad = atan(eye_relative.y.abs() / eye_relative.z.abs());
-        let rad = eye_relative.y.abs().atan2(eye_relative.z.abs());
-        if rad > max_rad { max_rad = rad; }
+/// Recompute a calibration from a session saved by `produce_calibration`, without touching
+/// the camera or control server. The eye position and surface can be changed from what was
+/// used at capture time. With redetect the corners are detected again from the saved photo
+/// rather than using the saved image points, writing debug artifacts in debug_encoding.
+#[cfg(feature = "opencv")]
+pub fn recompute_calibration(session_dir: &str, eye_position: Option<glm::Vec3>, surface: Option<surfaces::SurfaceType>, redetect: bool, debug_encoding: images::ImageEncoding) -> Result<CalibrationResult, Error> {
+    let mut session = CalibrationSession::load(session_dir, redetect, debug_encoding)?;
+    if let Some(surface) = surface {
+        session.recompute_with_surface(surface)?;
+    }
+    match eye_position {
+        Some(eye) => session.recompute_with_eye(eye),
+        None => session.result()
     }
-    
-    virtual_camera.fov = Some(glm::degrees(max_rad) * 2.001); // FIXMEshouldn't really need to add 10% on here?
-    
-    info!("eyePoint = {:?} lookAt = {:?} fovY = {:?}", virtual_camera.position, virtual_camera.look_at, virtual_camera.fov.unwrap());
+}
 
-    let mut uv_coords = vec![];
-    for &scene_coord in scene_coords.iter() {
-        let target_screen_point = project_scene_point(
-            scene_coord, &virtual_camera,
-            projector_res.aspect_ratio()
-        );
+/// Capture like `produce_calibration` but keep the detected corners in memory instead of
+/// posting or printing a calibration, so the math can be re-run quickly with other eye
+/// positions, surfaces or output conventions.
+#[cfg(feature = "opencv")]
+pub fn capture_calibration_session(surface: surfaces::SurfaceType, camera_cal_fname: &str, display: PatternDisplay, camera: Option<&str>, eye: EyePositionSource, grid: GridSpec, projector_res: Resolution, mut options: CalibrationOptions) -> Result<CalibrationSession, Error> {
+    let eye_position = eye.resolve()?;
+    let mut timings = if options.timings { Some(Timings::default()) } else { None };
+    let (physical_camera, mut meta, capture) = capture_single_camera(surface, camera_cal_fname, display, photo::CameraType::from_arg(camera), eye_position, grid, projector_res, &mut options, &mut timings)?;
+    meta.eye_position = Some(eye.meta(eye_position));
+    let mut session = CalibrationSession::new(surface, physical_camera.model(), capture.image_points, eye_position, options.warp_grid.unwrap_or(grid), projector_res, meta);
+    session.photo = Some(capture.photo);
+    session.recompute_with_virtual_camera(options.projector_optics, options.virtual_up, options.clip_planes, options.look_at_method)?;
+    Ok(session)
+}
 
-        // We now have the coord pixel of the render buffer that should be warped to the current chessboard corner
-        uv_coords.push(target_screen_point);
+/// Log the finished timings and put them, serialization included, in each result
+#[cfg(feature = "opencv")]
+fn finish_timings(results: &mut [CalibrationResult], timings: Option<Timings>) {
+    if let Some(timings) = timings {
+        info!("stage timings: {}", timings.summary());
+        for result in results.iter_mut() {
+            if let Some(diagnostics) = result.diagnostics.as_mut() {
+                diagnostics.timings = Some(timings.clone());
+            }
+        }
     }
-    uv_coords
 }
 
-/// Given virtual camera details, calculate normalized screen position of the point in 3D space
-fn project_scene_point(scene_pos: glm::Vec3, virtual_camera: &VirtualCamera, projector_aspect_ratio: f32) -> glm::Vec2 {
-    let model = glm::ext::look_at(virtual_camera.position, virtual_camera.look_at.unwrap(), virtual_camera.up_dir);
-    let proj = glm::ext::perspective(
-        glm::radians(virtual_camera.fov.unwrap()),
-        projector_aspect_ratio,
-        0.1,
-        100.
-    );
-    
-    let screen_pos = math::project(vec3(scene_pos.x, scene_pos.y, scene_pos.z), &model, &proj, vec4(0., 0., 1., 1.));
-    if screen_pos.x < 0. || screen_pos.y < 0. || screen_pos.x > 1. || screen_pos.y > 1. {
-        warn!("a point in the scene space projected off screen (in project_scene_point)");
+#[cfg(feature = "opencv")]
+fn session_record(surface: &surfaces::SurfaceType, physical_camera: &PhysicalCamera, meta: &output::Meta, grid: GridSpec, eye_position: glm::Vec3, capture: &Capture) -> session::SessionRecord {
+    let calibration = &physical_camera.calibration;
+    session::SessionRecord {
+        surface: *surface,
+        physical_camera: meta.physical_camera.clone(),
+        intrinsics: session::IntrinsicsRecord {
+            camera_matrix: camera_calibration::camera_matrix_values(calibration),
+            distortion_coefficients: camera_calibration::distortion_values(calibration),
+            image_width: calibration.image_width,
+            image_height: calibration.image_height,
+        },
+        camera_calibration: meta.camera_calibration.clone(),
+        warp_resolution: grid,
+        projector_resolution: meta.projector_resolution,
+        projector_orientation: meta.projector_orientation,
+        projector_optics: None,
+        pattern_placement: meta.pattern_placement,
+        tiling: meta.tiling,
+        structured_light: meta.structured_light,
+        pattern_encoding: None,
+        warp_grid: None,
+        look_at_method: None,
+        virtual_up: vec3(0., 1., 0.),
+        clip_planes: None,
+        eye_position: eye_position,
+        image_points: capture.image_points.points.clone(),
+        image_confidence: Some(capture.image_points.confidence.clone()),
+        photo_file: session::photo_file_name(&capture.photo),
     }
-    
-    screen_pos.truncate(2)
 }
 
+/// Produce a calibration for a simulated rig without any camera or projector hardware. The
+/// chessboard corner positions the camera would see are calculated analytically and fed into
+/// the same downstream stages as `produce_calibration`.
+pub fn simulate_calibration(surface: surfaces::SurfaceType, sim: &simulation::SimulationConfig, eye_position: glm::Vec3, grid: GridSpec, projector_res: Resolution) -> Result<CalibrationResult, Error> {
+    let camera = surfaces::CameraModel {
+        position: sim.camera_position,
+        look_at: sim.camera_direction,
+        up_dir: sim.camera_up,
+        intrinsics: surfaces::CameraIntrinsics {
+            fov: sim.camera_fov,
+            image_width: sim.camera_resolution.width,
+            image_height: sim.camera_resolution.height,
+        },
+    };
+    let mut virtual_camera = VirtualCamera::new(eye_position);
+    virtual_camera.look_at_method = sim.look_at_method;
+    let mut meta = output::Meta::new(
+        surface,
+        output::PhysicalCameraMeta {
+            position: [camera.position.x, camera.position.y, camera.position.z],
+            look_at: [camera.look_at.x, camera.look_at.y, camera.look_at.z],
+            up: [camera.up_dir.x, camera.up_dir.y, camera.up_dir.z],
+        },
+        None,
+        grid,
+        projector_res,
+        output::CameraSourceMeta::Simulated
+    );
+    meta.projector_orientation = sim.projector_orientation;
+    meta.pattern_placement = sim.pattern_placement;
+    meta.camera_intrinsics = Some(output::IntrinsicsMeta::ideal(&camera.intrinsics));
+
+    let points = simulation::simulated_image_points(&surface, sim, &camera, grid, projector_res).map_err(|err| Error::Config(err.to_string()))?;
+    let image_points = ImagePointGrid::new(grid.cols, grid.rows, points);
+    compute_calibration(&surface, &camera, &image_points, &mut virtual_camera, grid, projector_res, sim.projector_orientation, meta, &mut progress::NoProgress, &mut None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolution_spellings() {
+        let full_hd = Ok(Resolution {width: 1920, height: 1080});
+        for input in ["1920x1080", "1920X1080", "1920×1080", "1920 x 1080", " 1920x1080 ", "1080p", "1080P"].iter() {
+            assert_eq!(Resolution::parse(input), full_hd, "{}", input);
+        }
+        assert_eq!(Resolution::parse("WUXGA"), Ok(Resolution {width: 1920, height: 1200}));
+        assert_eq!(Resolution::parse("4K"), Resolution::parse("uhd"));
+        for (name, res) in RESOLUTION_PRESETS.iter() {
+            assert_eq!(Resolution::parse(name), Ok(*res));
+        }
+    }
 
-fn locate_chessboard_corners(photo: &Mat, warp_res: Resolution) -> opencv::Result<Vec<glm::Vec2>> {
-    // find chessboard corners
-    let mut point_buffer = VectorOfPoint2f::new();
-    let board_size = Size::new(warp_res.width, warp_res.height);
-    debug!("Finding chessboard corners...");
-    let found = find_chessboard_corners(&photo, board_size, &mut point_buffer, CALIB_CB_ADAPTIVE_THRESH)?;
-    
-    // draw found chessboard corners to image file
-    if false {
-        let mut color = Mat::default()?;
-        cvt_color(&photo, &mut color, COLOR_GRAY2BGR, 1)?;
-        draw_chessboard_corners(&mut color, board_size, &point_buffer, found)?;
-        imgcodecs::imwrite("alignment-corners.jpg", &color, &VectorOfi32::new())?;
+    #[test]
+    fn rejected_resolutions() {
+        for input in ["", "1920", "1920x", "x1080", "0x1080", "1920x0", "-1920x1080", "1920*1080", "1920x1080x3", "8k", "99999999999x1080"].iter() {
+            assert!(Resolution::parse(input).is_err(), "{} was accepted", input);
+        }
+        assert!(Resolution::new(0, 1080).is_err());
+        assert!(Resolution::new(1920, -1).is_err());
     }
 
-    if !found {
-        panic!("Complete set of chessboard corners not detected");
+    #[test]
+    fn chessboard_sizes_must_be_odd_by_even() {
+        assert_eq!(GridSpec::new(9, 6), Ok(GridSpec {cols: 9, rows: 6}));
+        assert_eq!(GridSpec::parse(" 9x6 "), Ok(GridSpec {cols: 9, rows: 6}));
+        for (cols, rows) in [(8, 6), (9, 5), (0, 6), (-9, 6), (9, 0), (9, -6)].iter() {
+            assert!(GridSpec::new(*cols, *rows).is_err(), "{}x{} was accepted", cols, rows);
+        }
+        for input in ["8x6", "9x5", "0x6", "9x0", "9", "9x6x2", "nine by six", "-9x6"].iter() {
+            assert!(GridSpec::parse(input).is_err(), "{} was accepted", input);
+        }
     }
 
-    // corner subpix analysis
-    corner_sub_pix(&photo, &mut point_buffer, board_size, Size::new(-1, -1),
-                     TermCriteria::new(3, 30, 0.1f64).unwrap())?; // 3 = COUNT + EPS
-    
-    // convert to vector of glm::Vec2
-    Ok(point_buffer.iter().map(|pt| vec2(pt.x, pt.y)).collect())
+    #[test]
+    fn warp_grids_can_be_any_positive_size() {
+        assert_eq!(GridSpec::parse_sized("32x32"), Ok(GridSpec {cols: 32, rows: 32}));
+        assert_eq!(GridSpec::sized(1, 1), Ok(GridSpec {cols: 1, rows: 1}));
+        assert!(GridSpec::parse_sized("0x32").is_err());
+        assert!(GridSpec::sized(4, -1).is_err());
+    }
 }
 
-fn take_undistorted_photo(calibration: &camera_calibration::Calibration, camera_type: photo::CameraType) -> opencv::Result<Mat> {
-    // take photo
-    let photo_data = photo::capture_photo(camera_type);
-    let photo = imgcodecs::imdecode(&photo_data, imgcodecs::IMREAD_COLOR)?;
+#[cfg(all(test, feature = "opencv"))]
+mod opencv_tests {
+    use super::*;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use opencv::{prelude::*, core::{copy_make_border, Mat, Size, Scalar, BORDER_CONSTANT}, imgcodecs, imgproc::{resize, INTER_AREA}};
+    use control::{RawPostProtocol, SessionCommands};
+    use network::{NetworkConfig, NetworkError};
+    use transport::{BodyMatching, Exchange, HttpReply, HttpRequest, RecordingTransport, ReplayTransport, SharedTransport, Transport, REDACTED};
 
-    // check dimentions match calibration data
-    if photo.rows() != calibration.image_height || photo.cols() != calibration.image_width {
-        panic!(
-            "photo dimentions ({}x{}) don't match width and height in calibration file ({}x{})",
-            photo.cols(), photo.rows(), calibration.image_width, calibration.image_height
-        );
+    const PROJECTOR: Resolution = Resolution {width: 960, height: 600};
+    const CONTROL_URL: &str = "http://rig.local";
+
+    /// An ideal camera with the projector's resolution and optics
+    const CAMERA_XML: &str = r#"<?xml version="1.0"?>
+<opencv_storage>
+<camera_matrix type_id="opencv-matrix"><rows>3</rows><cols>3</cols><dt>d</dt>
+  <data>480. 0. 480. 0. 480. 300. 0. 0. 1.</data></camera_matrix>
+<distortion_coefficients type_id="opencv-matrix"><rows>5</rows><cols>1</cols><dt>d</dt>
+  <data>0. 0. 0. 0. 0.</data></distortion_coefficients>
+<image_width>960</image_width>
+<image_height>600</image_height>
+</opencv_storage>
+"#;
+
+    /// A control server that saves "menu" as its state and accepts everything else
+    struct Server;
+
+    impl Transport for Server {
+        fn send(&self, _config: &NetworkConfig, request: &HttpRequest) -> Result<HttpReply, NetworkError> {
+            let body = if request.url.ends_with("/get_state") { r#"{"showing":"menu"}"# } else { "{}" };
+            Ok(HttpReply {status: 200, content_type: Some("application/json".to_string()), body: body.to_string()})
+        }
     }
 
-    let mut undistorted_img = Mat::default()?;
-    undistort(&photo, &mut undistorted_img, &calibration.camera_matrix, &calibration.distortion_coefficients, &calibration.camera_matrix)?;
-    imgcodecs::imwrite("alignment-undistorted.jpg", &undistorted_img, &VectorOfi32::new())?;
+    /// Keeps the last image posted so the camera photographs exactly what's shown, and zeroes
+    /// the calibration's timestamp so a later run posts the same document
+    struct Screen {
+        inner: SharedTransport,
+        shown: Arc<Mutex<Vec<u8>>>,
+    }
 
-    // convert to greyscale and invert back to expected color layout and white border
-    // required for the opencv corner detection to work
-    let mut gray = Mat::default()?;
-    let mut inverted_img = Mat::default()?;
-    cvt_color(&undistorted_img, &mut gray, COLOR_BGR2GRAY, 1)?;
-    bitwise_not(&gray, &mut inverted_img, &Mat::default().unwrap())?;
-    imgcodecs::imwrite("alignment-inverted.jpg", &inverted_img, &VectorOfi32::new())?;
-    Ok(inverted_img)
-}
+    impl Transport for Screen {
+        fn send(&self, config: &NetworkConfig, request: &HttpRequest) -> Result<HttpReply, NetworkError> {
+            let mut request = request.clone();
+            if request.content_type.starts_with("image/") {
+                *self.shown.lock().unwrap() = request.body.clone();
+            } else if request.url.ends_with("/set_calibration") {
+                let mut json: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                json["meta"]["timestamp"] = 0.into();
+                request.body = json.to_string().into_bytes();
+            }
+            self.inner.0.send(config, &request)
+        }
+    }
 
+    /// What the camera at the projector sees of the last frame shown: all of it, inset in the
+    /// lit surface around it, which gives the chessboard a quiet margin
+    fn photograph(shown: &[u8]) -> Vec<u8> {
+        let frame = imgcodecs::imdecode(&Mat::from_slice(shown).unwrap(), imgcodecs::IMREAD_COLOR).unwrap();
+        let mut inset = Mat::default().unwrap();
+        resize(&frame, &mut inset, Size::new(PROJECTOR.width - 100, PROJECTOR.height - 100), 0., 0., INTER_AREA).unwrap();
+        let mut photo = Mat::default().unwrap();
+        copy_make_border(&inset, &mut photo, 50, 50, 50, 50, BORDER_CONSTANT, Scalar::all(255.)).unwrap();
+        images::encode_image(&photo, ".png").to_vec()
+    }
 
-fn calibration_json_string(scene_coords: &Vec<glm::Vec3>, uv_coords: &Vec<glm::Vec2>, virtual_camera: &VirtualCamera, warp_res: Resolution) -> String {
-    // Build final "calibration" JSON document
-    let scene: Vec<&[f32; 3]> = scene_coords.iter().map(|p| p.as_array()).collect();
-    let warp: Vec<&[f32; 2]> = uv_coords.iter().map(|p| p.as_array()).collect();
+    /// Calibrate the dome through the raw protocol, with the camera at the projector
+    fn calibrate(inner: SharedTransport, camera_xml: &str) -> Result<CalibrationResult, Error> {
+        let shown = Arc::new(Mutex::new(vec![]));
+        let config = NetworkConfig {
+            headers: vec![("Authorization".to_string(), "Bearer secret".to_string()), ("X-Rig".to_string(), "dome".to_string())],
+            transport: Some(SharedTransport::new(Screen {inner: inner, shown: shown.clone()})),
+            ..NetworkConfig::default()
+        };
+        let protocol = || RawPostProtocol {
+            session: SessionCommands::new(Some("get_state".to_string()), Some("restore_state".to_string())),
+            ..RawPostProtocol::new(CONTROL_URL, config.clone())
+        };
+        let options = CalibrationOptions {
+            camera_pose: Some(PhysicalCameraPose {position: vec3(0., 0., 0.), look_at: normalize(vec3(0., 1., 1.)), up_dir: normalize(vec3(0., -1., 1.))}),
+            post_to: Some(Box::new(protocol())),
+            ..CalibrationOptions::default()
+        };
+        let camera = photo::CameraType::Supplied {
+            meta: output::CameraSourceMeta::Simulated,
+            capture: Arc::new(move || photograph(&shown.lock().unwrap())),
+        };
+        let surface = surfaces::SurfaceType::HemisphericalDome {radius: 5.};
+        let display = PatternDisplay::Control(Box::new(protocol()));
+        produce_calibration_with_camera(surface, camera_xml, display, camera, EyePositionSource::Fixed(vec3(0., 0., 0.)), GridSpec {cols: 9, rows: 6}, PROJECTOR, options)
+    }
 
-    debug!("scene has {} coordinates", scene.len());
-    debug!("warp has {} coordinates", warp.len());
+    #[test]
+    fn calibration_choreography_replays() {
+        let dir = tempfile::tempdir().unwrap();
+        let camera_xml = dir.path().join("camera.xml");
+        fs::write(&camera_xml, CAMERA_XML).unwrap();
+        let camera_xml = camera_xml.to_str().unwrap();
+        let transcript = dir.path().join("network.jsonl");
+        let transcript = transcript.to_str().unwrap();
 
-    let json = json!({
-        "fov": virtual_camera.fov,
-        "eye": virtual_camera.position.as_array(),
-        "lookAt": virtual_camera.look_at.unwrap().as_array(),
-        "up": virtual_camera.up_dir.as_array(),
-        "warpResX": warp_res.width,
-        "warpResY": warp_res.height,
-        "warp": warp,
-        "scene": scene
-    });
+        let recorded = calibrate(SharedTransport::new(RecordingTransport::wrapping(transcript, SharedTransport::new(Server)).unwrap()), camera_xml).unwrap();
+        let exchanges: Vec<Exchange> = fs::read_to_string(transcript).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
 
-    serde_json::to_string_pretty(&json).unwrap()
-}
\ No newline at end of file
+        // the state is saved, the chessboard and its orientation cue are shown, the state is
+        // put back and only then is the calibration posted
+        let urls: Vec<&str> = exchanges.iter().map(|exchange| exchange.url.as_str()).collect();
+        assert_eq!(urls, vec![
+            "http://rig.local/get_state",
+            "http://rig.local/show_image",
+            "http://rig.local/show_image",
+            "http://rig.local/restore_state",
+            "http://rig.local/set_calibration",
+        ]);
+        assert!(exchanges[1].body.is_none() && exchanges[1].content_type.starts_with("image/"));
+        assert_ne!(exchanges[1].body_hash, exchanges[2].body_hash, "the cue isn't the chessboard");
+        assert_eq!(exchanges[3].body.as_deref(), Some(r#"{"showing":"menu"}"#));
+        for exchange in exchanges.iter() {
+            assert_eq!(exchange.headers, vec![("Authorization".to_string(), REDACTED.to_string()), ("X-Rig".to_string(), REDACTED.to_string())]);
+        }
+
+        // the replay answers the same run without any server and uses up the transcript
+        let replay = Arc::new(ReplayTransport::load(transcript, BodyMatching::Exact).unwrap());
+        let replayed = calibrate(SharedTransport(replay.clone()), camera_xml).unwrap();
+        assert_eq!(replay.remaining(), 0);
+        assert_eq!(replayed.warp, recorded.warp);
+        assert_eq!(replayed.fov, recorded.fov);
+
+        // a run that doesn't follow the transcript fails against it
+        let mut skipped = exchanges.clone();
+        skipped.remove(0);
+        assert!(calibrate(SharedTransport::new(ReplayTransport::new(skipped, BodyMatching::Exact)), camera_xml).is_err());
+    }
+}
@@ -2,29 +2,238 @@
 use opencv::prelude::*;
 use opencv::types::*;
 use opencv::core::*;
-use opencv::imgcodecs;
-use super::PhysicalCamera;
+use opencv::aruco::PREDEFINED_DICTIONARY_NAME;
+use log::{info, warn};
+use super::{PhysicalCamera, Error};
 use super::camera_calibration::Calibration;
-use serde_json::json;
-use serde::Deserialize;
+use super::{images, math};
+use serde::{Serialize, Deserialize};
 use std::fs;
 
+/// Camera to marker distances outside this range (in meters) almost always mean marker_size
+/// was given in the wrong units
+const PLAUSIBLE_DISTANCE: (f32, f32) = (0.01, 100.);
 
-#[derive(Deserialize, Debug)]
-struct CameraLocation {
+/// Below this ratio of reprojection errors the two pose solutions are considered ambiguous
+const AMBIGUOUS_RATIO: f64 = 1.5;
+
+/// Camera pose found by `locate_aruco_marker`, written as the camera location JSON
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CameraLocation {
+    pub position: Vec<f32>,
+    pub direction: Vec<f32>,
+    pub up: Vec<f32>,
+    pub fov: f32,
+    /// absent in files written before these were recorded
+    #[serde(default)]
+    pub marker_size: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub marker_id: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dictionary: Option<ArucoDictionary>,
+    /// of the marker corners, in pixels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reprojection_error: Option<f64>,
+    /// the other pose of a planar marker's flip ambiguity
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alternative: Option<AlternativePose>,
+    /// `to_matrix4`, column by column
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matrix: Option<[f32; 16]>,
+    /// `to_quaternion`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quaternion: Option<[f32; 4]>,
+    /// `to_euler_degrees(EulerOrder::Yxz)`: yaw, pitch, roll
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub euler_degrees: Option<[f32; 3]>,
+}
+
+/// Order of Euler angle rotations. Each is intrinsic, e.g. Yxz rotates about the camera's y,
+/// then its new x, then its new z, so the matrix is Ry * Rx * Rz. Angles are right handed
+/// (counter clockwise looking down the axis towards the origin).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EulerOrder {
+    /// yaw about y (up), pitch about x, roll about z. The natural order for y up scenes.
+    Yxz,
+    /// yaw about z, pitch about y, roll about x, as used for z up robotics frames
+    Zyx,
+    Xyz,
+}
+
+/// The second pose solution for a marker. An error_ratio near 1 means the two can't be told
+/// apart and the pose may be flipped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlternativePose {
     pub position: Vec<f32>,
     pub direction: Vec<f32>,
     pub up: Vec<f32>,
-    pub fov: f32
+    pub reprojection_error: f64,
+    /// this solution's reprojection error over the chosen one's
+    pub error_ratio: f64,
+}
+
+/// Which marker to use when the photo has several
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MarkerSelection {
+    /// there must be exactly one
+    Single,
+    /// the marker with this id
+    Id(i32),
+    /// the one covering the most of the photo
+    Largest,
+}
+
+impl Default for MarkerSelection {
+    fn default() -> MarkerSelection {
+        MarkerSelection::Single
+    }
+}
+
+impl CameraLocation {
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Rotation from camera to marker space, row by row. The camera looks down its -z axis
+    /// with y up (the OpenGL convention), both spaces are right handed.
+    pub fn rotation(&self) -> [[f32; 3]; 3] {
+        let dir = glm::normalize(glm::vec3(self.direction[0], self.direction[1], self.direction[2]));
+        let up = glm::vec3(self.up[0], self.up[1], self.up[2]);
+        let right = glm::normalize(glm::cross(dir, up));
+        let up = glm::cross(right, dir);
+        [
+            [right.x, up.x, -dir.x],
+            [right.y, up.y, -dir.y],
+            [right.z, up.z, -dir.z],
+        ]
+    }
+
+    /// Camera to marker (world) transform, as `rotation` plus the camera position
+    pub fn to_matrix4(&self) -> glm::Mat4 {
+        let r = self.rotation();
+        glm::mat4(
+            r[0][0], r[1][0], r[2][0], 0.,
+            r[0][1], r[1][1], r[2][1], 0.,
+            r[0][2], r[1][2], r[2][2], 0.,
+            self.position[0], self.position[1], self.position[2], 1.
+        )
+    }
+
+    /// `rotation` as a unit quaternion [x, y, z, w]. Unity is left handed, so negate z and w
+    /// (and the position's z) to use it there.
+    pub fn to_quaternion(&self) -> [f32; 4] {
+        math::rotation_to_quaternion(&self.rotation())
+    }
+
+    /// `rotation` as Euler angles in degrees, in the order the rotations are applied
+    pub fn to_euler_degrees(&self, order: EulerOrder) -> [f32; 3] {
+        let m = self.rotation();
+        let clamp = |v: f32| v.max(-1.).min(1.);
+        let radians = match order {
+            EulerOrder::Yxz => [m[0][2].atan2(m[2][2]), clamp(-m[1][2]).asin(), m[1][0].atan2(m[1][1])],
+            EulerOrder::Zyx => [m[1][0].atan2(m[0][0]), clamp(-m[2][0]).asin(), m[2][1].atan2(m[2][2])],
+            EulerOrder::Xyz => [(-m[1][2]).atan2(m[2][2]), clamp(m[0][2]).asin(), (-m[0][1]).atan2(m[0][0])],
+        };
+        [radians[0].to_degrees(), radians[1].to_degrees(), radians[2].to_degrees()]
+    }
+
+    /// Fill in the matrix, quaternion and Euler angle fields written to the JSON
+    pub fn with_representations(mut self) -> CameraLocation {
+        self.matrix = Some(math::mat4_values(&self.to_matrix4()));
+        self.quaternion = Some(self.to_quaternion());
+        self.euler_degrees = Some(self.to_euler_degrees(EulerOrder::Yxz));
+        self
+    }
+}
+
+/// The predefined opencv marker dictionaries, written as the names `parse` accepts
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(into = "String", try_from = "String")]
+#[allow(non_camel_case_types)]
+pub enum ArucoDictionary {
+    Dict4x4_50, Dict4x4_100, Dict4x4_250, Dict4x4_1000,
+    Dict5x5_50, Dict5x5_100, Dict5x5_250, Dict5x5_1000,
+    Dict6x6_50, Dict6x6_100, Dict6x6_250, Dict6x6_1000,
+    Dict7x7_50, Dict7x7_100, Dict7x7_250, Dict7x7_1000,
+    /// the dictionary of the original aruco library, used before this was configurable
+    DictArucoOriginal,
+    DictApriltag16h5, DictApriltag25h9, DictApriltag36h10, DictApriltag36h11,
+}
+
+impl Default for ArucoDictionary {
+    fn default() -> ArucoDictionary {
+        ArucoDictionary::DictArucoOriginal
+    }
+}
+
+impl From<ArucoDictionary> for String {
+    fn from(dictionary: ArucoDictionary) -> String {
+        dictionary.name().to_string()
+    }
+}
+
+impl std::convert::TryFrom<String> for ArucoDictionary {
+    type Error = &'static str;
+    fn try_from(name: String) -> Result<ArucoDictionary, &'static str> {
+        ArucoDictionary::parse(&name)
+    }
 }
 
+impl ArucoDictionary {
+    const ALL: &'static [(&'static str, ArucoDictionary, PREDEFINED_DICTIONARY_NAME)] = &[
+        ("4x4_50", ArucoDictionary::Dict4x4_50, PREDEFINED_DICTIONARY_NAME::DICT_4X4_50),
+        ("4x4_100", ArucoDictionary::Dict4x4_100, PREDEFINED_DICTIONARY_NAME::DICT_4X4_100),
+        ("4x4_250", ArucoDictionary::Dict4x4_250, PREDEFINED_DICTIONARY_NAME::DICT_4X4_250),
+        ("4x4_1000", ArucoDictionary::Dict4x4_1000, PREDEFINED_DICTIONARY_NAME::DICT_4X4_1000),
+        ("5x5_50", ArucoDictionary::Dict5x5_50, PREDEFINED_DICTIONARY_NAME::DICT_5X5_50),
+        ("5x5_100", ArucoDictionary::Dict5x5_100, PREDEFINED_DICTIONARY_NAME::DICT_5X5_100),
+        ("5x5_250", ArucoDictionary::Dict5x5_250, PREDEFINED_DICTIONARY_NAME::DICT_5X5_250),
+        ("5x5_1000", ArucoDictionary::Dict5x5_1000, PREDEFINED_DICTIONARY_NAME::DICT_5X5_1000),
+        ("6x6_50", ArucoDictionary::Dict6x6_50, PREDEFINED_DICTIONARY_NAME::DICT_6X6_50),
+        ("6x6_100", ArucoDictionary::Dict6x6_100, PREDEFINED_DICTIONARY_NAME::DICT_6X6_100),
+        ("6x6_250", ArucoDictionary::Dict6x6_250, PREDEFINED_DICTIONARY_NAME::DICT_6X6_250),
+        ("6x6_1000", ArucoDictionary::Dict6x6_1000, PREDEFINED_DICTIONARY_NAME::DICT_6X6_1000),
+        ("7x7_50", ArucoDictionary::Dict7x7_50, PREDEFINED_DICTIONARY_NAME::DICT_7X7_50),
+        ("7x7_100", ArucoDictionary::Dict7x7_100, PREDEFINED_DICTIONARY_NAME::DICT_7X7_100),
+        ("7x7_250", ArucoDictionary::Dict7x7_250, PREDEFINED_DICTIONARY_NAME::DICT_7X7_250),
+        ("7x7_1000", ArucoDictionary::Dict7x7_1000, PREDEFINED_DICTIONARY_NAME::DICT_7X7_1000),
+        ("original", ArucoDictionary::DictArucoOriginal, PREDEFINED_DICTIONARY_NAME::DICT_ARUCO_ORIGINAL),
+        ("apriltag_16h5", ArucoDictionary::DictApriltag16h5, PREDEFINED_DICTIONARY_NAME::DICT_APRILTAG_16h5),
+        ("apriltag_25h9", ArucoDictionary::DictApriltag25h9, PREDEFINED_DICTIONARY_NAME::DICT_APRILTAG_25h9),
+        ("apriltag_36h10", ArucoDictionary::DictApriltag36h10, PREDEFINED_DICTIONARY_NAME::DICT_APRILTAG_36h10),
+        ("apriltag_36h11", ArucoDictionary::DictApriltag36h11, PREDEFINED_DICTIONARY_NAME::DICT_APRILTAG_36h11),
+    ];
 
-/// get camera position relative to single aruco marker
-pub fn locate_aruco_marker(calibration: &Calibration, photo: &mut Mat, marker_size: f32) {
+    /// "4x4_50", "6x6_250", "original", "apriltag_36h11" etc
+    pub fn parse(input: &str) -> Result<ArucoDictionary, &'static str> {
+        let input = input.trim().to_lowercase();
+        let input = input.trim_start_matches("dict_");
+        ArucoDictionary::ALL.iter()
+            .find(|(name, _, _)| *name == input)
+            .map(|(_, dict, _)| *dict)
+            .ok_or("unknown aruco dictionary, expected e.g. 4x4_50, 6x6_250, original or apriltag_36h11")
+    }
+
+    pub fn name(&self) -> &'static str {
+        ArucoDictionary::ALL.iter().find(|(_, dict, _)| dict == self).unwrap().0
+    }
+
+    pub(crate) fn predefined(&self) -> PREDEFINED_DICTIONARY_NAME {
+        ArucoDictionary::ALL.iter().find(|(_, dict, _)| dict == self).unwrap().2
+    }
+}
+
+
+/// get camera position relative to an aruco marker of marker_size (meters) from dictionary,
+/// chosen from those in the photo by selection
+pub fn locate_aruco_marker(calibration: &Calibration, photo: &mut Mat, marker_size: f32, dictionary: ArucoDictionary, selection: MarkerSelection, debug_encoding: images::ImageEncoding) -> Result<CameraLocation, Error> {
+    if !(marker_size > 0.) {
+        return Err(Error::Config(format!("marker size must be positive, not {}", marker_size)));
+    }
     let mut ids = VectorOfi32::new();
     let mut corners = VectorOfVectorOfPoint2f::new();
     let mut rejected = VectorOfVectorOfPoint2f::new();
-    let dict = opencv::aruco::get_predefined_dictionary(opencv::aruco::PREDEFINED_DICTIONARY_NAME::DICT_ARUCO_ORIGINAL).unwrap();
+    let dict = opencv::aruco::get_predefined_dictionary(dictionary.predefined()).unwrap();
     let params = opencv::aruco::DetectorParameters::create().unwrap();
     
     // aruco lib can undistort for us so work on the original image...
@@ -40,33 +249,114 @@ pub fn locate_aruco_marker(calibration: &Calibration, photo: &mut Mat, marker_si
     
     // draw onto image (for debugging purposes)
     opencv::aruco::draw_detected_markers(photo, &corners, &ids, opencv::core::Scalar::all(0.)).expect("draw markers failed");
-    imgcodecs::imwrite("locator-detected-markers.jpg", photo, &VectorOfi32::new()).unwrap();
+    images::write_debug("locator-detected-markers", photo, debug_encoding)?;
     
-    if corners.len() == 0 {
-        panic!("No markers detected. Stopping.");
-    } else if corners.len() > 1 {
-        panic!("Multiple markers detected. Stopping.");
-    }
-    let mut rvecs = VectorOfPoint3d::new();
-    let mut tvecs = VectorOfPoint3d::new();
-    let mut obj_points = VectorOfPoint3d::new(); // corners points of square
-    
-    opencv::aruco::estimate_pose_single_markers(
-        &corners,
-        marker_size,
+    let detected = ids.to_vec();
+    info!("detected {} aruco markers {:?}", dictionary.name(), detected);
+    if detected.is_empty() {
+        return Err(Error::Detection(format!("no {} aruco markers detected", dictionary.name())));
+    }
+    let index = match selection {
+        MarkerSelection::Single => {
+            if detected.len() > 1 {
+                return Err(Error::Detection(format!("{} aruco markers detected (ids {:?}), expected one", detected.len(), detected)));
+            }
+            0
+        },
+        MarkerSelection::Id(id) => detected.iter().position(|d| *d == id).ok_or_else(|| Error::Detection(format!(
+            "aruco marker {} not found, detected ids {:?}", id, detected
+        )))?,
+        MarkerSelection::Largest => {
+            let mut largest = (0, 0_f64);
+            for (i, marker) in corners.iter().enumerate() {
+                let area = opencv::imgproc::contour_area(&marker, false)?;
+                if area > largest.1 {
+                    largest = (i, area);
+                }
+            }
+            largest.0
+        },
+    };
+    let marker_id = detected[index];
+    info!("using marker {} ({:?})", marker_id, selection);
+
+    // IPPE square gives both poses of the planar ambiguity, sorted by reprojection error
+    let half = marker_size / 2.;
+    let mut object_points = VectorOfPoint3f::new();
+    for (x, y) in &[(-half, half), (half, half), (half, -half), (-half, -half)] {
+        object_points.push(Point3f::new(*x, *y, 0.));
+    }
+    let mut rvecs = VectorOfMat::new();
+    let mut tvecs = VectorOfMat::new();
+    let mut errors = Mat::default()?;
+    let solutions = opencv::calib3d::solve_pnp_generic(
+        &object_points,
+        &corners.get(index)?,
         &calibration.camera_matrix,
         &calibration.distortion_coefficients,
         &mut rvecs,
         &mut tvecs,
-        &mut obj_points
-    ).expect("estimate pose failed");
+        false,
+        opencv::calib3d::SolvePnPMethod::SOLVEPNP_IPPE_SQUARE,
+        &Mat::default()?,
+        &Mat::default()?,
+        &mut errors
+    )?;
+    if solutions < 1 {
+        return Err(Error::Detection(format!("no pose found for aruco marker {}", marker_id)));
+    }
+    let vec3d = |m: &Mat| -> opencv::Result<Point3d> {
+        Ok(Point3d::new(*m.at::<f64>(0)?, *m.at::<f64>(1)?, *m.at::<f64>(2)?))
+    };
+    let (rvec, tvec) = (vec3d(&rvecs.get(0)?)?, vec3d(&tvecs.get(0)?)?);
 
     // TODO - sometimes the X axis is flipped - need a way to detect this and retry/fail
     // or just flip the axis if that fixes everything
 
-    let mut rvec = rvecs.iter().nth(0).unwrap();
-    let mut tvec = tvecs.iter().nth(0).unwrap();
+    let distance = (tvec.x * tvec.x + tvec.y * tvec.y + tvec.z * tvec.z).sqrt() as f32;
+    info!("found {} marker {} at {} from the camera", dictionary.name(), marker_id, distance);
+    if distance < PLAUSIBLE_DISTANCE.0 || distance > PLAUSIBLE_DISTANCE.1 {
+        warn!("the marker appears to be {} meters away, is the marker size ({}) in meters?", distance, marker_size);
+    }
+
+    let (position, dir, up) = camera_pose(rvec, tvec);
+    let reprojection_error = *errors.at::<f64>(0)?;
+    let alternative = if solutions > 1 {
+        let (position, dir, up) = camera_pose(vec3d(&rvecs.get(1)?)?, vec3d(&tvecs.get(1)?)?);
+        let alt_error = *errors.at::<f64>(1)?;
+        let ratio = alt_error / reprojection_error.max(1e-9);
+        if ratio < AMBIGUOUS_RATIO {
+            warn!("marker pose is ambiguous, the second solution's reprojection error is only {:.2}x the first's", ratio);
+        }
+        Some(AlternativePose {
+            position: position.as_array().to_vec(),
+            direction: dir.as_array().to_vec(),
+            up: up.as_array().to_vec(),
+            reprojection_error: alt_error,
+            error_ratio: ratio,
+        })
+    } else {
+        None
+    };
+
+    Ok(CameraLocation {
+        position: position.as_array().to_vec(),
+        direction: dir.as_array().to_vec(),
+        up: up.as_array().to_vec(),
+        fov: calibration.fov,
+        marker_size: Some(marker_size),
+        marker_id: Some(marker_id),
+        dictionary: Some(dictionary),
+        reprojection_error: Some(reprojection_error),
+        alternative: alternative,
+        matrix: None,
+        quaternion: None,
+        euler_degrees: None,
+    }.with_representations())
+}
 
+/// Camera position, direction and up in marker space from an opencv marker pose
+fn camera_pose(mut rvec: Point3d, mut tvec: Point3d) -> (glm::Vec3, glm::Vec3, glm::Vec3) {
     // convert to opengl axis layout
     rvec.y = -rvec.y;
     rvec.z = -rvec.z;
@@ -105,29 +395,188 @@ pub fn locate_aruco_marker(calibration: &Calibration, photo: &mut Mat, marker_si
     let position = inv_rotation * negative;
     let dir = inv_rotation * glm::vec4(0., 0., -1., 1.);
     let up = inv_rotation * glm::vec4(0., 1., 0., 1.);
-
-    let json = location_json_string(&position.truncate(3), &dir.truncate(3), &up.truncate(3) , calibration.fov, marker_size);
-    println!("{}", json);
+    (position.truncate(3), dir.truncate(3), up.truncate(3))
 }
 
-pub fn update_physical_camera_location(physical_camera: &mut PhysicalCamera, json_fname: &str) {
-    let json_str = fs::read_to_string(json_fname).expect("camera location json file not found");
-    let cl: CameraLocation = serde_json::from_str(json_str.as_str()).unwrap();
+/// Move physical_camera to the location saved in json_fname by `locate_camera`
+pub fn update_physical_camera_location(physical_camera: &mut PhysicalCamera, json_fname: &str) -> Result<(), Error> {
+    let json_str = fs::read_to_string(json_fname)
+        .map_err(|err| Error::Config(format!("can't read camera location {}: {}", json_fname, err)))?;
+    let cl: CameraLocation = serde_json::from_str(json_str.as_str())
+        .map_err(|err| Error::Config(format!("camera location {} isn't valid: {}", json_fname, err)))?;
+    if [&cl.position, &cl.direction, &cl.up].iter().any(|v| v.len() != 3) {
+        return Err(Error::Config(format!("camera location {} needs 3 values each for its position, direction and up", json_fname)));
+    }
 
     physical_camera.position = glm::vec3(cl.position[0], cl.position[1], cl.position[2]);
     physical_camera.look_at = glm::vec3(cl.direction[0], cl.direction[1], cl.direction[2]);
     physical_camera.up_dir = glm::vec3(cl.up[0], cl.up[1], cl.up[2]);
+    Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera_calibration;
+
+    fn camera() -> PhysicalCamera {
+        PhysicalCamera {
+            position: glm::vec3(0., 0., 0.),
+            look_at: glm::vec3(0., 1., 0.),
+            up_dir: glm::vec3(0., 0., 1.),
+            calibration: camera_calibration::ideal_calibration(60., 960, 600),
+        }
+    }
+
+    #[test]
+    fn camera_locations_are_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("location.json");
+        fs::write(&path, r#"{"position": [1, 2, 3], "direction": [0, 0, -1], "up": [0, 1, 0], "fov": 60}"#).unwrap();
+        let mut physical_camera = camera();
+        update_physical_camera_location(&mut physical_camera, path.to_str().unwrap()).unwrap();
+        assert_eq!(physical_camera.position, glm::vec3(1., 2., 3.));
+        assert_eq!(physical_camera.look_at, glm::vec3(0., 0., -1.));
+        assert_eq!(physical_camera.up_dir, glm::vec3(0., 1., 0.));
+    }
+
+    #[test]
+    fn bad_camera_locations_are_config_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.json");
+        let path = dir.path().join("location.json");
+        for contents in [None, Some("not json"), Some(r#"{"position": [1, 2], "direction": [0, 0, -1], "up": [0, 1, 0], "fov": 60}"#)].iter() {
+            let fname = match contents {
+                Some(contents) => {
+                    fs::write(&path, contents).unwrap();
+                    path.to_str().unwrap()
+                },
+                None => missing.to_str().unwrap()
+            };
+            match update_physical_camera_location(&mut camera(), fname) {
+                Err(Error::Config(_)) => {},
+                other => panic!("{:?} gave {:?}", contents, other.map(|_| ()))
+            }
+        }
+    }
+
+    /// A camera pose and its representations, computed independently of this crate: the
+    /// rotation built as Ry * Rx * Rz from the Yxz angles, the quaternion as the product of
+    /// the three axis rotations' quaternions, and the other orders' angles by numerically
+    /// fitting their rotation products to the matrix
+    struct Pose {
+        direction: [f32; 3],
+        up: [f32; 3],
+        position: [f32; 3],
+        /// column by column
+        matrix: [f32; 16],
+        quaternion: [f32; 4],
+        yxz: [f32; 3],
+        zyx: [f32; 3],
+        xyz: [f32; 3],
+    }
+
+    const POSES: [Pose; 8] = [
+        Pose {
+            direction: [0., 0., -1.], up: [0., 1., 0.], position: [0., 0., 0.],
+            matrix: [1., 0., 0., 0., 0., 1., 0., 0., 0., 0., 1., 0., 0., 0., 0., 1.],
+            quaternion: [0., 0., 0., 1.],
+            yxz: [0., 0., 0.], zyx: [0., 0., 0.], xyz: [0., 0., 0.],
+        },
+        Pose {
+            direction: [-0.5, 0., -0.866025], up: [0., 1., 0.], position: [1., 2., 3.],
+            matrix: [0.866025, 0., -0.5, 0., 0., 1., 0., 0., 0.5, 0., 0.866025, 0., 1., 2., 3., 1.],
+            quaternion: [0., 0.258819, 0., 0.965926],
+            yxz: [30., 0., 0.], zyx: [0., 30., 0.], xyz: [0., 30., 0.],
+        },
+        Pose {
+            direction: [0., -0.34202, -0.939693], up: [0., 0.939693, -0.34202], position: [0., 1.5, 0.],
+            matrix: [1., 0., 0., 0., 0., 0.939693, -0.34202, 0., 0., 0.34202, 0.939693, 0., 0., 1.5, 0., 1.],
+            quaternion: [-0.173648, 0., 0., 0.984808],
+            yxz: [0., -20., 0.], zyx: [0., 0., -20.], xyz: [-20., 0., 0.],
+        },
+        Pose {
+            direction: [-0.612372, 0.5, -0.612372], up: [0.225394, 0.852869, 0.47097], position: [-2., 0.5, 4.],
+            matrix: [0.757758, 0.150384, -0.63497, 0., 0.225394, 0.852869, 0.47097, 0., 0.612372, -0.5, 0.612372, 0., -2., 0.5, 4., 1.],
+            quaternion: [0.270424, 0.347397, -0.020891, 0.897636],
+            yxz: [45., 30., 10.], zyx: [11.224997, 39.417785, 37.563548], xyz: [39.23152, 37.761244, -16.565051],
+        },
+        Pose {
+            direction: [0.836516, 0.258819, 0.482963], up: [-0.545085, 0.482963, 0.685295], position: [3., -1., 2.],
+            matrix: [-0.055886, -0.836516, 0.545085, 0., -0.545085, 0.482963, 0.685295, 0., -0.836516, -0.258819, -0.482963, 0., 3., -1., 2., 1.],
+            quaternion: [0.485828, -0.710952, -0.149967, 0.485828],
+            yxz: [-120., 15., -60.], zyx: [-93.822119, -33.030448, 125.174328], xyz: [151.813215, -56.774058, 95.853892],
+        },
+        Pose {
+            direction: [-0.133022, -0.642788, 0.754407], up: [0.990788, -0.066765, 0.117816], position: [0.25, 0.75, -1.],
+            matrix: [-0.025363, 0.763129, 0.645748, 0., 0.990788, -0.066765, 0.117816, 0., 0.133022, 0.642788, -0.754407, 0., 0.25, 0.75, -1., 1.],
+            quaternion: [-0.670039, -0.654409, -0.290569, 0.195874],
+            yxz: [170., -40., 95.], zyx: [91.903517, -40.221767, 171.1238], xyz: [-139.567539, 7.64427, -91.466354],
+        },
+        Pose {
+            direction: [0., 0., 1.], up: [0., 1., 0.], position: [0., 0., 5.],
+            matrix: [-1., 0., 0., 0., 0., 1., 0., 0., 0., 0., -1., 0., 0., 0., 5., 1.],
+            quaternion: [0., 1., 0., 0.],
+            yxz: [-180., 0., 0.], zyx: [-180., 0., -180.], xyz: [-180., 0., -180.],
+        },
+        Pose {
+            direction: [0., 0., -1.], up: [0., -1., 0.], position: [1., 1., 1.],
+            matrix: [-1., 0., 0., 0., 0., -1., 0., 0., 0., 0., 1., 0., 1., 1., 1., 1.],
+            quaternion: [0., 0., 1., 0.],
+            yxz: [0., 0., -180.], zyx: [-180., 0., 0.], xyz: [0., 0., -180.],
+        },
+    ];
 
-fn location_json_string(position: &glm::Vec3, dir: &glm::Vec3, up: &glm::Vec3, fov: f32, marker_size: f32) -> String {
-    let json = json!({
-        "position": position.as_array(),
-        "direction": dir.as_array(),
-        "up": up.as_array(),
-        "fov": fov,
-        "marker_size": marker_size
-    });
+    fn location(pose: &Pose) -> CameraLocation {
+        CameraLocation {
+            position: pose.position.to_vec(),
+            direction: pose.direction.to_vec(),
+            up: pose.up.to_vec(),
+            fov: 60.,
+            marker_size: None,
+            marker_id: None,
+            dictionary: None,
+            reprojection_error: None,
+            alternative: None,
+            matrix: None,
+            quaternion: None,
+            euler_degrees: None,
+        }
+    }
+
+    /// Degrees apart, going the short way round
+    fn angle_between(a: f32, b: f32) -> f32 {
+        ((a - b + 540.) % 360. - 180.).abs()
+    }
 
-    serde_json::to_string_pretty(&json).unwrap()
-}
\ No newline at end of file
+    #[test]
+    fn pose_representations_match_the_fixture() {
+        for (i, pose) in POSES.iter().enumerate() {
+            let location = location(pose);
+            let matrix = math::mat4_values(&location.to_matrix4());
+            for k in 0..16 {
+                assert!((matrix[k] - pose.matrix[k]).abs() < 1e-4, "pose {} matrix {:?}, not {:?}", i, matrix, pose.matrix);
+            }
+            // q and -q are the same rotation
+            let q = location.to_quaternion();
+            let sign = if (0..4).map(|k| q[k] * pose.quaternion[k]).sum::<f32>() < 0. { -1. } else { 1. };
+            for k in 0..4 {
+                assert!((q[k] * sign - pose.quaternion[k]).abs() < 1e-4, "pose {} quaternion {:?}, not {:?}", i, q, pose.quaternion);
+            }
+            for (order, expected) in [(EulerOrder::Yxz, pose.yxz), (EulerOrder::Zyx, pose.zyx), (EulerOrder::Xyz, pose.xyz)].iter() {
+                let angles = location.to_euler_degrees(*order);
+                for k in 0..3 {
+                    assert!(angle_between(angles[k], expected[k]) < 5e-3, "pose {} {:?} angles {:?}, not {:?}", i, order, angles, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn representations_are_written_to_the_json() {
+        let location = location(&POSES[3]).with_representations();
+        assert_eq!(location.matrix, Some(math::mat4_values(&location.to_matrix4())));
+        assert_eq!(location.quaternion, Some(location.to_quaternion()));
+        assert_eq!(location.euler_degrees, Some(location.to_euler_degrees(EulerOrder::Yxz)));
+    }
+}
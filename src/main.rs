@@ -1,7 +1,14 @@
 
-use aligner::{produce_calibration, locate_camera, Resolution};
+use aligner::{GridSpec, OutputConventions, WarpUnits, WarpOrder, OutputTransform, AxisConvention, produce_calibration, produce_keystone, KeystoneOutput, verify_calibration, CalibrationResult, DetectionOptions, Polarity, produce_multi_camera_calibration, produce_eye_calibrations, NamedEyePosition, EyePositionSource, EyeTransform, ProjectorOrientation, ProjectorOptics, recompute_calibration, locate_camera, ArucoDictionary, MarkerSelection, Resolution, PatternDisplay, LocalDisplay, StdinPrompt, TimeoutPrompt, NonInteractive, CalibrationOptions, LookAtMethod, PhysicalCameraPose, WarmUp, StabilityCheck, SurfaceParameter, PatternPlacement, Tiling, write_session_report, monitor_calibration, MonitorOptions, DriftThresholds, identify_projectors, DefectOptions, DefectFrames};
 use aligner::surfaces;
+use aligner::compare::{compare_calibrations, uv_heatmap_png};
+use aligner::multi_camera::CameraSetup;
+use aligner::network::NetworkConfig;
+use aligner::transport::{SharedTransport, RecordingTransport, ReplayTransport, BodyMatching};
+use aligner::images::ImageEncoding;
+use aligner::control::{ControlProtocol, RawPostProtocol, MultipartProtocol, JsonCommandProtocol, SessionCommands};
 use clap::Clap;
+use log::error;
 
 /// Projection warp and alignment generator
 #[derive(Clap)]
@@ -17,9 +24,68 @@ struct Opts {
     /// new line
     #[clap(short = "h", long = "control-url")]
     control_url: Option<String>,
+    /// How images and calibrations are sent to the control server. "raw" posts image bytes,
+    /// "multipart" uploads a form, "json" embeds base64 images in JSON commands.
+    #[clap(long = "control-protocol", default_value = "raw", possible_values=&["raw", "multipart", "json"])]
+    control_protocol: String,
+    /// Endpoint (command for the json protocol) asked for what the control server is showing
+    /// before the first pattern. Its reply is sent back with --control-restore.
+    #[clap(long = "control-save-state")]
+    control_save_state: Option<String>,
+    /// Endpoint (command for the json protocol) sent after the last pattern, even when the
+    /// run fails. Without it the projector is blanked.
+    #[clap(long = "control-restore")]
+    control_restore: Option<String>,
+    /// Control URL of another projector lighting the surface, blanked for the run. Can be repeated.
+    #[clap(long = "blank-output")]
+    blank_outputs: Vec<String>,
+    /// Show patterns in a fullscreen window on this monitor (0 is the primary) instead of
+    /// using a control URL or asking the operator to show them
+    #[clap(long = "fullscreen-monitor")]
+    fullscreen_monitor: Option<i32>,
+    /// Fail instead of waiting for the operator when there's no control URL or fullscreen
+    /// monitor, e.g. when running as a service without a terminal
+    #[clap(long = "non-interactive")]
+    non_interactive: bool,
+    /// Give up if the operator hasn't answered a prompt after this many seconds
+    #[clap(long = "prompt-timeout")]
+    prompt_timeout: Option<f32>,
     /// Pass a http(s) URL or file name to use for camera images (instead of a USB tethered camera)
     #[clap(short = "c", long = "camera")]
     camera: Option<String>,
+    /// Extra HTTP header sent with every control server request, as "Name: value". Can be repeated.
+    #[clap(long = "header")]
+    headers: Vec<String>,
+    /// Accept self-signed or otherwise invalid TLS certificates from the control server
+    #[clap(long = "accept-invalid-certs")]
+    accept_invalid_certs: bool,
+    /// PEM file containing an additional root certificate to trust for the control server
+    #[clap(long = "ca-cert")]
+    ca_cert: Option<String>,
+    /// How patterns are encoded for the control server, as "FORMAT" or "FORMAT:SETTING" with
+    /// png (compression 0-9), jpeg (quality 0-100), bmp or webp (quality 1-100, above 100
+    /// lossless). Black and white patterns are sent as 1-bit PNGs.
+    #[clap(long = "pattern-encoding", default_value = "png:9")]
+    pattern_encoding: String,
+    /// How debug images are written, in the same form as --pattern-encoding
+    #[clap(long = "debug-encoding", default_value = "png:1")]
+    debug_encoding: String,
+    /// Append every request to the control server and its reply to this JSON lines transcript.
+    /// Header values are redacted unless given with --record-header-value.
+    #[clap(long = "record-network")]
+    record_network: Option<String>,
+    /// Header whose value --record-network writes as sent, so a replay checks it. Can be repeated.
+    #[clap(long = "record-header-value")]
+    record_header_values: Vec<String>,
+    /// Answer control server requests from a transcript made with --record-network instead
+    /// of a server, failing on any request that differs from the recorded one. Takes
+    /// precedence over --record-network.
+    #[clap(long = "replay-network")]
+    replay_network: Option<String>,
+    /// How replayed request bodies are compared: exact, hash or ignore. Image bodies are
+    /// only ever compared by hash.
+    #[clap(long = "replay-match", default_value = "exact")]
+    replay_match: String,
 
     #[clap(subcommand)]
     subcmd: SubCommand
@@ -33,6 +99,27 @@ enum SubCommand {
     /// Locate the physical camera relative to a single aruco marker
     #[clap(name = "locate-camera")]
     LocateCameraCommand(LocateCameraCommand),
+    /// Show a marker on every control URL and report which projectors the camera sees
+    #[clap(name = "identify-outputs")]
+    IdentifyOutputsCommand(IdentifyOutputsCommand),
+    /// Recompute a warp from a saved session without the camera or projector
+    #[clap(name = "recompute")]
+    RecomputeCommand(RecomputeCommand),
+    /// Keystone correction for a flat screen, without a surface model or eye position
+    #[clap(name = "keystone")]
+    KeystoneCommand(KeystoneCommand),
+    /// Check an existing warp still matches the rig
+    #[clap(name = "verify")]
+    VerifyCommand(VerifyCommand),
+    /// Verify an existing warp unattended and report drift, for cron or a service
+    #[clap(name = "monitor")]
+    MonitorCommand(MonitorCommand),
+    /// Report how much one calibration differs from another
+    #[clap(name = "compare")]
+    CompareCommand(CompareCommand),
+    /// Write an HTML report of a calibration from its saved session
+    #[clap(name = "report")]
+    ReportCommand(ReportCommand),
 }
 
 /// Start process of aligning and warping for a static virtual camera. Results in
@@ -44,13 +131,26 @@ struct GenerateWarpCommand {
     /// Ignored if surface type is "dome" (physical camera is assumed to be at the center of the dome).
     #[clap(short = "j", long = "camera-location-json")]
     camera_location_json: Option<String>,
+
+    /// Physical camera position "x,y,z" in scene space, instead of --camera-location-json
+    #[clap(long = "camera-position")]
+    camera_position: Option<String>,
+
+    /// Direction "x,y,z" the physical camera faces, used with --camera-position
+    #[clap(long = "camera-direction", default_value = "0,1,0")]
+    camera_direction: String,
+
+    /// Physical camera up direction "x,y,z", used with --camera-position
+    #[clap(long = "camera-up", default_value = "0,0,1")]
+    camera_up: String,
     
-    /// Chessboard pattern size
+    /// Chessboard size in inner corners (COLSxROWS), which is also the size of the warp grid
     #[clap(short = "p", long = "pattern-size
     ", default_value = "25x16")]
     pattern_size: String,
 
-    /// Projector output resolution
+    /// Projector output resolution, WIDTHxHEIGHT or one of 720p, 1080p, 1200p/WUXGA, 1440p,
+    /// WQXGA, 4K/UHD
     #[clap(short = "z", long = "resolution", default_value = "1024x768")]
     resolution: String,
 
@@ -64,9 +164,378 @@ struct GenerateWarpCommand {
     #[clap(long = "eye", default_value = "0,0,0")]
     eye_position: String,
 
+    /// Fetch the eye position as JSON from this URL (e.g. a head tracker bridge) instead of --eye
+    #[clap(long = "eye-url")]
+    eye_url: Option<String>,
+
+    /// Read the eye position from this JSON file instead of --eye
+    #[clap(long = "eye-file")]
+    eye_file: Option<String>,
+
+    /// JSON pointer to the [x, y, z] array or {x, y, z} object in the --eye-url or --eye-file
+    /// document, e.g. "/head/position"
+    #[clap(long = "eye-pointer", default_value = "")]
+    eye_pointer: String,
+
+    /// Translation "x,y,z" from tracker to scene coordinates, applied after --eye-rotation
+    #[clap(long = "eye-translation")]
+    eye_translation: Option<String>,
+
+    /// Rotation "x,y,z" in degrees from tracker to scene coordinates
+    #[clap(long = "eye-rotation")]
+    eye_rotation: Option<String>,
+
+    /// Output warp grid "COLSxROWS", interpolated from the detected chessboard. Defaults to
+    /// the chessboard's inner corners.
+    #[clap(long = "warp-grid")]
+    warp_grid: Option<String>,
+
+    /// Up vector "x,y,z" of the virtual camera the content is rendered with, for renders that
+    /// are rolled rather than level with the horizon
+    #[clap(long = "virtual-up", default_value = "0,1,0")]
+    virtual_up: String,
+
+    /// Near and far clip distances "near,far" of the virtual camera, in scene units. Derived
+    /// from the scene when not given.
+    #[clap(long = "clip-planes")]
+    clip_planes: Option<String>,
+
+    /// How the virtual camera's look_at is chosen: centered (turned until the content is
+    /// centered and needs the smallest fov), scene-mean or image-center (the middle of the
+    /// detected chessboard, as before these existed)
+    #[clap(long = "look-at", default_value = "centered", possible_values=&["centered", "scene-mean", "image-center"])]
+    look_at: String,
+
+    /// Weight the scene mean look_at starts from by corner confidence
+    #[clap(long = "weight-look-at")]
+    weight_look_at: bool,
+
+    /// Log how long each stage took and include the timings in the diagnostics
+    #[clap(long = "timings")]
+    timings: bool,
+
+    /// How the projector is mounted: landscape, portrait90 (image appears rotated clockwise),
+    /// portrait270 or rotated180. --resolution is always the projector's native resolution.
+    #[clap(long = "orientation", default_value = "landscape", possible_values=&["landscape", "portrait90", "portrait270", "rotated180"])]
+    orientation: String,
+
+    /// Projector throw ratio (throw distance / image width). When given the warp is calculated
+    /// for the frustum implied by this and --lens-shift, which are included in the output.
+    #[clap(long = "throw-ratio")]
+    throw_ratio: Option<f32>,
+
+    /// Projector lens shift "x,y" as fractions of the image width and height
+    #[clap(long = "lens-shift", default_value = "0,0")]
+    lens_shift: String,
+
+    /// Write the warp with the origin at the top left instead of the bottom left
+    #[clap(long = "flip-y")]
+    flip_y: bool,
+
+    /// Scale applied to eye, lookAt and scene in the output, e.g. 0.001 for surface and camera
+    /// positions measured in millimeters and a renderer in meters
+    #[clap(long = "output-scale", default_value = "1")]
+    output_scale: f32,
+
+    /// Axis convention of eye, lookAt, up and scene in the output. The calibration is always
+    /// computed y up, right handed.
+    #[clap(long = "output-axes", default_value = "y-up", possible_values=&["y-up", "z-up", "y-up-left-handed"])]
+    output_axes: String,
+
+    /// Write the warp in projector pixels instead of normalized 0-1 coordinates
+    #[clap(long = "pixel-units")]
+    pixel_units: bool,
+
+    /// Write the warp and scene column by column instead of row by row
+    #[clap(long = "column-major")]
+    column_major: bool,
+
+    /// Named eye position as "name=x,y,z". Can be repeated to make a calibration per eye
+    /// position from one capture, replacing --eye.
+    #[clap(long = "named-eye")]
+    named_eyes: Vec<String>,
+
     /// Radius of dome [required if --surface-type=dome]
     #[clap(long = "radius", default_value = "5")]
     radius: f32,
+
+    /// Save the captured photo and detected corners into this directory so the warp can be
+    /// recomputed later with the recompute command
+    #[clap(long = "session-dir")]
+    session_dir: Option<String>,
+
+    /// Write an HTML report of the calibration here, needs --session-dir
+    #[clap(long = "report")]
+    report: Option<String>,
+
+    /// Times to show and photograph the chessboard before giving up on detecting it
+    #[clap(long = "detection-attempts", default_value = "3")]
+    detection_attempts: u32,
+
+    /// Only detect the chessboard in the photo as is, without trying other preprocessing
+    #[clap(long = "no-detection-sweep")]
+    no_detection_sweep: bool,
+
+    /// Always use this preprocessing, as JSON (the detectionVariant from a previous run's
+    /// diagnostics)
+    #[clap(long = "detection-variant")]
+    detection_variant: Option<String>,
+
+    /// Seconds to spend trying preprocessing variants on each photo
+    #[clap(long = "detection-budget", default_value = "20")]
+    detection_budget: f32,
+
+    /// Which way round to look for the chessboard: invert (dark surfaces), no-invert (light
+    /// grey screens) or auto to try both
+    #[clap(long = "detection-polarity", default_value = "invert", possible_values=&["invert", "no-invert", "auto"])]
+    detection_polarity: String,
+
+    /// Skip showing the frame that checks which end of the chessboard its corners start from
+    #[clap(long = "no-orientation-check")]
+    no_orientation_check: bool,
+
+    /// Only look for the chessboard in this part of the photo, as JSON: {"type": "rect", "x",
+    /// "y", "width", "height"}, {"type": "polygon", "points": [[x, y], ...]} in photo pixels,
+    /// or {"type": "auto", "threshold": 30} to find it by photographing a black frame too
+    #[clap(long = "detection-roi")]
+    detection_roi: Option<String>,
+
+    /// Fail when the chessboard covers less than this fraction (0-1) of the camera frame,
+    /// instead of only warning
+    #[clap(long = "min-coverage")]
+    min_coverage: Option<f32>,
+    /// Fit these surface parameters ("normal", "offset", comma separated) to the corners
+    /// seen by several cameras. Flat surfaces and --cameras-json only.
+    #[clap(long = "refine-surface")]
+    refine_surface: Option<String>,
+
+    /// Show the chessboard only in this rectangle of the (upright) projector raster,
+    /// "x,y,width,height" in 0-1 or in pixels with a "px" suffix. The rest of the frame is
+    /// black and the warp is extrapolated to cover it.
+    #[clap(long = "pattern-placement")]
+    pattern_placement: Option<String>,
+
+    /// Capture the chessboard in COLSxROWS tiles shown one at a time, for boards too dense
+    /// to detect in one photo. Single camera only.
+    #[clap(long = "tiles")]
+    tiles: Option<String>,
+
+    /// Inner corners neighbouring tiles share at least
+    #[clap(long = "tile-overlap", default_value = "2")]
+    tile_overlap: i32,
+
+    /// Locate the chessboard's corners with a sequence of Gray code stripe frames instead of
+    /// detecting the chessboard. Needs a control URL or fullscreen monitor. Single camera only.
+    #[clap(long = "structured-light")]
+    structured_light: bool,
+
+    /// Before the chessboard, show a marker from this aruco dictionary (e.g. 4x4_50) on the
+    /// control URL and every --blank-output to check the camera sees the right projector.
+    /// Mislabeled outputs are swapped with a warning. Single camera only.
+    #[clap(long = "identify-outputs")]
+    identify_outputs: Option<String>,
+
+    /// Photo taken with the lens covered, for masking hot pixels. Needs --flat-frame.
+    #[clap(long = "dark-frame")]
+    dark_frame: Option<String>,
+
+    /// Photo of even light, e.g. through a diffuser over the lens, for masking dead pixels and
+    /// lens dirt and evening out the vignetting. Needs --dark-frame.
+    #[clap(long = "flat-frame")]
+    flat_frame: Option<String>,
+
+    /// Ask for the dark and flat frames and take them with the camera, unless they're cached
+    /// in --defect-cache-dir
+    #[clap(long = "capture-defect-frames")]
+    capture_defect_frames: bool,
+
+    /// Directory the camera defects derived from the dark and flat frames are cached in,
+    /// named after the camera calibration file. On later runs they're loaded from there.
+    #[clap(long = "defect-cache-dir")]
+    defect_cache_dir: Option<String>,
+
+    /// Fail when photos aren't exactly the size in the camera calibration file. Otherwise a
+    /// camera set to a uniform scale of it, e.g. half, is handled by scaling the calibration.
+    #[clap(long = "strict-photo-size")]
+    strict_photo_size: bool,
+
+    /// Seconds to wait after showing each pattern before fetching from a remote camera
+    #[clap(long = "camera-settle", default_value = "0")]
+    camera_settle: f32,
+
+    /// Remote camera frames to fetch and throw away before the one that's used
+    #[clap(long = "camera-discard-frames", default_value = "0")]
+    camera_discard_frames: u32,
+
+    /// Keep fetching remote camera frames until two in a row differ by less than this mean
+    /// greyscale difference (of 255)
+    #[clap(long = "camera-stable-threshold")]
+    camera_stable_threshold: Option<f64>,
+
+    /// Seconds between the remote camera frames compared with --camera-stable-threshold
+    #[clap(long = "camera-stable-interval", default_value = "0.5")]
+    camera_stable_interval: f32,
+
+    /// Seconds after which the latest remote camera frame is used even if it's still changing
+    #[clap(long = "camera-stable-timeout", default_value = "10")]
+    camera_stable_timeout: f32,
+
+    /// JSON file listing several cameras, each with "calibrationFname" and optionally
+    /// "locationFname", "camera", "warmUp" and the "region" {col, row, cols, rows} of the grid it sees.
+    /// Replaces --camera-xml-file, --camera and --camera-location-json.
+    #[clap(long = "cameras")]
+    cameras_json: Option<String>,
+}
+
+/// Recompute a warp from a directory saved with generate-warp --session-dir. The surface
+/// and eye position are taken from the session unless overridden.
+#[derive(Clap)]
+struct RecomputeCommand {
+    #[clap(long = "session-dir")]
+    session_dir: String,
+
+    /// Eye position in scene space
+    #[clap(long = "eye")]
+    eye_position: Option<String>,
+
+    /// Radius of dome, replaces the saved surface with a dome of this radius
+    #[clap(long = "radius")]
+    radius: Option<f32>,
+
+    /// Detect the chessboard corners again from the saved photo
+    #[clap(long = "redetect")]
+    redetect: bool,
+}
+
+/// Fit a homography between the projected and photographed chessboard and print it, or the
+/// corner pin that makes the image rectangular, as JSON. The camera XML file is only used
+/// for undistortion when it exists.
+#[derive(Clap)]
+struct KeystoneCommand {
+    /// Chessboard size in inner corners (COLSxROWS), which is also the size of the warp grid
+    #[clap(short = "p", long = "pattern-size", default_value = "25x16")]
+    pattern_size: String,
+
+    /// Projector output resolution, WIDTHxHEIGHT or one of 720p, 1080p, 1200p/WUXGA, 1440p,
+    /// WQXGA, 4K/UHD
+    #[clap(short = "z", long = "resolution", default_value = "1024x768")]
+    resolution: String,
+
+    /// What to output: "homography" or "corner-pin"
+    #[clap(long = "output", default_value = "corner-pin", possible_values=&["homography", "corner-pin"])]
+    output: String,
+}
+
+/// Project the chessboard again and compare the surface points with those of an existing
+/// calibration JSON. Prints a JSON report and exits with status 2 when the check fails.
+#[derive(Clap)]
+struct VerifyCommand {
+    /// Calibration JSON written by generate-warp
+    #[clap(long = "calibration")]
+    calibration: String,
+
+    /// Chessboard size in inner corners (COLSxROWS), must match the stored calibration
+    #[clap(short = "p", long = "pattern-size", default_value = "25x16")]
+    pattern_size: String,
+
+    /// JSON file containing the camera location, when it moved since the calibration was made
+    #[clap(short = "j", long = "camera-location-json")]
+    camera_location_json: Option<String>,
+
+    /// Largest allowed displacement of any corner on the surface, in scene units
+    #[clap(long = "tolerance", default_value = "0.01")]
+    tolerance: f32,
+}
+
+/// Verify an existing calibration JSON without an operator, needs --control-url. Writes a
+/// timestamped JSON report to the results directory and exits with status 0 when the warp is
+/// fine, 3 for a drift warning, 4 when the drift exceeds the limit and 5 when the drift
+/// couldn't be measured (e.g. the camera was offline).
+#[derive(Clap)]
+struct MonitorCommand {
+    /// Baseline calibration JSON written by generate-warp
+    #[clap(long = "calibration")]
+    calibration: String,
+
+    /// Chessboard size in inner corners (COLSxROWS), must match the baseline
+    #[clap(short = "p", long = "pattern-size", default_value = "25x16")]
+    pattern_size: String,
+
+    /// JSON file containing the camera location, when it moved since the baseline was made
+    #[clap(short = "j", long = "camera-location-json")]
+    camera_location_json: Option<String>,
+
+    /// Directory the reports are written to
+    #[clap(long = "results-dir")]
+    results_dir: String,
+
+    /// Warn when any corner's projected point moved more than this, 0-1 across the image
+    #[clap(long = "warning-threshold", default_value = "0.002")]
+    warning_threshold: f32,
+
+    /// Fail when any corner's projected point moved more than this, 0-1 across the image
+    #[clap(long = "exceeded-threshold", default_value = "0.01")]
+    exceeded_threshold: f32,
+
+    /// Largest allowed displacement of any corner on the surface, in scene units, recorded
+    /// in the report
+    #[clap(long = "tolerance", default_value = "0.01")]
+    tolerance: f32,
+
+    /// Captures to try before reporting the run as failed
+    #[clap(long = "attempts", default_value = "3")]
+    attempts: u32,
+
+    /// Seconds to wait between failed captures
+    #[clap(long = "retry-seconds", default_value = "30")]
+    retry_seconds: f32,
+
+    /// Post the baseline corrected for the measured drift when it's a warning no larger than
+    /// this, 0-1 across the image
+    #[clap(long = "self-heal-below")]
+    self_heal_below: Option<f32>,
+
+    /// Directory generate-warp cached the camera defects in with --defect-cache-dir
+    #[clap(long = "defect-cache-dir")]
+    defect_cache_dir: Option<String>,
+}
+
+/// Compare two calibration JSON files written by generate-warp and print the differences
+/// as JSON
+#[derive(Clap)]
+struct CompareCommand {
+    /// The earlier calibration
+    a: String,
+
+    /// The calibration compared with it
+    b: String,
+
+    /// Fail when the grids differ instead of resampling the smaller one
+    #[clap(long = "no-resample")]
+    no_resample: bool,
+
+    /// Write the warp displacement of each corner as a color mapped PNG
+    #[clap(long = "heatmap")]
+    heatmap: Option<String>,
+
+    /// Warp displacement (0-1 across the image) shown red in the heatmap, the largest when
+    /// not given
+    #[clap(long = "heatmap-max")]
+    heatmap_max: Option<f32>,
+}
+
+/// Write a self-contained HTML report of a calibration from the session saved with it
+#[derive(Clap)]
+struct ReportCommand {
+    #[clap(long = "session-dir")]
+    session_dir: String,
+
+    /// Calibration JSON written by generate-warp. Recomputed from the session when not given.
+    #[clap(long = "calibration")]
+    calibration: Option<String>,
+
+    #[clap(short = "o", long = "out", default_value = "report.html")]
+    out: String,
 }
 
 /// Locate the camera in physical space. Place an aruco marker at 0,0,0 facing Z axis.
@@ -75,34 +544,329 @@ struct LocateCameraCommand {
     /// Aruco marker size in meters
     #[clap(short = "m", long = "marker-size")]
     marker_size: Option<f32>,
+
+    /// Aruco dictionary the marker is from, e.g. 4x4_50, 6x6_250, original or apriltag_36h11
+    #[clap(long = "dictionary", default_value = "original")]
+    dictionary: String,
+
+    /// Use the marker with this id when several are in view
+    #[clap(long = "marker-id")]
+    marker_id: Option<i32>,
+
+    /// Use the largest marker in view when several are
+    #[clap(long = "largest-marker")]
+    largest_marker: bool,
+}
+
+/// Show marker 0 on --control-url and marker i on the i-th --blank-output at once, then
+/// print which of them the camera sees and where
+#[derive(Clap)]
+struct IdentifyOutputsCommand {
+    /// Projector output resolution, WIDTHxHEIGHT or one of 720p, 1080p, 1200p/WUXGA, 1440p,
+    /// WQXGA, 4K/UHD
+    #[clap(short = "z", long = "resolution", default_value = "1024x768")]
+    resolution: String,
+
+    /// How the projectors are mounted: landscape, portrait90, portrait270 or rotated180
+    #[clap(long = "orientation", default_value = "landscape", possible_values=&["landscape", "portrait90", "portrait270", "rotated180"])]
+    orientation: String,
+
+    /// Aruco dictionary the markers are from, it needs a marker for every output
+    #[clap(long = "dictionary", default_value = "4x4_50")]
+    dictionary: String,
+
+    /// Exit with status 2 when any output's marker isn't in view
+    #[clap(long = "require-all")]
+    require_all: bool,
 }
 
 fn main() {
     simplelog::SimpleLogger::init(simplelog::LevelFilter::Info, simplelog::Config::default()).unwrap();
 
     let opts: Opts = Opts::parse();
+    let debug_encoding = ImageEncoding::parse(&opts.debug_encoding).expect("invalid debug encoding");
+    let network_config = network_config(&opts);
+    let display = pattern_display(&opts, &network_config);
+    let other_outputs = other_outputs(&opts, &network_config);
     // You can handle information about subcommands by requesting their matches by name
     // (as below), requesting just the name used, or both at the same time
     match opts.subcmd {
         SubCommand::GenerateWarpCommand(cmd) => {
-            produce_calibration(
-                surface_type(&opts.surface_type, &cmd),
+            let options = CalibrationOptions {
+                camera_location_fname: cmd.camera_location_json.clone(),
+                camera_pose: cmd.camera_position.as_deref().map(|position| PhysicalCameraPose {
+                    position: parse_vec3(position).expect("invalid camera position"),
+                    look_at: parse_vec3(&cmd.camera_direction).expect("invalid camera direction"),
+                    up_dir: parse_vec3(&cmd.camera_up).expect("invalid camera up direction"),
+                }),
+                post_to: cmd.post_json_to.as_deref().map(|url| control_protocol(&opts.control_protocol, pattern_encoding(&opts.pattern_encoding), url, &network_config, SessionCommands::default())),
+                session_dir: cmd.session_dir.clone(),
+                detection: DetectionOptions {
+                    attempts: cmd.detection_attempts,
+                    variant: cmd.detection_variant.as_deref().map(|json| serde_json::from_str(json).expect("invalid detection variant")),
+                    polarity: Polarity::parse(&cmd.detection_polarity).unwrap(),
+                    sweep: !cmd.no_detection_sweep,
+                    time_budget: std::time::Duration::from_secs_f32(cmd.detection_budget),
+                    orientation_check: !cmd.no_orientation_check,
+                    roi: cmd.detection_roi.as_deref().map(|json| serde_json::from_str(json).expect("invalid detection ROI")),
+                    min_camera_coverage: cmd.min_coverage,
+                    coverage_warnings: true,
+                },
+                projector_orientation: ProjectorOrientation::parse(&cmd.orientation).expect("invalid orientation"),
+                output_conventions: OutputConventions {
+                    flip_y: cmd.flip_y,
+                    units: if cmd.pixel_units { WarpUnits::Pixels } else { WarpUnits::Normalized },
+                    order: if cmd.column_major { WarpOrder::ColumnMajor } else { WarpOrder::RowMajor },
+                    transform: OutputTransform {
+                        scale: cmd.output_scale,
+                        axes: match cmd.output_axes.as_str() {
+                            "z-up" => AxisConvention::ZUpRightHanded,
+                            "y-up-left-handed" => AxisConvention::YUpLeftHanded,
+                            _ => AxisConvention::YUpRightHanded,
+                        },
+                    },
+                },
+                projector_optics: cmd.throw_ratio.map(|throw_ratio| projector_optics(throw_ratio, &cmd.lens_shift).expect("invalid lens shift")),
+                virtual_up: parse_vec3(&cmd.virtual_up).expect("invalid virtual camera up vector"),
+                clip_planes: cmd.clip_planes.as_deref().map(|planes| parse_clip_planes(planes).expect("invalid clip planes")),
+                warp_grid: cmd.warp_grid.as_deref().map(|grid| GridSpec::parse_sized(grid).expect("invalid warp grid")),
+                timings: cmd.timings,
+                camera_warm_up: WarmUp {
+                    settle_seconds: cmd.camera_settle,
+                    discard_frames: cmd.camera_discard_frames,
+                    stability: cmd.camera_stable_threshold.map(|threshold| StabilityCheck {
+                        interval_seconds: cmd.camera_stable_interval,
+                        threshold: threshold,
+                        timeout_seconds: cmd.camera_stable_timeout,
+                    }),
+                },
+                other_outputs: other_outputs,
+                refine_surface: cmd.refine_surface.as_deref()
+                    .map(|list| list.split(',').map(|name| SurfaceParameter::parse(name.trim()).expect("invalid surface parameter")).collect())
+                    .unwrap_or(vec![]),
+                pattern_placement: cmd.pattern_placement.as_deref().map(|placement| {
+                    let orientation = ProjectorOrientation::parse(&cmd.orientation).expect("invalid orientation");
+                    let upright = orientation.effective_resolution(Resolution::parse(&cmd.resolution).expect("invalid projector resolution"));
+                    PatternPlacement::parse(placement, upright).expect("invalid pattern placement")
+                }),
+                tiling: cmd.tiles.as_deref().map(|tiles| Tiling::parse(tiles, cmd.tile_overlap).expect("invalid tiles")),
+                identify_outputs: cmd.identify_outputs.as_deref().map(|name| ArucoDictionary::parse(name).expect("invalid aruco dictionary")),
+                defects: DefectOptions {
+                    frames: match (&cmd.dark_frame, &cmd.flat_frame) {
+                        (Some(dark), Some(flat)) => Some(DefectFrames::Files {dark: dark.clone(), flat: flat.clone()}),
+                        (None, None) => if cmd.capture_defect_frames { Some(DefectFrames::Capture) } else { None },
+                        _ => panic!("--dark-frame and --flat-frame must be given together"),
+                    },
+                    cache_dir: cmd.defect_cache_dir.clone(),
+                },
+                structured_light: cmd.structured_light,
+                strict_photo_size: cmd.strict_photo_size,
+                look_at_method: LookAtMethod::parse(&cmd.look_at, cmd.weight_look_at).unwrap(),
+                debug_encoding: debug_encoding,
+                ..Default::default()
+            };
+            let result = if let Some(fname) = &cmd.cameras_json {
+                let json = std::fs::read_to_string(fname).expect("can't read cameras JSON file");
+                let cameras: Vec<CameraSetup> = serde_json::from_str(&json).expect("invalid cameras JSON file");
+                produce_multi_camera_calibration(
+                    surface_type(&opts.surface_type, &cmd),
+                    &cameras,
+                    display,
+                    eye_position_source(&cmd),
+                    GridSpec::parse(&cmd.pattern_size).expect("invalid pattern size"),
+                    Resolution::parse(&cmd.resolution).expect("invalid projector resolution"),
+                    options
+                ).map(|_| ())
+            } else if !cmd.named_eyes.is_empty() {
+                produce_eye_calibrations(
+                    surface_type(&opts.surface_type, &cmd),
+                    &opts.camera_calib_xml,
+                    display,
+                    opts.camera.as_deref(),
+                    &cmd.named_eyes.iter().map(|eye| parse_named_eye(eye).expect("invalid named eye position")).collect::<Vec<_>>(),
+                    GridSpec::parse(&cmd.pattern_size).expect("invalid pattern size"),
+                    Resolution::parse(&cmd.resolution).expect("invalid projector resolution"),
+                    options
+                ).map(|_| ())
+            } else {
+                produce_calibration(
+                    surface_type(&opts.surface_type, &cmd),
+                    &opts.camera_calib_xml,
+                    display,
+                    opts.camera.as_deref(),
+                    eye_position_source(&cmd),
+                    GridSpec::parse(&cmd.pattern_size).expect("invalid pattern size"),
+                    Resolution::parse(&cmd.resolution).expect("invalid projector resolution"),
+                    options
+                ).and_then(|result| match (&cmd.report, &cmd.session_dir) {
+                    (Some(fname), Some(dir)) => write_session_report(dir, &result, fname),
+                    (Some(_), None) => Err(aligner::Error::Config("--report needs --session-dir".to_string())),
+                    _ => Ok(())
+                })
+            };
+            if let Err(err) = result {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::RecomputeCommand(cmd) => {
+            let result = recompute_calibration(
+                &cmd.session_dir,
+                cmd.eye_position.as_deref().map(|eye| parse_vec3(eye).expect("invalid eye position")),
+                cmd.radius.map(|radius| surfaces::SurfaceType::HemisphericalDome {radius: radius}),
+                cmd.redetect,
+                debug_encoding
+            );
+            match result {
+                Ok(result) => println!("{}", result.to_json_string()),
+                Err(err) => {
+                    error!("{}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        SubCommand::KeystoneCommand(cmd) => {
+            let camera_xml = Some(opts.camera_calib_xml.as_str()).filter(|path| std::path::Path::new(path).exists());
+            let result = produce_keystone(
+                camera_xml,
+                opts.camera.as_deref(),
+                display,
+                GridSpec::parse(&cmd.pattern_size).expect("invalid pattern size"),
+                Resolution::parse(&cmd.resolution).expect("invalid projector resolution"),
+                if cmd.output == "homography" { KeystoneOutput::Homography } else { KeystoneOutput::CornerPin },
+                debug_encoding
+            );
+            match result {
+                Ok(result) => println!("{}", result.to_json_string()),
+                Err(err) => {
+                    error!("{}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        SubCommand::VerifyCommand(cmd) => {
+            let json = std::fs::read_to_string(&cmd.calibration).expect("can't read calibration JSON file");
+            let stored = CalibrationResult::from_json(&json).expect("invalid calibration JSON file");
+            let result = verify_calibration(
+                &stored,
                 &opts.camera_calib_xml,
-                opts.control_url.as_deref(),
+                display,
                 opts.camera.as_deref(),
                 cmd.camera_location_json.as_deref(),
-                parse_vec3(&cmd.eye_position).expect("invalid eye position"),
-                Resolution::parse(&cmd.pattern_size).expect("invalid pattern size"),
+                GridSpec::parse(&cmd.pattern_size).expect("invalid pattern size"),
+                cmd.tolerance,
+                debug_encoding
+            );
+            match result {
+                Ok(report) => if !report.passed {
+                    std::process::exit(2);
+                },
+                Err(err) => {
+                    error!("{}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        SubCommand::MonitorCommand(cmd) => {
+            let json = std::fs::read_to_string(&cmd.calibration).expect("can't read calibration JSON file");
+            let stored = CalibrationResult::from_json(&json).expect("invalid calibration JSON file");
+            let options = MonitorOptions {
+                thresholds: DriftThresholds {warning: cmd.warning_threshold, exceeded: cmd.exceeded_threshold},
+                scene_tolerance: cmd.tolerance,
+                results_dir: cmd.results_dir.clone(),
+                attempts: cmd.attempts,
+                retry_seconds: cmd.retry_seconds,
+                self_heal_below: cmd.self_heal_below,
+                defect_cache_dir: cmd.defect_cache_dir.clone(),
+                debug_encoding: debug_encoding,
+            };
+            let result = monitor_calibration(
+                &stored,
+                &opts.camera_calib_xml,
+                display,
+                opts.camera.as_deref(),
+                cmd.camera_location_json.as_deref(),
+                GridSpec::parse(&cmd.pattern_size).expect("invalid pattern size"),
+                &options
+            );
+            match result {
+                Ok(report) => std::process::exit(report.status.exit_code()),
+                Err(err) => {
+                    error!("{}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        SubCommand::CompareCommand(cmd) => {
+            let load = |fname: &str| {
+                let json = std::fs::read_to_string(fname).expect("can't read calibration JSON file");
+                CalibrationResult::from_json(&json).expect("invalid calibration JSON file")
+            };
+            match compare_calibrations(&load(&cmd.a), &load(&cmd.b), !cmd.no_resample) {
+                Ok(diff) => {
+                    println!("{}", diff.to_json_string());
+                    if let Some(fname) = &cmd.heatmap {
+                        let png = uv_heatmap_png(&diff, cmd.heatmap_max, 16).expect("failed to render heatmap");
+                        std::fs::write(fname, png).expect("can't write heatmap");
+                    }
+                },
+                Err(err) => {
+                    error!("{}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        SubCommand::ReportCommand(cmd) => {
+            let result = match &cmd.calibration {
+                Some(fname) => {
+                    let json = std::fs::read_to_string(fname).expect("can't read calibration JSON file");
+                    Ok(CalibrationResult::from_json(&json).expect("invalid calibration JSON file"))
+                },
+                None => recompute_calibration(&cmd.session_dir, None, None, false, debug_encoding),
+            }.and_then(|result| write_session_report(&cmd.session_dir, &result, &cmd.out));
+            if let Err(err) = result {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::IdentifyOutputsCommand(cmd) => {
+            let result = identify_projectors(
+                &opts.camera_calib_xml,
+                opts.camera.as_deref(),
+                &display,
+                &other_outputs,
                 Resolution::parse(&cmd.resolution).expect("invalid projector resolution"),
-                cmd.post_json_to.as_deref()
+                ProjectorOrientation::parse(&cmd.orientation).expect("invalid orientation"),
+                ArucoDictionary::parse(&cmd.dictionary).expect("invalid aruco dictionary"),
+                debug_encoding
             );
+            match result {
+                Ok(report) => if cmd.require_all && !report.unseen().is_empty() {
+                    std::process::exit(2);
+                },
+                Err(err) => {
+                    error!("{}", err);
+                    std::process::exit(1);
+                }
+            }
         }
         SubCommand::LocateCameraCommand(cmd) => {
-            locate_camera(
+            let result = locate_camera(
                 &opts.camera_calib_xml,
                 opts.camera.as_deref(),
-                cmd.marker_size.expect("missing maker size option")
+                cmd.marker_size.expect("missing maker size option"),
+                ArucoDictionary::parse(&cmd.dictionary).expect("invalid aruco dictionary"),
+                match (cmd.marker_id, cmd.largest_marker) {
+                    (Some(id), _) => MarkerSelection::Id(id),
+                    (None, true) => MarkerSelection::Largest,
+                    (None, false) => MarkerSelection::Single,
+                },
+                debug_encoding
             );
+            if let Err(err) = result {
+                error!("{}", err);
+                std::process::exit(1);
+            }
         }
     }
 }
@@ -115,6 +879,109 @@ fn surface_type(surface: &str, opts: &GenerateWarpCommand) -> surfaces::SurfaceT
     }
 }
 
+fn eye_position_source(cmd: &GenerateWarpCommand) -> EyePositionSource {
+    let transform = if cmd.eye_translation.is_some() || cmd.eye_rotation.is_some() {
+        EyeTransform::TranslationRotation {
+            translation: parse_vec3(cmd.eye_translation.as_deref().unwrap_or("0,0,0")).expect("invalid eye translation"),
+            rotation: parse_vec3(cmd.eye_rotation.as_deref().unwrap_or("0,0,0")).expect("invalid eye rotation"),
+        }
+    } else {
+        EyeTransform::Identity
+    };
+    if let Some(url) = &cmd.eye_url {
+        EyePositionSource::HttpJson {url: url.clone(), json_pointer: cmd.eye_pointer.clone(), transform: transform}
+    } else if let Some(path) = &cmd.eye_file {
+        EyePositionSource::File {path: path.clone(), json_pointer: cmd.eye_pointer.clone(), transform: transform}
+    } else {
+        EyePositionSource::Fixed(parse_vec3(&cmd.eye_position).expect("invalid eye position"))
+    }
+}
+
+fn pattern_display(opts: &Opts, network_config: &NetworkConfig) -> PatternDisplay {
+    if let Some(monitor) = opts.fullscreen_monitor {
+        PatternDisplay::LocalFullscreen(LocalDisplay {monitor: monitor, origin: None})
+    } else if let Some(url) = &opts.control_url {
+        PatternDisplay::Control(control_protocol(&opts.control_protocol, pattern_encoding(&opts.pattern_encoding), url, network_config, session_commands(opts)))
+    } else if opts.non_interactive {
+        PatternDisplay::Manual(Box::new(NonInteractive))
+    } else if let Some(seconds) = opts.prompt_timeout {
+        PatternDisplay::Manual(Box::new(TimeoutPrompt {inner: std::sync::Arc::new(StdinPrompt), timeout: std::time::Duration::from_secs_f32(seconds)}))
+    } else {
+        PatternDisplay::Manual(Box::new(StdinPrompt))
+    }
+}
+
+fn control_protocol(kind: &str, encoding: ImageEncoding, url: &str, config: &NetworkConfig, session: SessionCommands) -> Box<dyn ControlProtocol + Send> {
+    match kind {
+        "raw" => Box::new(RawPostProtocol {session: session, pattern_encoding: encoding, ..RawPostProtocol::new(url, config.clone())}),
+        "multipart" => Box::new(MultipartProtocol {session: session, pattern_encoding: encoding, ..MultipartProtocol::new(url, config.clone())}),
+        "json" => Box::new(JsonCommandProtocol {session: session, pattern_encoding: encoding, ..JsonCommandProtocol::new(url, config.clone())}),
+        _ => panic!("Unknown control protocol. Please specify 'raw', 'multipart' or 'json'")
+    }
+}
+
+fn pattern_encoding(input: &str) -> ImageEncoding {
+    ImageEncoding::parse(input).expect("invalid pattern encoding")
+}
+
+fn session_commands(opts: &Opts) -> SessionCommands {
+    SessionCommands::new(opts.control_save_state.clone(), opts.control_restore.clone())
+}
+
+/// The other projectors' control servers, restored the same way as the measured one
+fn other_outputs(opts: &Opts, network_config: &NetworkConfig) -> Vec<Box<dyn ControlProtocol + Send>> {
+    opts.blank_outputs.iter().map(|url| control_protocol(&opts.control_protocol, pattern_encoding(&opts.pattern_encoding), url, network_config, session_commands(opts))).collect()
+}
+
+fn network_config(opts: &Opts) -> NetworkConfig {
+    NetworkConfig {
+        headers: opts.headers.iter().map(|h| parse_header(h).expect("invalid header")).collect(),
+        accept_invalid_certs: opts.accept_invalid_certs,
+        root_certificate: opts.ca_cert.clone(),
+        timeout: None,
+        transport: if let Some(path) = &opts.replay_network {
+            let matching = BodyMatching::parse(&opts.replay_match).expect("invalid --replay-match");
+            Some(SharedTransport::new(ReplayTransport::load(path, matching).expect("can't load network transcript")))
+        } else if let Some(path) = &opts.record_network {
+            let kept: Vec<&str> = opts.record_header_values.iter().map(String::as_str).collect();
+            Some(SharedTransport::new(RecordingTransport::new(path).expect("can't record network transcript").keeping_header_values(&kept)))
+        } else {
+            None
+        },
+    }
+}
+
+fn parse_header(input: &str) -> Result<(String, String), &'static str> {
+    let mut parts = input.splitn(2, ':');
+    let name = parts.next().unwrap().trim();
+    let value = parts.next().ok_or("header must be in the form \"Name: value\"")?.trim();
+    Ok((name.to_string(), value.to_string()))
+}
+
+fn projector_optics(throw_ratio: f32, lens_shift: &str) -> Result<ProjectorOptics, &'static str> {
+    let mut parts = lens_shift.splitn(2, ',');
+    let x = parts.next().unwrap().trim().parse().map_err(|_| "lens shift must be in the form \"x,y\"")?;
+    let y = parts.next().ok_or("lens shift must be in the form \"x,y\"")?.trim().parse().map_err(|_| "lens shift must be in the form \"x,y\"")?;
+    Ok(ProjectorOptics {throw_ratio: throw_ratio, lens_shift_x: x, lens_shift_y: y})
+}
+
+fn parse_named_eye(input: &str) -> Result<NamedEyePosition, &'static str> {
+    let mut parts = input.splitn(2, '=');
+    let name = parts.next().unwrap().trim();
+    let position = parts.next().ok_or("named eye position must be in the form \"name=x,y,z\"")?;
+    Ok(NamedEyePosition {name: name.to_string(), position: parse_vec3(position)?})
+}
+
+fn parse_clip_planes(input: &str) -> Result<(f32, f32), &'static str> {
+    let mut parts = input.splitn(2, ',');
+    let near: f32 = parts.next().unwrap().trim().parse().map_err(|_| "clip planes must be in the form \"near,far\"")?;
+    let far: f32 = parts.next().ok_or("clip planes must be in the form \"near,far\"")?.trim().parse().map_err(|_| "clip planes must be in the form \"near,far\"")?;
+    if near <= 0. || far <= near {
+        return Err("clip planes must have 0 < near < far");
+    }
+    Ok((near, far))
+}
+
 fn parse_vec3(input: &str) -> Result<glm::Vec3, &'static str> {
     let mut floats = [0_f32; 3];
     for (i, word) in input.split(|c| c == ',').enumerate() {
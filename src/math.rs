@@ -43,4 +43,159 @@ tmp = (tmp * T::from(2).unwrap()) - T::one();
 obj = obj / obj.w;
 
   Ok(obj.truncate(3))
-}
\ No newline at end of file
+}
+/// Unit quaternion [x, y, z, w] of a rotation matrix given row by row
+pub fn rotation_to_quaternion(m: &[[f32; 3]; 3]) -> [f32; 4] {
+    let trace = m[0][0] + m[1][1] + m[2][2];
+    if trace > 0. {
+        let s = (trace + 1.).sqrt() * 2.;
+        [(m[2][1] - m[1][2]) / s, (m[0][2] - m[2][0]) / s, (m[1][0] - m[0][1]) / s, s / 4.]
+    } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+        let s = (1. + m[0][0] - m[1][1] - m[2][2]).sqrt() * 2.;
+        [s / 4., (m[0][1] + m[1][0]) / s, (m[0][2] + m[2][0]) / s, (m[2][1] - m[1][2]) / s]
+    } else if m[1][1] > m[2][2] {
+        let s = (1. + m[1][1] - m[0][0] - m[2][2]).sqrt() * 2.;
+        [(m[0][1] + m[1][0]) / s, s / 4., (m[1][2] + m[2][1]) / s, (m[0][2] - m[2][0]) / s]
+    } else {
+        let s = (1. + m[2][2] - m[0][0] - m[1][1]).sqrt() * 2.;
+        [(m[0][2] + m[2][0]) / s, (m[1][2] + m[2][1]) / s, s / 4., (m[1][0] - m[0][1]) / s]
+    }
+}
+
+/// A matrix's 16 values, column by column
+pub fn mat4_values(m: &Matrix4<f32>) -> [f32; 16] {
+    let mut values = [0_f32; 16];
+    for (i, column) in [m.c0, m.c1, m.c2, m.c3].iter().enumerate() {
+        values[i * 4..i * 4 + 4].copy_from_slice(column.as_array());
+    }
+    values
+}
+
+/// A matrix from its 16 values, column by column, the inverse of `mat4_values`
+pub fn mat4_from_values(values: &[f32; 16]) -> Matrix4<f32> {
+    Matrix4::new(
+        vec4(values[0], values[1], values[2], values[3]),
+        vec4(values[4], values[5], values[6], values[7]),
+        vec4(values[8], values[9], values[10], values[11]),
+        vec4(values[12], values[13], values[14], values[15])
+    )
+}
+
+/// projection followed by the affine map m * uv + t (m row by row) of the warp it gives,
+/// where a point's warp is its normalized device x and y mapped from -1..1 to 0..1. The map
+/// is linear in clip space so the result is still one matrix.
+pub fn remap_warp(projection: &Matrix4<f32>, m: [[f32; 2]; 2], t: [f32; 2]) -> Matrix4<f32> {
+    // with warp = (ndc + 1) / 2, m * warp + t is the warp of m * ndc + m * (1, 1) + 2t - (1, 1)
+    let c = [m[0][0] + m[0][1] + 2. * t[0] - 1., m[1][0] + m[1][1] + 2. * t[1] - 1.];
+    let remap = Matrix4::new(
+        vec4(m[0][0], m[1][0], 0., 0.),
+        vec4(m[0][1], m[1][1], 0., 0.),
+        vec4(0., 0., 1., 0.),
+        vec4(c[0], c[1], 0., 1.)
+    );
+    remap * *projection
+}
+
+/// The inverse of the affine map m * uv + t
+pub fn invert_affine(m: [[f32; 2]; 2], t: [f32; 2]) -> ([[f32; 2]; 2], [f32; 2]) {
+    let det = m[0][0] * m[1][1] - m[0][1] * m[1][0];
+    let inverse = [[m[1][1] / det, -m[0][1] / det], [-m[1][0] / det, m[0][0] / det]];
+    let offset = [
+        -(inverse[0][0] * t[0] + inverse[0][1] * t[1]),
+        -(inverse[1][0] * t[0] + inverse[1][1] * t[1]),
+    ];
+    (inverse, offset)
+}
+
+/// Minimize f with the Nelder-Mead simplex method, starting from a simplex that steps
+/// along each axis from start. Stops when the simplex's values agree to within tolerance
+/// or after max_iterations. Returns the best point, its value and the iterations used.
+pub fn nelder_mead<F: Fn(&[f32]) -> f32>(f: F, start: &[f32], steps: &[f32], max_iterations: usize, tolerance: f32) -> (Vec<f32>, f32, usize) {
+    let n = start.len();
+    let mut simplex: Vec<(Vec<f32>, f32)> = vec![(start.to_vec(), f(start))];
+    for i in 0..n {
+        let mut point = start.to_vec();
+        point[i] += steps[i];
+        let value = f(&point);
+        simplex.push((point, value));
+    }
+    let along = |from: &[f32], to: &[f32], t: f32| -> Vec<f32> {
+        from.iter().zip(to.iter()).map(|(a, b)| a + (b - a) * t).collect()
+    };
+
+    let mut iterations = 0;
+    while iterations < max_iterations {
+        simplex.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Greater));
+        let (best, worst) = (simplex[0].1, simplex[n].1);
+        if (worst - best).abs() <= tolerance {
+            break;
+        }
+        iterations += 1;
+
+        let mut centroid = vec![0_f32; n];
+        for (point, _) in simplex[..n].iter() {
+            for (c, p) in centroid.iter_mut().zip(point.iter()) {
+                *c += p / n as f32;
+            }
+        }
+        let reflected = along(&centroid, &simplex[n].0, -1.);
+        let reflected_value = f(&reflected);
+        if reflected_value < best {
+            let expanded = along(&centroid, &simplex[n].0, -2.);
+            let expanded_value = f(&expanded);
+            simplex[n] = if expanded_value < reflected_value { (expanded, expanded_value) } else { (reflected, reflected_value) };
+        } else if reflected_value < simplex[n - 1].1 {
+            simplex[n] = (reflected, reflected_value);
+        } else {
+            let contracted = along(&centroid, &simplex[n].0, 0.5);
+            let contracted_value = f(&contracted);
+            if contracted_value < worst {
+                simplex[n] = (contracted, contracted_value);
+            } else {
+                // shrink everything towards the best point
+                let best_point = simplex[0].0.clone();
+                for vertex in simplex[1..].iter_mut() {
+                    let point = along(&best_point, &vertex.0, 0.5);
+                    let value = f(&point);
+                    *vertex = (point, value);
+                }
+            }
+        }
+    }
+    simplex.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Greater));
+    let (point, value) = simplex.swap_remove(0);
+    (point, value, iterations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rotations whose quaternions are known by hand, including half turns, which take each
+    /// of the non-trace branches
+    #[test]
+    fn rotation_quaternions() {
+        let h = 0.5_f32.sqrt();
+        let cases = [
+            ([[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]], [0., 0., 0., 1.]),
+            // a quarter turn about y
+            ([[0., 0., 1.], [0., 1., 0.], [-1., 0., 0.]], [0., h, 0., h]),
+            // a quarter turn about z
+            ([[0., -1., 0.], [1., 0., 0.], [0., 0., 1.]], [0., 0., h, h]),
+            // half turns about x, y and z
+            ([[1., 0., 0.], [0., -1., 0.], [0., 0., -1.]], [1., 0., 0., 0.]),
+            ([[-1., 0., 0.], [0., 1., 0.], [0., 0., -1.]], [0., 1., 0., 0.]),
+            ([[-1., 0., 0.], [0., -1., 0.], [0., 0., 1.]], [0., 0., 1., 0.]),
+            // a half turn about (1, 1, 0)
+            ([[0., 1., 0.], [1., 0., 0.], [0., 0., -1.]], [h, h, 0., 0.]),
+        ];
+        for (m, expected) in cases.iter() {
+            let q = rotation_to_quaternion(m);
+            // q and -q are the same rotation
+            let sign = if q.iter().zip(expected.iter()).map(|(a, b)| a * b).sum::<f32>() < 0. { -1. } else { 1. };
+            for i in 0..4 {
+                assert!((q[i] * sign - expected[i]).abs() < 1e-5, "{:?} gave {:?}, not {:?}", m, q, expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Unattended re-verification of a permanently rigged camera against a baseline calibration,
+//! e.g. nightly from cron. See `monitor_calibration`.
+//!
+//! A run either measures the drift and classifies it against the thresholds, or fails to
+//! measure it at all (camera offline, chessboard not found). The two are reported distinctly
+//! so a flaky camera isn't mistaken for a projector that has moved.
+
+use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::{CalibrationResult, Error};
+use super::pipeline::{self, VirtualCamera, LookAtMethod};
+use super::verify::VerificationReport;
+use super::images::ImageEncoding;
+
+/// How far the projected corners may move before the run is flagged, in 0-1 across the
+/// upright image. Judged on the corner that moved furthest.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DriftThresholds {
+    /// DriftWarning above this
+    pub warning: f32,
+    /// DriftExceeded above this
+    pub exceeded: f32,
+}
+
+/// Options for `monitor_calibration`
+#[derive(Clone, Debug)]
+pub struct MonitorOptions {
+    pub thresholds: DriftThresholds,
+    /// largest displacement of any corner on the surface for the verification to pass, in
+    /// scene units. Only recorded in the report, the status follows the uv thresholds.
+    pub scene_tolerance: f32,
+    /// the timestamped reports are written here, created when it doesn't exist
+    pub results_dir: String,
+    /// captures tried before the run counts as failed
+    pub attempts: u32,
+    /// wait between failed captures
+    pub retry_seconds: f32,
+    /// when the drift is a warning no larger than this, post the baseline corrected by the
+    /// measured displacement to the control server
+    pub self_heal_below: Option<f32>,
+    /// where the camera's defects were cached by a calibration run, see `defects`
+    pub defect_cache_dir: Option<String>,
+    /// how debug artifacts of the captures are written
+    pub debug_encoding: ImageEncoding,
+}
+
+/// Outcome of a monitoring run
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum MonitorStatus {
+    Ok,
+    DriftWarning,
+    DriftExceeded,
+    /// the drift couldn't be measured, see the report's failures
+    Failed,
+}
+
+impl MonitorStatus {
+    pub fn classify(uv_max: f32, thresholds: &DriftThresholds) -> MonitorStatus {
+        if uv_max > thresholds.exceeded {
+            MonitorStatus::DriftExceeded
+        } else if uv_max > thresholds.warning {
+            MonitorStatus::DriftWarning
+        } else {
+            MonitorStatus::Ok
+        }
+    }
+
+    /// Process exit status for the command line, 1 is left for errors in the setup
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MonitorStatus::Ok => 0,
+            MonitorStatus::DriftWarning => 3,
+            MonitorStatus::DriftExceeded => 4,
+            MonitorStatus::Failed => 5,
+        }
+    }
+}
+
+/// What a monitoring run found, written to the results directory
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MonitorReport {
+    /// seconds since the unix epoch
+    pub timestamp: u64,
+    pub status: MonitorStatus,
+    pub thresholds: DriftThresholds,
+    /// captures tried
+    pub attempts: u32,
+    /// why each failed capture failed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failures: Vec<String>,
+    /// the comparison with the baseline, None when every capture failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verification: Option<VerificationReport>,
+    /// a corrected calibration was posted
+    #[serde(default)]
+    pub corrected: bool,
+    /// why a correction that was due couldn't be posted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correction_error: Option<String>,
+}
+
+impl MonitorReport {
+    pub fn new(thresholds: DriftThresholds) -> MonitorReport {
+        MonitorReport {
+            timestamp: now(),
+            status: MonitorStatus::Failed,
+            thresholds: thresholds,
+            attempts: 0,
+            failures: vec![],
+            verification: None,
+            corrected: false,
+            correction_error: None,
+        }
+    }
+
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Write the report into dir as monitor-TIMESTAMP.json, returning its path
+    pub fn write(&self, dir: &str) -> Result<PathBuf, Error> {
+        std::fs::create_dir_all(dir)?;
+        let path = Path::new(dir).join(format!("monitor-{}.json", self.timestamp));
+        std::fs::write(&path, self.to_json_string())?;
+        Ok(path)
+    }
+}
+
+/// The baseline with each valid corner's warp moved by how far its fresh scene point is from
+/// the stored one as seen from the baseline's virtual camera, and the fresh scene points in
+/// place of the stored ones. fresh_scene is row by row on the baseline's grid in the native
+/// system, as for `verify::compare`. The result is in the native conventions.
+pub fn corrected_calibration(baseline: &CalibrationResult, fresh_scene: &Vec<glm::Vec3>) -> Result<CalibrationResult, Error> {
+    let meta = baseline.meta.as_ref().ok_or(Error::Config("the baseline calibration has no meta, so its projector isn't known".to_string()))?;
+    let mut corrected = meta.output_conventions.revert(baseline, meta.projector_resolution);
+    if fresh_scene.len() != corrected.scene.len() || corrected.warp.len() != corrected.scene.len() {
+        return Err(Error::Config(format!(
+            "fresh capture has {} scene points but the baseline has {} scene and {} warp points",
+            fresh_scene.len(), corrected.scene.len(), corrected.warp.len()
+        )));
+    }
+    let orientation = meta.projector_orientation;
+    let virtual_camera = VirtualCamera {
+        position: corrected.eye,
+        up_dir: corrected.up,
+        look_at: Some(corrected.look_at),
+        fov: Some(corrected.fov),
+        optics: corrected.projector_optics,
+        clip_planes: meta.clip_planes.map(|[near, far]| (near, far)),
+        look_at_method: meta.look_at_method.unwrap_or(LookAtMethod::ImageCenter),
+    };
+    let aspect_ratio = orientation.effective_resolution(meta.projector_resolution).aspect_ratio();
+    let (model, proj) = pipeline::view_and_projection(&virtual_camera, aspect_ratio);
+    let valid = corrected.valid.clone().unwrap_or(vec![true; fresh_scene.len()]);
+    for i in 0..fresh_scene.len() {
+        if !valid[i] {
+            continue;
+        }
+        let moved = orientation.to_native_uv(pipeline::project_with(fresh_scene[i], &model, &proj));
+        let stored = orientation.to_native_uv(pipeline::project_with(corrected.scene[i], &model, &proj));
+        corrected.warp[i] = corrected.warp[i] + (moved - stored);
+        corrected.scene[i] = fresh_scene[i];
+    }
+    if let Some(meta) = corrected.meta.as_mut() {
+        meta.timestamp = now();
+    }
+    Ok(corrected)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
@@ -0,0 +1,275 @@
+//! Calibrating a surface no single camera can see by merging the corners detected by several
+//! cameras at known poses.
+//!
+//! Each camera is given the region of the chessboard's inner corners it can see. Only the
+//! squares of that region are projected while the camera takes its photo, so every camera
+//! detects a complete (smaller) chessboard and the local corner indices map directly onto
+//! the full grid.
+
+use glm::*;
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use super::{PhysicalCamera, Resolution, GridSpec, Error, PatternDisplay};
+use super::{camera_calibration, confidence, defects, images, locator, output, photo, refine, surfaces};
+use super::images::GridRegion;
+use super::pipeline::{self, ImagePointGrid};
+use super::progress::ProgressSink;
+use super::projector::{ProjectorOrientation, PatternPlacement};
+use super::detection::{DetectionOptions, DetectionVariant};
+use super::timings::Timings;
+use super::coverage::Coverage;
+
+/// One of the cameras used for a multi-camera calibration
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CameraSetup {
+    /// camera calibration XML
+    pub calibration_fname: String,
+    /// JSON file containing the camera pose (output of `locate_camera` with this camera)
+    #[serde(default)]
+    pub location_fname: Option<String>,
+    /// http(s) URL or image file, None for the tethered camera
+    #[serde(default)]
+    pub camera: Option<String>,
+    /// the inner corners this camera can see, None for the whole grid. col + row must be even
+    /// so the region's chessboard starts with the same color square as the full board.
+    #[serde(default)]
+    pub region: Option<GridRegion>,
+    /// waiting for a remote camera to catch up with the pattern, replaces the run's
+    #[serde(default)]
+    pub warm_up: Option<photo::WarmUp>,
+}
+
+/// A camera ready for capture, with its pose loaded
+pub struct SetupCamera {
+    pub physical_camera: PhysicalCamera,
+    pub camera_type: photo::CameraType,
+    pub region: GridRegion,
+    pub calibration_path: String,
+}
+
+/// Corners detected by one camera, in its own photo space
+pub struct CameraCorners {
+    pub region: GridRegion,
+    pub image_points: ImagePointGrid,
+    pub detection_variant: DetectionVariant,
+    pub flipped: bool,
+    pub coverage: Coverage,
+}
+
+/// The scene grid merged from every camera
+pub struct MergedScene {
+    /// scene point for each corner of the full grid, row by row. Corners no camera saw are
+    /// filled in from their neighbours.
+    pub scene: Vec<glm::Vec3>,
+    /// false for the filled in corners
+    pub valid: Vec<bool>,
+    /// confidence of each corner, the best of the cameras that saw it
+    pub confidence: Vec<f32>,
+    /// indices of the cameras that saw each corner
+    pub contributions: Vec<Vec<usize>>,
+    pub rms_disagreement: f32,
+    pub max_disagreement: f32,
+}
+
+impl SetupCamera {
+    /// The camera's defects are loaded from defect_cache_dir when it's given
+    pub fn load(setup: &CameraSetup, grid: GridSpec, warm_up: &photo::WarmUp, defect_cache_dir: Option<&str>) -> Result<SetupCamera, Error> {
+        let mut calibration = camera_calibration::load_calibration_file(&setup.calibration_fname)?;
+        if let Some(dir) = defect_cache_dir {
+            calibration.defects = defects::CameraDefects::load(dir, &setup.calibration_fname, &calibration)?;
+        }
+        let mut physical_camera = PhysicalCamera {
+            position: vec3(0., 0., 0.),
+            look_at: vec3(0., 1., 0.),
+            up_dir: vec3(0., 0., 1.),
+            calibration: calibration
+        };
+        if let Some(fname) = &setup.location_fname {
+            locator::update_physical_camera_location(&mut physical_camera, fname)?;
+        }
+        let region = setup.region.unwrap_or(GridRegion {col: 0, row: 0, cols: grid.cols, rows: grid.rows});
+        if region.col < 0 || region.row < 0 || region.col + region.cols > grid.cols || region.row + region.rows > grid.rows {
+            return Err(Error::Config(format!("camera region {:?} is outside the {} grid", region, grid)));
+        }
+        if (region.col + region.row) % 2 != 0 {
+            return Err(Error::Config(format!("camera region {:?} must start on an even col + row", region)));
+        }
+        Ok(SetupCamera {
+            physical_camera: physical_camera,
+            camera_type: photo::CameraType::from_arg(setup.camera.as_deref()).with_warm_up(setup.warm_up.as_ref().unwrap_or(warm_up)),
+            region: region,
+            calibration_path: setup.calibration_fname.clone(),
+        })
+    }
+}
+
+/// Show each camera's region of the chessboard, in placement when it's given, and detect its
+/// corners. Each camera's calibration is scaled to its photos, see `PhysicalCamera::match_photo`.
+pub fn detect_all(cameras: &mut [SetupCamera], display: &PatternDisplay, grid: GridSpec, projector_res: Resolution, orientation: ProjectorOrientation, placement: Option<&PatternPlacement>, detection: &DetectionOptions, debug_encoding: images::ImageEncoding, progress: &mut dyn ProgressSink, timings: &mut Option<Timings>) -> Result<Vec<CameraCorners>, Error> {
+    let mut detected = vec![];
+    for camera in cameras.iter_mut() {
+        let region = camera.region;
+        let pattern = images::Pattern::ChessboardRegion {grid: grid, region: region}.placed(placement);
+        let capture = pipeline::detect_pattern_corners(
+            &camera.physical_camera,
+            display,
+            camera.camera_type.clone(),
+            &pattern,
+            GridSpec {cols: region.cols, rows: region.rows},
+            projector_res,
+            orientation,
+            detection,
+            debug_encoding,
+            progress,
+            timings
+        )?;
+        camera.physical_camera.match_photo(capture.undistorted.cols(), capture.undistorted.rows())?;
+        info!("camera {} detected {} corners", camera.calibration_path, capture.image_points.len());
+        detected.push(CameraCorners {region: region, image_points: capture.image_points, detection_variant: capture.detection_variant, flipped: capture.flipped, coverage: capture.coverage});
+    }
+    Ok(detected)
+}
+
+/// Every camera's corners with their index into the full grid, for refining the surface
+pub fn observations(cameras: &[SetupCamera], detected: &[CameraCorners], grid: GridSpec) -> Vec<refine::Observation> {
+    cameras.iter().zip(detected.iter()).map(|(camera, corners)| {
+        let mut points = vec![];
+        for row in 0..corners.region.rows {
+            for col in 0..corners.region.cols {
+                if let Some(point) = corners.image_points.get(col, row) {
+                    points.push((((corners.region.row + row) * grid.cols + corners.region.col + col) as usize, point));
+                }
+            }
+        }
+        refine::Observation {camera: camera.physical_camera.model(), points: points}
+    }).collect()
+}
+
+/// Map every camera's corners onto the surface and average them per grid corner, weighted by
+/// each camera's confidence in it
+pub fn merge_scene_points(surface: &surfaces::SurfaceType, cameras: &[SetupCamera], detected: &[CameraCorners], grid: GridSpec) -> Result<MergedScene, Error> {
+    let count = grid.len();
+    let mut seen: Vec<Vec<(usize, glm::Vec3, f32)>> = vec![vec![]; count];
+
+    for (i, (camera, corners)) in cameras.iter().zip(detected.iter()).enumerate() {
+        let mapper = surfaces::SceneMapper::new(surface, &camera.physical_camera.model());
+        for row in 0..corners.region.rows {
+            for col in 0..corners.region.cols {
+                let point = match corners.image_points.get(col, row) {
+                    Some(point) => point,
+                    None => continue
+                };
+                // corners that map off the surface count as not seen
+                if let Ok(scene) = mapper.map(point) {
+                    let global = ((corners.region.row + row) * grid.cols + corners.region.col + col) as usize;
+                    let weight = corners.image_points.confidence[(row * corners.region.cols + col) as usize];
+                    seen[global].push((i, scene, weight));
+                }
+            }
+        }
+    }
+
+    let mut scene = vec![vec3(0., 0., 0.); count];
+    let mut valid = vec![false; count];
+    let mut confidence = vec![confidence::EXTRAPOLATED; count];
+    let mut sum_sq = 0_f32;
+    let mut samples = 0;
+    let mut max_disagreement = 0_f32;
+    for (i, points) in seen.iter().enumerate() {
+        if points.is_empty() {
+            continue;
+        }
+        let mut avg = vec3(0., 0., 0.);
+        let mut total = 0_f32;
+        for (_, p, weight) in points {
+            avg = avg + *p * *weight;
+            total += *weight;
+        }
+        // a plain mean when no camera has any confidence in it
+        avg = if total > 1e-6 {
+            avg / total
+        } else {
+            points.iter().fold(vec3(0., 0., 0.), |sum, (_, p, _)| sum + *p) / points.len() as f32
+        };
+        if points.len() > 1 {
+            for (_, p, _) in points {
+                let d = length(*p - avg);
+                sum_sq += d * d;
+                samples += 1;
+                max_disagreement = max_disagreement.max(d);
+            }
+        }
+        scene[i] = avg;
+        valid[i] = true;
+        confidence[i] = points.iter().fold(0_f32, |best, (_, _, weight)| best.max(*weight));
+    }
+
+    let missing = valid.iter().filter(|v| !**v).count();
+    if missing == count {
+        return Err(Error::Display("no camera detected any chessboard corners".to_string()));
+    }
+    if missing > 0 {
+        warn!("{} grid corners weren't seen by any camera, filling them in from their neighbours", missing);
+        fill_missing(&mut scene, &valid, grid);
+    }
+
+    Ok(MergedScene {
+        scene: scene,
+        valid: valid,
+        confidence: confidence,
+        contributions: seen.iter().map(|points| points.iter().map(|(i, _, _)| *i).collect()).collect(),
+        rms_disagreement: if samples > 0 { (sum_sq / samples as f32).sqrt() } else { 0. },
+        max_disagreement: max_disagreement,
+    })
+}
+
+/// Repeatedly set missing corners to the average of their known 4-neighbours until the
+/// grid is full
+fn fill_missing(scene: &mut Vec<glm::Vec3>, valid: &Vec<bool>, grid: GridSpec) {
+    let (w, h) = (grid.cols, grid.rows);
+    let mut known = valid.clone();
+    while known.iter().any(|k| !*k) {
+        let mut next = known.clone();
+        for row in 0..h {
+            for col in 0..w {
+                let i = (row * w + col) as usize;
+                if known[i] {
+                    continue;
+                }
+                let mut sum = vec3(0., 0., 0.);
+                let mut n = 0;
+                for (dc, dr) in &[(-1, 0), (1, 0), (0, -1), (0, 1)] {
+                    let (c, r) = (col + dc, row + dr);
+                    if c >= 0 && r >= 0 && c < w && r < h && known[(r * w + c) as usize] {
+                        sum = sum + scene[(r * w + c) as usize];
+                        n += 1;
+                    }
+                }
+                if n > 0 {
+                    scene[i] = sum / n as f32;
+                    next[i] = true;
+                }
+            }
+        }
+        known = next;
+    }
+}
+
+/// Diagnostics section describing each camera's contribution
+pub fn diagnostics(cameras: &[SetupCamera], detected: &[CameraCorners], merged: &MergedScene) -> output::MultiCameraDiagnostics {
+    output::MultiCameraDiagnostics {
+        cameras: cameras.iter().zip(detected.iter()).map(|(camera, corners)| output::CameraContribution {
+            calibration_path: camera.calibration_path.clone(),
+            region: [camera.region.col, camera.region.row, camera.region.cols, camera.region.rows],
+            detected_corners: corners.image_points.valid_points().count(),
+            detection_variant: Some(corners.detection_variant),
+            orientation_flipped: Some(corners.flipped),
+            coverage: Some(corners.coverage.clone()),
+        }).collect(),
+        contributions: merged.contributions.clone(),
+        rms_disagreement: merged.rms_disagreement,
+        max_disagreement: merged.max_disagreement,
+        missing_corners: merged.valid.iter().filter(|v| !**v).count(),
+    }
+}
@@ -1,27 +1,384 @@
-use reqwest::blocking::Response;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use log::{debug, warn};
+use std::fmt;
+use std::fs;
+use std::time::Duration;
+use super::transport::{self, HttpReply, HttpRequest, SharedTransport};
 
-/// format should be "png", "jpg" etc
+/// Connection options shared by every request made to the control server during a session
+#[derive(Clone, Debug, Default)]
+pub struct NetworkConfig {
+    /// headers attached to every request, e.g. ("X-Api-Key", "...") or ("Authorization", "Bearer ...")
+    pub headers: Vec<(String, String)>,
+    /// accept any TLS certificate. Only for self-signed setups on a trusted network.
+    pub accept_invalid_certs: bool,
+    /// PEM file with an extra root certificate to trust (e.g. the venue's own CA)
+    pub root_certificate: Option<String>,
+    pub timeout: Option<Duration>,
+    /// carries the requests instead of HTTP, e.g. to record or replay them, see `transport`
+    pub transport: Option<SharedTransport>,
+}
+
+impl NetworkConfig {
+    /// Build a HTTP(S) client with these options applied
+    pub fn client(&self) -> Result<Client, NetworkError> {
+        let mut builder = Client::builder().default_headers(self.header_map()?);
+        if self.accept_invalid_certs {
+            warn!("TLS certificate validation is disabled for the control server");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if let Some(cert) = self.root_certificate()? {
+            builder = builder.add_root_certificate(cert);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        builder.build().map_err(NetworkError::from)
+    }
+
+    /// `client` for async code
+    #[cfg(feature = "async")]
+    pub fn async_client(&self) -> Result<reqwest::Client, NetworkError> {
+        let mut builder = reqwest::Client::builder().default_headers(self.header_map()?);
+        if self.accept_invalid_certs {
+            warn!("TLS certificate validation is disabled for the control server");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if let Some(cert) = self.root_certificate()? {
+            builder = builder.add_root_certificate(cert);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        builder.build().map_err(NetworkError::from)
+    }
+
+    fn header_map(&self) -> Result<HeaderMap, NetworkError> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in self.headers.iter() {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| NetworkError::Config(format!("invalid header name '{}'", name)))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| NetworkError::Config(format!("invalid value for header '{}'", name)))?;
+            headers.insert(name, value);
+        }
+        Ok(headers)
+    }
+
+    fn root_certificate(&self) -> Result<Option<reqwest::Certificate>, NetworkError> {
+        let path = match &self.root_certificate {
+            Some(path) => path,
+            None => return Ok(None)
+        };
+        let pem = fs::read(path)
+            .map_err(|err| NetworkError::Config(format!("can't read root certificate {}: {}", path, err)))?;
+        let cert = reqwest::Certificate::from_pem(&pem)
+            .map_err(|err| NetworkError::Config(format!("invalid root certificate {}: {}", path, err)))?;
+        Ok(Some(cert))
+    }
+
+    /// A POST of body to url with the headers set on this config
+    pub fn request(&self, url: &str, content_type: &str, body: Vec<u8>) -> HttpRequest {
+        HttpRequest {url: url.to_string(), content_type: content_type.to_string(), headers: self.headers.clone(), body: body}
+    }
+}
+
+/// Reply from the control server to a command
+#[derive(Debug)]
+pub struct CommandResponse {
+    pub status: u16,
+    pub body: String,
+    /// the body parsed as JSON, when the server said it was JSON
+    pub json: Option<serde_json::Value>,
+}
+
+#[derive(Debug)]
+pub enum NetworkError {
+    /// server replied with a non-2xx status
+    Status {status: u16, body: String},
+    /// couldn't connect to the server at all
+    Connect(reqwest::Error),
+    Timeout(reqwest::Error),
+    Other(reqwest::Error),
+    /// the NetworkConfig couldn't be applied
+    Config(String),
+    /// the request body couldn't be built
+    InvalidPayload(String),
+    /// the async calibration the request was made for was dropped
+    Cancelled,
+    /// a transcript couldn't be recorded or replayed, or a replayed request didn't match it
+    Transcript(String),
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::Status {status, body} => write!(f, "control server replied with status {}: {}", status, body),
+            NetworkError::Connect(err) => write!(f, "couldn't connect to control server: {}", err),
+            NetworkError::Timeout(err) => write!(f, "request to control server timed out: {}", err),
+            NetworkError::Other(err) => write!(f, "request to control server failed: {}", err),
+            NetworkError::Config(msg) => write!(f, "invalid network configuration: {}", msg),
+            NetworkError::InvalidPayload(msg) => write!(f, "invalid request body: {}", msg),
+            NetworkError::Cancelled => write!(f, "the calibration was cancelled"),
+            NetworkError::Transcript(msg) => write!(f, "network transcript: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+impl From<reqwest::Error> for NetworkError {
+    fn from(err: reqwest::Error) -> NetworkError {
+        if err.is_timeout() {
+            NetworkError::Timeout(err)
+        } else if err.is_connect() {
+            NetworkError::Connect(err)
+        } else {
+            NetworkError::Other(err)
+        }
+    }
+}
+
+/// POST raw image bytes to url. format should be "png", "jpg" etc
 pub fn post_image(
-    control_url: &str,
+    config: &NetworkConfig,
+    url: &str,
     image_bytes: &[u8],
     format: &str,
-) -> reqwest::Result<Response> {
-    let url = format!("{}/show_image", control_url);
+) -> Result<CommandResponse, NetworkError> {
     let ctype = format!("image/{}", format);
-    let client = reqwest::blocking::Client::new();
-    client
-        .post(&url)
-        .body(image_bytes.to_vec())
-        .header("Content-Type", &ctype)
-        .send() //.expect("Failed to post image to projector");
+    transport::send_command_request(config, &config.request(url, &ctype, image_bytes.to_vec()))
 }
 
 /// Send a command and optional json body to the remote control URL
-pub fn send_command(url: &str, command: &str, json_str: &str) {
+pub fn send_command(config: &NetworkConfig, url: &str, command: &str, json_str: &str) -> Result<CommandResponse, NetworkError> {
+    send_command_with_query(config, url, command, &[], json_str)
+}
+
+/// send_command with query parameters added to the URL
+pub fn send_command_with_query(config: &NetworkConfig, url: &str, command: &str, query: &[(&str, &str)], json_str: &str) -> Result<CommandResponse, NetworkError> {
+    let mut url = reqwest::Url::parse(&format!("{}/{}", url, command))
+        .map_err(|err| NetworkError::Config(format!("invalid control URL: {}", err)))?;
+    if !query.is_empty() {
+        url.query_pairs_mut().extend_pairs(query.iter());
+    }
+    let request = config.request(url.as_str(), "application/json", json_str.as_bytes().to_vec());
+    let response = transport::send_command_request(config, &request)?;
+    debug!("{} replied with status {}: {}", url, response.status, response.body);
+    Ok(response)
+}
+
+/// Check the status of a response and collect its body
+pub fn command_response(res: Response) -> Result<CommandResponse, NetworkError> {
+    let status = res.status().as_u16();
+    let content_type = res.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(String::from);
+    reply_response(HttpReply {status: status, content_type: content_type, body: res.text()?})
+}
+
+/// `command_response` for a reply from any transport
+pub fn reply_response(reply: HttpReply) -> Result<CommandResponse, NetworkError> {
+    if !(200..300).contains(&reply.status) {
+        return Err(NetworkError::Status {status: reply.status, body: reply.body});
+    }
+
+    let is_json = reply.content_type.map(|v| v.starts_with("application/json")).unwrap_or(false);
+    let json = if is_json { serde_json::from_str(&reply.body).ok() } else { None };
+    Ok(CommandResponse {status: reply.status, body: reply.body, json: json})
+}
+
+/// Run request on the config's transport off the async runtime, transports are blocking
+#[cfg(feature = "async")]
+async fn send_with_transport_async(config: &NetworkConfig, request: HttpRequest) -> Result<CommandResponse, NetworkError> {
+    let config = config.clone();
+    tokio::task::spawn_blocking(move || transport::send_command_request(&config, &request))
+        .await
+        .map_err(|_| NetworkError::Cancelled)?
+}
+
+/// `post_image` with the async client
+#[cfg(feature = "async")]
+pub async fn post_image_async(config: &NetworkConfig, url: &str, image_bytes: &[u8], format: &str) -> Result<CommandResponse, NetworkError> {
+    if config.transport.is_some() {
+        return send_with_transport_async(config, config.request(url, &format!("image/{}", format), image_bytes.to_vec())).await;
+    }
+    let res = config.async_client()?
+        .post(url)
+        .body(image_bytes.to_vec())
+        .header("Content-Type", format!("image/{}", format))
+        .send()
+        .await?;
+    command_response_async(res).await
+}
+
+/// `send_command` with the async client
+#[cfg(feature = "async")]
+pub async fn send_command_async(config: &NetworkConfig, url: &str, command: &str, json_str: &str) -> Result<CommandResponse, NetworkError> {
     let url = format!("{}/{}", url, command);
-    let req = reqwest::blocking::Client::new()
+    if config.transport.is_some() {
+        return send_with_transport_async(config, config.request(&url, "application/json", json_str.as_bytes().to_vec())).await;
+    }
+    let res = config.async_client()?
         .post(&url)
         .header("Content-Type", "application/json")
-        .body(String::from(json_str));
-    req.send().expect("failed to send comand to projector");
+        .body(String::from(json_str))
+        .send()
+        .await?;
+    let response = command_response_async(res).await?;
+    debug!("{} replied with status {}: {}", url, response.status, response.body);
+    Ok(response)
+}
+
+/// `command_response` for the async client's responses
+#[cfg(feature = "async")]
+pub async fn command_response_async(res: reqwest::Response) -> Result<CommandResponse, NetworkError> {
+    let status = res.status();
+    let is_json = res.headers().get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("application/json"))
+        .unwrap_or(false);
+    let body = res.text().await?;
+
+    if !status.is_success() {
+        return Err(NetworkError::Status {status: status.as_u16(), body: body});
+    }
+
+    let json = if is_json { serde_json::from_str(&body).ok() } else { None };
+    Ok(CommandResponse {status: status.as_u16(), body: body, json: json})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::process::Command;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use transport::Transport;
+
+    /// Keeps every request and replies 200 with an empty JSON object
+    #[derive(Default)]
+    struct Capture {
+        requests: Mutex<Vec<HttpRequest>>,
+    }
+
+    impl Transport for Arc<Capture> {
+        fn send(&self, _config: &NetworkConfig, request: &HttpRequest) -> Result<HttpReply, NetworkError> {
+            self.requests.lock().unwrap().push(request.clone());
+            Ok(HttpReply {status: 200, content_type: Some("application/json".to_string()), body: "{}".to_string()})
+        }
+    }
+
+    fn headers() -> Vec<(String, String)> {
+        vec![("Authorization".to_string(), "Bearer secret".to_string()), ("X-Rig".to_string(), "dome".to_string())]
+    }
+
+    #[test]
+    fn headers_are_sent_with_every_request() {
+        let capture = Arc::new(Capture::default());
+        let config = NetworkConfig {headers: headers(), transport: Some(SharedTransport::new(capture.clone())), ..NetworkConfig::default()};
+        post_image(&config, "http://rig.local/show_image", &[1, 2, 3], "png").unwrap();
+        let response = send_command(&config, "http://rig.local", "set_calibration", "{\"fov\":40}").unwrap();
+        assert_eq!(response.json, Some(serde_json::json!({})));
+        send_command_with_query(&config, "http://rig.local", "set_calibration", &[("eye", "row 2")], "{}").unwrap();
+
+        let requests = capture.requests.lock().unwrap();
+        let sent: Vec<(&str, &str)> = requests.iter().map(|r| (r.url.as_str(), r.content_type.as_str())).collect();
+        assert_eq!(sent, vec![
+            ("http://rig.local/show_image", "image/png"),
+            ("http://rig.local/set_calibration", "application/json"),
+            ("http://rig.local/set_calibration?eye=row+2", "application/json"),
+        ]);
+        for request in requests.iter() {
+            assert_eq!(request.headers, headers(), "{}", request.url);
+        }
+    }
+
+    #[test]
+    fn invalid_headers_are_config_errors() {
+        let config = NetworkConfig {headers: vec![("X Rig".to_string(), "dome".to_string())], ..NetworkConfig::default()};
+        match config.client() {
+            Err(NetworkError::Config(_)) => {},
+            other => panic!("a header name with a space gave {:?}", other.map(|_| ())),
+        }
+        let config = NetworkConfig {root_certificate: Some("missing.pem".to_string()), ..NetworkConfig::default()};
+        assert!(matches!(config.client(), Err(NetworkError::Config(_))));
+    }
+
+    /// A self-signed certificate for localhost and its key, made with the openssl command.
+    /// None when it isn't installed.
+    fn self_signed(dir: &Path) -> Option<(String, String)> {
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let made = Command::new("openssl")
+            .args(&["req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1", "-subj", "/CN=localhost", "-addext", "subjectAltName=DNS:localhost,IP:127.0.0.1"])
+            .arg("-keyout").arg(&key)
+            .arg("-out").arg(&cert)
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false);
+        if made { Some((cert.to_string_lossy().into_owned(), key.to_string_lossy().into_owned())) } else { None }
+    }
+
+    /// Serve HTTPS on localhost with the certificate, answering each request that gets past
+    /// the handshake with an empty JSON object. Returns the URL and the requests' headers.
+    fn serve_tls(cert: &str, key: &str) -> (String, Arc<Mutex<Vec<String>>>) {
+        let identity = native_tls::Identity::from_pkcs8(&fs::read(cert).unwrap(), &fs::read(key).unwrap()).unwrap();
+        let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("https://localhost:{}", listener.local_addr().unwrap().port());
+        let heads = Arc::new(Mutex::new(vec![]));
+        let seen = heads.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                // a client refusing the certificate ends the handshake, wait for the next one
+                let mut stream = match stream.map(|stream| acceptor.accept(stream)) {
+                    Ok(Ok(stream)) => stream,
+                    _ => continue
+                };
+                let mut request = vec![];
+                let mut buf = [0; 4096];
+                while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let text = String::from_utf8_lossy(&request).into_owned();
+                seen.lock().unwrap().push(text.split("\r\n\r\n").next().unwrap_or_default().to_lowercase());
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}");
+                let _ = stream.shutdown();
+            }
+        });
+        (url, heads)
+    }
+
+    #[test]
+    fn self_signed_servers_are_only_trusted_when_asked() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = match self_signed(dir.path()) {
+            Some(files) => files,
+            None => {
+                eprintln!("skipping the TLS test, the openssl command isn't available to make a certificate");
+                return;
+            }
+        };
+        let (url, heads) = serve_tls(&cert, &key);
+        let config = NetworkConfig {headers: headers(), timeout: Some(Duration::from_secs(10)), ..NetworkConfig::default()};
+
+        assert!(send_command(&config, &url, "set_calibration", "{}").is_err(), "the self-signed certificate was trusted by default");
+        let with_ca = NetworkConfig {root_certificate: Some(cert.clone()), ..config.clone()};
+        assert_eq!(send_command(&with_ca, &url, "set_calibration", "{}").unwrap().status, 200);
+        let insecure = NetworkConfig {accept_invalid_certs: true, ..config.clone()};
+        assert_eq!(send_command(&insecure, &url, "set_calibration", "{}").unwrap().status, 200);
+
+        // both requests that got through carried the config's headers
+        let heads = heads.lock().unwrap();
+        assert_eq!(heads.len(), 2);
+        for head in heads.iter() {
+            assert!(head.starts_with("post /set_calibration"), "{}", head);
+            assert!(head.contains("authorization: bearer secret") && head.contains("x-rig: dome"), "{}", head);
+        }
+    }
 }
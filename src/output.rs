@@ -0,0 +1,748 @@
+
+use serde::{Serialize, Deserialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::{Resolution, GridSpec, math};
+use super::surfaces::{SurfaceType, CameraIntrinsics};
+use super::projector::{ProjectorOrientation, ProjectorOptics, PatternPlacement};
+use super::detection::DetectionVariant;
+use super::timings::Timings;
+use super::coverage::Coverage;
+use super::refine::SurfaceRefinement;
+use super::tiling::Tiling;
+use super::pipeline::LookAtMethod;
+
+/// Version of the calibration JSON layout, emitted as `formatVersion`. Files written
+/// before the field existed should be treated as version 0.
+///
+/// Bump rules:
+///  - adding a new key (top level or inside `meta`) does NOT change the version, consumers
+///    must ignore keys they don't understand
+///  - removing or renaming a key, or changing the meaning, units or layout of an existing
+///    key (e.g. the ordering of `warp`) increments the version
+pub const CALIBRATION_FORMAT_VERSION: u32 = 1;
+
+/// A complete calibration document. Field names match the JSON keys consumers already read,
+/// everything added after the original format is optional so older files still load.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CalibrationResult {
+    #[serde(default)]
+    pub format_version: u32,
+    /// name of the eye position this calibration was made for, when several were made from
+    /// the same capture
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eye_name: Option<String>,
+    /// vertical field of view of the virtual camera in degrees
+    pub fov: f32,
+    #[serde(with = "glm_serde::vec3")]
+    pub eye: glm::Vec3,
+    #[serde(with = "glm_serde::vec3")]
+    pub look_at: glm::Vec3,
+    #[serde(with = "glm_serde::vec3")]
+    pub up: glm::Vec3,
+    /// when present the renderer should build its projection from these rather than fov, the
+    /// warp was calculated with that frustum
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub projector_optics: Option<ProjectorOptics>,
+    /// the virtual camera's view matrix, scene to eye space
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub view_matrix: Option<[f32; 16]>,
+    /// the projection the warp was calculated with, eye to clip space, including the
+    /// projector's rotation and the output conventions' units and flip. Each point's warp is
+    /// its normalized device x and y (of projection * view * point) mapped from -1..1 to 0..1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub projection_matrix: Option<[f32; 16]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matrix_layout: Option<MatrixLayout>,
+    #[serde(rename = "warpResX")]
+    pub warp_res_x: i32,
+    #[serde(rename = "warpResY")]
+    pub warp_res_y: i32,
+    /// normalized screen position for each grid corner, row by row
+    #[serde(with = "glm_serde::vec2_list")]
+    pub warp: Vec<glm::Vec2>,
+    /// scene space position for each grid corner, parallel to `warp`
+    #[serde(with = "glm_serde::vec3_list")]
+    pub scene: Vec<glm::Vec3>,
+    /// false for grid corners no camera saw, whose warp and scene entries were filled in from
+    /// their neighbours. Absent when every corner was seen.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid: Option<Vec<bool>>,
+    /// how far each grid corner can be trusted, 0-1, parallel to `warp`. See `confidence`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<Vec<f32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Meta>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diagnostics: Option<Diagnostics>,
+}
+
+impl CalibrationResult {
+    pub fn from_json(json: &str) -> serde_json::Result<CalibrationResult> {
+        serde_json::from_str(json)
+    }
+
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// How viewMatrix and projectionMatrix are laid out
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MatrixLayout {
+    pub order: MatrixOrder,
+    pub depth_range: DepthRange,
+}
+
+impl Default for MatrixLayout {
+    fn default() -> MatrixLayout {
+        MatrixLayout {order: MatrixOrder::ColumnMajor, depth_range: DepthRange::MinusOneToOne}
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum MatrixOrder {
+    /// the 16 values are column by column, column vectors: clip = projection * view * point
+    ColumnMajor,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum DepthRange {
+    /// OpenGL clip space, normalized depth runs -1 (near) to 1 (far)
+    MinusOneToOne,
+}
+
+/// How the warp is written out. The defaults are the original format: normalized
+/// coordinates with the origin at the bottom left, row by row.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct OutputConventions {
+    /// put the origin at the top left
+    pub flip_y: bool,
+    pub units: WarpUnits,
+    pub order: WarpOrder,
+    /// scale and axes of `eye`, `lookAt`, `up` and `scene`
+    pub transform: OutputTransform,
+}
+
+/// Conversion of the scene space values from the system the calibration was computed in
+/// (the inputs' units, y up, right handed) to the one the renderer uses. meta keeps the
+/// original values.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct OutputTransform {
+    /// uniform scale applied to points, e.g. 0.001 for millimeters to meters
+    pub scale: f32,
+    pub axes: AxisConvention,
+}
+
+impl Default for OutputTransform {
+    fn default() -> OutputTransform {
+        OutputTransform {scale: 1., axes: AxisConvention::YUpRightHanded}
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum AxisConvention {
+    YUpRightHanded,
+    /// x stays, y becomes z and z becomes -y
+    ZUpRightHanded,
+    /// z is negated
+    YUpLeftHanded,
+}
+
+impl Default for AxisConvention {
+    fn default() -> AxisConvention {
+        AxisConvention::YUpRightHanded
+    }
+}
+
+impl OutputTransform {
+    /// Convert a point, scaling it
+    pub fn point(&self, p: glm::Vec3) -> glm::Vec3 {
+        self.direction(p) * self.scale
+    }
+
+    /// Convert a direction, which isn't scaled
+    pub fn direction(&self, d: glm::Vec3) -> glm::Vec3 {
+        match self.axes {
+            AxisConvention::YUpRightHanded => d,
+            AxisConvention::ZUpRightHanded => glm::vec3(d.x, -d.z, d.y),
+            AxisConvention::YUpLeftHanded => glm::vec3(d.x, d.y, -d.z),
+        }
+    }
+
+    /// Convert a point from the output system back again
+    pub fn inverse_point(&self, p: glm::Vec3) -> glm::Vec3 {
+        self.inverse_direction(p / self.scale)
+    }
+
+    pub fn inverse_direction(&self, d: glm::Vec3) -> glm::Vec3 {
+        match self.axes {
+            AxisConvention::YUpRightHanded => d,
+            AxisConvention::ZUpRightHanded => glm::vec3(d.x, d.z, -d.y),
+            AxisConvention::YUpLeftHanded => glm::vec3(d.x, d.y, -d.z),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum WarpUnits {
+    /// 0-1 across the projector image
+    Normalized,
+    /// projector pixels at its native resolution
+    Pixels,
+}
+
+impl Default for WarpUnits {
+    fn default() -> WarpUnits {
+        WarpUnits::Normalized
+    }
+}
+
+/// Order of the corners in `warp` and `scene`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum WarpOrder {
+    /// row by row, like opencv
+    RowMajor,
+    /// column by column
+    ColumnMajor,
+}
+
+impl Default for WarpOrder {
+    fn default() -> WarpOrder {
+        WarpOrder::RowMajor
+    }
+}
+
+impl OutputConventions {
+    /// The warp conversion as the affine map m * uv + t, see `math::remap_warp`
+    fn warp_transform(&self, projector_res: Resolution) -> ([[f32; 2]; 2], [f32; 2]) {
+        let (w, h) = match self.units {
+            WarpUnits::Normalized => (1., 1.),
+            WarpUnits::Pixels => (projector_res.width as f32, projector_res.height as f32),
+        };
+        if self.flip_y {
+            ([[w, 0.], [0., -h]], [0., h])
+        } else {
+            ([[w, 0.], [0., h]], [0., 0.])
+        }
+    }
+
+    /// A copy of result with the warp converted to these conventions and them recorded in meta
+    pub fn apply(&self, result: &CalibrationResult, projector_res: Resolution) -> CalibrationResult {
+        let mut out = result.clone();
+        let (w, h) = match self.units {
+            WarpUnits::Normalized => (1., 1.),
+            WarpUnits::Pixels => (projector_res.width as f32, projector_res.height as f32),
+        };
+        out.warp = result.warp.iter().map(|uv| {
+            let y = if self.flip_y { 1. - uv.y } else { uv.y };
+            glm::vec2(uv.x * w, y * h)
+        }).collect();
+
+        // look_at is the point the eye looks at, not a direction
+        let transform = self.transform;
+        out.eye = transform.point(result.eye);
+        out.look_at = transform.point(result.look_at);
+        out.up = transform.direction(result.up);
+        out.scene = result.scene.iter().map(|p| transform.point(*p)).collect();
+        if let Some(view) = &result.view_matrix {
+            // the view must take output space points to the same eye space points
+            let inverse = glm::Matrix4::new(
+                transform.inverse_point(glm::vec3(1., 0., 0.)).extend(0.),
+                transform.inverse_point(glm::vec3(0., 1., 0.)).extend(0.),
+                transform.inverse_point(glm::vec3(0., 0., 1.)).extend(0.),
+                glm::vec4(0., 0., 0., 1.)
+            );
+            out.view_matrix = Some(math::mat4_values(&(math::mat4_from_values(view) * inverse)));
+        }
+        if let Some(projection) = &result.projection_matrix {
+            // and the projection must give the converted warp
+            let (m, t) = self.warp_transform(projector_res);
+            out.projection_matrix = Some(math::mat4_values(&math::remap_warp(&math::mat4_from_values(projection), m, t)));
+        }
+
+        if self.order == WarpOrder::ColumnMajor {
+            let (cols, rows) = (result.warp_res_x as usize, result.warp_res_y as usize);
+            let transpose = |i: usize| (i % rows) * cols + i / rows;
+            let warp = out.warp.clone();
+            out.warp = (0..warp.len()).map(|i| warp[transpose(i)]).collect();
+            let scene = out.scene.clone();
+            out.scene = (0..scene.len()).map(|i| scene[transpose(i)]).collect();
+            if let Some(valid) = &result.valid {
+                out.valid = Some((0..valid.len()).map(|i| valid[transpose(i)]).collect());
+            }
+            if let Some(confidence) = &result.confidence {
+                out.confidence = Some((0..confidence.len()).map(|i| confidence[transpose(i)]).collect());
+            }
+        }
+
+        if let Some(meta) = out.meta.as_mut() {
+            meta.output_conventions = *self;
+        }
+        out
+    }
+
+    /// The inverse of `apply`, a copy of a result written with these conventions back in the
+    /// native ones
+    pub fn revert(&self, result: &CalibrationResult, projector_res: Resolution) -> CalibrationResult {
+        let mut out = result.clone();
+        if self.order == WarpOrder::ColumnMajor {
+            let (cols, rows) = (result.warp_res_x as usize, result.warp_res_y as usize);
+            let stored_index = |i: usize| (i % cols) * rows + i / cols;
+            out.warp = (0..result.warp.len()).map(|i| result.warp[stored_index(i)]).collect();
+            out.scene = (0..result.scene.len()).map(|i| result.scene[stored_index(i)]).collect();
+            if let Some(valid) = &result.valid {
+                out.valid = Some((0..valid.len()).map(|i| valid[stored_index(i)]).collect());
+            }
+            if let Some(confidence) = &result.confidence {
+                out.confidence = Some((0..confidence.len()).map(|i| confidence[stored_index(i)]).collect());
+            }
+        }
+
+        let (w, h) = match self.units {
+            WarpUnits::Normalized => (1., 1.),
+            WarpUnits::Pixels => (projector_res.width as f32, projector_res.height as f32),
+        };
+        out.warp = out.warp.iter().map(|uv| {
+            let y = uv.y / h;
+            glm::vec2(uv.x / w, if self.flip_y { 1. - y } else { y })
+        }).collect();
+
+        let transform = self.transform;
+        out.eye = transform.inverse_point(result.eye);
+        out.look_at = transform.inverse_point(result.look_at);
+        out.up = transform.inverse_direction(result.up);
+        out.scene = out.scene.iter().map(|p| transform.inverse_point(*p)).collect();
+        if let Some(view) = &result.view_matrix {
+            let forward = glm::Matrix4::new(
+                transform.point(glm::vec3(1., 0., 0.)).extend(0.),
+                transform.point(glm::vec3(0., 1., 0.)).extend(0.),
+                transform.point(glm::vec3(0., 0., 1.)).extend(0.),
+                glm::vec4(0., 0., 0., 1.)
+            );
+            out.view_matrix = Some(math::mat4_values(&(math::mat4_from_values(view) * forward)));
+        }
+        if let Some(projection) = &result.projection_matrix {
+            let (m, t) = self.warp_transform(projector_res);
+            let (m, t) = math::invert_affine(m, t);
+            out.projection_matrix = Some(math::mat4_values(&math::remap_warp(&math::mat4_from_values(projection), m, t)));
+        }
+
+        if let Some(meta) = out.meta.as_mut() {
+            meta.output_conventions = OutputConventions::default();
+        }
+        out
+    }
+}
+
+/// One JSON document holding per-eye calibrations keyed by eye name
+pub fn eye_calibrations_json(results: &[CalibrationResult]) -> String {
+    let mut map = serde_json::Map::new();
+    for result in results {
+        let name = result.eye_name.clone().unwrap_or_default();
+        map.insert(name, serde_json::to_value(result).unwrap());
+    }
+    serde_json::to_string_pretty(&serde_json::Value::Object(map)).unwrap()
+}
+
+/// Details about the run that are useful when a calibration looks wrong
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Diagnostics {
+    pub detected_corners: usize,
+    pub expected_corners: usize,
+    /// the preprocessing the chessboard was found with, can be locked in for later runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detection_variant: Option<DetectionVariant>,
+    /// the corners were detected in reverse order and put back by the orientation check
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orientation_flipped: Option<bool>,
+    /// how much of the camera frame and projector raster the detected chessboard covers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coverage: Option<Coverage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub multi_camera: Option<MultiCameraDiagnostics>,
+    /// the surface parameters fitted to the captured data, when asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub surface_refinement: Option<SurfaceRefinement>,
+    /// seconds spent in each stage, when timings were recorded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+}
+
+/// How the cameras of a multi-camera run contributed to the merged grid
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct MultiCameraDiagnostics {
+    pub cameras: Vec<CameraContribution>,
+    /// indices into `cameras` of the cameras that saw each grid corner, row by row
+    pub contributions: Vec<Vec<usize>>,
+    /// distance between each camera's scene point and the merged point, over every corner
+    /// seen by more than one camera. In scene units.
+    pub rms_disagreement: f32,
+    pub max_disagreement: f32,
+    /// corners no camera saw
+    pub missing_corners: usize,
+}
+
+/// One camera of a multi-camera run
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CameraContribution {
+    pub calibration_path: String,
+    /// first inner corner column and row, and its size in corners
+    pub region: [i32; 4],
+    pub detected_corners: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detection_variant: Option<DetectionVariant>,
+    /// the chessboard was detected rotated 180° and its corners were reversed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orientation_flipped: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coverage: Option<Coverage>,
+}
+
+/// Record of how a calibration was produced, emitted as `meta`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Meta {
+    pub crate_version: String,
+    /// seconds since the unix epoch
+    pub timestamp: u64,
+    pub surface: SurfaceType,
+    pub physical_camera: PhysicalCameraMeta,
+    /// absent for simulated runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera_calibration: Option<CalibrationFileMeta>,
+    /// the camera intrinsics that were used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera_intrinsics: Option<IntrinsicsMeta>,
+    /// size of the output grid, as in warpResX/warpResY
+    pub warp_resolution: Resolution,
+    /// inner corners of the detected chessboard. Where this differs from warpResolution the
+    /// warp was interpolated from it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detection_grid: Option<GridSpec>,
+    /// native, the warp is in this image space
+    pub projector_resolution: Resolution,
+    #[serde(default)]
+    pub projector_orientation: ProjectorOrientation,
+    /// the part of the upright raster the chessboard was shown in, when it didn't fill it.
+    /// The warp still covers the whole raster.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern_placement: Option<PatternPlacement>,
+    /// the tiles the chessboard was captured in, when it wasn't captured whole
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tiling: Option<Tiling>,
+    /// the corners were located with structured light rather than a chessboard photo
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub structured_light: bool,
+    pub camera_source: CameraSourceMeta,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eye_position: Option<EyePositionMeta>,
+    /// how `warp` is written, files from before this was recorded use the defaults
+    #[serde(default)]
+    pub output_conventions: OutputConventions,
+    /// near and far distances of the virtual camera's projection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clip_planes: Option<[f32; 2]>,
+    /// how the virtual camera's look_at was chosen, files from before this was recorded
+    /// used `LookAtMethod::ImageCenter`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub look_at_method: Option<LookAtMethod>,
+}
+
+/// The eye position and where it came from
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EyePositionMeta {
+    pub source: EyeSourceMeta,
+    /// in scene space, after any tracker transform
+    pub position: [f32; 3],
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum EyeSourceMeta {
+    Fixed,
+    #[serde(rename_all = "camelCase")]
+    HttpJson {url: String, json_pointer: String},
+    #[serde(rename_all = "camelCase")]
+    File {path: String, json_pointer: String},
+}
+
+/// Physical camera pose used to map photo points onto the surface
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PhysicalCameraMeta {
+    pub position: [f32; 3],
+    pub look_at: [f32; 3],
+    pub up: [f32; 3],
+}
+
+/// The physical camera's intrinsics, for people and tooling checking a run
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IntrinsicsMeta {
+    /// fx, fy in pixels
+    pub focal_length: [f64; 2],
+    /// cx, cy in pixels
+    pub principal_point: [f64; 2],
+    pub image_width: i32,
+    pub image_height: i32,
+    /// vertical field of view in degrees
+    pub fov: f32,
+    /// opencv's model for the number of coefficients: radialTangential (k1 k2 p1 p2 [k3]),
+    /// rational, thinPrism or tilted
+    pub distortion_model: String,
+    pub distortion_coefficients: Vec<f64>,
+}
+
+impl IntrinsicsMeta {
+    /// A distortion free camera with the principal point at the image center
+    pub fn ideal(intrinsics: &CameraIntrinsics) -> IntrinsicsMeta {
+        let (width, height) = (intrinsics.image_width as f64, intrinsics.image_height as f64);
+        let f = height / (2. * (intrinsics.fov as f64 / 2.).to_radians().tan());
+        IntrinsicsMeta {
+            focal_length: [f, f],
+            principal_point: [width / 2., height / 2.],
+            image_width: intrinsics.image_width,
+            image_height: intrinsics.image_height,
+            fov: intrinsics.fov,
+            distortion_model: "radialTangential".to_string(),
+            distortion_coefficients: vec![0.; 5],
+        }
+    }
+}
+
+/// Identity of the camera calibration XML file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CalibrationFileMeta {
+    pub path: String,
+    /// FNV-1a 64 bit hash of the file contents as hex
+    pub hash: String,
+}
+
+/// Where the camera photos came from
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum CameraSourceMeta {
+    Tethered,
+    RemoteHttp {url: String},
+    ImageFile {path: String},
+    Simulated,
+    /// recomputed from a saved session directory
+    Session {path: String},
+}
+
+impl Meta {
+    pub fn new(
+        surface: SurfaceType,
+        physical_camera: PhysicalCameraMeta,
+        camera_calibration: Option<CalibrationFileMeta>,
+        detection_grid: GridSpec,
+        projector_resolution: Resolution,
+        camera_source: CameraSourceMeta
+    ) -> Meta {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Meta {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            timestamp: timestamp,
+            surface: surface,
+            physical_camera: physical_camera,
+            camera_calibration: camera_calibration,
+            camera_intrinsics: None,
+            warp_resolution: Resolution {width: detection_grid.cols, height: detection_grid.rows},
+            detection_grid: Some(detection_grid),
+            projector_resolution: projector_resolution,
+            projector_orientation: ProjectorOrientation::Landscape,
+            pattern_placement: None,
+            tiling: None,
+            structured_light: false,
+            camera_source: camera_source,
+            eye_position: None,
+            output_conventions: OutputConventions::default(),
+            clip_planes: None,
+            look_at_method: None,
+        }
+    }
+}
+
+/// FNV-1a hash, stable across platforms and compiler versions unlike std's DefaultHasher
+pub fn fnv1a_hex(bytes: &[u8]) -> String {
+    let mut hash = 0xcbf29ce484222325_u64;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// serde support for the glm vector types, which are written as plain arrays
+pub(crate) mod glm_serde {
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+    pub mod vec3 {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(v: &glm::Vec3, s: S) -> Result<S::Ok, S::Error> {
+            [v.x, v.y, v.z].serialize(s)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<glm::Vec3, D::Error> {
+            let a = <[f32; 3]>::deserialize(d)?;
+            Ok(glm::vec3(a[0], a[1], a[2]))
+        }
+    }
+
+    pub mod vec2_list {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(v: &Vec<glm::Vec2>, s: S) -> Result<S::Ok, S::Error> {
+            let arrays: Vec<[f32; 2]> = v.iter().map(|p| [p.x, p.y]).collect();
+            arrays.serialize(s)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<glm::Vec2>, D::Error> {
+            let arrays = Vec::<[f32; 2]>::deserialize(d)?;
+            Ok(arrays.iter().map(|a| glm::vec2(a[0], a[1])).collect())
+        }
+    }
+
+    pub mod vec3_list {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(v: &Vec<glm::Vec3>, s: S) -> Result<S::Ok, S::Error> {
+            let arrays: Vec<[f32; 3]> = v.iter().map(|p| [p.x, p.y, p.z]).collect();
+            arrays.serialize(s)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<glm::Vec3>, D::Error> {
+            let arrays = Vec::<[f32; 3]>::deserialize(d)?;
+            Ok(arrays.iter().map(|a| glm::vec3(a[0], a[1], a[2])).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AXES: [AxisConvention; 3] = [AxisConvention::YUpRightHanded, AxisConvention::ZUpRightHanded, AxisConvention::YUpLeftHanded];
+
+    fn close(a: glm::Vec3, b: glm::Vec3) -> bool {
+        glm::length(a - b) < 1e-5
+    }
+
+    #[test]
+    fn output_transform_round_trip() {
+        let points = [glm::vec3(1., 2., 3.), glm::vec3(-0.5, 0., 4.25), glm::vec3(0., -7., -1.)];
+        for axes in AXES.iter() {
+            for scale in [1., 0.001, 2.54].iter() {
+                let transform = OutputTransform {scale: *scale, axes: *axes};
+                for p in points.iter() {
+                    assert!(close(transform.inverse_point(transform.point(*p)), *p), "{:?} point {:?}", transform, p);
+                    assert!(close(transform.point(transform.inverse_point(*p)), *p), "{:?} point {:?}", transform, p);
+                    assert!(close(transform.inverse_direction(transform.direction(*p)), *p), "{:?} direction {:?}", transform, p);
+                    assert!(close(transform.direction(*p) * *scale, transform.point(*p)), "{:?} scales {:?}", transform, p);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn output_axes() {
+        let z_up = OutputTransform {scale: 1., axes: AxisConvention::ZUpRightHanded};
+        assert!(close(z_up.direction(glm::vec3(0., 1., 0.)), glm::vec3(0., 0., 1.)));
+        // still right handed, x cross y is z
+        let (x, y) = (z_up.direction(glm::vec3(1., 0., 0.)), z_up.direction(glm::vec3(0., 1., 0.)));
+        assert!(close(glm::cross(x, y), z_up.direction(glm::vec3(0., 0., 1.))));
+
+        let left = OutputTransform {scale: 1., axes: AxisConvention::YUpLeftHanded};
+        let (x, y) = (left.direction(glm::vec3(1., 0., 0.)), left.direction(glm::vec3(0., 1., 0.)));
+        assert!(close(glm::cross(x, y), -left.direction(glm::vec3(0., 0., 1.))));
+    }
+
+    fn calibration() -> CalibrationResult {
+        let grid = GridSpec {cols: 3, rows: 2};
+        let camera = PhysicalCameraMeta {position: [0., 0., 0.], look_at: [0., 1., 0.], up: [0., 0., 1.]};
+        CalibrationResult {
+            format_version: CALIBRATION_FORMAT_VERSION,
+            eye_name: Some("front row".to_string()),
+            fov: 72.5,
+            eye: glm::vec3(0., 0., 0.5),
+            look_at: glm::vec3(0., 5., 2.),
+            up: glm::vec3(0., 0., 1.),
+            projector_optics: None,
+            view_matrix: Some([1., 0., 0., 0., 0., 1., 0., 0., 0., 0., 1., 0., 0., 0., 0., 1.]),
+            projection_matrix: Some([1., 0., 0., 0., 0., 1., 0., 0., 0., 0., 1., 0., 0., 0., 0., 1.]),
+            matrix_layout: Some(MatrixLayout::default()),
+            warp_res_x: grid.cols,
+            warp_res_y: grid.rows,
+            warp: vec![glm::vec2(0.1, 0.2), glm::vec2(0.5, 0.2), glm::vec2(0.9, 0.25), glm::vec2(0.1, 0.8), glm::vec2(0.5, 0.8), glm::vec2(0.875, 0.75)],
+            scene: (0..6).map(|i| glm::vec3(i as f32 - 2.5, 4., i as f32 * 0.25)).collect(),
+            valid: Some(vec![true, true, false, true, true, true]),
+            confidence: Some(vec![1., 0.5, 0., 0.75, 1., 0.25]),
+            meta: Some(Meta::new(SurfaceType::HemisphericalDome {radius: 5.}, camera, None, grid, Resolution {width: 1920, height: 1080}, CameraSourceMeta::Simulated)),
+            diagnostics: Some(Diagnostics {
+                detected_corners: 5,
+                expected_corners: 6,
+                detection_variant: None,
+                orientation_flipped: Some(false),
+                coverage: None,
+                multi_camera: None,
+                surface_refinement: None,
+                timings: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn calibration_json_round_trip() {
+        let json = calibration().to_json_string();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["formatVersion"], serde_json::json!(CALIBRATION_FORMAT_VERSION));
+        assert_eq!(value["warpResX"], serde_json::json!(3));
+        assert_eq!(value["lookAt"], serde_json::json!([0., 5., 2.]));
+        assert_eq!(value["warp"][2], serde_json::json!([0.9, 0.25]));
+
+        let read = CalibrationResult::from_json(&json).unwrap();
+        assert_eq!(read.format_version, CALIBRATION_FORMAT_VERSION);
+        assert_eq!(read.eye_name.as_deref(), Some("front row"));
+        assert_eq!(read.warp, calibration().warp);
+        assert_eq!(read.valid, calibration().valid);
+        assert_eq!(read.to_json_string(), json);
+    }
+
+    /// Files from before formatVersion are version 0, and keys added later are ignored
+    #[test]
+    fn original_format_still_loads() {
+        let json = r#"{
+            "fov": 90.0,
+            "eye": [0.0, 0.0, 0.0],
+            "lookAt": [0.0, 1.0, 0.0],
+            "up": [0.0, 0.0, 1.0],
+            "warpResX": 2,
+            "warpResY": 2,
+            "warp": [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0], [1.0, 1.0]],
+            "scene": [[-1.0, 1.0, 0.0], [1.0, 1.0, 0.0], [-1.0, 1.0, 1.0], [1.0, 1.0, 1.0]],
+            "someLaterKey": {"nested": true}
+        }"#;
+        let read = CalibrationResult::from_json(json).unwrap();
+        assert_eq!(read.format_version, 0);
+        assert_eq!((read.warp_res_x, read.warp_res_y, read.scene.len()), (2, 2, 4));
+        assert!(read.meta.is_none() && read.valid.is_none() && read.view_matrix.is_none());
+        // and it isn't written back with keys it didn't have
+        let written: serde_json::Value = serde_json::from_str(&read.to_json_string()).unwrap();
+        assert!(written.get("meta").is_none() && written.get("someLaterKey").is_none());
+    }
+}
@@ -1,24 +1,95 @@
 
 use opencv::prelude::*;
-use opencv::highgui;
+use opencv::{core, highgui, imgcodecs};
 use log::{warn, info, error, debug};
+use serde::{Serialize, Deserialize};
 use std::fs::{File};
 use std::io::{Read, ErrorKind};
 use tempfile::NamedTempFile;
 use std::{thread::sleep, process::{exit, Command}};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use super::output::CameraSourceMeta;
 
+#[derive(Clone)]
 pub enum CameraType {
     TetheredCamera,
-    RemoteHttpCamera{url: String},
-    SingleImageFile{path: String}
+    RemoteHttpCamera{url: String, warm_up: WarmUp},
+    SingleImageFile{path: String},
+    /// encoded photos handed over by the embedding code, e.g. fetched by `async_api`. meta
+    /// describes the camera they really come from.
+    Supplied{meta: CameraSourceMeta, capture: Arc<dyn Fn() -> Vec<u8> + Send + Sync>}
+}
+
+/// Making sure a remote camera's photo shows the pattern that was just put up. Some IP
+/// cameras return a cached frame for the first request after the scene changes. Applied
+/// before every capture, the default doesn't wait at all.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct WarmUp {
+    /// wait this long after the pattern is shown before the first request
+    pub settle_seconds: f32,
+    /// frames fetched and thrown away before the one that's used
+    pub discard_frames: u32,
+    /// keep fetching until two frames agree
+    pub stability: Option<StabilityCheck>,
+}
+
+/// Two frames fetched interval_seconds apart with a mean absolute greyscale difference
+/// below threshold (of 255) mean the scene has stopped changing
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StabilityCheck {
+    pub interval_seconds: f32,
+    pub threshold: f64,
+    /// use the latest frame anyway after this long
+    pub timeout_seconds: f32,
+}
+
+impl CameraType {
+    /// Camera from a command line style argument: a http(s) URL, an image file, or None for
+    /// the tethered camera
+    pub fn from_arg(camera: Option<&str>) -> CameraType {
+        match camera {
+            Some(url_or_path) => {
+                if url_or_path.starts_with("http") {
+                    CameraType::RemoteHttpCamera {url: url_or_path.to_string(), warm_up: WarmUp::default()}
+                } else {
+                    // TODO check early that file exists
+                    CameraType::SingleImageFile {path: url_or_path.to_string()}
+                }
+            }
+            None => CameraType::TetheredCamera
+        }
+    }
+
+    /// The same camera, waiting for a remote camera to catch up with the pattern as warm_up
+    /// says before each photo
+    pub fn with_warm_up(self, warm_up: &WarmUp) -> CameraType {
+        match self {
+            CameraType::RemoteHttpCamera {url, ..} => CameraType::RemoteHttpCamera {url: url, warm_up: warm_up.clone()},
+            other => other
+        }
+    }
+
+    /// Description of the camera source for the output metadata
+    pub fn meta(&self) -> CameraSourceMeta {
+        match self {
+            CameraType::TetheredCamera => CameraSourceMeta::Tethered,
+            CameraType::RemoteHttpCamera{url, ..} => CameraSourceMeta::RemoteHttp {url: url.clone()},
+            CameraType::SingleImageFile{path} => CameraSourceMeta::ImageFile {path: path.clone()},
+            CameraType::Supplied{meta, ..} => meta.clone()
+        }
+    }
 }
 
 /// Acquire a photo
 pub fn capture_photo(camera_type: CameraType) -> Mat {
     match camera_type {
         CameraType::TetheredCamera => take_photo(),
-        CameraType::RemoteHttpCamera{url} => fetch_photo_from_url(&url),
-        CameraType::SingleImageFile{path} => load_from_file(&path)
+        CameraType::RemoteHttpCamera{url, warm_up} => fetch_settled_photo(&url, &warm_up),
+        CameraType::SingleImageFile{path} => load_from_file(&path),
+        CameraType::Supplied{capture, ..} => Mat::from_slice(&capture()).unwrap()
     }
 }
 
@@ -31,6 +102,53 @@ fn load_from_file(path: &str) -> Mat {
     Mat::from_slice(buffer.as_slice()).unwrap()
 }
 
+/// Fetch a photo from a remote camera once it has caught up with the scene
+fn fetch_settled_photo(url: &str, warm_up: &WarmUp) -> Mat {
+    if *warm_up == WarmUp::default() {
+        return fetch_photo_from_url(url);
+    }
+    let started = Instant::now();
+    sleep(Duration::from_secs_f32(warm_up.settle_seconds));
+    for _ in 0..warm_up.discard_frames {
+        fetch_photo_from_url(url);
+    }
+    let mut photo = fetch_photo_from_url(url);
+    let mut frames = warm_up.discard_frames + 1;
+    if let Some(check) = warm_up.stability {
+        loop {
+            sleep(Duration::from_secs_f32(check.interval_seconds));
+            let next = fetch_photo_from_url(url);
+            frames += 1;
+            let difference = frame_difference(&photo, &next);
+            photo = next;
+            match difference {
+                Some(difference) if difference < check.threshold => break,
+                Some(difference) => debug!("remote camera frames differ by {:.1}, waiting", difference),
+                None => debug!("remote camera frames couldn't be compared, waiting"),
+            }
+            if started.elapsed().as_secs_f32() > check.timeout_seconds {
+                warn!("remote camera frames still changing after {:.1}s, using the latest", started.elapsed().as_secs_f32());
+                break;
+            }
+        }
+    }
+    info!("remote camera settled after {:.2}s and {} frames", started.elapsed().as_secs_f32(), frames);
+    photo
+}
+
+/// Mean absolute difference of two encoded photos in greyscale, of 255. None when they
+/// can't be decoded or have different sizes.
+pub(crate) fn frame_difference(a: &Mat, b: &Mat) -> Option<f64> {
+    let decode = |data: &Mat| imgcodecs::imdecode(data, imgcodecs::IMREAD_GRAYSCALE).ok().filter(|image| !image.empty().unwrap_or(true));
+    let (a, b) = (decode(a)?, decode(b)?);
+    if a.size().ok()? != b.size().ok()? {
+        return None;
+    }
+    let mut difference = Mat::default().ok()?;
+    core::absdiff(&a, &b, &mut difference).ok()?;
+    Some(core::mean(&difference, &Mat::default().ok()?).ok()?[0])
+}
+
 fn fetch_photo_from_url(url: &str) -> Mat {
     info!("fetching camera photo from {}", &url);
     let client = reqwest::blocking::Client::new();
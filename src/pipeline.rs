@@ -0,0 +1,1536 @@
+//! The individual stages of a calibration run. `produce_calibration` is a composition of
+//! these, applications with their own capture or detection can use them directly.
+
+#[cfg(feature = "opencv")]
+use opencv::{prelude::*, types::*, core::*, imgcodecs, imgproc::*, calib3d::*};
+use glm::*;
+use glm::ext::*;
+use log::{info, warn, debug};
+use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
+use super::{Resolution, GridSpec, Error, CalibrationResult, CALIBRATION_FORMAT_VERSION};
+use super::{confidence, grid_order, math, output, surfaces};
+use super::surfaces::CameraModel;
+#[cfg(feature = "opencv")]
+use super::{PhysicalCamera, PatternDisplay, camera_calibration, images, photo};
+use super::progress::{CalibrationEvent, ProgressSink};
+use super::projector::{ProjectorOrientation, ProjectorOptics, PatternPlacement};
+#[cfg(feature = "opencv")]
+use super::detection::{DetectionOptions, DetectionVariant, DetectionRoi};
+use super::timings::{self, Stage, Timings};
+#[cfg(feature = "opencv")]
+use super::coverage::{self, Coverage};
+
+/// The camera the content is rendered from. look_at and fov are calculated by the pipeline.
+pub struct VirtualCamera {
+    pub position: glm::Vec3,
+    /// world up for the rendered content, 0, 1, 0 unless the render is rolled
+    pub up_dir: glm::Vec3,
+    pub look_at: Option<glm::Vec3>, // this is calculated during calibration
+    pub fov: Option<f32>, // this is calculated during calibration
+    /// when set the content is rendered with the projector's frustum rather than fov
+    pub optics: Option<ProjectorOptics>,
+    /// near and far clip distances, derived from the scene during calibration when None
+    pub clip_planes: Option<(f32, f32)>,
+    /// how look_at is chosen from the scene, see `choose_look_at`
+    pub look_at_method: LookAtMethod,
+}
+
+impl VirtualCamera {
+    pub fn new(eye_position: glm::Vec3) -> VirtualCamera {
+        VirtualCamera {
+            position: eye_position,
+            look_at: None,
+            up_dir: vec3(0.0, 1.0, 0.0),
+            fov: None,
+            optics: None,
+            clip_planes: None,
+            look_at_method: LookAtMethod::default(),
+        }
+    }
+}
+
+/// Detected chessboard corners in photo space, row by row
+#[derive(Clone, Debug)]
+pub struct ImagePointGrid {
+    pub cols: i32,
+    pub rows: i32,
+    pub points: Vec<glm::Vec2>,
+    /// false where the corner at the same index wasn't detected and its point is meaningless
+    pub valid: Vec<bool>,
+    /// how far each corner can be trusted, see `confidence`
+    pub confidence: Vec<f32>,
+}
+
+impl ImagePointGrid {
+    /// A grid where every point was detected
+    pub fn new(cols: i32, rows: i32, points: Vec<glm::Vec2>) -> ImagePointGrid {
+        assert_eq!(points.len(), (cols * rows) as usize, "point count doesn't match grid size");
+        let valid = vec![true; points.len()];
+        let confidence = vec![1.; points.len()];
+        ImagePointGrid {cols: cols, rows: rows, points: points, valid: valid, confidence: confidence}
+    }
+
+    pub fn get(&self, col: i32, row: i32) -> Option<glm::Vec2> {
+        let i = (row * self.cols + col) as usize;
+        if self.valid[i] { Some(self.points[i]) } else { None }
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.valid.iter().all(|v| *v)
+    }
+
+    pub fn valid_points(&self) -> impl Iterator<Item = &glm::Vec2> {
+        self.points.iter().zip(self.valid.iter()).filter(|(_, v)| **v).map(|(p, _)| p)
+    }
+
+    /// Reverse the order of the corners, so corner (col, row) becomes (cols - 1 - col, rows - 1 - row)
+    pub fn rotate_180(&mut self) {
+        self.points.reverse();
+        self.valid.reverse();
+        self.confidence.reverse();
+    }
+}
+
+/// A photo of the projected chessboard and the corners found in it
+#[cfg(feature = "opencv")]
+pub struct Capture {
+    /// encoded, exactly as delivered by the camera
+    pub photo: Vec<u8>,
+    pub undistorted: Mat,
+    pub image_points: ImagePointGrid,
+    /// the preprocessing the corners were found with
+    pub detection_variant: DetectionVariant,
+    /// the corners were detected in reverse order and have been put back, see `orient_corners`
+    pub flipped: bool,
+    pub coverage: Coverage,
+}
+
+/// The stages downstream of corner detection: scene coordinates, look_at, fov and UV warp.
+/// warp_grid is the size of the output grid, when it differs from the detected grid or the
+/// chessboard was shown in meta.pattern_placement the scene points are interpolated. Stage
+/// times are added to timings when it's Some.
+pub fn compute_calibration(surface: &surfaces::SurfaceType, camera: &CameraModel, image_points: &ImagePointGrid, virtual_camera: &mut VirtualCamera, warp_grid: GridSpec, projector_res: Resolution, orientation: ProjectorOrientation, meta: output::Meta, progress: &mut dyn ProgressSink, timings: &mut Option<Timings>) -> Result<CalibrationResult, Error> {
+    let detected = GridSpec {cols: image_points.cols, rows: image_points.rows};
+    let placement = meta.pattern_placement;
+    let (scene_coords, look_at) = timings::timed(timings, Stage::Scene, || {
+        let scene_coords = locate_scene_coords(surface, camera, image_points)?;
+        let scene_coords = resample_placed_scene(surface, &scene_coords, detected, warp_grid, placement.as_ref());
+        Ok::<_, Error>((scene_coords, calculate_look_at(surface, image_points, camera)?))
+    })?;
+    let confidence = resample_confidence(&image_points.confidence, detected, warp_grid, placement.as_ref());
+    let mut result = compute_calibration_from_scene(&scene_coords, Some(&confidence), look_at, virtual_camera, warp_grid, projector_res, orientation, meta, progress, timings)?;
+    result.valid = placed_valid(detected, warp_grid, placement.as_ref());
+    result.confidence = Some(confidence);
+    Ok(result)
+}
+
+/// Bilinearly interpolate a grid of scene points to a grid of a different size covering the
+/// same corners. Points on a dome are pushed back out onto the sphere. Returns the points
+/// unchanged when the sizes match.
+pub fn resample_scene(surface: &surfaces::SurfaceType, scene_coords: &Vec<glm::Vec3>, from: GridSpec, to: GridSpec) -> Vec<glm::Vec3> {
+    if from == to {
+        return scene_coords.clone();
+    }
+    let at = |col: i32, row: i32| scene_coords[(row * from.cols + col) as usize];
+    let mut resampled = Vec::with_capacity(to.len());
+    for j in 0..to.rows {
+        for i in 0..to.cols {
+            let (c0, c1, tc) = grid_span(i, to.cols, from.cols);
+            let (r0, r1, tr) = grid_span(j, to.rows, from.rows);
+            let top = at(c0, r0) * (1. - tc) + at(c1, r0) * tc;
+            let bottom = at(c0, r1) * (1. - tc) + at(c1, r1) * tc;
+            let p = top * (1. - tr) + bottom * tr;
+            resampled.push(match surface {
+                surfaces::SurfaceType::HemisphericalDome {radius} => normalize(p) * *radius,
+                _ => p
+            });
+        }
+    }
+    resampled
+}
+
+/// Resample a warp grid the same way as `resample_scene`, bilinearly between the corners
+pub fn resample_warp(uv_coords: &Vec<glm::Vec2>, from: GridSpec, to: GridSpec) -> Vec<glm::Vec2> {
+    if from == to {
+        return uv_coords.clone();
+    }
+    let at = |col: i32, row: i32| uv_coords[(row * from.cols + col) as usize];
+    let mut resampled = Vec::with_capacity(to.len());
+    for j in 0..to.rows {
+        for i in 0..to.cols {
+            let (c0, c1, tc) = grid_span(i, to.cols, from.cols);
+            let (r0, r1, tr) = grid_span(j, to.rows, from.rows);
+            let top = at(c0, r0) * (1. - tc) + at(c1, r0) * tc;
+            let bottom = at(c0, r1) * (1. - tc) + at(c1, r1) * tc;
+            resampled.push(top * (1. - tr) + bottom * tr);
+        }
+    }
+    resampled
+}
+
+/// Validity of each point of a grid resampled with `resample_scene`. A point is only valid
+/// if every corner it was interpolated from was.
+pub fn resample_valid(valid: &Vec<bool>, from: GridSpec, to: GridSpec) -> Vec<bool> {
+    if from == to {
+        return valid.clone();
+    }
+    let at = |col: i32, row: i32| valid[(row * from.cols + col) as usize];
+    let mut resampled = Vec::with_capacity(to.len());
+    for j in 0..to.rows {
+        for i in 0..to.cols {
+            let (c0, c1, _) = grid_span(i, to.cols, from.cols);
+            let (r0, r1, _) = grid_span(j, to.rows, from.rows);
+            resampled.push(at(c0, r0) && at(c1, r0) && at(c0, r1) && at(c1, r1));
+        }
+    }
+    resampled
+}
+
+/// Resample scene points detected on a from-sized chessboard shown in placement onto a
+/// to-sized warp grid covering the raster as the chessboard would without it. Warp corners
+/// beyond the placed chessboard are extrapolated from its edge squares. Without a placement
+/// this is `resample_scene`.
+pub fn resample_placed_scene(surface: &surfaces::SurfaceType, scene_coords: &Vec<glm::Vec3>, from: GridSpec, to: GridSpec, placement: Option<&PatternPlacement>) -> Vec<glm::Vec3> {
+    let placement = match placement {
+        Some(placement) => placement,
+        None => return resample_scene(surface, scene_coords, from, to)
+    };
+    let at = |col: i32, row: i32| scene_coords[(row * from.cols + col) as usize];
+    placed_positions(from, to, placement).iter().map(|p| {
+        let (c0, c1, tc) = placed_span(p.x, from.cols);
+        let (r0, r1, tr) = placed_span(p.y, from.rows);
+        let top = at(c0, r0) * (1. - tc) + at(c1, r0) * tc;
+        let bottom = at(c0, r1) * (1. - tc) + at(c1, r1) * tc;
+        let p = top * (1. - tr) + bottom * tr;
+        match surface {
+            surfaces::SurfaceType::HemisphericalDome {radius} => normalize(p) * *radius,
+            _ => p
+        }
+    }).collect()
+}
+
+/// Validity of each point of a grid resampled with `resample_placed_scene` from a grid with
+/// the given validity. Extrapolated points aren't valid.
+pub fn resample_placed_valid(valid: &Vec<bool>, from: GridSpec, to: GridSpec, placement: Option<&PatternPlacement>) -> Vec<bool> {
+    let placement = match placement {
+        Some(placement) => placement,
+        None => return resample_valid(valid, from, to)
+    };
+    let at = |col: i32, row: i32| valid[(row * from.cols + col) as usize];
+    let inside = |x: f32, n: i32| x >= -1e-4 && x <= (n - 1) as f32 + 1e-4;
+    placed_positions(from, to, placement).iter().map(|p| {
+        let (c0, c1, _) = placed_span(p.x, from.cols);
+        let (r0, r1, _) = placed_span(p.y, from.rows);
+        inside(p.x, from.cols) && inside(p.y, from.rows) && at(c0, r0) && at(c1, r0) && at(c0, r1) && at(c1, r1)
+    }).collect()
+}
+
+/// Confidence of each point of a grid resampled with `resample_placed_scene`, interpolated
+/// like the points. Points that don't fall on a detected corner are discounted by
+/// `confidence::INTERPOLATED`, extrapolated ones by `confidence::EXTRAPOLATED`.
+pub fn resample_confidence(confidence: &Vec<f32>, from: GridSpec, to: GridSpec, placement: Option<&PatternPlacement>) -> Vec<f32> {
+    if from == to && placement.is_none() {
+        return confidence.clone();
+    }
+    let positions = match placement {
+        Some(placement) => placed_positions(from, to, placement),
+        None => {
+            let axis = |i: i32, to: i32, from: i32| if to < 2 { 0. } else { i as f32 * (from - 1) as f32 / (to - 1) as f32 };
+            let mut positions = Vec::with_capacity(to.len());
+            for j in 0..to.rows {
+                for i in 0..to.cols {
+                    positions.push(vec2(axis(i, to.cols, from.cols), axis(j, to.rows, from.rows)));
+                }
+            }
+            positions
+        }
+    };
+    let at = |col: i32, row: i32| confidence[(row * from.cols + col) as usize];
+    let on_corner = |x: f32| (x - x.round()).abs() < 1e-3;
+    let inside = |x: f32, n: i32| x >= -1e-4 && x <= (n - 1) as f32 + 1e-4;
+    positions.iter().map(|p| {
+        let (c0, c1, tc) = placed_span(p.x, from.cols);
+        let (r0, r1, tr) = placed_span(p.y, from.rows);
+        let (tc, tr) = (tc.max(0.).min(1.), tr.max(0.).min(1.));
+        let top = at(c0, r0) * (1. - tc) + at(c1, r0) * tc;
+        let bottom = at(c0, r1) * (1. - tc) + at(c1, r1) * tc;
+        let value = top * (1. - tr) + bottom * tr;
+        if !inside(p.x, from.cols) || !inside(p.y, from.rows) {
+            value * confidence::EXTRAPOLATED
+        } else if on_corner(p.x) && on_corner(p.y) {
+            value
+        } else {
+            value * confidence::INTERPOLATED
+        }
+    }).collect()
+}
+
+/// The warp's validity for a complete detected grid, None when nothing was extrapolated
+pub fn placed_valid(from: GridSpec, to: GridSpec, placement: Option<&PatternPlacement>) -> Option<Vec<bool>> {
+    placement?;
+    let valid = resample_placed_valid(&vec![true; from.len()], from, to, placement);
+    if valid.iter().any(|v| !*v) {
+        warn!(
+            "{} warp corners are beyond the placed chessboard and were extrapolated",
+            valid.iter().filter(|v| !**v).count()
+        );
+        Some(valid)
+    } else {
+        None
+    }
+}
+
+/// Where each corner of a to-sized warp grid falls on a from-sized chessboard shown in
+/// placement, in (fractional) corners of the chessboard. The warp grid spans the raster
+/// between the first and last inner corner of a full-raster from-sized chessboard.
+fn placed_positions(from: GridSpec, to: GridSpec, placement: &PatternPlacement) -> Vec<glm::Vec2> {
+    let axis = |i: i32, to: i32, from: i32| {
+        let first = 1. / (from + 1) as f32;
+        let last = from as f32 / (from + 1) as f32;
+        if to < 2 { first } else { first + (last - first) * i as f32 / (to - 1) as f32 }
+    };
+    let mut positions = Vec::with_capacity(to.len());
+    for j in 0..to.rows {
+        for i in 0..to.cols {
+            let on_pattern = placement.from_raster(vec2(axis(i, to.cols, from.cols), axis(j, to.rows, from.rows)));
+            positions.push(vec2(on_pattern.x * (from.cols + 1) as f32 - 1., on_pattern.y * (from.rows + 1) as f32 - 1.));
+        }
+    }
+    positions
+}
+
+/// The corners of a from-sized axis to interpolate position x from and how far between them
+/// it lies, outside 0-1 when x is beyond the axis
+fn placed_span(x: f32, from: i32) -> (i32, i32, f32) {
+    if from < 2 {
+        return (0, 0, 0.);
+    }
+    let lower = (x.floor() as i32).max(0).min(from - 2);
+    (lower, lower + 1, x - lower as f32)
+}
+
+/// The corners of a from-sized axis either side of index i of a to-sized axis and how far
+/// between them it lies
+pub(crate) fn grid_span(i: i32, to: i32, from: i32) -> (i32, i32, f32) {
+    if from < 2 || to < 2 {
+        return (0, 0, 0.);
+    }
+    let x = i as f32 * (from - 1) as f32 / (to - 1) as f32;
+    let lower = (x.floor() as i32).min(from - 2);
+    (lower, lower + 1, x - lower as f32)
+}
+
+/// The stages downstream of scene coordinates, for scene points that didn't come from a
+/// single camera (see `multi_camera`). The fov is calculated for the upright image, the warp
+/// is in the projector's native (rotated) image space.
+pub fn compute_calibration_from_scene(scene_coords: &Vec<glm::Vec3>, confidence: Option<&Vec<f32>>, look_at: glm::Vec3, virtual_camera: &mut VirtualCamera, grid: GridSpec, projector_res: Resolution, orientation: ProjectorOrientation, meta: output::Meta, progress: &mut dyn ProgressSink, timings: &mut Option<Timings>) -> Result<CalibrationResult, Error> {
+    progress.event(CalibrationEvent::SceneComputed {scene: scene_coords.clone()});
+    check_view_basis(virtual_camera.position, look_at, virtual_camera.up_dir)?;
+    let look_at = choose_look_at(scene_coords, confidence, look_at, virtual_camera);
+    check_view_basis(virtual_camera.position, look_at, virtual_camera.up_dir)?;
+    virtual_camera.look_at = Some(look_at);
+    let upright = orientation.effective_resolution(projector_res);
+    let uv_coords = timings::timed(timings, Stage::Uv, || generate_uv_warp_and_fov(&scene_coords, virtual_camera, upright))?;
+    let uv_coords = uv_coords.iter().map(|uv| orientation.to_native_uv(*uv)).collect();
+    progress.event(CalibrationEvent::FovComputed {fov: virtual_camera.fov.unwrap()});
+    Ok(calibration_result(&scene_coords, &uv_coords, virtual_camera, grid, meta))
+}
+
+/// look_at() needs a view direction and an up vector that isn't parallel to it
+fn check_view_basis(eye: glm::Vec3, look_at: glm::Vec3, up: glm::Vec3) -> Result<(), Error> {
+    let direction = look_at - eye;
+    if length(direction) < 1e-6 {
+        return Err(Error::Config(format!("the eye {:?} is at the point it looks at", eye)));
+    }
+    if length(up) < 1e-6 {
+        return Err(Error::Config("the virtual camera up vector is zero".to_string()));
+    }
+    if length(cross(normalize(direction), normalize(up))) < 1e-3 {
+        return Err(Error::Config(format!("the virtual camera up vector {:?} is parallel to its view direction {:?}", up, direction)));
+    }
+    Ok(())
+}
+
+/// Scene space point the virtual camera should look at, the center of the detected chessboard
+pub fn calculate_look_at(surface: &surfaces::SurfaceType, image_points: &ImagePointGrid, camera: &CameraModel) -> Result<glm::Vec3, Error> {
+    // possibly naively, we just look_at the center of the chessboard
+    let mut avg = vec2(0., 0.);
+    let mut count = 0;
+    for p in image_points.valid_points() { avg = avg + *p; count += 1; }
+    if count == 0 {
+        return Err(Error::Detection("no chessboard corners were detected to look at".to_string()));
+    }
+    avg = avg / count as f32;
+    
+    debug!("Projection area center point is {:?}", avg);
+
+    surfaces::camera_to_scene(&surface, camera, avg)
+        .map_err(|err| Error::Geometry(format!("the middle of the chessboard at {:?} isn't on the surface: {}", avg, err)))
+}
+
+/// How the point the virtual camera looks at is chosen
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum LookAtMethod {
+    /// the surface under the middle of the detected corners in the photo, see
+    /// `calculate_look_at`. How it was chosen before the others existed.
+    ImageCenter,
+    /// the mean of the scene points, weighted by corner confidence when weighted
+    SceneMean {weighted: bool},
+    /// the scene mean, then turned until the scene's angular extents are the same either
+    /// side of the view axis. Centers the content and needs the smallest fov.
+    Centered {weighted: bool},
+}
+
+impl Default for LookAtMethod {
+    fn default() -> LookAtMethod {
+        LookAtMethod::Centered {weighted: false}
+    }
+}
+
+impl LookAtMethod {
+    /// "image-center", "scene-mean" or "centered"
+    pub fn parse(input: &str, weighted: bool) -> Result<LookAtMethod, &'static str> {
+        match input {
+            "image-center" => Ok(LookAtMethod::ImageCenter),
+            "scene-mean" => Ok(LookAtMethod::SceneMean {weighted: weighted}),
+            "centered" => Ok(LookAtMethod::Centered {weighted: weighted}),
+            _ => Err("look at must be image-center, scene-mean or centered")
+        }
+    }
+}
+
+/// Iterations turning the view toward the middle of the scene, see `centered_look_at`
+const CENTERING_ITERATIONS: usize = 50;
+/// Centering stops once the extents are this close to symmetric, radians
+const CENTERING_TOLERANCE: f32 = 1e-5;
+
+/// The look_at virtual_camera's look_at_method picks for scene_coords. initial is the
+/// caller's own choice, the center of the detected chessboard for a single camera, and is
+/// kept by `LookAtMethod::ImageCenter`. The candidates are logged with the fov each needs.
+pub fn choose_look_at(scene_coords: &Vec<glm::Vec3>, confidence: Option<&Vec<f32>>, initial: glm::Vec3, virtual_camera: &VirtualCamera) -> glm::Vec3 {
+    let eye = virtual_camera.position;
+    let up = virtual_camera.up_dir;
+    let weights = match virtual_camera.look_at_method {
+        LookAtMethod::SceneMean {weighted: true} | LookAtMethod::Centered {weighted: true} => confidence,
+        _ => None
+    };
+    let mean = scene_mean(scene_coords, weights);
+    let centered = centered_look_at(scene_coords, eye, up, mean);
+    for (name, candidate) in &[("image center", initial), ("scene mean", mean), ("centered", centered)] {
+        let [left, right, bottom, top] = view_extents(scene_coords, eye, *candidate, up);
+        info!(
+            "{} look_at {:?} needs fovY {}, extents {} to {} horizontally and {} to {} vertically",
+            name, candidate, extents_fov(bottom, top), glm::degrees(left), glm::degrees(right), glm::degrees(bottom), glm::degrees(top)
+        );
+    }
+    match virtual_camera.look_at_method {
+        LookAtMethod::ImageCenter => initial,
+        LookAtMethod::SceneMean {..} => mean,
+        LookAtMethod::Centered {..} if is_finite_vec3(centered) => centered,
+        LookAtMethod::Centered {..} => {
+            warn!("couldn't center the view on the scene, looking at its mean");
+            mean
+        }
+    }
+}
+
+/// Mean of the scene points, weighted when there's a weight for each of them
+pub fn scene_mean(scene_coords: &Vec<glm::Vec3>, weights: Option<&Vec<f32>>) -> glm::Vec3 {
+    let weights = weights.filter(|weights| weights.len() == scene_coords.len() && weights.iter().sum::<f32>() > 1e-6);
+    let mut sum = vec3(0., 0., 0.);
+    let mut total = 0.;
+    for (i, p) in scene_coords.iter().enumerate() {
+        let w = weights.map(|weights| weights[i]).unwrap_or(1.);
+        sum = sum + *p * w;
+        total += w;
+    }
+    sum / total
+}
+
+/// Turn the view from eye toward start until the scene's horizontal and vertical angular
+/// extents are symmetric about the view axis. The distance to start is kept.
+pub fn centered_look_at(scene_coords: &Vec<glm::Vec3>, eye: glm::Vec3, up: glm::Vec3, start: glm::Vec3) -> glm::Vec3 {
+    let distance = length(start - eye);
+    let mut direction = normalize(start - eye);
+    for _ in 0..CENTERING_ITERATIONS {
+        let [left, right, bottom, top] = view_extents(scene_coords, eye, eye + direction, up);
+        let (h, v) = ((left + right) / 2., (bottom + top) / 2.);
+        if h.abs() < CENTERING_TOLERANCE && v.abs() < CENTERING_TOLERANCE {
+            break;
+        }
+        // the view's own right and up, as look_at() builds them
+        let side = normalize(cross(direction, up));
+        let view_up = cross(side, direction);
+        direction = normalize(direction + side * h.tan() + view_up * v.tan());
+    }
+    eye + direction * distance
+}
+
+/// Angles of the scene's extremes from the view axis, eye looking at look_at: [left, right,
+/// bottom, top] in radians, left and bottom negative when the scene spans the axis
+pub fn view_extents(scene_coords: &Vec<glm::Vec3>, eye: glm::Vec3, look_at_point: glm::Vec3, up: glm::Vec3) -> [f32; 4] {
+    let trans = look_at(eye, look_at_point, up);
+    let mut extents = [f32::MAX, f32::MIN, f32::MAX, f32::MIN];
+    for p in scene_coords.iter() {
+        let eye_relative = trans * p.extend(1.);
+        // the camera looks down -z
+        let h = eye_relative.x.atan2(-eye_relative.z);
+        let v = eye_relative.y.atan2(-eye_relative.z);
+        extents = [extents[0].min(h), extents[1].max(h), extents[2].min(v), extents[3].max(v)];
+    }
+    extents
+}
+
+/// The fovY `generate_uv_warp_and_fov` would give a scene with these vertical extents
+fn extents_fov(bottom: f32, top: f32) -> f32 {
+    glm::degrees(bottom.abs().max(top.abs())) * 2.001
+}
+
+fn is_finite_vec3(v: glm::Vec3) -> bool {
+    v.x.is_finite() && v.y.is_finite() && v.z.is_finite()
+}
+
+/// Map each detected corner onto the projection surface. The grid must be complete.
+pub fn locate_scene_coords(surface: &surfaces::SurfaceType, camera: &CameraModel, image_points: &ImagePointGrid) -> Result<Vec<glm::Vec3>, Error> {
+    if !image_points.is_complete() {
+        return Err(Error::Detection(format!(
+            "scene coordinates need a complete grid of image points, only {} of {} were detected",
+            image_points.valid_points().count(), image_points.len()
+        )));
+    }
+    let mapper = surfaces::SceneMapper::new(surface, camera);
+
+    // Convert each point in camera space to a point in 3d world space, collect keeps the order
+    image_points.points.par_iter()
+        .map(|point| mapper.map(*point).map_err(|err| Error::Geometry(format!("corner at {:?} can't be mapped onto the surface: {}", point, err))))
+        .collect()
+}
+
+/// Display the chessboard, in placement when it's given, photograph it and find its corners
+#[cfg(feature = "opencv")]
+pub fn detect_image_points(physical_camera: &PhysicalCamera, display: &PatternDisplay, camera_type: photo::CameraType, grid: GridSpec, projector_res: Resolution, orientation: ProjectorOrientation, placement: Option<&PatternPlacement>, detection: &DetectionOptions, debug_encoding: images::ImageEncoding, progress: &mut dyn ProgressSink, timings: &mut Option<Timings>) -> Result<Capture, Error> {
+    // show chessboard image on first projector
+    let chessboard = images::Pattern::Chessboard {grid: grid}.placed(placement);
+    detect_pattern_corners(physical_camera, display, camera_type, &chessboard, grid, projector_res, orientation, detection, debug_encoding, progress, timings)
+}
+
+/// Display a chessboard pattern, photograph it and find the corners of a board_size
+/// chessboard (in inner corners) in the photo. The pattern is shown upright, so the corners come back in the
+/// board's own row order whatever the projector orientation.
+///
+/// When the chessboard isn't found the likely cause is reported and the pattern is shown
+/// and photographed again, up to detection.attempts times. In manual mode the operator is asked to fix
+/// the problem and show the pattern again, otherwise it's re-sent automatically.
+#[cfg(feature = "opencv")]
+pub fn detect_pattern_corners(physical_camera: &PhysicalCamera, display: &PatternDisplay, camera_type: photo::CameraType, chessboard: &images::Pattern, board_size: GridSpec, projector_res: Resolution, orientation: ProjectorOrientation, detection: &DetectionOptions, debug_encoding: images::ImageEncoding, progress: &mut dyn ProgressSink, timings: &mut Option<Timings>) -> Result<Capture, Error> {
+    let attempts = detection.attempts.max(1);
+    let mut failure = String::new();
+    let blank = match detection.roi {
+        Some(DetectionRoi::Auto {..}) => Some(photograph_blank(physical_camera, display, camera_type.clone(), projector_res, orientation, debug_encoding, progress, timings)?),
+        _ => None
+    };
+    for attempt in 1..=attempts {
+        progress.event(CalibrationEvent::DisplayingPattern {description: chessboard.describe()});
+        if display.prompt().is_some() {
+            let message = if attempt == 1 {
+                format!("display the {}", chessboard.describe())
+            } else {
+                format!("fix the problem ({}) and display the {} again", failure, chessboard.describe())
+            };
+            progress.event(CalibrationEvent::WaitingForOperator {message: message});
+        }
+        timings::timed(timings, Stage::Display, || if attempt == 1 {
+            display.show(chessboard, projector_res, orientation)
+        } else {
+            display.show_again(chessboard, projector_res, orientation, &failure)
+        })?;
+
+        let photo_data = timings::timed(timings, Stage::Capture, || photo::capture_photo(camera_type.clone()));
+        let photo_bytes = photo_data.data_typed::<u8>()?.to_vec();
+        progress.event(CalibrationEvent::PhotoCaptured {bytes: photo_bytes.clone()});
+        let (undistorted, photo) = timings::timed(timings, Stage::Undistort, || take_undistorted_photo(&physical_camera.calibration, &photo_data, debug_encoding))?;
+        let debug_image = format!("alignment-corners-attempt{}", attempt);
+        let roi = match (&detection.roi, &blank) {
+            (Some(DetectionRoi::Auto {threshold}), Some(blank)) => {
+                let roi = projected_region(blank, &photo, *threshold)?;
+                if roi.is_none() {
+                    warn!("the chessboard photo doesn't differ from the black frame, looking for it in the whole photo");
+                }
+                roi
+            },
+            (Some(roi), _) => roi.polygon(),
+            (None, _) => None
+        };
+        match find_corners(&photo, board_size, detection, debug_encoding, roi.as_ref(), Some(&physical_camera.calibration), &debug_image, timings)? {
+            Ok((mut corners, variant)) => {
+                if let Err(reason) = order_corners(&mut corners, &photo, board_size, &format!("alignment-corner-order-attempt{}", attempt), debug_encoding)? {
+                    warn!("chessboard detection attempt {} of {} failed: {}", attempt, attempts, reason);
+                    progress.event(CalibrationEvent::CornersDetected {found: 0, expected: board_size.len(), corners: vec![]});
+                    failure = reason;
+                    continue;
+                }
+                let flipped = match (detection.orientation_check, chessboard.orientation_cue()) {
+                    (true, Some(cue)) => check_orientation(physical_camera, display, camera_type.clone(), &cue, &mut corners, projector_res, orientation, debug_encoding, timings)?,
+                    _ => false
+                };
+                let coverage = check_coverage(&corners, &photo, chessboard, detection)?;
+                progress.event(CalibrationEvent::CornersDetected {
+                    found: corners.len(),
+                    expected: board_size.len(),
+                    corners: corners.points.clone(),
+                });
+                return Ok(Capture {photo: photo_bytes, undistorted: undistorted, image_points: corners, detection_variant: variant, flipped: flipped, coverage: coverage});
+            },
+            Err(reason) => {
+                warn!("chessboard detection attempt {} of {} failed: {}", attempt, attempts, reason);
+                progress.event(CalibrationEvent::CornersDetected {found: 0, expected: board_size.len(), corners: vec![]});
+                failure = reason;
+            }
+        }
+    }
+    Err(Error::Detection(format!("chessboard not detected after {} attempts: {}", attempts, failure)))
+}
+
+/// Put the detected corners in row order, see `grid_order`. When they can't be read as a
+/// coherent grid the photo is written as debug artifact diagnostic_image, in debug_encoding,
+/// with each corner numbered.
+#[cfg(feature = "opencv")]
+fn order_corners(corners: &mut ImagePointGrid, photo: &Mat, grid: GridSpec, diagnostic_image: &str, debug_encoding: images::ImageEncoding) -> Result<Result<(), String>, Error> {
+    match grid_order::canonical_order(&corners.points, grid) {
+        Ok(order) => {
+            if order != grid_order::CornerOrder::default() {
+                warn!("the chessboard corners were detected out of order ({:?}), putting them back in row order", order);
+                corners.points = order.apply(&corners.points, grid);
+                corners.valid = order.apply(&corners.valid, grid);
+                corners.confidence = order.apply(&corners.confidence, grid);
+            }
+            Ok(Ok(()))
+        },
+        Err(reason) => {
+            let (image, _) = to_8bit(photo)?;
+            let mut numbered = Mat::default()?;
+            cvt_color(&greyscale_image(&image)?, &mut numbered, COLOR_GRAY2BGR, 1)?;
+            let scale = (photo.cols() as f64 / 2000.).max(0.4);
+            for (i, p) in corners.points.iter().enumerate() {
+                let at = Point::new(p.x.round() as i32, p.y.round() as i32);
+                circle(&mut numbered, at, 3, Scalar::new(0., 0., 255., 0.), -1, LINE_8, 0)?;
+                put_text(&mut numbered, &i.to_string(), at, FONT_HERSHEY_SIMPLEX, scale, Scalar::new(0., 255., 255., 0.), 1, LINE_AA, false)?;
+            }
+            let fname = images::write_debug(diagnostic_image, &numbered, debug_encoding)?;
+            Ok(Err(format!("{} (corner numbering written to {})", reason, fname)))
+        }
+    }
+}
+
+/// Measure how much of the photo and the projector raster the detected corners cover,
+/// warning the operator when it's too little. Fails when the board covers less of the photo
+/// than `DetectionOptions::min_camera_coverage`.
+#[cfg(feature = "opencv")]
+pub(crate) fn check_coverage(corners: &ImagePointGrid, photo: &Mat, chessboard: &images::Pattern, detection: &DetectionOptions) -> Result<Coverage, Error> {
+    let camera_points: Vec<glm::Vec2> = corners.valid_points().cloned().collect();
+    let projector_points = chessboard.corner_positions().map(|positions| {
+        positions.iter().zip(corners.valid.iter()).filter(|(_, v)| **v).map(|(p, _)| *p).collect::<Vec<glm::Vec2>>()
+    });
+    let coverage = coverage::measure(&camera_points, photo.cols(), photo.rows(), projector_points.as_deref());
+    report_coverage(&coverage, detection)?;
+    Ok(coverage)
+}
+
+/// Log how much the chessboard covers, with its warnings when detection asks for them, and
+/// fail when it's less of the photo than `DetectionOptions::min_camera_coverage`
+#[cfg(feature = "opencv")]
+pub(crate) fn report_coverage(coverage: &Coverage, detection: &DetectionOptions) -> Result<(), Error> {
+    info!(
+        "chessboard covers {:.0}% of the camera frame{}",
+        coverage.camera_fraction * 100.,
+        coverage.projector_fraction.map(|f| format!(" and {:.0}% of the projector raster", f * 100.)).unwrap_or_default()
+    );
+    if detection.coverage_warnings {
+        for warning in coverage.warnings.iter() {
+            warn!("{}", warning);
+        }
+    }
+    if let Some(min) = detection.min_camera_coverage {
+        if coverage.camera_fraction < min {
+            return Err(Error::Detection(format!(
+                "chessboard covers {:.0}% of the camera frame, at least {:.0}% is required. Move the camera closer or zoom in.",
+                coverage.camera_fraction * 100., min * 100.
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Brightness difference (of 255) between the first and last corner needed to trust the
+/// orientation cue
+const ORIENTATION_CONTRAST: f64 = 20.;
+
+/// Show the orientation cue for the chessboard just detected and put its corners in the
+/// board's own order. Returns whether they had to be reversed.
+#[cfg(feature = "opencv")]
+fn check_orientation(physical_camera: &PhysicalCamera, display: &PatternDisplay, camera_type: photo::CameraType, cue: &images::Pattern, corners: &mut ImagePointGrid, projector_res: Resolution, orientation: ProjectorOrientation, debug_encoding: images::ImageEncoding, timings: &mut Option<Timings>) -> Result<bool, Error> {
+    timings::timed(timings, Stage::Display, || display.show(cue, projector_res, orientation))?;
+    let photo_data = timings::timed(timings, Stage::Capture, || photo::capture_photo(camera_type));
+    let (_, photo) = timings::timed(timings, Stage::Undistort, || take_undistorted_photo(&physical_camera.calibration, &photo_data, debug_encoding))?;
+    let first = mean_around(&photo, corners.points[0])?;
+    let last = mean_around(&photo, corners.points[corners.len() - 1])?;
+    Ok(orient_corners(corners, first, last))
+}
+
+/// Mean brightness (of 255) of the few pixels around a point
+#[cfg(feature = "opencv")]
+fn mean_around(photo: &Mat, point: glm::Vec2) -> opencv::Result<f64> {
+    let radius = 4;
+    let x = (point.x.round() as i32 - radius).max(0).min(photo.cols() - 1);
+    let y = (point.y.round() as i32 - radius).max(0).min(photo.rows() - 1);
+    let rect = Rect::new(x, y, (radius * 2 + 1).min(photo.cols() - x), (radius * 2 + 1).min(photo.rows() - y));
+    Ok(mean(&Mat::roi(photo, rect)?, &Mat::default()?)?[0] * 255. / white_level(photo)?)
+}
+
+/// The orientation cue lights the quadrant of the chessboard containing corner (0, 0) as
+/// shown. opencv can return a symmetric board's corners starting from either end, when the
+/// cue was brighter at the last detected corner than the first the order is reversed. A cue
+/// that isn't clearly brighter at either end leaves the order alone.
+pub fn orient_corners(corners: &mut ImagePointGrid, first_brightness: f64, last_brightness: f64) -> bool {
+    if (first_brightness - last_brightness).abs() < ORIENTATION_CONTRAST {
+        warn!(
+            "the orientation cue wasn't clear (brightness {:.0} at the first corner, {:.0} at the last), keeping the detected corner order",
+            first_brightness, last_brightness
+        );
+        return false;
+    }
+    if last_brightness > first_brightness {
+        warn!("the chessboard was detected rotated 180°, reversing the corner order");
+        corners.rotate_180();
+        return true;
+    }
+    false
+}
+
+/// Calculate the virtual camera's vertical fov so it sees every scene point, then the
+/// normalized screen position of each scene point as seen by the virtual camera. Without
+/// clip planes they're set to half the nearest and twice the furthest scene point distance.
+/// Scene points behind the eye or nearer than the near plane are an error.
+pub fn generate_uv_warp_and_fov(scene_coords: &Vec<glm::Vec3>, virtual_camera: &mut VirtualCamera, projector_res: Resolution) -> Result<Vec<glm::Vec2>, Error> {
+    let trans = look_at(virtual_camera.position, virtual_camera.look_at.unwrap(), virtual_camera.up_dir);
+
+    // distance along the view axis, the camera looks down -z
+    let depths: Vec<f32> = scene_coords.iter().map(|p| -(trans * p.extend(1.)).z).collect();
+    let behind = depths.iter().filter(|d| **d <= 0.).count();
+    if behind > 0 {
+        return Err(Error::Geometry(format!("{} scene points are behind the eye {:?}, check the eye position and look at", behind, virtual_camera.position)));
+    }
+    let nearest = depths.iter().cloned().fold(f32::INFINITY, f32::min);
+    let furthest = depths.iter().cloned().fold(0_f32, f32::max);
+    let (near, far) = virtual_camera.clip_planes.unwrap_or((nearest * 0.5, furthest * 2.));
+    let clipped = depths.iter().filter(|d| **d < near || **d > far).count();
+    if clipped > 0 {
+        return Err(Error::Geometry(format!(
+            "{} scene points are outside the clip planes (near {}, far {}), the scene is {} to {} from the eye",
+            clipped, near, far, nearest, furthest
+        )));
+    }
+    virtual_camera.clip_planes = Some((near, far));
+    debug!("clip planes are near {} far {}", near, far);
+    let max_rad = scene_coords.par_iter()
+        .map(|scene_point| {
+            let eye_relative = trans * scene_point.extend(1.);
+            //let rad = atan(eye_relative.y.abs() / eye_relative.z.abs());
+            eye_relative.y.abs().atan2(eye_relative.z.abs())
+        })
+        .reduce(|| -1_f32, f32::max);
+    
+    virtual_camera.fov = Some(glm::degrees(max_rad) * 2.001); // FIXMEshouldn't really need to add 10% on here?
+    
+    info!("eyePoint = {:?} lookAt = {:?} fovY = {:?}", virtual_camera.position, virtual_camera.look_at, virtual_camera.fov.unwrap());
+    if let Some(optics) = &virtual_camera.optics {
+        check_frustum(scene_coords, &trans, optics, projector_res.aspect_ratio());
+    }
+
+    let (model, proj) = view_and_projection(virtual_camera, projector_res.aspect_ratio());
+    let off_screen = std::sync::atomic::AtomicUsize::new(0);
+    let uv_coords: Vec<glm::Vec2> = scene_coords.par_iter()
+        .map(|scene_coord| {
+            // the coord of the render buffer that should be warped to the current chessboard corner
+            let screen_pos = project_with(*scene_coord, &model, &proj);
+            if is_off_screen(screen_pos) {
+                off_screen.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+            screen_pos
+        })
+        .collect();
+
+    let off_screen = off_screen.into_inner();
+    if off_screen > 0 {
+        warn!("{} points in the scene space projected off screen", off_screen);
+    }
+    Ok(uv_coords)
+}
+
+/// Warn about scene points outside the frustum the projector optics imply
+fn check_frustum(scene_coords: &Vec<glm::Vec3>, view: &glm::Mat4, optics: &ProjectorOptics, aspect_ratio: f32) {
+    let [left, right, bottom, top] = optics.frustum_tangents(aspect_ratio);
+    // the scene's extent as tangents from the view axis
+    let (mut min_x, mut max_x, mut min_y, mut max_y) = (f32::MAX, f32::MIN, f32::MAX, f32::MIN);
+    for scene_point in scene_coords {
+        let p = *view * scene_point.extend(1.);
+        let (tx, ty) = (p.x / -p.z, p.y / -p.z);
+        min_x = min_x.min(tx);
+        max_x = max_x.max(tx);
+        min_y = min_y.min(ty);
+        max_y = max_y.max(ty);
+    }
+    let edges = [
+        ("left", left.atan() - min_x.atan()),
+        ("right", max_x.atan() - right.atan()),
+        ("bottom", bottom.atan() - min_y.atan()),
+        ("top", max_y.atan() - top.atan()),
+    ];
+    for (edge, short) in edges.iter() {
+        if *short > 0. {
+            warn!("the scene extends {:.1} degrees past the {} edge of the projector frustum", glm::degrees(*short), edge);
+        }
+    }
+}
+
+/// Given virtual camera details, calculate normalized screen position of the point in 3D space
+pub fn project_scene_point(scene_pos: glm::Vec3, virtual_camera: &VirtualCamera, projector_aspect_ratio: f32) -> glm::Vec2 {
+    let (model, proj) = view_and_projection(virtual_camera, projector_aspect_ratio);
+    let screen_pos = project_with(scene_pos, &model, &proj);
+    if is_off_screen(screen_pos) {
+        warn!("a point in the scene space projected off screen (in project_scene_point)");
+    }
+    screen_pos
+}
+
+/// The view and projection matrices of the virtual camera
+pub fn view_and_projection(virtual_camera: &VirtualCamera, projector_aspect_ratio: f32) -> (glm::Mat4, glm::Mat4) {
+    let model = glm::ext::look_at(virtual_camera.position, virtual_camera.look_at.unwrap(), virtual_camera.up_dir);
+    let (near, far) = virtual_camera.clip_planes.unwrap_or((0.1, 100.));
+    let proj = match &virtual_camera.optics {
+        Some(optics) => optics.projection(projector_aspect_ratio, near, far),
+        None => glm::ext::perspective(
+            glm::radians(virtual_camera.fov.unwrap()),
+            projector_aspect_ratio,
+            near,
+            far
+        )
+    };
+    (model, proj)
+}
+
+pub(crate) fn project_with(scene_pos: glm::Vec3, model: &glm::Mat4, proj: &glm::Mat4) -> glm::Vec2 {
+    math::project(scene_pos, model, proj, vec4(0., 0., 1., 1.)).truncate(2)
+}
+
+fn is_off_screen(screen_pos: glm::Vec2) -> bool {
+    screen_pos.x < 0. || screen_pos.y < 0. || screen_pos.x > 1. || screen_pos.y > 1.
+}
+
+/// Find the grid.cols x grid.rows inner corners of a chessboard in a greyscale photo,
+/// refined to sub-pixel accuracy. When the photo as is doesn't work the variants of
+/// `detection` are tried in turn, the one that worked is returned with the corners.
+#[cfg(feature = "opencv")]
+pub fn locate_chessboard_corners(photo: &Mat, grid: GridSpec, detection: &DetectionOptions, debug_encoding: images::ImageEncoding) -> Result<(ImagePointGrid, DetectionVariant), Error> {
+    let roi = detection.roi.as_ref().and_then(|roi| roi.polygon());
+    if let Some(DetectionRoi::Auto {..}) = detection.roi {
+        warn!("an automatic detection ROI needs a photo of a black frame, looking for the chessboard in the whole photo");
+    }
+    let (mut corners, variant) = find_corners(photo, grid, detection, debug_encoding, roi.as_ref(), None, "alignment-corners", &mut None)?.map_err(Error::Detection)?;
+    order_corners(&mut corners, photo, grid, "alignment-corner-order", debug_encoding)?.map_err(Error::Detection)?;
+    Ok((corners, variant))
+}
+
+/// The corners and the variant they were found with, or why they weren't found. Outside
+/// roi the photo is masked out for detection, but corners are refined on the whole photo.
+/// With debug logging the photo is written as debug artifact debug_image, in debug_encoding,
+/// with the roi and whatever corners were found drawn on it.
+#[cfg(feature = "opencv")]
+fn find_corners(photo: &Mat, grid: GridSpec, detection: &DetectionOptions, debug_encoding: images::ImageEncoding, roi: Option<&Vec<glm::Vec2>>, calibration: Option<&camera_calibration::Calibration>, debug_image: &str, timings: &mut Option<Timings>) -> opencv::Result<Result<(ImagePointGrid, DetectionVariant), String>> {
+    // find chessboard corners
+    let mut point_buffer = VectorOfPoint2f::new();
+    let board_size = Size::new(grid.cols, grid.rows);
+    let started = std::time::Instant::now();
+    let mut found = false;
+    let mut winner = DetectionVariant::default();
+    let (mut detection_image, window) = to_8bit(photo)?;
+    if let Some((low, high)) = window {
+        debug!("detecting on the photo windowed to {:.0}-{:.0}", low, high);
+    }
+    if let Some(roi) = roi {
+        detection_image = mask_outside(&detection_image, roi)?;
+    }
+    for (i, variant) in detection.variants().iter().enumerate() {
+        if i > 0 && started.elapsed() > detection.time_budget {
+            warn!("stopped trying detection variants after {:.1}s", started.elapsed().as_secs_f32());
+            break;
+        }
+        debug!("Finding chessboard corners with {:?}...", variant);
+        let image = variant.apply(&detection_image)?;
+        found = find_chessboard_corners(&image, board_size, &mut point_buffer, CALIB_CB_ADAPTIVE_THRESH)?;
+        if found {
+            info!("chessboard found on the {} photo", if variant.invert { "inverted" } else { "non-inverted" });
+            if i > 0 {
+                info!("chessboard found after preprocessing the photo with {:?}", variant);
+            }
+            winner = *variant;
+            break;
+        }
+    }
+    if let Some(timings) = timings {
+        timings.add(Stage::Detection, started.elapsed().as_secs_f64());
+    }
+    
+    // draw found chessboard corners to image file
+    if log::log_enabled!(log::Level::Debug) {
+        let mut color = Mat::default()?;
+        cvt_color(&detection_image, &mut color, COLOR_GRAY2BGR, 1)?;
+        draw_chessboard_corners(&mut color, board_size, &point_buffer, found)?;
+        if let Some(roi) = roi {
+            polylines(&mut color, &polygon_points(roi), true, Scalar::new(0., 255., 255., 0.), 2, LINE_8, 0)?;
+        }
+        images::write_debug(debug_image, &color, debug_encoding)?;
+    }
+
+    if !found {
+        let mut reason = format!("found {} of {} corners", point_buffer.len(), grid.len());
+        if let Some(exposure) = exposure_problem(photo)? {
+            reason = format!("{}, {}", reason, exposure);
+        }
+        return Ok(Err(reason));
+    }
+
+    // corner subpix analysis, on the photo's own values when it's deeper than 8-bit
+    let mut refine_image = Mat::default()?;
+    photo.convert_to(&mut refine_image, if photo.depth()? == CV_8U { CV_8U } else { CV_32F }, 1., 0.)?;
+    let unrefined: Vec<glm::Vec2> = point_buffer.iter().map(|pt| vec2(pt.x, pt.y)).collect();
+    let half_window = subpixel_half_window(&unrefined, grid);
+    debug!("refining corners with a {}px half window", half_window);
+    timings::timed(timings, Stage::Subpixel, || corner_sub_pix(&refine_image, &mut point_buffer, Size::new(half_window, half_window), Size::new(-1, -1),
+                     TermCriteria::new(3, 30, 0.1f64).unwrap()))?; // 3 = COUNT + EPS
+    
+    // convert to vector of glm::Vec2
+    let points: Vec<glm::Vec2> = point_buffer.iter().map(|pt| vec2(pt.x, pt.y)).collect();
+    let mut corners = ImagePointGrid::new(grid.cols, grid.rows, points);
+    corners.confidence = corner_confidences(photo, &corners.points, &unrefined, grid, half_window, calibration)?;
+    Ok(Ok((corners, winner)))
+}
+
+/// Fraction of the corner spacing that the subpixel search reaches either side of a corner,
+/// well short of the neighbouring corners
+#[cfg(feature = "opencv")]
+const SUBPIXEL_WINDOW_FRACTION: f32 = 0.25;
+
+/// Mean distance in pixels between neighbouring corners along the rows of a grid's points
+#[cfg(feature = "opencv")]
+fn corner_spacing(points: &[glm::Vec2], grid: GridSpec) -> f32 {
+    let mut spacing = 0_f32;
+    for row in 0..grid.rows {
+        for col in 1..grid.cols {
+            let i = (row * grid.cols + col) as usize;
+            spacing += length(points[i] - points[i - 1]);
+        }
+    }
+    spacing / ((grid.cols - 1).max(1) * grid.rows) as f32
+}
+
+/// Half the side in pixels of the window `corner_sub_pix` searches around each of points
+#[cfg(feature = "opencv")]
+fn subpixel_half_window(points: &[glm::Vec2], grid: GridSpec) -> i32 {
+    ((corner_spacing(points, grid) * SUBPIXEL_WINDOW_FRACTION).round() as i32).max(2)
+}
+
+/// Confidence of each refined corner from the contrast around it in photo, how far it was
+/// refined from unrefined, its distance from the principal point (the photo's center without
+/// a calibration) and whether the camera's defects cover its subpixel window, half_window
+/// pixels either side of it. See `confidence`.
+#[cfg(feature = "opencv")]
+fn corner_confidences(photo: &Mat, refined: &[glm::Vec2], unrefined: &[glm::Vec2], grid: GridSpec, half_window: i32, calibration: Option<&camera_calibration::Calibration>) -> opencv::Result<Vec<f32>> {
+    let mut values = Mat::default()?;
+    photo.convert_to(&mut values, CV_32F, 1. / white_level(photo)?, 0.)?;
+    // about a third of a square either side, so the window reaches into all four squares
+    let radius = ((corner_spacing(refined, grid) * 0.35).round() as i32).max(2);
+    let (w, h) = (photo.cols(), photo.rows());
+    // the calibration may be for a larger or smaller photo, see `Calibration::for_photo`
+    let center = calibration
+        .map(|c| vec2((c.cx() as f32 + 0.5) * w as f32 / c.image_width as f32 - 0.5, (c.cy() as f32 + 0.5) * h as f32 / c.image_height as f32 - 0.5))
+        .unwrap_or(vec2(w as f32 / 2., h as f32 / 2.));
+    let defects = calibration.filter(|c| c.image_width == w && c.image_height == h).and_then(|c| c.defects.as_ref());
+    let half_diagonal = 0.5 * ((w * w + h * h) as f32).sqrt();
+
+    let mut confidences = Vec::with_capacity(refined.len());
+    for (p, before) in refined.iter().zip(unrefined.iter()) {
+        let (x0, x1) = ((p.x.round() as i32 - radius).max(0), (p.x.round() as i32 + radius).min(w - 1));
+        let (y0, y1) = ((p.y.round() as i32 - radius).max(0), (p.y.round() as i32 + radius).min(h - 1));
+        let (mut low, mut high) = (std::f32::MAX, std::f32::MIN);
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let v = *values.at_2d::<f32>(y, x)?;
+                low = low.min(v);
+                high = high.max(v);
+            }
+        }
+        let masked = match defects {
+            Some(defects) => defects.masks_undistorted(Rect::new(p.x.round() as i32 - half_window, p.y.round() as i32 - half_window, 2 * half_window + 1, 2 * half_window + 1))?,
+            None => false
+        };
+        confidences.push(confidence::corner_confidence(&confidence::CornerEvidence {
+            contrast: if high >= low { high - low } else { 0. },
+            subpixel_shift: length(*p - *before),
+            radius: length(*p - center) / half_diagonal,
+            masked: masked,
+        }));
+    }
+    Ok(confidences)
+}
+
+/// Show a black frame and photograph it, for working out the automatic detection ROI
+#[cfg(feature = "opencv")]
+fn photograph_blank(physical_camera: &PhysicalCamera, display: &PatternDisplay, camera_type: photo::CameraType, projector_res: Resolution, orientation: ProjectorOrientation, debug_encoding: images::ImageEncoding, progress: &mut dyn ProgressSink, timings: &mut Option<Timings>) -> Result<Mat, Error> {
+    let black = images::Pattern::SolidColor {r: 0, g: 0, b: 0};
+    progress.event(CalibrationEvent::DisplayingPattern {description: black.describe()});
+    timings::timed(timings, Stage::Display, || display.show(&black, projector_res, orientation))?;
+    let photo_data = timings::timed(timings, Stage::Capture, || photo::capture_photo(camera_type));
+    let (_, photo) = timings::timed(timings, Stage::Undistort, || take_undistorted_photo(&physical_camera.calibration, &photo_data, debug_encoding))?;
+    Ok(photo)
+}
+
+/// The area the projector lights: the convex hull of the largest area where the pattern
+/// photo differs from the black one by more than threshold (of 255). Gaps the size of the
+/// pattern's dark squares are closed first. None if nothing changed.
+#[cfg(feature = "opencv")]
+fn projected_region(blank: &Mat, pattern: &Mat, threshold_level: f64) -> opencv::Result<Option<Vec<glm::Vec2>>> {
+    let mut difference = Mat::default()?;
+    absdiff(pattern, blank, &mut difference)?;
+    let mut changed = Mat::default()?;
+    threshold(&difference, &mut changed, threshold_level * white_level(pattern)? / 255., 255., THRESH_BINARY)?;
+    let mut mask = Mat::default()?;
+    changed.convert_to(&mut mask, CV_8U, 1., 0.)?;
+
+    let kernel_size = (pattern.cols().min(pattern.rows()) / 20) | 1;
+    let kernel = get_structuring_element(MORPH_RECT, Size::new(kernel_size, kernel_size), Point::new(-1, -1))?;
+    let mut closed = Mat::default()?;
+    morphology_ex(&mask, &mut closed, MORPH_CLOSE, &kernel, Point::new(-1, -1), 1, BORDER_CONSTANT, morphology_default_border_value()?)?;
+
+    let mut contours = VectorOfVectorOfPoint::new();
+    find_contours(&closed, &mut contours, RETR_EXTERNAL, CHAIN_APPROX_SIMPLE, Point::new(0, 0))?;
+    let mut largest = None;
+    let mut largest_area = 0.;
+    for contour in contours.iter() {
+        let area = contour_area(&contour, false)?;
+        if area > largest_area {
+            largest_area = area;
+            largest = Some(contour);
+        }
+    }
+    let largest = match largest {
+        Some(contour) => contour,
+        None => return Ok(None)
+    };
+    let mut hull = VectorOfPoint::new();
+    convex_hull(&largest, &mut hull, false, true)?;
+    info!("detection ROI is {} points covering {:.0}% of the photo", hull.len(), largest_area * 100. / (pattern.cols() * pattern.rows()) as f64);
+    Ok(Some(hull.iter().map(|p| vec2(p.x as f32, p.y as f32)).collect()))
+}
+
+/// A copy of an 8-bit image set to mid grey outside the polygon
+#[cfg(feature = "opencv")]
+fn mask_outside(image: &Mat, roi: &Vec<glm::Vec2>) -> opencv::Result<Mat> {
+    let mut mask = Mat::new_rows_cols_with_default(image.rows(), image.cols(), CV_8UC1, Scalar::all(0.))?;
+    fill_poly(&mut mask, &polygon_points(roi), Scalar::all(255.), LINE_8, 0, Point::new(0, 0))?;
+    let mut masked = Mat::new_rows_cols_with_default(image.rows(), image.cols(), image.typ()?, Scalar::all(128.))?;
+    image.copy_to_masked(&mut masked, &mask)?;
+    Ok(masked)
+}
+
+#[cfg(feature = "opencv")]
+fn polygon_points(polygon: &Vec<glm::Vec2>) -> VectorOfVectorOfPoint {
+    let mut points = VectorOfPoint::new();
+    for p in polygon {
+        points.push(Point::new(p.x.round() as i32, p.y.round() as i32));
+    }
+    let mut polygons = VectorOfVectorOfPoint::new();
+    polygons.push(points);
+    polygons
+}
+
+/// Guess from the histogram of the greyscale photo whether it was badly exposed. Levels
+/// are out of 255 whatever the photo's depth.
+#[cfg(feature = "opencv")]
+fn exposure_problem(photo: &Mat) -> opencv::Result<Option<String>> {
+    let total = (photo.rows() * photo.cols()).max(1) as f64;
+    let white = white_level(photo)?;
+    let fraction = |thresh: f64, threshold_type: i32| -> opencv::Result<f64> {
+        let mut mask = Mat::default()?;
+        threshold(photo, &mut mask, thresh * white / 255., white, threshold_type)?;
+        Ok(count_non_zero(&mask)? as f64 / total)
+    };
+    let blown_out = fraction(250., THRESH_BINARY)?;
+    let black = fraction(5., THRESH_BINARY_INV)?;
+    let brightness = mean(photo, &Mat::default()?)?[0] * 255. / white;
+    Ok(if blown_out > 0.25 {
+        Some(format!("{:.0}% of the photo is overexposed, reduce the exposure or turn off the lights", blown_out * 100.))
+    } else if black > 0.5 || brightness < 30. {
+        Some(format!("the photo is underexposed (mean brightness {:.0} of 255), is the pattern showing?", brightness))
+    } else if brightness > 200. {
+        Some(format!("the photo is very bright (mean brightness {:.0} of 255), are the lights on?", brightness))
+    } else {
+        None
+    })
+}
+
+/// Decode and undistort a photo. Returns the undistorted photo and the greyscale image the
+/// chessboard is detected in, both at the photo's bit depth and neither inverted: detection
+/// inverts its own copy for each `DetectionVariant` whose polarity calls for it.
+#[cfg(feature = "opencv")]
+pub fn take_undistorted_photo(calibration: &camera_calibration::Calibration, photo_data: &Mat, debug_encoding: images::ImageEncoding) -> Result<(Mat, Mat), Error> {
+    let undistorted = undistort_photo(calibration, photo_data)?;
+    write_debug_image("alignment-undistorted", &undistorted, debug_encoding)?;
+    let greyscale = greyscale_image(&undistorted)?;
+    write_debug_image("alignment-greyscale", &greyscale, debug_encoding)?;
+    Ok((undistorted, greyscale))
+}
+
+/// Decode a photo and remove the lens distortion, for detectors that need the color image.
+/// A photo at a uniform scale of the calibrated size is undistorted with the camera matrix
+/// scaled to match, see `Calibration::for_photo`.
+#[cfg(feature = "opencv")]
+pub fn undistort_photo(calibration: &camera_calibration::Calibration, photo_data: &Mat) -> Result<Mat, Error> {
+    let photo = decode_photo(photo_data)?;
+    let scaled = calibration.for_photo(photo.cols(), photo.rows())?;
+    let calibration = scaled.as_ref().unwrap_or(calibration);
+
+    let photo = match &calibration.defects {
+        Some(defects) => defects.correct(&photo)?,
+        None => photo
+    };
+    let mut undistorted_img = Mat::default()?;
+    undistort(&photo, &mut undistorted_img, &calibration.camera_matrix, &calibration.distortion_coefficients, &calibration.camera_matrix)?;
+    Ok(undistorted_img)
+}
+
+/// The greyscale version of a photo used for corner detection, at the photo's bit depth
+#[cfg(feature = "opencv")]
+pub fn greyscale_image(photo: &Mat) -> opencv::Result<Mat> {
+    let mut gray = Mat::default()?;
+    match photo.channels()? {
+        1 => photo.copy_to(&mut gray)?,
+        4 => cvt_color(photo, &mut gray, COLOR_BGRA2GRAY, 1)?,
+        _ => cvt_color(photo, &mut gray, COLOR_BGR2GRAY, 1)?,
+    }
+    Ok(gray)
+}
+
+/// Percentiles of a deep photo's values that become black and white when it's brought
+/// down to 8 bits for the detectors
+const WINDOW_PERCENTILES: (f64, f64) = (0.5, 99.5);
+
+/// Decode a photo keeping its bit depth and channels, so 16-bit TIFF and PNG frames stay
+/// 16-bit. Camera raw files are rejected with an error saying so.
+#[cfg(feature = "opencv")]
+pub fn decode_photo(photo_data: &Mat) -> opencv::Result<Mat> {
+    let bytes = photo_data.data_typed::<u8>()?;
+    if let Some(format) = raw_format(bytes) {
+        return Err(opencv::Error::new(StsError, format!(
+            "the photo is a {} camera raw file, which can't be decoded. Set the camera to save JPEG or TIFF, or convert the file first (e.g. dcraw -T -4)",
+            format
+        )));
+    }
+    let photo = imgcodecs::imdecode(photo_data, imgcodecs::IMREAD_ANYDEPTH | imgcodecs::IMREAD_ANYCOLOR)?;
+    if photo.empty()? {
+        return Err(opencv::Error::new(StsError, format!("the photo ({} bytes) isn't an image format opencv can decode", bytes.len())));
+    }
+    Ok(photo)
+}
+
+/// The camera raw format of photo data, for the formats with a recognizable signature.
+/// Nikon and Sony raws look like plain TIFF and are left to the decoder.
+#[cfg(feature = "opencv")]
+fn raw_format(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"FUJIFILMCCD-RAW") {
+        Some("Fujifilm RAF")
+    } else if data.len() >= 12 && &data[4..12] == b"ftypcrx " {
+        Some("Canon CR3")
+    } else if data.len() >= 10 && data.starts_with(b"II*\0") && &data[8..10] == b"CR" {
+        Some("Canon CR2")
+    } else if data.starts_with(b"IIRO") || data.starts_with(b"IIRS") || data.starts_with(b"MMOR") {
+        Some("Olympus ORF")
+    } else if data.starts_with(b"IIU\0") {
+        Some("Panasonic RW2")
+    } else {
+        None
+    }
+}
+
+/// The value of white in an image of this depth
+#[cfg(feature = "opencv")]
+pub(crate) fn white_level(image: &Mat) -> opencv::Result<f64> {
+    Ok(match image.depth()? {
+        CV_8U => 255.,
+        CV_16U => 65535.,
+        _ => 1.,
+    })
+}
+
+/// An 8-bit copy of an image for the opencv functions that only take 8-bit. Deeper images
+/// are windowed between the `WINDOW_PERCENTILES` of their values rather than truncated,
+/// the window used is returned.
+#[cfg(feature = "opencv")]
+pub fn to_8bit(image: &Mat) -> opencv::Result<(Mat, Option<(f64, f64)>)> {
+    let mut out = Mat::default()?;
+    if image.depth()? == CV_8U {
+        image.copy_to(&mut out)?;
+        return Ok((out, None));
+    }
+    let (low, high) = percentile_window(image)?;
+    let scale = 255. / (high - low).max(1e-6);
+    image.convert_to(&mut out, CV_8U, scale, -low * scale)?;
+    Ok((out, Some((low, high))))
+}
+
+/// The `WINDOW_PERCENTILES` of an image's values over all channels, from a sample of at
+/// most a million of them
+#[cfg(feature = "opencv")]
+fn percentile_window(image: &Mat) -> opencv::Result<(f64, f64)> {
+    let mut values = Mat::default()?;
+    image.convert_to(&mut values, CV_32F, 1., 0.)?;
+    let values = values.reshape(1, 1)?;
+    let data = values.data_typed::<f32>()?;
+    let step = (data.len() / 1_000_000).max(1);
+    let mut sample: Vec<f32> = data.iter().step_by(step).cloned().collect();
+    sample.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let at = |percentile: f64| sample[((sample.len() - 1) as f64 * percentile / 100.).round() as usize] as f64;
+    Ok((at(WINDOW_PERCENTILES.0), at(WINDOW_PERCENTILES.1)))
+}
+
+/// Write an image for looking at, in encoding. Deep images are windowed to 8-bit
+/// with the window in the file name.
+#[cfg(feature = "opencv")]
+fn write_debug_image(name: &str, image: &Mat, encoding: images::ImageEncoding) -> opencv::Result<()> {
+    let (viewable, window) = to_8bit(image)?;
+    let name = match window {
+        Some((low, high)) => format!("{}-window{:.0}-{:.0}", name, low, high),
+        None => name.to_string(),
+    };
+    images::write_debug(&name, &viewable, encoding)?;
+    Ok(())
+}
+
+/// Assemble the calibration document from the computed scene and warp
+pub fn calibration_result(scene_coords: &Vec<glm::Vec3>, uv_coords: &Vec<glm::Vec2>, virtual_camera: &VirtualCamera, grid: GridSpec, mut meta: output::Meta) -> CalibrationResult {
+    debug!("scene has {} coordinates", scene_coords.len());
+    debug!("warp has {} coordinates", uv_coords.len());
+    let detection_grid = meta.detection_grid.unwrap_or(grid);
+    meta.warp_resolution = Resolution {width: grid.cols, height: grid.rows};
+    meta.clip_planes = virtual_camera.clip_planes.map(|(near, far)| [near, far]);
+    meta.look_at_method = Some(virtual_camera.look_at_method);
+    let upright = meta.projector_orientation.effective_resolution(meta.projector_resolution);
+    let (view, projection) = view_and_projection(virtual_camera, upright.aspect_ratio());
+    // the warp is in the native image, so rotate the projection's output there too
+    let (rotation, offset) = meta.projector_orientation.native_uv_transform();
+    let projection = math::remap_warp(&projection, rotation, offset);
+
+    CalibrationResult {
+        format_version: CALIBRATION_FORMAT_VERSION,
+        eye_name: None,
+        fov: virtual_camera.fov.unwrap(),
+        eye: virtual_camera.position,
+        look_at: virtual_camera.look_at.unwrap(),
+        up: virtual_camera.up_dir,
+        projector_optics: virtual_camera.optics,
+        view_matrix: Some(math::mat4_values(&view)),
+        projection_matrix: Some(math::mat4_values(&projection)),
+        matrix_layout: Some(output::MatrixLayout::default()),
+        warp_res_x: grid.cols,
+        warp_res_y: grid.rows,
+        warp: uv_coords.clone(),
+        scene: scene_coords.clone(),
+        valid: None,
+        confidence: None,
+        meta: Some(meta),
+        diagnostics: Some(output::Diagnostics {
+            detected_corners: detection_grid.len(),
+            expected_corners: detection_grid.len(),
+            detection_variant: None,
+            orientation_flipped: None,
+            coverage: None,
+            multi_camera: None,
+            surface_refinement: None,
+            timings: None,
+        }),
+    }
+}
+
+/// The calibration JSON document with the warp in the given conventions
+pub fn calibration_json_string(result: &CalibrationResult, conventions: &output::OutputConventions, projector_res: Resolution) -> String {
+    // Build final "calibration" JSON document
+    conventions.apply(result, projector_res).to_json_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::surfaces::{SurfaceType, CameraIntrinsics};
+
+    /// A fisheye looking up into a dome of radius 5, with a grid of corners over the front
+    /// half of its image
+    fn dome_fixture(cols: i32, rows: i32) -> (SurfaceType, CameraModel, ImagePointGrid) {
+        let camera = CameraModel {
+            position: vec3(0., 0., 0.),
+            look_at: vec3(0., 1., 0.),
+            up_dir: vec3(0., 0., -1.),
+            intrinsics: CameraIntrinsics {fov: 180., image_width: 2000, image_height: 2000},
+        };
+        let mut points = vec![];
+        for j in 0..rows {
+            for i in 0..cols {
+                points.push(vec2(
+                    600. + 800. * i as f32 / (cols - 1) as f32,
+                    1050. + 400. * j as f32 / (rows - 1) as f32
+                ));
+            }
+        }
+        (SurfaceType::HemisphericalDome {radius: 5.}, camera, ImagePointGrid::new(cols, rows, points))
+    }
+
+    #[test]
+    fn parallel_scene_coords_match_sequential() {
+        let (surface, camera, image_points) = dome_fixture(41, 30);
+        let scene = locate_scene_coords(&surface, &camera, &image_points).unwrap();
+        assert_eq!(scene.len(), image_points.len());
+        for (point, scene_point) in image_points.points.iter().zip(scene.iter()) {
+            let expected = surfaces::camera_to_scene(&surface, &camera, *point).unwrap();
+            assert_eq!((scene_point.x, scene_point.y, scene_point.z), (expected.x, expected.y, expected.z));
+        }
+    }
+
+    #[test]
+    fn parallel_warp_and_fov_match_sequential() {
+        let (surface, camera, image_points) = dome_fixture(41, 30);
+        let scene = locate_scene_coords(&surface, &camera, &image_points).unwrap();
+        let projector_res = Resolution {width: 1920, height: 1080};
+        let mut virtual_camera = VirtualCamera::new(vec3(0., 0., 0.));
+        virtual_camera.look_at = Some(calculate_look_at(&surface, &image_points, &camera).unwrap());
+        let uv = generate_uv_warp_and_fov(&scene, &mut virtual_camera, projector_res).unwrap();
+
+        // the same thing one point at a time
+        let trans = look_at(virtual_camera.position, virtual_camera.look_at.unwrap(), virtual_camera.up_dir);
+        let mut max_rad = -1_f32;
+        for p in scene.iter() {
+            let eye_relative = trans * p.extend(1.);
+            max_rad = max_rad.max(eye_relative.y.abs().atan2(eye_relative.z.abs()));
+        }
+        assert_eq!(virtual_camera.fov, Some(glm::degrees(max_rad) * 2.001));
+
+        let (model, proj) = view_and_projection(&virtual_camera, projector_res.aspect_ratio());
+        assert_eq!(uv.len(), scene.len());
+        for (p, uv) in scene.iter().zip(uv.iter()) {
+            let expected = project_with(*p, &model, &proj);
+            assert_eq!((uv.x, uv.y), (expected.x, expected.y));
+        }
+    }
+
+    #[test]
+    fn rolled_up_vector_rotates_the_warp() {
+        let (surface, camera, image_points) = dome_fixture(9, 6);
+        let scene = locate_scene_coords(&surface, &camera, &image_points).unwrap();
+        let projector_res = Resolution {width: 1920, height: 1080};
+        let aspect = projector_res.aspect_ratio();
+        let eye = vec3(0., 0., 0.);
+        let target = calculate_look_at(&surface, &image_points, &camera).unwrap();
+        let warp = |up: glm::Vec3| {
+            let mut virtual_camera = VirtualCamera::new(eye);
+            virtual_camera.look_at = Some(target);
+            virtual_camera.up_dir = up;
+            generate_uv_warp_and_fov(&scene, &mut virtual_camera, projector_res).unwrap()
+        };
+
+        // up turned 30 degrees towards the right about the view axis
+        let forward = normalize(target - eye);
+        let right = normalize(cross(forward, vec3(0., 1., 0.)));
+        let up = cross(right, forward);
+        let roll = radians(30_f32);
+        let level = warp(vec3(0., 1., 0.));
+        let rolled = warp(up * roll.cos() + right * roll.sin());
+
+        // the content turns the other way, anticlockwise in uv space where y is up
+        let angle = |uv: glm::Vec2| (uv.y - 0.5).atan2((uv.x - 0.5) * aspect);
+        for (a, b) in level.iter().zip(rolled.iter()) {
+            if length(*a - vec2(0.5, 0.5)) < 0.05 {
+                continue;
+            }
+            let mut turn = glm::degrees(angle(*b) - angle(*a));
+            if turn > 180. { turn -= 360.; }
+            if turn <= -180. { turn += 360.; }
+            assert!((turn - 30.).abs() < 0.1, "{:?} turned {} degrees to {:?}", a, turn, b);
+        }
+    }
+
+    /// The warp as projectionMatrix * viewMatrix * point gives it
+    fn warp_from_matrices(result: &CalibrationResult) -> Vec<glm::Vec2> {
+        let view = math::mat4_from_values(result.view_matrix.as_ref().unwrap());
+        let projection = math::mat4_from_values(result.projection_matrix.as_ref().unwrap());
+        result.scene.iter().map(|p| {
+            let clip = projection * view * p.extend(1.);
+            vec2(clip.x / clip.w, clip.y / clip.w) * 0.5 + vec2(0.5, 0.5)
+        }).collect()
+    }
+
+    #[test]
+    fn emitted_matrices_rebuild_the_warp() {
+        let (surface, camera, image_points) = dome_fixture(9, 6);
+        let grid = GridSpec {cols: 9, rows: 6};
+        let scene = locate_scene_coords(&surface, &camera, &image_points).unwrap();
+        let look_at = calculate_look_at(&surface, &image_points, &camera).unwrap();
+        let projector_res = Resolution {width: 1920, height: 1080};
+        let camera_meta = output::PhysicalCameraMeta {position: [0., 0., 0.], look_at: [0., 1., 0.], up: [0., 0., -1.]};
+        let conventions = [
+            output::OutputConventions::default(),
+            output::OutputConventions {
+                flip_y: true,
+                units: output::WarpUnits::Pixels,
+                order: output::WarpOrder::ColumnMajor,
+                transform: output::OutputTransform {scale: 0.001, axes: output::AxisConvention::ZUpRightHanded},
+            },
+        ];
+        let orientations = [ProjectorOrientation::Landscape, ProjectorOrientation::Portrait90, ProjectorOrientation::Portrait270, ProjectorOrientation::Rotated180];
+        for orientation in orientations.iter() {
+            let mut meta = output::Meta::new(surface, camera_meta.clone(), None, grid, projector_res, output::CameraSourceMeta::Simulated);
+            meta.projector_orientation = *orientation;
+            let mut virtual_camera = VirtualCamera::new(vec3(0., 0., 0.));
+            let result = compute_calibration_from_scene(&scene, None, look_at, &mut virtual_camera, grid, projector_res, *orientation, meta, &mut crate::progress::NoProgress, &mut None).unwrap();
+            for conventions in conventions.iter() {
+                let json = calibration_json_string(&result, conventions, projector_res);
+                let emitted = CalibrationResult::from_json(&json).unwrap();
+                let tolerance = if conventions.units == output::WarpUnits::Pixels { 0.05 } else { 1e-4 };
+                for (rebuilt, warp) in warp_from_matrices(&emitted).iter().zip(emitted.warp.iter()) {
+                    assert!(length(*rebuilt - *warp) < tolerance, "{:?} {:?}: matrices give {:?} for {:?}", orientation, conventions, rebuilt, warp);
+                }
+
+                // and reverting the conventions keeps them consistent
+                let reverted = conventions.revert(&emitted, projector_res);
+                for (rebuilt, warp) in warp_from_matrices(&reverted).iter().zip(result.warp.iter()) {
+                    assert!(length(*rebuilt - *warp) < 1e-4, "{:?} {:?} reverted: matrices give {:?} for {:?}", orientation, conventions, rebuilt, warp);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scene_points_project_where_the_eye_sees_them() {
+        let mut virtual_camera = VirtualCamera::new(vec3(0., 0., 0.));
+        virtual_camera.look_at = Some(vec3(0., 0., -10.));
+        virtual_camera.fov = Some(90.);
+        virtual_camera.clip_planes = Some((1., 100.));
+        let aspect = 2.;
+        let close = |a: glm::Vec2, b: glm::Vec2| length(a - b) < 1e-4;
+
+        assert!(close(project_scene_point(vec3(0., 0., -10.), &virtual_camera, aspect), vec2(0.5, 0.5)));
+        // the top and right edges of a 90 degree fov, 2 wide for every 1 high
+        assert!(close(project_scene_point(vec3(0., 10., -10.), &virtual_camera, aspect), vec2(0.5, 1.)));
+        assert!(close(project_scene_point(vec3(20., 0., -10.), &virtual_camera, aspect), vec2(1., 0.5)));
+        assert!(close(project_scene_point(vec3(-10., -5., -10.), &virtual_camera, aspect), vec2(0.25, 0.25)));
+        // depth along the ray doesn't move it
+        assert!(close(project_scene_point(vec3(1., 2., -3.), &virtual_camera, aspect), project_scene_point(vec3(2., 4., -6.), &virtual_camera, aspect)));
+
+        // rolled upside down the content is mirrored through the center
+        virtual_camera.up_dir = vec3(0., -1., 0.);
+        assert!(close(project_scene_point(vec3(-10., -5., -10.), &virtual_camera, aspect), vec2(0.75, 0.75)));
+    }
+
+    #[test]
+    fn reversed_corners_are_put_back() {
+        let (_, _, detected) = dome_fixture(9, 6);
+        let mut reversed = detected.clone();
+        reversed.points.reverse();
+        reversed.valid[0] = false;
+        reversed.confidence[0] = 0.25;
+
+        // the cue lit the last detected corner, so they were found from the far end
+        let mut corners = reversed.clone();
+        assert!(orient_corners(&mut corners, 40., 200.));
+        assert_eq!(corners.points, detected.points);
+        assert_eq!(corners.get(8, 5), None);
+        assert_eq!(corners.confidence[corners.len() - 1], 0.25);
+        assert_eq!(corners.get(0, 0), Some(detected.points[0]));
+
+        // already in order, or a cue too faint to tell
+        let mut corners = detected.clone();
+        assert!(!orient_corners(&mut corners, 200., 40.));
+        assert_eq!(corners.points, detected.points);
+        let mut corners = reversed.clone();
+        assert!(!orient_corners(&mut corners, 100., 110.));
+        assert_eq!(corners.points, reversed.points);
+
+        // rotating twice is no rotation
+        let mut corners = reversed.clone();
+        corners.rotate_180();
+        corners.rotate_180();
+        assert_eq!(corners.points, reversed.points);
+        assert_eq!(corners.valid, reversed.valid);
+    }
+
+    #[test]
+    fn incomplete_grid_has_no_scene_coords() {
+        let (surface, camera, mut image_points) = dome_fixture(9, 6);
+        image_points.valid[3] = false;
+        assert!(locate_scene_coords(&surface, &camera, &image_points).is_err());
+    }
+}
+
+#[cfg(all(test, feature = "opencv"))]
+mod opencv_tests {
+    use super::*;
+
+    /// The subpixel window is a quarter of the corner spacing, and never vanishingly small
+    #[test]
+    fn subpixel_window_follows_the_corner_spacing() {
+        let grid = GridSpec::new(9, 6).unwrap();
+        let points = |spacing: f32| -> Vec<glm::Vec2> {
+            (0..grid.len()).map(|i| vec2((i as i32 % grid.cols) as f32 * spacing + 100., (i as i32 / grid.cols) as f32 * spacing + 100.)).collect()
+        };
+        assert!((corner_spacing(&points(40.), grid) - 40.).abs() < 1e-3);
+        assert_eq!(subpixel_half_window(&points(40.), grid), 10);
+        assert_eq!(subpixel_half_window(&points(120.), grid), 30);
+        assert_eq!(subpixel_half_window(&points(3.), grid), 2);
+    }
+
+    /// Every inner corner of a rendered chessboard is detected
+    #[test]
+    fn detects_every_corner_of_the_generated_board() {
+        for (cols, rows) in [(9, 6), (7, 4), (11, 8)].iter() {
+            let grid = GridSpec::new(*cols, *rows).unwrap();
+            let board = images::chessboard(grid).unwrap();
+            let mut grey = Mat::default().unwrap();
+            cvt_color(&board, &mut grey, COLOR_BGR2GRAY, 0).unwrap();
+            // a quiet white margin, like the projection surface around a full screen board
+            let mut photo = Mat::default().unwrap();
+            copy_make_border(&grey, &mut photo, 50, 50, 50, 50, BORDER_CONSTANT, Scalar::all(255.)).unwrap();
+            let (corners, _) = locate_chessboard_corners(&photo, grid, &DetectionOptions::default(), images::ImageEncoding::DEBUG_DEFAULT).unwrap();
+            assert_eq!((corners.cols, corners.rows), (grid.cols, grid.rows));
+            assert_eq!(corners.valid_points().count(), grid.len());
+        }
+    }
+
+    /// A quarter of the frame fails a 30% minimum, quiet warnings don't change that
+    #[test]
+    fn coverage_below_the_minimum_fails() {
+        let quarter = coverage::measure(&[vec2(0., 0.), vec2(50., 0.), vec2(50., 50.), vec2(0., 50.)], 100, 100, None);
+        let detection = DetectionOptions {min_camera_coverage: Some(0.3), ..DetectionOptions::default()};
+        assert!(report_coverage(&quarter, &detection).is_err());
+        assert!(report_coverage(&quarter, &DetectionOptions {coverage_warnings: false, ..detection.clone()}).is_err());
+        assert!(report_coverage(&quarter, &DetectionOptions {min_camera_coverage: Some(0.2), ..detection}).is_ok());
+    }
+
+    #[test]
+    fn boards_that_arent_odd_by_even_arent_drawn() {
+        assert!(images::chessboard(GridSpec {cols: 8, rows: 6}).is_err());
+        assert!(images::chessboard(GridSpec {cols: 9, rows: 5}).is_err());
+    }
+}
@@ -0,0 +1,33 @@
+/// Something notable that happened during a calibration run, for driving a UI
+#[derive(Clone, Debug)]
+pub enum CalibrationEvent {
+    DisplayingPattern {description: String},
+    /// the operator has been asked to do something and the run is blocked until they do
+    WaitingForOperator {message: String},
+    /// the photo exactly as the camera delivered it (encoded jpeg, png, etc)
+    PhotoCaptured {bytes: Vec<u8>},
+    /// chessboard corner positions in the undistorted photo
+    CornersDetected {found: usize, expected: usize, corners: Vec<glm::Vec2>},
+    SceneComputed {scene: Vec<glm::Vec3>},
+    FovComputed {fov: f32},
+    Posting,
+    Done,
+}
+
+/// Receives events as a calibration run progresses. Implemented for any `FnMut(CalibrationEvent)`.
+pub trait ProgressSink {
+    fn event(&mut self, event: CalibrationEvent);
+}
+
+impl<F: FnMut(CalibrationEvent)> ProgressSink for F {
+    fn event(&mut self, event: CalibrationEvent) {
+        self(event)
+    }
+}
+
+/// The default sink, ignores everything
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn event(&mut self, _event: CalibrationEvent) {}
+}
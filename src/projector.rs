@@ -0,0 +1,225 @@
+//! How the projector is physically mounted.
+
+#[cfg(feature = "opencv")]
+use opencv::{prelude::*, core::{self, Mat}};
+use serde::{Serialize, Deserialize};
+use super::Resolution;
+
+/// Rotation of the projector's image on the surface. Portrait90 means that without correction
+/// the image appears rotated 90° clockwise on the surface (projector rolled clockwise), so
+/// patterns are rotated 90° counter clockwise before being shown. Resolutions passed around
+/// are always the projector's native (unrotated) pixel dimensions.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ProjectorOrientation {
+    Landscape,
+    Portrait90,
+    Portrait270,
+    Rotated180,
+}
+
+impl Default for ProjectorOrientation {
+    fn default() -> ProjectorOrientation {
+        ProjectorOrientation::Landscape
+    }
+}
+
+impl ProjectorOrientation {
+    pub fn parse(input: &str) -> Result<ProjectorOrientation, &'static str> {
+        match input {
+            "landscape" => Ok(ProjectorOrientation::Landscape),
+            "portrait90" => Ok(ProjectorOrientation::Portrait90),
+            "portrait270" => Ok(ProjectorOrientation::Portrait270),
+            "rotated180" => Ok(ProjectorOrientation::Rotated180),
+            _ => Err("orientation must be landscape, portrait90, portrait270 or rotated180")
+        }
+    }
+
+    fn is_portrait(&self) -> bool {
+        match self {
+            ProjectorOrientation::Portrait90 | ProjectorOrientation::Portrait270 => true,
+            _ => false
+        }
+    }
+
+    /// Dimensions of the image as it appears upright on the surface
+    pub fn effective_resolution(&self, native: Resolution) -> Resolution {
+        if self.is_portrait() {
+            Resolution {width: native.height, height: native.width}
+        } else {
+            native
+        }
+    }
+
+    /// Rotate an upright image into the projector's native pixel layout
+    #[cfg(feature = "opencv")]
+    pub fn to_native_image(&self, upright: &Mat) -> opencv::Result<Mat> {
+        let mut native = Mat::default()?;
+        let code = match self {
+            ProjectorOrientation::Landscape => {
+                upright.copy_to(&mut native)?;
+                return Ok(native);
+            },
+            ProjectorOrientation::Portrait90 => core::ROTATE_90_COUNTERCLOCKWISE,
+            ProjectorOrientation::Portrait270 => core::ROTATE_90_CLOCKWISE,
+            ProjectorOrientation::Rotated180 => core::ROTATE_180,
+        };
+        core::rotate(upright, &mut native, code)?;
+        Ok(native)
+    }
+
+    /// Convert a normalized (0-1, y up) position in the upright image to the same point in
+    /// the projector's native image
+    pub fn to_native_uv(&self, uv: glm::Vec2) -> glm::Vec2 {
+        match self {
+            ProjectorOrientation::Landscape => uv,
+            ProjectorOrientation::Portrait90 => glm::vec2(1. - uv.y, uv.x),
+            ProjectorOrientation::Portrait270 => glm::vec2(uv.y, 1. - uv.x),
+            ProjectorOrientation::Rotated180 => glm::vec2(1. - uv.x, 1. - uv.y),
+        }
+    }
+
+    /// `to_native_uv` as the affine map m * uv + t, m row by row
+    pub fn native_uv_transform(&self) -> ([[f32; 2]; 2], [f32; 2]) {
+        match self {
+            ProjectorOrientation::Landscape => ([[1., 0.], [0., 1.]], [0., 0.]),
+            ProjectorOrientation::Portrait90 => ([[0., -1.], [1., 0.]], [1., 0.]),
+            ProjectorOrientation::Portrait270 => ([[0., 1.], [-1., 0.]], [0., 1.]),
+            ProjectorOrientation::Rotated180 => ([[-1., 0.], [0., -1.]], [1., 1.]),
+        }
+    }
+}
+
+/// Known optics of the projector. The renderer builds its (possibly off axis) projection from
+/// these instead of a symmetric fov, so when given the warp is calculated with the same
+/// frustum.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ProjectorOptics {
+    /// throw distance / image width
+    pub throw_ratio: f32,
+    /// shift of the image center as a fraction of the image width, positive is right
+    pub lens_shift_x: f32,
+    /// shift of the image center as a fraction of the image height, positive is up
+    pub lens_shift_y: f32,
+}
+
+impl ProjectorOptics {
+    /// Tangents of the angles from the lens axis to the left, right, bottom and top edges of
+    /// the image, for an image of the given aspect ratio (width/height)
+    pub fn frustum_tangents(&self, aspect_ratio: f32) -> [f32; 4] {
+        let half_width = 0.5 / self.throw_ratio;
+        let half_height = half_width / aspect_ratio;
+        let cx = self.lens_shift_x * half_width * 2.;
+        let cy = self.lens_shift_y * half_height * 2.;
+        [cx - half_width, cx + half_width, cy - half_height, cy + half_height]
+    }
+
+    /// Off axis projection matrix, like glFrustum
+    pub fn projection(&self, aspect_ratio: f32, near: f32, far: f32) -> glm::Mat4 {
+        let [l, r, b, t] = self.frustum_tangents(aspect_ratio);
+        glm::Matrix4::new(
+            glm::vec4(2. / (r - l), 0., 0., 0.),
+            glm::vec4(0., 2. / (t - b), 0., 0.),
+            glm::vec4((r + l) / (r - l), (t + b) / (t - b), -(far + near) / (far - near), -1.),
+            glm::vec4(0., 0., -2. * far * near / (far - near), 0.)
+        )
+    }
+}
+
+/// The part of the upright projector raster the chessboard is drawn in, the rest of the frame
+/// is black. For wide rasters whose edges fall off the screen. In 0-1 across the raster from
+/// the top left.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PatternPlacement {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl PatternPlacement {
+    /// "x,y,width,height" in 0-1 across the raster, or in pixels of the upright raster when
+    /// suffixed with "px"
+    pub fn parse(input: &str, upright: Resolution) -> Result<PatternPlacement, String> {
+        let format = "pattern placement must be in the form \"x,y,width,height\" or \"x,y,width,heightpx\"";
+        let input = input.trim();
+        let pixels = input.ends_with("px");
+        let values = if pixels { &input[..input.len() - 2] } else { input };
+        let values = values.split(',').map(|v| v.trim().parse::<f32>()).collect::<Result<Vec<f32>, _>>().map_err(|_| format.to_string())?;
+        if values.len() != 4 {
+            return Err(format.to_string());
+        }
+        let placement = if pixels {
+            let (w, h) = (upright.width as f32, upright.height as f32);
+            PatternPlacement {x: values[0] / w, y: values[1] / h, width: values[2] / w, height: values[3] / h}
+        } else {
+            PatternPlacement {x: values[0], y: values[1], width: values[2], height: values[3]}
+        };
+        placement.check()?;
+        Ok(placement)
+    }
+
+    /// Err when the rectangle is empty or not inside the raster
+    pub fn check(&self) -> Result<(), String> {
+        if self.width <= 0. || self.height <= 0. {
+            return Err(format!("pattern placement {:?} is empty", self));
+        }
+        if self.x < 0. || self.y < 0. || self.x + self.width > 1. + 1e-6 || self.y + self.height > 1. + 1e-6 {
+            return Err(format!("pattern placement {:?} is outside the projector raster", self));
+        }
+        Ok(())
+    }
+
+    /// Convert a position 0-1 across the placed pattern to 0-1 across the raster
+    pub fn to_raster(&self, p: glm::Vec2) -> glm::Vec2 {
+        glm::vec2(self.x + p.x * self.width, self.y + p.y * self.height)
+    }
+
+    /// Convert a position 0-1 across the raster to 0-1 across the placed pattern, outside 0-1
+    /// when it's beyond the pattern
+    pub fn from_raster(&self, p: glm::Vec2) -> glm::Vec2 {
+        glm::vec2((p.x - self.x) / self.width, (p.y - self.y) / self.height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIENTATIONS: [ProjectorOrientation; 4] = [
+        ProjectorOrientation::Landscape,
+        ProjectorOrientation::Portrait90,
+        ProjectorOrientation::Portrait270,
+        ProjectorOrientation::Rotated180,
+    ];
+
+    #[test]
+    fn native_uv_transform_is_to_native_uv() {
+        for orientation in ORIENTATIONS.iter() {
+            let (m, t) = orientation.native_uv_transform();
+            for uv in [glm::vec2(0., 0.), glm::vec2(1., 0.), glm::vec2(0.25, 0.8), glm::vec2(1., 1.)].iter() {
+                let mapped = glm::vec2(m[0][0] * uv.x + m[0][1] * uv.y + t[0], m[1][0] * uv.x + m[1][1] * uv.y + t[1]);
+                assert!(glm::length(mapped - orientation.to_native_uv(*uv)) < 1e-6, "{:?} maps {:?} to {:?}", orientation, uv, mapped);
+            }
+        }
+    }
+
+    #[test]
+    fn pattern_placement_parse_and_mapping() {
+        let upright = Resolution {width: 1920, height: 1080};
+        let inset = PatternPlacement {x: 0.25, y: 0.25, width: 0.5, height: 0.5};
+        assert_eq!(PatternPlacement::parse("0.25,0.25,0.5,0.5", upright), Ok(inset));
+        assert_eq!(PatternPlacement::parse(" 480, 270, 960, 540px", upright), Ok(inset));
+        for input in ["0.25,0.25,0.5", "0,0,0,0.5", "0.75,0,0.5,0.5", "a,b,c,d"].iter() {
+            assert!(PatternPlacement::parse(input, upright).is_err(), "{} was accepted", input);
+        }
+
+        // the middle of the pattern is the middle of the raster, its corners the inset's
+        assert!(glm::length(inset.to_raster(glm::vec2(0.5, 0.5)) - glm::vec2(0.5, 0.5)) < 1e-6);
+        assert!(glm::length(inset.to_raster(glm::vec2(1., 0.)) - glm::vec2(0.75, 0.25)) < 1e-6);
+        let p = glm::vec2(0.1, 0.9);
+        assert!(glm::length(inset.from_raster(inset.to_raster(p)) - p) < 1e-6);
+    }
+}
@@ -0,0 +1,90 @@
+//! Asking the operator to do something when patterns are shown by hand. GUI hosts implement
+//! `OperatorPrompt` with a dialog, the CLI reads a line from stdin.
+
+use std::fmt;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum PromptError {
+    /// there's nobody to ask, the message says what they would have been asked
+    NotInteractive(String),
+    /// nobody answered in time
+    TimedOut(Duration),
+    /// stdin closed before the operator answered
+    Closed,
+    Io(std::io::Error),
+    /// the async calibration waiting for the answer was dropped
+    Cancelled,
+}
+
+impl fmt::Display for PromptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PromptError::NotInteractive(message) => write!(
+                f,
+                "the operator would be asked to {} but the run is non-interactive. Use a control URL or a fullscreen monitor to show patterns.",
+                message
+            ),
+            PromptError::TimedOut(timeout) => write!(f, "no answer from the operator after {}s", timeout.as_secs_f32()),
+            PromptError::Closed => write!(f, "stdin closed while waiting for the operator"),
+            PromptError::Io(err) => write!(f, "{}", err),
+            PromptError::Cancelled => write!(f, "the calibration was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for PromptError {}
+
+/// Something that can ask the operator to do something and wait until they have
+pub trait OperatorPrompt {
+    /// Show the message and block until the operator confirms it's done
+    fn wait(&self, message: &str) -> Result<(), PromptError>;
+}
+
+/// Print to stderr and wait for enter on stdin, the CLI default
+pub struct StdinPrompt;
+
+impl OperatorPrompt for StdinPrompt {
+    fn wait(&self, message: &str) -> Result<(), PromptError> {
+        eprintln!("Please {} and press enter", message);
+        let mut line = String::new();
+        match std::io::stdin().read_line(&mut line) {
+            Ok(0) => Err(PromptError::Closed),
+            Ok(_) => Ok(()),
+            Err(err) => Err(PromptError::Io(err)),
+        }
+    }
+}
+
+/// Gives up on another prompt if it isn't answered in time. The inner prompt keeps waiting
+/// on its own thread, its answer is ignored.
+pub struct TimeoutPrompt {
+    pub inner: Arc<dyn OperatorPrompt + Send + Sync>,
+    pub timeout: Duration,
+}
+
+impl OperatorPrompt for TimeoutPrompt {
+    fn wait(&self, message: &str) -> Result<(), PromptError> {
+        let (sender, receiver) = mpsc::channel();
+        let inner = self.inner.clone();
+        let message = message.to_string();
+        thread::spawn(move || {
+            let _ = sender.send(inner.wait(&message));
+        });
+        match receiver.recv_timeout(self.timeout) {
+            Ok(result) => result,
+            Err(_) => Err(PromptError::TimedOut(self.timeout)),
+        }
+    }
+}
+
+/// For runs with nobody watching, fails as soon as the operator would be asked anything
+pub struct NonInteractive;
+
+impl OperatorPrompt for NonInteractive {
+    fn wait(&self, message: &str) -> Result<(), PromptError> {
+        Err(PromptError::NotInteractive(message.to_string()))
+    }
+}
@@ -0,0 +1,162 @@
+//! Adjusting the surface model to the captured data. With several cameras at known poses each
+//! chessboard corner is seen along several rays, and those rays only meet on the surface when
+//! the surface model matches the real one.
+
+use glm::*;
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use super::Error;
+use super::math;
+use super::surfaces::{SurfaceType, SceneMapper, CameraModel};
+
+/// Iterations of the simplex search before giving up
+pub const MAX_ITERATIONS: usize = 500;
+
+/// A surface parameter that can be refined, the others are held at their nominal values
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum SurfaceParameter {
+    /// the direction a flat surface faces
+    Normal,
+    /// how far a flat surface is from the origin along its normal
+    Offset,
+}
+
+impl SurfaceParameter {
+    pub fn parse(input: &str) -> Result<SurfaceParameter, &'static str> {
+        match input {
+            "normal" => Ok(SurfaceParameter::Normal),
+            "offset" => Ok(SurfaceParameter::Offset),
+            _ => Err("surface parameter must be normal or offset")
+        }
+    }
+}
+
+/// The corners one camera detected, as (grid index, photo point)
+pub struct Observation {
+    pub camera: CameraModel,
+    pub points: Vec<(usize, glm::Vec2)>,
+}
+
+/// What refinement did to the surface
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SurfaceRefinement {
+    pub nominal: SurfaceType,
+    /// the surface used for the rest of the run, the nominal one when refinement was rejected
+    pub surface: SurfaceType,
+    pub parameters: Vec<SurfaceParameter>,
+    /// rms distance between each camera's scene point and the corner's mean, over every
+    /// corner seen by more than one camera. In scene units.
+    pub residual_before: f32,
+    pub residual_after: f32,
+    pub iterations: usize,
+    /// false when the refined surface didn't reduce the residual and the nominal one was kept
+    pub accepted: bool,
+}
+
+/// Refine the chosen parameters of a flat surface so the cameras' scene points for each
+/// corner agree as closely as possible. The dome mapping ignores the camera pose, so there's
+/// nothing to constrain its radius with and it's an error to ask.
+pub fn refine_surface(nominal: &SurfaceType, observations: &[Observation], parameters: &[SurfaceParameter]) -> Result<SurfaceRefinement, Error> {
+    let (normal, offset) = nominal.plane().ok_or(Error::Config(
+        "the dome is mapped without the camera poses, so the captured data can't constrain its radius".to_string()
+    ))?;
+    if parameters.is_empty() {
+        return Err(Error::Config("no surface parameters chosen for refinement".to_string()));
+    }
+    let residual_before = disagreement(nominal, observations).ok_or(Error::Geometry(
+        "surface refinement needs corners seen by more than one camera, all on the nominal surface".to_string()
+    ))?;
+
+    // the normal is tilted along two directions perpendicular to it
+    let other = if normal.x.abs() < 0.9 { vec3(1., 0., 0.) } else { vec3(0., 1., 0.) };
+    let tangent = normalize(cross(normal, other));
+    let bitangent = cross(normal, tangent);
+    let refine_normal = parameters.contains(&SurfaceParameter::Normal);
+    let refine_offset = parameters.contains(&SurfaceParameter::Offset);
+    let surface_at = |x: &[f32]| -> SurfaceType {
+        let mut values = x.iter();
+        let mut n = normal;
+        if refine_normal {
+            n = normalize(normal + tangent * *values.next().unwrap() + bitangent * *values.next().unwrap());
+        }
+        let d = if refine_offset { offset + values.next().unwrap() } else { offset };
+        SurfaceType::Plane {normal: *n.as_array(), offset: d}
+    };
+
+    // offset steps start at a twentieth of the distance from the cameras to the surface
+    let scale = camera_distance(nominal, observations).max(1e-3);
+    let mut steps = vec![];
+    if refine_normal {
+        steps.extend_from_slice(&[0.05, 0.05]);
+    }
+    if refine_offset {
+        steps.push(scale * 0.05);
+    }
+    let start = vec![0_f32; steps.len()];
+    let (best, residual_after, iterations) = math::nelder_mead(
+        |x| disagreement(&surface_at(x), observations).unwrap_or(std::f32::INFINITY),
+        &start,
+        &steps,
+        MAX_ITERATIONS,
+        residual_before * 1e-6
+    );
+
+    let accepted = residual_after.is_finite() && residual_after < residual_before;
+    let surface = if accepted {
+        info!("surface refinement reduced the cross-camera disagreement from {} to {} rms", residual_before, residual_after);
+        surface_at(&best)
+    } else {
+        warn!("surface refinement didn't reduce the cross-camera disagreement ({} to {} rms), keeping the nominal surface", residual_before, residual_after);
+        *nominal
+    };
+    Ok(SurfaceRefinement {
+        nominal: *nominal,
+        surface: surface,
+        parameters: parameters.to_vec(),
+        residual_before: residual_before,
+        residual_after: residual_after,
+        iterations: iterations,
+        accepted: accepted,
+    })
+}
+
+/// Rms distance of each camera's scene point from the corner's mean. None when no corner is
+/// seen by two cameras or a camera's point misses the surface.
+fn disagreement(surface: &SurfaceType, observations: &[Observation]) -> Option<f32> {
+    let mut seen: std::collections::HashMap<usize, Vec<glm::Vec3>> = std::collections::HashMap::new();
+    for observation in observations {
+        let mapper = SceneMapper::new(surface, &observation.camera);
+        for (index, point) in observation.points.iter() {
+            seen.entry(*index).or_default().push(mapper.map(*point).ok()?);
+        }
+    }
+    let (mut sum_sq, mut samples) = (0_f32, 0);
+    for points in seen.values().filter(|points| points.len() > 1) {
+        let mut mean = vec3(0., 0., 0.);
+        for p in points { mean = mean + *p; }
+        mean = mean / points.len() as f32;
+        for p in points {
+            let d = length(*p - mean);
+            sum_sq += d * d;
+            samples += 1;
+        }
+    }
+    if samples > 0 { Some((sum_sq / samples as f32).sqrt()) } else { None }
+}
+
+/// Mean distance from the cameras to the scene points they see on the surface
+fn camera_distance(surface: &SurfaceType, observations: &[Observation]) -> f32 {
+    let (mut sum, mut n) = (0_f32, 0);
+    for observation in observations {
+        let mapper = SceneMapper::new(surface, &observation.camera);
+        for (_, point) in observation.points.iter() {
+            if let Ok(scene) = mapper.map(*point) {
+                sum += length(scene - observation.camera.position);
+                n += 1;
+            }
+        }
+    }
+    if n > 0 { sum / n as f32 } else { 0. }
+}
@@ -0,0 +1,172 @@
+//! A self-contained HTML page describing a calibration, for handing to the venue after a
+//! visit. It's built from a saved session, so it can be regenerated offline at any time.
+
+use opencv::{prelude::*, core::{Mat, Point, Scalar, Size, CV_8UC3}, imgcodecs, imgproc::*};
+use glm::*;
+use std::path::Path;
+use super::{CalibrationResult, GridSpec, Error};
+use super::{images, pipeline, session};
+use super::timings::Timings;
+
+/// Widest image embedded in the page, larger photos are scaled down
+const MAX_IMAGE_WIDTH: i32 = 1600;
+/// Width of the warp grid plot, its height follows the projector's aspect ratio
+const PLOT_WIDTH: i32 = 800;
+/// Size in pixels of each corner's square in the irregularity heatmap
+const HEATMAP_CELL: i32 = 24;
+
+/// The report for a calibration made from the session saved in session_dir. result can be
+/// the document as posted, in any output conventions, or as returned by the library.
+pub fn session_report(session_dir: &str, result: &CalibrationResult) -> Result<String, Error> {
+    let record = session::load_session(session_dir)?;
+    let result = match &result.meta {
+        Some(meta) => meta.output_conventions.revert(result, meta.projector_resolution),
+        None => result.clone(),
+    };
+    let photo_data = Mat::from_slice(&std::fs::read(session::photo_path(session_dir, &record))?)?;
+    let (photo, _) = pipeline::to_8bit(&pipeline::decode_photo(&photo_data)?)?;
+    let undistorted = imgcodecs::imread(&Path::new(session_dir).join(session::UNDISTORTED_FILE).to_string_lossy(), imgcodecs::IMREAD_COLOR)?;
+    let irregularity = grid_irregularity(&record.image_points, record.warp_resolution);
+    let max_irregularity = irregularity.iter().filter_map(|v| *v).fold(0_f32, f32::max);
+
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Calibration report</title>\n");
+    html.push_str("<style>body{font-family:sans-serif;margin:2em}img{max-width:100%;border:1px solid #ccc}td,th{padding:2px 12px;text-align:left}pre{background:#f4f4f4;padding:1em}</style>\n");
+    html.push_str("</head><body>\n<h1>Calibration report</h1>\n");
+
+    html.push_str("<h2>Virtual camera</h2>\n<table>\n");
+    row(&mut html, "fov", &format!("{:.3}°", result.fov));
+    row(&mut html, "eye", &vector(result.eye));
+    row(&mut html, "look at", &vector(result.look_at));
+    row(&mut html, "up", &vector(result.up));
+    row(&mut html, "warp grid", &format!("{}x{}", result.warp_res_x, result.warp_res_y));
+    row(&mut html, "surface", &serde_json::to_string(&result.meta.as_ref().map(|meta| meta.surface).unwrap_or(record.surface))?);
+    row(&mut html, "projector", &format!("{} {:?}", record.projector_resolution, record.projector_orientation));
+    html.push_str("</table>\n");
+
+    html.push_str("<h2>Captured photo</h2>\n");
+    image(&mut html, "captured photo", &photo)?;
+    html.push_str("<h2>Detected corners</h2>\n<p>on the undistorted photo</p>\n");
+    image(&mut html, "detected corners", &with_corners(&undistorted, &record.image_points)?)?;
+    html.push_str("<h2>Undistorted photo</h2>\n");
+    image(&mut html, "undistorted photo", &undistorted)?;
+    html.push_str("<h2>Warp grid</h2>\n");
+    image(&mut html, "warp grid", &warp_plot(&result, record.projector_resolution.aspect_ratio())?)?;
+    html.push_str(&format!(
+        "<h2>Corner irregularity</h2>\n<p>distance of each detected corner from the midpoint of its neighbours, blue 0 to red {:.2} photo pixels. Isolated hot corners are likely misdetections.</p>\n",
+        max_irregularity
+    ));
+    image(&mut html, "corner irregularity", &images::heatmap(&irregularity, record.warp_resolution.cols, max_irregularity, HEATMAP_CELL)?)?;
+
+    if let Some(diagnostics) = &result.diagnostics {
+        if let Some(timings) = &diagnostics.timings {
+            html.push_str("<h2>Timings</h2>\n<table>\n");
+            timing_rows(&mut html, timings);
+            html.push_str("</table>\n");
+        }
+        html.push_str(&format!("<h2>Diagnostics</h2>\n<pre>{}</pre>\n", escape(&serde_json::to_string_pretty(diagnostics)?)));
+    }
+    if let Some(meta) = &result.meta {
+        html.push_str(&format!("<h2>Meta</h2>\n<pre>{}</pre>\n", escape(&serde_json::to_string_pretty(meta)?)));
+    }
+    html.push_str("</body></html>\n");
+    Ok(html)
+}
+
+/// `session_report` written to out_fname
+pub fn write_session_report(session_dir: &str, result: &CalibrationResult, out_fname: &str) -> Result<(), Error> {
+    std::fs::write(out_fname, session_report(session_dir, result)?)?;
+    Ok(())
+}
+
+/// For each corner, how far it is from the midpoint of its left and right neighbours or its
+/// upper and lower ones, whichever is further. Edge corners are None.
+fn grid_irregularity(points: &[glm::Vec2], grid: GridSpec) -> Vec<Option<f32>> {
+    if points.len() != grid.len() {
+        return vec![None; grid.len()];
+    }
+    let at = |col: i32, row: i32| points[(row * grid.cols + col) as usize];
+    let mut irregularity = vec![];
+    for row in 0..grid.rows {
+        for col in 0..grid.cols {
+            let mut worst: Option<f32> = None;
+            if col > 0 && col < grid.cols - 1 {
+                worst = Some(length(at(col, row) - (at(col - 1, row) + at(col + 1, row)) * 0.5));
+            }
+            if row > 0 && row < grid.rows - 1 {
+                let d = length(at(col, row) - (at(col, row - 1) + at(col, row + 1)) * 0.5);
+                worst = Some(worst.map(|w| w.max(d)).unwrap_or(d));
+            }
+            irregularity.push(worst);
+        }
+    }
+    irregularity
+}
+
+/// A copy of image with a circle drawn on each corner
+fn with_corners(image: &Mat, points: &[glm::Vec2]) -> Result<Mat, Error> {
+    let mut out = Mat::default()?;
+    image.copy_to(&mut out)?;
+    let radius = (image.cols() / 300).max(2);
+    for p in points {
+        circle(&mut out, Point::new(p.x.round() as i32, p.y.round() as i32), radius, Scalar::new(0., 0., 255., 0.), -1, LINE_AA, 0)?;
+    }
+    Ok(out)
+}
+
+/// The warp's uv points joined to their neighbours, with v pointing up
+fn warp_plot(result: &CalibrationResult, aspect_ratio: f32) -> Result<Mat, Error> {
+    let (w, h) = (PLOT_WIDTH, (PLOT_WIDTH as f32 / aspect_ratio).round() as i32);
+    let mut plot = Mat::new_rows_cols_with_default(h, w, CV_8UC3, Scalar::all(255.))?;
+    let cols = result.warp_res_x;
+    let valid = |i: usize| result.valid.as_ref().map(|valid| valid[i]).unwrap_or(true);
+    let at = |i: usize| Point::new((result.warp[i].x * w as f32).round() as i32, ((1. - result.warp[i].y) * h as f32).round() as i32);
+    for i in 0..result.warp.len() {
+        let (col, row) = (i as i32 % cols, i as i32 / cols);
+        let colour = if valid(i) { Scalar::new(80., 40., 0., 0.) } else { Scalar::new(160., 160., 160., 0.) };
+        if col + 1 < cols {
+            line(&mut plot, at(i), at(i + 1), colour, 1, LINE_AA, 0)?;
+        }
+        if row + 1 < result.warp_res_y {
+            line(&mut plot, at(i), at(i + cols as usize), colour, 1, LINE_AA, 0)?;
+        }
+    }
+    Ok(plot)
+}
+
+/// Append image to the page as an embedded PNG
+fn image(html: &mut String, alt: &str, image: &Mat) -> Result<(), Error> {
+    let mut scaled = Mat::default()?;
+    if image.cols() > MAX_IMAGE_WIDTH {
+        let height = (image.rows() as f64 * MAX_IMAGE_WIDTH as f64 / image.cols() as f64).round() as i32;
+        resize(image, &mut scaled, Size::new(MAX_IMAGE_WIDTH, height), 0., 0., INTER_AREA)?;
+    } else {
+        image.copy_to(&mut scaled)?;
+    }
+    let png = images::encode_image(&scaled, ".png");
+    html.push_str(&format!("<p><img alt=\"{}\" src=\"data:image/png;base64,{}\"></p>\n", alt, base64::encode(png.to_slice())));
+    Ok(())
+}
+
+fn row(html: &mut String, name: &str, value: &str) {
+    html.push_str(&format!("<tr><th>{}</th><td>{}</td></tr>\n", name, escape(value)));
+}
+
+fn timing_rows(html: &mut String, timings: &Timings) {
+    let stages = [
+        ("display", timings.display), ("capture", timings.capture), ("undistort", timings.undistort),
+        ("detection", timings.detection), ("subpixel", timings.subpixel), ("scene", timings.scene),
+        ("uv", timings.uv), ("serialization", timings.serialization), ("total", timings.total()),
+    ];
+    for (name, seconds) in stages.iter() {
+        row(html, name, &format!("{:.2}s", seconds));
+    }
+}
+
+fn vector(v: glm::Vec3) -> String {
+    format!("{:.4}, {:.4}, {:.4}", v.x, v.y, v.z)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
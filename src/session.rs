@@ -0,0 +1,170 @@
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use log::info;
+use super::{Resolution, GridSpec, Error};
+use super::surfaces::SurfaceType;
+use super::projector::{ProjectorOrientation, ProjectorOptics, PatternPlacement};
+use super::tiling::Tiling;
+use super::pipeline::LookAtMethod;
+use super::output::{glm_serde, PhysicalCameraMeta, CalibrationFileMeta};
+
+/// Name of the session record inside a session directory
+pub const SESSION_FILE: &str = "session.json";
+pub const PHOTO_FILE: &str = "photo";
+pub const UNDISTORTED_FILE: &str = "undistorted.png";
+pub const IMAGE_POINTS_FILE: &str = "image_points.json";
+
+/// Everything needed to re-run the math of a calibration without the camera or projector
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionRecord {
+    pub surface: SurfaceType,
+    pub physical_camera: PhysicalCameraMeta,
+    pub intrinsics: IntrinsicsRecord,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera_calibration: Option<CalibrationFileMeta>,
+    /// the detected chessboard's inner corners
+    pub warp_resolution: GridSpec,
+    /// output grid when it differs from warp_resolution
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warp_grid: Option<GridSpec>,
+    pub projector_resolution: Resolution,
+    #[serde(default)]
+    pub projector_orientation: ProjectorOrientation,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub projector_optics: Option<ProjectorOptics>,
+    /// where the chessboard was shown, None for the whole raster
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern_placement: Option<PatternPlacement>,
+    /// the tiles the chessboard was captured in, the photo is the first tile's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tiling: Option<Tiling>,
+    /// the corners were located with structured light, the photo is its white frame's
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub structured_light: bool,
+    /// how the patterns were encoded for the control server, e.g. "PNG, compression 9"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern_encoding: Option<String>,
+    /// how look_at was chosen, None in sessions saved before it could be, which used
+    /// `LookAtMethod::ImageCenter`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub look_at_method: Option<LookAtMethod>,
+    /// the virtual camera's up, Y up in sessions saved before it was recorded
+    #[serde(default = "default_virtual_up", with = "glm_serde::vec3")]
+    pub virtual_up: glm::Vec3,
+    /// the virtual camera's near and far planes when they were given rather than fitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clip_planes: Option<[f32; 2]>,
+    #[serde(with = "glm_serde::vec3")]
+    pub eye_position: glm::Vec3,
+    /// detected chessboard corners in the undistorted photo
+    #[serde(with = "glm_serde::vec2_list")]
+    pub image_points: Vec<glm::Vec2>,
+    /// confidence of each detected corner, parallel to image_points
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_confidence: Option<Vec<f32>>,
+    /// file name of the photo as captured, relative to the session directory
+    pub photo_file: String,
+}
+
+fn default_virtual_up() -> glm::Vec3 {
+    glm::vec3(0., 1., 0.)
+}
+
+/// Camera intrinsics as plain values
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IntrinsicsRecord {
+    /// 3x3 camera matrix, row by row
+    pub camera_matrix: [f64; 9],
+    pub distortion_coefficients: Vec<f64>,
+    pub image_width: i32,
+    pub image_height: i32,
+}
+
+/// Write the captured photo, undistorted image, detected points and session record into dir
+pub fn save_session(dir: &str, record: &SessionRecord, photo: &[u8], undistorted_png: &[u8]) -> Result<(), Error> {
+    let dir = Path::new(dir);
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(&record.photo_file), photo)?;
+    fs::write(dir.join(UNDISTORTED_FILE), undistorted_png)?;
+    let points: Vec<[f32; 2]> = record.image_points.iter().map(|p| [p.x, p.y]).collect();
+    fs::write(dir.join(IMAGE_POINTS_FILE), serde_json::to_string_pretty(&points)?)?;
+    fs::write(dir.join(SESSION_FILE), serde_json::to_string_pretty(record)?)?;
+    info!("session saved to {}", dir.display());
+    Ok(())
+}
+
+pub fn load_session(dir: &str) -> Result<SessionRecord, Error> {
+    let json = fs::read_to_string(Path::new(dir).join(SESSION_FILE))?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+/// Path of the captured photo of a saved session
+pub fn photo_path(dir: &str, record: &SessionRecord) -> PathBuf {
+    Path::new(dir).join(&record.photo_file)
+}
+
+/// Photo file name with an extension guessed from the encoded data
+pub fn photo_file_name(photo: &[u8]) -> String {
+    let ext = if photo.starts_with(&[0x89, b'P', b'N', b'G']) {
+        "png"
+    } else if photo.starts_with(&[0xff, 0xd8]) {
+        "jpg"
+    } else if photo.starts_with(b"II*\0") || photo.starts_with(b"MM\0*") {
+        "tif"
+    } else {
+        "bin"
+    };
+    format!("{}.{}", PHOTO_FILE, ext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> SessionRecord {
+        SessionRecord {
+            surface: SurfaceType::HemisphericalDome {radius: 5.},
+            physical_camera: PhysicalCameraMeta {position: [0., 0., 0.], look_at: [0., 1., 0.], up: [0., 0., 1.]},
+            intrinsics: IntrinsicsRecord {camera_matrix: [480., 0., 480., 0., 480., 300., 0., 0., 1.], distortion_coefficients: vec![0.; 5], image_width: 960, image_height: 600},
+            camera_calibration: None,
+            warp_resolution: GridSpec {cols: 9, rows: 6},
+            warp_grid: None,
+            projector_resolution: Resolution {width: 1920, height: 1080},
+            projector_orientation: ProjectorOrientation::Landscape,
+            projector_optics: None,
+            pattern_placement: None,
+            tiling: None,
+            structured_light: false,
+            pattern_encoding: None,
+            look_at_method: Some(LookAtMethod::default()),
+            virtual_up: glm::vec3(0., 0., 1.),
+            clip_planes: Some([0.5, 20.]),
+            eye_position: glm::vec3(0., 0., 0.),
+            image_points: vec![glm::vec2(1., 2.)],
+            image_confidence: None,
+            photo_file: "photo.png".to_string(),
+        }
+    }
+
+    #[test]
+    fn the_virtual_camera_is_kept() {
+        let json = serde_json::to_string(&record()).unwrap();
+        let loaded: SessionRecord = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.virtual_up, glm::vec3(0., 0., 1.));
+        assert_eq!(loaded.clip_planes, Some([0.5, 20.]));
+    }
+
+    #[test]
+    fn older_sessions_are_y_up_with_fitted_clip_planes() {
+        let mut json = serde_json::to_value(&record()).unwrap();
+        let fields = json.as_object_mut().unwrap();
+        fields.remove("virtualUp");
+        fields.remove("clipPlanes");
+        let loaded: SessionRecord = serde_json::from_value(json).unwrap();
+        assert_eq!(loaded.virtual_up, glm::vec3(0., 1., 0.));
+        assert_eq!(loaded.clip_planes, None);
+    }
+}
@@ -0,0 +1,215 @@
+use glm::*;
+use glm::ext::*;
+use super::{Resolution, GridSpec};
+use super::math::un_project;
+use super::surfaces::{self, SurfaceType, CameraModel};
+use super::projector::{ProjectorOrientation, PatternPlacement};
+use super::pipeline::LookAtMethod;
+
+/// A virtual rig: a projector lighting the surface and a physical camera photographing it
+pub struct SimulationConfig {
+    pub camera_position: glm::Vec3,
+    pub camera_direction: glm::Vec3,
+    pub camera_up: glm::Vec3,
+    /// vertical field of view in degrees
+    pub camera_fov: f32,
+    pub camera_resolution: Resolution,
+    pub projector_position: glm::Vec3,
+    pub projector_direction: glm::Vec3,
+    pub projector_up: glm::Vec3,
+    /// vertical field of view in degrees, of the projector's native image
+    pub projector_fov: f32,
+    /// how the chessboard is rotated before being shown, projector_up should match the mounting
+    pub projector_orientation: ProjectorOrientation,
+    /// the part of the upright raster the chessboard is drawn in, all of it when None
+    pub pattern_placement: Option<PatternPlacement>,
+    /// how the calibration's virtual camera picks its look_at
+    pub look_at_method: LookAtMethod,
+}
+
+/// Calculate where each inner chessboard corner would be detected in the camera photo,
+/// by casting a ray from the projector through the corner onto the surface and then
+/// projecting the hit into the camera. Row by row, starting top left, like opencv.
+pub fn simulated_image_points(surface: &SurfaceType, sim: &SimulationConfig, camera: &CameraModel, grid: GridSpec, projector_res: Resolution) -> Result<Vec<glm::Vec2>, &'static str> {
+    let model = look_at(sim.projector_position, sim.projector_position + sim.projector_direction, sim.projector_up);
+    let proj = perspective(radians(sim.projector_fov), projector_res.aspect_ratio(), 0.1, 100.);
+    let viewport = vec4(0., 0., 1., 1.);
+
+    let mut points = vec![];
+    for j in 0..grid.rows {
+        for i in 0..grid.cols {
+            // inner corners of the chessboard image as displayed, from the top left
+            let on_pattern = vec2((i + 1) as f32 / (grid.cols + 1) as f32, (j + 1) as f32 / (grid.rows + 1) as f32);
+            let on_raster = sim.pattern_placement.map(|placement| placement.to_raster(on_pattern)).unwrap_or(on_pattern);
+            let upright = vec2(on_raster.x, 1. - on_raster.y);
+            let uv = sim.projector_orientation.to_native_uv(upright);
+            let (u, v) = (uv.x, uv.y);
+
+            let near = un_project(vec3(u, v, 0.), &model, &proj, viewport)?;
+            let far = un_project(vec3(u, v, 1.), &model, &proj, viewport)?;
+            let hit = surfaces::intersect_ray(surface, near, far - near)
+                .ok_or("simulated projector ray misses the surface")?;
+            let pixel = surfaces::scene_to_camera(surface, camera, hit)
+                .ok_or("simulated surface point is not visible to the camera")?;
+            points.push(pixel);
+        }
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::project;
+
+    const ORIENTATIONS: [ProjectorOrientation; 4] = [
+        ProjectorOrientation::Landscape,
+        ProjectorOrientation::Portrait90,
+        ProjectorOrientation::Portrait270,
+        ProjectorOrientation::Rotated180,
+    ];
+
+    /// A projector at the center of a dome, tilted up and mounted for orientation,
+    /// photographed by a fisheye looking straight up
+    fn dome_rig(orientation: ProjectorOrientation) -> (SurfaceType, SimulationConfig) {
+        // up and right of the upright image
+        let (up, right) = (vec3(0., 1., 1.), vec3(1., 0., 0.));
+        let sim = SimulationConfig {
+            camera_position: vec3(0., 0., 0.),
+            camera_direction: vec3(0., 1., 0.),
+            camera_up: vec3(0., 0., -1.),
+            camera_fov: 180.,
+            camera_resolution: Resolution {width: 2000, height: 2000},
+            projector_position: vec3(0., 0., 0.),
+            projector_direction: vec3(0., 1., -1.),
+            // the native image's up
+            projector_up: match orientation {
+                ProjectorOrientation::Landscape => up,
+                ProjectorOrientation::Portrait90 => right,
+                ProjectorOrientation::Portrait270 => -right,
+                ProjectorOrientation::Rotated180 => -up,
+            },
+            projector_fov: 40.,
+            projector_orientation: orientation,
+            pattern_placement: None,
+            look_at_method: LookAtMethod::default(),
+        };
+        (SurfaceType::HemisphericalDome {radius: 5.}, sim)
+    }
+
+    /// The inverse of `ProjectorOrientation::to_native_uv`
+    fn upright_uv(orientation: ProjectorOrientation, uv: glm::Vec2) -> glm::Vec2 {
+        match orientation {
+            ProjectorOrientation::Landscape => uv,
+            ProjectorOrientation::Portrait90 => vec2(uv.y, 1. - uv.x),
+            ProjectorOrientation::Portrait270 => vec2(1. - uv.y, uv.x),
+            ProjectorOrientation::Rotated180 => vec2(1. - uv.x, 1. - uv.y),
+        }
+    }
+
+    fn check_simulated_dome(orientation: ProjectorOrientation) {
+        let (surface, sim) = dome_rig(orientation);
+        let grid = GridSpec {cols: 9, rows: 6};
+        let projector_res = Resolution {width: 1920, height: 1080};
+        let result = crate::simulate_calibration(surface, &sim, vec3(0., 0., 0.), grid, projector_res).unwrap();
+        assert_eq!(result.scene.len(), grid.len());
+        assert_eq!(result.warp.len(), grid.len());
+
+        // every scene point is on the dome, where the projector lit the chessboard corner
+        let model = look_at(sim.projector_position, sim.projector_position + sim.projector_direction, sim.projector_up);
+        let proj = perspective(radians(sim.projector_fov), projector_res.aspect_ratio(), 0.1, 100.);
+        for j in 0..grid.rows {
+            for i in 0..grid.cols {
+                let k = (j * grid.cols + i) as usize;
+                let p = result.scene[k];
+                assert!((length(p) - 5.).abs() < 1e-3, "{:?} corner {} is off the dome at {:?}", orientation, k, p);
+                let lit = project(p, &model, &proj, vec4(0., 0., 1., 1.)).truncate(2);
+                let upright = vec2((i + 1) as f32 / (grid.cols + 1) as f32, 1. - (j + 1) as f32 / (grid.rows + 1) as f32);
+                let expected = orientation.to_native_uv(upright);
+                assert!(length(lit - expected) < 1e-3, "{:?} corner {} was lit at {:?}, not {:?}", orientation, k, lit, expected);
+            }
+        }
+
+        // the eye sees all of them, and with the warp undone the chessboard is upright
+        for uv in result.warp.iter() {
+            assert!(uv.x >= 0. && uv.x <= 1. && uv.y >= 0. && uv.y <= 1., "{:?} {:?} is off screen", orientation, uv);
+        }
+        let upright: Vec<glm::Vec2> = result.warp.iter().map(|uv| upright_uv(orientation, *uv)).collect();
+        for j in 0..grid.rows {
+            for i in 0..grid.cols {
+                let k = (j * grid.cols + i) as usize;
+                if i > 0 {
+                    assert!(upright[k].x > upright[k - 1].x, "{:?} row {} doesn't run left to right", orientation, j);
+                }
+                if j > 0 {
+                    assert!(upright[k].y < upright[k - grid.cols as usize].y, "{:?} column {} doesn't run top to bottom", orientation, i);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn simulated_dome_reproduces_ground_truth() {
+        check_simulated_dome(ProjectorOrientation::Landscape);
+    }
+
+    #[test]
+    fn placed_chessboard_warp_covers_its_quadrant() {
+        let grid = GridSpec {cols: 9, rows: 6};
+        let projector_res = Resolution {width: 1920, height: 1080};
+        let quadrants = [
+            (PatternPlacement {x: 0., y: 0., width: 0.5, height: 0.5}, false, true),
+            (PatternPlacement {x: 0.5, y: 0.5, width: 0.5, height: 0.5}, true, false),
+        ];
+        for (placement, right, top) in quadrants.iter() {
+            let (surface, mut sim) = dome_rig(ProjectorOrientation::Landscape);
+            sim.pattern_placement = Some(*placement);
+            let result = crate::simulate_calibration(surface, &sim, vec3(0., 0., 0.), grid, projector_res).unwrap();
+            assert_eq!(result.meta.as_ref().unwrap().pattern_placement, Some(*placement));
+
+            // the warp still spans the raster, only the corners inside the placement were seen
+            let valid = result.valid.as_ref().expect("corners outside the placement are extrapolated");
+            assert_eq!(valid.len(), grid.len());
+            assert_eq!(valid.iter().filter(|v| **v).count(), 12, "{:?}", placement);
+            for (uv, valid) in result.warp.iter().zip(valid.iter()) {
+                if *valid {
+                    assert_eq!((uv.x > 0.5, uv.y > 0.5), (*right, *top), "{:?} is outside {:?} in the warp", uv, placement);
+                }
+            }
+            // and the warp corners at the far corner of the raster are in the opposite quadrant
+            let far = if *right { result.warp[0] } else { result.warp[grid.len() - 1] };
+            assert_eq!((far.x > 0.5, far.y > 0.5), (!*right, !*top), "{:?} for {:?}", far, placement);
+        }
+    }
+
+    /// Seen from an eye behind the projector the middle of the chessboard isn't the middle of
+    /// the view, centering the look_at makes the extents symmetric and needs less fov
+    #[test]
+    fn centered_look_at_needs_less_fov_than_the_image_center() {
+        let grid = GridSpec {cols: 9, rows: 6};
+        let projector_res = Resolution {width: 1920, height: 1080};
+        let eye = vec3(0., 0., 2.);
+        let calibrate = |method: LookAtMethod| {
+            let (surface, mut sim) = dome_rig(ProjectorOrientation::Landscape);
+            sim.look_at_method = method;
+            crate::simulate_calibration(surface, &sim, eye, grid, projector_res).unwrap()
+        };
+        let image_center = calibrate(LookAtMethod::ImageCenter);
+        let centered = calibrate(LookAtMethod::Centered {weighted: false});
+        assert_eq!(centered.meta.as_ref().unwrap().look_at_method, Some(LookAtMethod::Centered {weighted: false}));
+
+        let extents = |result: &crate::CalibrationResult| crate::pipeline::view_extents(&result.scene, result.eye, result.look_at, result.up);
+        let [_, _, bottom, top] = extents(&image_center);
+        assert!((bottom + top).abs() > 1e-2, "the image center is already centered, extents {} to {}", bottom, top);
+        let [left, right, bottom, top] = extents(&centered);
+        assert!((left + right).abs() < 1e-4 && (bottom + top).abs() < 1e-4, "centered extents {:?} aren't symmetric", [left, right, bottom, top]);
+        assert!(centered.fov < image_center.fov, "centered fov {} isn't below the image center's {}", centered.fov, image_center.fov);
+    }
+
+    #[test]
+    fn simulated_dome_in_every_orientation() {
+        for orientation in ORIENTATIONS.iter() {
+            check_simulated_dome(*orientation);
+        }
+    }
+}
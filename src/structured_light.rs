@@ -0,0 +1,639 @@
+//! Structured light: instead of detecting a chessboard, every projector column and row is
+//! encoded in a sequence of Gray code stripe frames, each shown with its inverse, and every
+//! camera pixel decoded to the projector pixel lighting it. The chessboard's corners are then
+//! located in that dense correspondence, so the rest of the pipeline sees image points like
+//! a chessboard capture's.
+//!
+//! `GrayCodeSequence` is the whole protocol as a state machine, so hosts with their own
+//! display path can show the frames and hand back the photos themselves.
+//! `detect_structured_light` is the built-in loop on top of it.
+
+use glm::*;
+use serde::{Serialize, Deserialize};
+use super::{Resolution, GridSpec, Error};
+use super::pipeline::ImagePointGrid;
+use super::confidence;
+#[cfg(feature = "opencv")]
+use opencv::{prelude::*, core::{Mat, CV_8U}, imgproc::{cvt_color, COLOR_BGR2GRAY, COLOR_BGRA2GRAY}};
+#[cfg(feature = "opencv")]
+use log::info;
+#[cfg(feature = "opencv")]
+use super::{PhysicalCamera, PatternDisplay, images::{self, ImageEncoding}, photo};
+#[cfg(feature = "opencv")]
+use super::{pipeline::{self, Capture}, progress::{CalibrationEvent, ProgressSink}, timings::{self, Stage, Timings}};
+#[cfg(feature = "opencv")]
+use super::{projector::{ProjectorOrientation, PatternPlacement}, detection::{DetectionOptions, DetectionVariant}};
+
+/// White minus black (of 255) a camera pixel needs to be decoded at all
+pub const MIN_CONTRAST: i32 = 20;
+/// Fraction of its white minus black a stripe and its inverse must differ by at a camera
+/// pixel for that bit to be trusted
+pub const BIT_MARGIN: f32 = 0.1;
+/// Projector pixels either side of a corner whose camera pixels locate it
+pub const CORNER_WINDOW: i32 = 3;
+/// Camera pixels a corner needs inside its window
+const MIN_CORNER_SAMPLES: usize = 6;
+
+/// Which projector coordinate a stripe frame encodes
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum StripeAxis {
+    Columns,
+    Rows,
+}
+
+/// What one frame of the sequence shows
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+pub enum FrameKind {
+    /// the full raster white, the brightest each camera pixel gets
+    White,
+    Black,
+    /// bit of each column's or row's Gray code, most significant first, white where it's 1
+    /// (0 when inverted)
+    Stripes {axis: StripeAxis, bit: u32, inverted: bool},
+}
+
+/// One frame to show, at the sequence's resolution
+#[derive(Clone, Copy, Debug)]
+pub struct PatternFrame {
+    /// position in the sequence, what its capture is submitted with
+    pub index: usize,
+    pub kind: FrameKind,
+    pub resolution: Resolution,
+}
+
+impl PatternFrame {
+    /// Whether projector pixel x, y is white in this frame
+    pub fn is_white(&self, x: i32, y: i32) -> bool {
+        match self.kind {
+            FrameKind::White => true,
+            FrameKind::Black => false,
+            FrameKind::Stripes {axis, bit, inverted} => {
+                let (position, length) = match axis {
+                    StripeAxis::Columns => (x, self.resolution.width),
+                    StripeAxis::Rows => (y, self.resolution.height),
+                };
+                stripe_on(position as u32, code_bits(length), bit) != inverted
+            }
+        }
+    }
+
+    /// The frame as a pattern, for `PatternDisplay`
+    #[cfg(feature = "opencv")]
+    pub fn pattern(&self) -> images::Pattern {
+        match self.kind {
+            FrameKind::White => images::Pattern::white(),
+            FrameKind::Black => images::Pattern::black(),
+            FrameKind::Stripes {axis, bit, inverted} => images::Pattern::GrayCode {axis: axis, bit: bit, inverted: inverted},
+        }
+    }
+
+    #[cfg(feature = "opencv")]
+    pub fn render(&self) -> Result<Mat, Error> {
+        self.pattern().render(self.resolution.width, self.resolution.height)
+    }
+
+    /// The frame as image bytes for showing it some other way, e.g. in a game engine
+    #[cfg(feature = "opencv")]
+    pub fn encode(&self, encoding: ImageEncoding) -> Result<Vec<u8>, Error> {
+        Ok(images::encode_with(&self.render()?, encoding)?.to_vec())
+    }
+}
+
+/// Gray code bits needed to number length columns or rows
+pub fn code_bits(length: i32) -> u32 {
+    let mut bits = 1;
+    while (1_i64 << bits) < length as i64 {
+        bits += 1;
+    }
+    bits
+}
+
+/// Bit (most significant first, of bits) of position's Gray code
+pub fn stripe_on(position: u32, bits: u32, bit: u32) -> bool {
+    let gray = position ^ (position >> 1);
+    (gray >> (bits - 1 - bit)) & 1 == 1
+}
+
+/// Binary number from a Gray code
+fn from_gray(gray: u32) -> u32 {
+    let mut binary = gray;
+    let mut shift = gray >> 1;
+    while shift != 0 {
+        binary ^= shift;
+        shift >>= 1;
+    }
+    binary
+}
+
+/// A Gray code sequence for one projector and what's been captured of it. Frames can be
+/// submitted in any order, each stripe pair is folded in as soon as it and the white and
+/// black frames are all there so only the codes are kept rather than every photo.
+pub struct GrayCodeSequence {
+    resolution: Resolution,
+    frames: Vec<FrameKind>,
+    next: usize,
+    camera_size: Option<(i32, i32)>,
+    submitted: Vec<bool>,
+    white: Option<Vec<u8>>,
+    black: Option<Vec<u8>>,
+    /// stripe frames waiting for their inverse or for the white and black frames
+    pending: Vec<Option<Vec<u8>>>,
+    /// Gray code bits decoded so far, columns then rows
+    codes: [Vec<u32>; 2],
+    /// every bit so far was clear at the pixel
+    reliable: Vec<bool>,
+}
+
+impl GrayCodeSequence {
+    /// The frames for a projector_res raster: white, black, then the column bits and the row
+    /// bits, each followed by its inverse
+    pub fn new(projector_res: Resolution) -> GrayCodeSequence {
+        let mut frames = vec![FrameKind::White, FrameKind::Black];
+        for (axis, length) in &[(StripeAxis::Columns, projector_res.width), (StripeAxis::Rows, projector_res.height)] {
+            for bit in 0..code_bits(*length) {
+                frames.push(FrameKind::Stripes {axis: *axis, bit: bit, inverted: false});
+                frames.push(FrameKind::Stripes {axis: *axis, bit: bit, inverted: true});
+            }
+        }
+        let count = frames.len();
+        GrayCodeSequence {
+            resolution: projector_res,
+            frames: frames,
+            next: 0,
+            camera_size: None,
+            submitted: vec![false; count],
+            white: None,
+            black: None,
+            pending: vec![None; count],
+            codes: [vec![], vec![]],
+            reliable: vec![],
+        }
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn frame(&self, index: usize) -> Option<PatternFrame> {
+        self.frames.get(index).map(|kind| PatternFrame {index: index, kind: *kind, resolution: self.resolution})
+    }
+
+    /// The next frame to show, None after the last
+    pub fn next_pattern(&mut self) -> Option<PatternFrame> {
+        let frame = self.frame(self.next)?;
+        self.next += 1;
+        Some(frame)
+    }
+
+    /// Indices of the frames nothing has been submitted for yet
+    pub fn missing(&self) -> Vec<usize> {
+        self.submitted.iter().enumerate().filter(|(_, s)| !**s).map(|(i, _)| i).collect()
+    }
+
+    /// Hand back the photo of frame_index as encoded by the camera. It's used as it is, so
+    /// undistort it first or submit the undistorted photo with `submit_image` to get image
+    /// points in the undistorted photo.
+    #[cfg(feature = "opencv")]
+    pub fn submit_capture(&mut self, frame_index: usize, photo_bytes: &[u8]) -> Result<(), Error> {
+        let photo = pipeline::decode_photo(&Mat::from_slice(photo_bytes)?)?;
+        self.submit_image(frame_index, &photo)
+    }
+
+    /// Hand back the photo of frame_index as a decoded image. Deep images are scaled to 8-bit
+    /// by their white level rather than windowed, so every frame is scaled alike.
+    #[cfg(feature = "opencv")]
+    pub fn submit_image(&mut self, frame_index: usize, photo: &Mat) -> Result<(), Error> {
+        let mut grey = Mat::default()?;
+        match photo.channels()? {
+            1 => photo.copy_to(&mut grey)?,
+            4 => cvt_color(photo, &mut grey, COLOR_BGRA2GRAY, 0)?,
+            _ => cvt_color(photo, &mut grey, COLOR_BGR2GRAY, 0)?,
+        }
+        let mut eight_bit = Mat::default()?;
+        grey.convert_to(&mut eight_bit, CV_8U, 255. / pipeline::white_level(&grey)?, 0.)?;
+        let pixels = eight_bit.data_typed::<u8>()?.to_vec();
+        self.submit_greyscale(frame_index, eight_bit.cols(), eight_bit.rows(), pixels)
+    }
+
+    /// Hand back the photo of frame_index as width x height 8-bit greyscale pixels, row by row
+    pub fn submit_greyscale(&mut self, frame_index: usize, width: i32, height: i32, pixels: Vec<u8>) -> Result<(), Error> {
+        if frame_index >= self.frames.len() {
+            return Err(Error::Config(format!("frame {} isn't in the sequence of {}", frame_index, self.frames.len())));
+        }
+        if pixels.len() != (width * height) as usize {
+            return Err(Error::Config(format!("{} pixels given for a {}x{} capture", pixels.len(), width, height)));
+        }
+        match self.camera_size {
+            Some((w, h)) if (w, h) != (width, height) => return Err(Error::Detection(format!(
+                "the capture of frame {} is {}x{} but the earlier ones are {}x{}", frame_index, width, height, w, h
+            ))),
+            Some(_) => {},
+            None => {
+                let count = pixels.len();
+                self.camera_size = Some((width, height));
+                self.codes = [vec![0; count], vec![0; count]];
+                self.reliable = vec![true; count];
+            }
+        }
+        self.submitted[frame_index] = true;
+        match self.frames[frame_index] {
+            FrameKind::White => self.white = Some(pixels),
+            FrameKind::Black => self.black = Some(pixels),
+            FrameKind::Stripes {..} => self.pending[frame_index] = Some(pixels),
+        }
+        self.fold_ready();
+        Ok(())
+    }
+
+    /// Fold every stripe pair that's ready into the codes and drop its photos
+    fn fold_ready(&mut self) {
+        let (white, black) = match (&self.white, &self.black) {
+            (Some(white), Some(black)) => (white, black),
+            _ => return
+        };
+        for i in (2..self.frames.len()).step_by(2) {
+            if self.pending[i].is_none() || self.pending[i + 1].is_none() {
+                continue;
+            }
+            let (axis, bit) = match self.frames[i] {
+                FrameKind::Stripes {axis, bit, ..} => (axis, bit),
+                _ => continue
+            };
+            let (on, off) = (self.pending[i].take().unwrap(), self.pending[i + 1].take().unwrap());
+            let length = match axis { StripeAxis::Columns => self.resolution.width, StripeAxis::Rows => self.resolution.height };
+            let value = 1 << (code_bits(length) - 1 - bit);
+            let codes = &mut self.codes[match axis { StripeAxis::Columns => 0, StripeAxis::Rows => 1 }];
+            for p in 0..on.len() {
+                let contrast = white[p] as i32 - black[p] as i32;
+                let difference = on[p] as i32 - off[p] as i32;
+                if contrast < MIN_CONTRAST || (difference.abs() as f32) < BIT_MARGIN * contrast as f32 {
+                    self.reliable[p] = false;
+                }
+                if difference > 0 {
+                    codes[p] |= value;
+                }
+            }
+        }
+    }
+
+    /// The projector pixel each camera pixel sees. Err until every frame has been submitted.
+    pub fn decode(&self) -> Result<DenseCorrespondence, Error> {
+        let missing = self.missing();
+        if !missing.is_empty() {
+            return Err(Error::Config(format!("frames {:?} of the structured light sequence haven't been captured", missing)));
+        }
+        let (width, height) = self.camera_size.unwrap();
+        let mut projector = Vec::with_capacity(self.reliable.len());
+        let mut valid = Vec::with_capacity(self.reliable.len());
+        for p in 0..self.reliable.len() {
+            let (x, y) = (from_gray(self.codes[0][p]), from_gray(self.codes[1][p]));
+            projector.push([x, y]);
+            valid.push(self.reliable[p] && (x as i32) < self.resolution.width && (y as i32) < self.resolution.height);
+        }
+        Ok(DenseCorrespondence {camera_width: width, camera_height: height, projector_res: self.resolution, projector: projector, valid: valid})
+    }
+}
+
+/// The projector pixel lighting each camera pixel, row by row
+#[derive(Clone, Debug)]
+pub struct DenseCorrespondence {
+    pub camera_width: i32,
+    pub camera_height: i32,
+    pub projector_res: Resolution,
+    /// column and row of the projector pixel, meaningless where valid is false
+    pub projector: Vec<[u32; 2]>,
+    pub valid: Vec<bool>,
+}
+
+impl DenseCorrespondence {
+    pub fn get(&self, x: i32, y: i32) -> Option<[u32; 2]> {
+        if x < 0 || y < 0 || x >= self.camera_width || y >= self.camera_height {
+            return None;
+        }
+        let i = (y * self.camera_width + x) as usize;
+        if self.valid[i] { Some(self.projector[i]) } else { None }
+    }
+
+    /// Fraction of the camera pixels that were decoded
+    pub fn valid_fraction(&self) -> f32 {
+        self.valid.iter().filter(|v| **v).count() as f32 / self.valid.len().max(1) as f32
+    }
+
+    /// Where each target (projector pixels from the raster's top left corner, so pixel x's
+    /// center is x + 0.5) lies in the camera photo, with the rms residual of the fit. The
+    /// camera position is fitted as an affine function of the projector position over the
+    /// camera pixels decoded within `CORNER_WINDOW` of the target. None where too few were.
+    pub fn locate(&self, targets: &[glm::Vec2]) -> Vec<Option<(glm::Vec2, f32)>> {
+        let (pw, ph) = (self.projector_res.width, self.projector_res.height);
+        // which target each projector pixel is near, windows are assumed not to overlap
+        let mut nearest = vec![-1_i32; (pw * ph) as usize];
+        for (t, target) in targets.iter().enumerate() {
+            let (cx, cy) = ((target.x - 0.5).round() as i32, (target.y - 0.5).round() as i32);
+            for y in (cy - CORNER_WINDOW).max(0)..=(cy + CORNER_WINDOW).min(ph - 1) {
+                for x in (cx - CORNER_WINDOW).max(0)..=(cx + CORNER_WINDOW).min(pw - 1) {
+                    nearest[(y * pw + x) as usize] = t as i32;
+                }
+            }
+        }
+        let mut fits = vec![AffineFit::default(); targets.len()];
+        for y in 0..self.camera_height {
+            for x in 0..self.camera_width {
+                let i = (y * self.camera_width + x) as usize;
+                if !self.valid[i] {
+                    continue;
+                }
+                let [px, py] = self.projector[i];
+                let t = nearest[(py as i32 * pw + px as i32) as usize];
+                if t >= 0 {
+                    let target = targets[t as usize];
+                    fits[t as usize].add((px as f64 + 0.5 - target.x as f64, py as f64 + 0.5 - target.y as f64), (x as f64, y as f64));
+                }
+            }
+        }
+        fits.iter().map(|fit| fit.solve()).collect()
+    }
+
+    /// The inner corners of a grid chessboard at positions (0-1 across the raster, row by
+    /// row as `Pattern::corner_positions` gives them) located in the camera photo. Corners
+    /// that can't be located are invalid, the confidence falls with the fit's residual.
+    pub fn image_points(&self, grid: GridSpec, positions: &[glm::Vec2]) -> ImagePointGrid {
+        let (pw, ph) = (self.projector_res.width as f32, self.projector_res.height as f32);
+        let targets: Vec<glm::Vec2> = positions.iter().map(|p| vec2(p.x * pw, p.y * ph)).collect();
+        let located = self.locate(&targets);
+        let mut grid_points = ImagePointGrid::new(grid.cols, grid.rows, vec![vec2(0., 0.); grid.len()]);
+        for (i, found) in located.iter().enumerate().take(grid.len()) {
+            match found {
+                Some((point, rms)) => {
+                    grid_points.points[i] = *point;
+                    let shift = rms / confidence::SUBPIXEL_HALF;
+                    grid_points.confidence[i] = 1. / (1. + shift * shift);
+                },
+                None => {
+                    grid_points.valid[i] = false;
+                    grid_points.confidence[i] = 0.;
+                }
+            }
+        }
+        grid_points
+    }
+}
+
+/// Least squares camera position = a + b·dx + c·dy over the samples around one target
+#[derive(Clone, Copy, Default)]
+struct AffineFit {
+    n: f64,
+    /// sums of dx, dy and their products
+    sx: f64,
+    sy: f64,
+    sxx: f64,
+    sxy: f64,
+    syy: f64,
+    /// sums of each camera coordinate, and times dx and dy
+    u: [f64; 3],
+    v: [f64; 3],
+    uu: f64,
+    vv: f64,
+}
+
+impl AffineFit {
+    fn add(&mut self, (dx, dy): (f64, f64), (u, v): (f64, f64)) {
+        self.n += 1.;
+        self.sx += dx;
+        self.sy += dy;
+        self.sxx += dx * dx;
+        self.sxy += dx * dy;
+        self.syy += dy * dy;
+        self.u[0] += u;
+        self.u[1] += u * dx;
+        self.u[2] += u * dy;
+        self.v[0] += v;
+        self.v[1] += v * dx;
+        self.v[2] += v * dy;
+        self.uu += u * u;
+        self.vv += v * v;
+    }
+
+    /// The camera position at the target and the rms residual
+    fn solve(&self) -> Option<(glm::Vec2, f32)> {
+        if (self.n as usize) < MIN_CORNER_SAMPLES {
+            return None;
+        }
+        let m = [[self.n, self.sx, self.sy], [self.sx, self.sxx, self.sxy], [self.sy, self.sxy, self.syy]];
+        let det = det3(&m);
+        if det.abs() < 1e-9 {
+            return None;
+        }
+        let solve = |rhs: &[f64; 3]| -> [f64; 3] {
+            let mut out = [0.; 3];
+            for k in 0..3 {
+                let mut column = m;
+                for r in 0..3 {
+                    column[r][k] = rhs[r];
+                }
+                out[k] = det3(&column) / det;
+            }
+            out
+        };
+        let (a, b) = (solve(&self.u), solve(&self.v));
+        // sum of squared residuals is sum(u^2) - coefficients . sums
+        let residual = (self.uu - (a[0] * self.u[0] + a[1] * self.u[1] + a[2] * self.u[2]))
+            + (self.vv - (b[0] * self.v[0] + b[1] * self.v[1] + b[2] * self.v[2]));
+        Some((vec2(a[0] as f32, b[0] as f32), (residual.max(0.) / self.n).sqrt() as f32))
+    }
+}
+
+fn det3(m: &[[f64; 3]; 3]) -> f64 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+/// Show every frame of a Gray code sequence for the upright raster, photograph and decode
+/// them, then locate the grid chessboard's corners (in placement when it's given) in the
+/// correspondence rather than detecting a chessboard. The capture's photos are the white
+/// frame's. Needs a control server or fullscreen window, each of the frames would otherwise
+/// be a prompt.
+#[cfg(feature = "opencv")]
+pub fn detect_structured_light(physical_camera: &PhysicalCamera, display: &PatternDisplay, camera_type: photo::CameraType, grid: GridSpec, projector_res: Resolution, orientation: ProjectorOrientation, placement: Option<&PatternPlacement>, detection: &DetectionOptions, debug_encoding: ImageEncoding, progress: &mut dyn ProgressSink, timings: &mut Option<Timings>) -> Result<Capture, Error> {
+    if display.prompt().is_some() {
+        return Err(Error::Config("structured light needs a control server or fullscreen window to show its frames".to_string()));
+    }
+    let mut sequence = GrayCodeSequence::new(orientation.effective_resolution(projector_res));
+    info!("showing {} structured light frames", sequence.frame_count());
+    let mut white = None;
+    while let Some(frame) = sequence.next_pattern() {
+        let pattern = frame.pattern();
+        progress.event(CalibrationEvent::DisplayingPattern {description: pattern.describe()});
+        timings::timed(timings, Stage::Display, || display.show(&pattern, projector_res, orientation))?;
+        let photo_data = timings::timed(timings, Stage::Capture, || photo::capture_photo(camera_type.clone()));
+        let photo_bytes = photo_data.data_typed::<u8>()?.to_vec();
+        progress.event(CalibrationEvent::PhotoCaptured {bytes: photo_bytes.clone()});
+        let (undistorted, greyscale) = timings::timed(timings, Stage::Undistort, || pipeline::take_undistorted_photo(&physical_camera.calibration, &photo_data, debug_encoding))?;
+        sequence.submit_image(frame.index, &greyscale)?;
+        if frame.kind == FrameKind::White {
+            white = Some((photo_bytes, undistorted));
+        }
+    }
+    let (photo, undistorted) = white.ok_or(Error::Config("the structured light sequence has no white frame".to_string()))?;
+
+    let chessboard = images::Pattern::Chessboard {grid: grid}.placed(placement);
+    let positions = chessboard.corner_positions().unwrap_or_default();
+    let image_points = timings::timed(timings, Stage::Detection, || -> Result<ImagePointGrid, Error> {
+        let correspondence = sequence.decode()?;
+        info!("structured light decoded {:.0}% of the camera frame", correspondence.valid_fraction() * 100.);
+        Ok(correspondence.image_points(grid, &positions))
+    })?;
+    progress.event(CalibrationEvent::CornersDetected {
+        found: image_points.valid_points().count(),
+        expected: grid.len(),
+        corners: image_points.valid_points().cloned().collect(),
+    });
+    if !image_points.is_complete() {
+        return Err(Error::Detection(format!(
+            "structured light located {} of {} chessboard corners, the rest fall where the camera doesn't see the projector clearly",
+            image_points.valid_points().count(), grid.len()
+        )));
+    }
+    let coverage = pipeline::check_coverage(&image_points, &undistorted, &chessboard, detection)?;
+    Ok(Capture {photo: photo, undistorted: undistorted, image_points: image_points, detection_variant: DetectionVariant::default(), flipped: false, coverage: coverage})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROJECTOR: Resolution = Resolution {width: 40, height: 24};
+
+    /// Photograph of frame by a camera at twice the projector's resolution that sees it
+    /// straight on, as 8-bit greyscale. Camera pixels from dead_from across see nothing.
+    fn photograph(frame: &PatternFrame, dead_from: i32) -> Vec<u8> {
+        let (w, h) = (PROJECTOR.width * 2, PROJECTOR.height * 2);
+        let mut pixels = Vec::with_capacity((w * h) as usize);
+        for y in 0..h {
+            for x in 0..w {
+                pixels.push(if x < dead_from && frame.is_white(x / 2, y / 2) { 230 } else { 20 });
+            }
+        }
+        pixels
+    }
+
+    fn captured(dead_from: i32) -> GrayCodeSequence {
+        let mut sequence = GrayCodeSequence::new(PROJECTOR);
+        let frames: Vec<PatternFrame> = (0..sequence.frame_count()).map(|i| sequence.frame(i).unwrap()).collect();
+        // out of order, so stripe pairs arrive before the white and black frames
+        for frame in frames.iter().rev() {
+            sequence.submit_greyscale(frame.index, PROJECTOR.width * 2, PROJECTOR.height * 2, photograph(frame, dead_from)).unwrap();
+        }
+        sequence
+    }
+
+    #[test]
+    fn gray_codes_number_every_position() {
+        assert_eq!((code_bits(1), code_bits(2), code_bits(1024), code_bits(1025), code_bits(1920)), (1, 1, 10, 11, 11));
+        let bits = code_bits(1920);
+        for position in 0..1920 {
+            let gray = (0..bits).fold(0, |gray, bit| (gray << 1) | stripe_on(position, bits, bit) as u32);
+            assert_eq!(from_gray(gray), position);
+            // neighbours differ in a single stripe
+            if position > 0 {
+                assert_eq!((0..bits).filter(|bit| stripe_on(position, bits, *bit) != stripe_on(position - 1, bits, *bit)).count(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn sequence_shows_white_black_then_stripe_pairs() {
+        let mut sequence = GrayCodeSequence::new(PROJECTOR);
+        assert_eq!(sequence.frame_count(), 2 + 2 * (6 + 5));
+        assert_eq!(sequence.next_pattern().unwrap().kind, FrameKind::White);
+        assert_eq!(sequence.next_pattern().unwrap().kind, FrameKind::Black);
+        assert_eq!(sequence.next_pattern().unwrap().kind, FrameKind::Stripes {axis: StripeAxis::Columns, bit: 0, inverted: false});
+        assert_eq!(sequence.next_pattern().unwrap().kind, FrameKind::Stripes {axis: StripeAxis::Columns, bit: 0, inverted: true});
+        while sequence.next_pattern().is_some() {}
+        assert_eq!(sequence.frame(sequence.frame_count() - 1).unwrap().kind, FrameKind::Stripes {axis: StripeAxis::Rows, bit: 4, inverted: true});
+    }
+
+    #[test]
+    fn decodes_the_projector_pixel_of_every_camera_pixel() {
+        let correspondence = captured(std::i32::MAX).decode().unwrap();
+        assert_eq!((correspondence.camera_width, correspondence.camera_height), (80, 48));
+        assert_eq!(correspondence.valid_fraction(), 1.);
+        for y in 0..48 {
+            for x in 0..80 {
+                assert_eq!(correspondence.get(x, y), Some([(x / 2) as u32, (y / 2) as u32]));
+            }
+        }
+        assert_eq!(correspondence.get(80, 0), None);
+    }
+
+    #[test]
+    fn pixels_without_contrast_arent_decoded() {
+        let correspondence = captured(50).decode().unwrap();
+        assert_eq!(correspondence.get(49, 10), Some([24, 5]));
+        assert_eq!(correspondence.get(50, 10), None);
+        assert!((correspondence.valid_fraction() - 50. / 80.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn incomplete_and_mismatched_captures_are_refused() {
+        let mut sequence = GrayCodeSequence::new(PROJECTOR);
+        let white = sequence.frame(0).unwrap();
+        sequence.submit_greyscale(0, 80, 48, photograph(&white, std::i32::MAX)).unwrap();
+        assert_eq!(sequence.missing().len(), sequence.frame_count() - 1);
+        assert!(sequence.decode().is_err());
+        assert!(sequence.submit_greyscale(1, 40, 24, vec![0; 40 * 24]).is_err());
+        assert!(sequence.submit_greyscale(1, 80, 48, vec![0; 10]).is_err());
+        assert!(sequence.submit_greyscale(sequence.frame_count(), 80, 48, vec![0; 80 * 48]).is_err());
+    }
+
+    #[test]
+    fn corners_are_located_where_the_camera_sees_them() {
+        let correspondence = captured(50).decode().unwrap();
+        let grid = GridSpec {cols: 3, rows: 2};
+        let positions: Vec<glm::Vec2> = [(0.25, 0.25), (0.5, 0.25), (0.75, 0.25), (0.25, 0.75), (0.5, 0.75), (0.75, 0.75)]
+            .iter().map(|(x, y)| vec2(*x, *y)).collect();
+        let image_points = correspondence.image_points(grid, &positions);
+        for (i, position) in positions.iter().enumerate() {
+            // camera pixel x is lit by projector pixel x / 2, so projector coordinate t is seen
+            // at camera coordinate 2t - 0.5
+            let expected = vec2(position.x * 80. - 0.5, position.y * 48. - 0.5);
+            if position.x < 0.7 {
+                assert!(image_points.valid[i]);
+                assert!(length(image_points.points[i] - expected) < 1e-3, "{:?} isn't at {:?}", image_points.points[i], expected);
+                assert!(image_points.confidence[i] > 0.);
+            } else {
+                // its window is all in the part the camera doesn't see
+                assert!(!image_points.valid[i]);
+                assert_eq!(image_points.confidence[i], 0.);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "opencv"))]
+mod opencv_tests {
+    use super::*;
+    use opencv::imgcodecs;
+
+    const PROJECTOR: Resolution = Resolution {width: 40, height: 24};
+
+    /// Frames render and encode as `is_white` describes them, and decode back to the raster
+    #[test]
+    fn rendered_frames_decode_back_to_the_raster() {
+        let mut sequence = GrayCodeSequence::new(PROJECTOR);
+        while let Some(frame) = sequence.next_pattern() {
+            let encoded = frame.encode(ImageEncoding::Png {compression: 1}).unwrap();
+            let image = imgcodecs::imdecode(&Mat::from_slice(&encoded).unwrap(), imgcodecs::IMREAD_GRAYSCALE).unwrap();
+            assert_eq!((image.cols(), image.rows()), (PROJECTOR.width, PROJECTOR.height));
+            for (x, y) in [(0, 0), (7, 3), (20, 11), (39, 23)].iter() {
+                assert_eq!(*image.at_2d::<u8>(*y, *x).unwrap() > 127, frame.is_white(*x, *y), "frame {} at {},{}", frame.index, x, y);
+            }
+            sequence.submit_image(frame.index, &frame.render().unwrap()).unwrap();
+        }
+        let correspondence = sequence.decode().unwrap();
+        assert_eq!(correspondence.valid_fraction(), 1.);
+        assert_eq!(correspondence.get(27, 13), Some([27, 13]));
+    }
+}
@@ -1,22 +1,99 @@
+//! Mapping between the physical camera's photo and the projection surface. Only needs the
+//! camera's pose and field of view, so it works without opencv.
 
-use super::PhysicalCamera;
 use super::math::*;
 use glm::*;
 use glm::ext::*;
 use std::f32::consts;
 use log::{debug};
+use serde::{Serialize, Deserialize};
 
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
 pub enum SurfaceType {
     HemisphericalDome {radius: f32},
-    Wall
+    Wall,
+    /// a flat surface whose points p satisfy dot(normal, p) = offset, normal being a unit
+    /// vector. Wall is the plane z = 0.
+    Plane {normal: [f32; 3], offset: f32},
+}
+
+impl SurfaceType {
+    /// Normal and offset of a flat surface, None for the dome
+    pub fn plane(&self) -> Option<(glm::Vec3, f32)> {
+        match self {
+            SurfaceType::HemisphericalDome {..} => None,
+            SurfaceType::Wall => Some((vec3(0., 0., 1.), 0.)),
+            SurfaceType::Plane {normal, offset} => Some((vec3(normal[0], normal[1], normal[2]), *offset)),
+        }
+    }
+}
+
+/// The parts of the physical camera the surface mapping uses, see `PhysicalCamera::model`
+#[derive(Clone, Copy, Debug)]
+pub struct CameraModel {
+    pub position: glm::Vec3,
+    /// the direction the camera faces
+    pub look_at: glm::Vec3,
+    pub up_dir: glm::Vec3,
+    pub intrinsics: CameraIntrinsics,
+}
+
+/// A distortion free camera, photos are undistorted before their points are mapped
+#[derive(Clone, Copy, Debug)]
+pub struct CameraIntrinsics {
+    /// vertical field of view in degrees
+    pub fov: f32,
+    pub image_width: i32,
+    pub image_height: i32,
+}
+
+impl CameraModel {
+    fn view_and_projection(&self) -> (glm::Mat4, glm::Mat4) {
+        let intrinsics = &self.intrinsics;
+        let model = look_at(self.position, self.look_at + self.position, self.up_dir);
+        let proj = perspective(radians(intrinsics.fov), (intrinsics.image_width as f32) / (intrinsics.image_height as f32), 0.1_f32, 1000_f32);
+        (model, proj)
+    }
 }
 
 /// convert a point in camera photo space to a 3d point on the projection surface in scene space
-pub fn camera_to_scene(surface_type: &SurfaceType, physical_camera: &PhysicalCamera, point: glm::Vec2, image_width: i32, image_height: i32) -> Result<glm::Vec3, &'static str> {
-    match surface_type {
-        SurfaceType::HemisphericalDome{radius} => camera_to_scene_dome(point, image_width, image_height, *radius),
-        SurfaceType::Wall => camera_to_scene_wall(physical_camera, point, image_width, image_height)
+pub fn camera_to_scene(surface_type: &SurfaceType, camera: &CameraModel, point: glm::Vec2) -> Result<glm::Vec3, &'static str> {
+    SceneMapper::new(surface_type, camera).map(point)
+}
+
+/// camera_to_scene with everything that doesn't depend on the point calculated up front,
+/// for mapping many points. Only holds plain values so it can be shared between threads.
+pub struct SceneMapper {
+    surface_type: SurfaceType,
+    camera_position: glm::Vec3,
+    model: glm::Mat4,
+    proj: glm::Mat4,
+    image_width: i32,
+    image_height: i32,
+}
+
+impl SceneMapper {
+    pub fn new(surface_type: &SurfaceType, camera: &CameraModel) -> SceneMapper {
+        // the camera model used to unproject photo points onto the wall
+        let (model, proj) = camera.view_and_projection();
+        SceneMapper {
+            surface_type: *surface_type,
+            camera_position: camera.position,
+            model: model,
+            proj: proj,
+            image_width: camera.intrinsics.image_width,
+            image_height: camera.intrinsics.image_height,
+        }
+    }
+
+    pub fn map(&self, point: glm::Vec2) -> Result<glm::Vec3, &'static str> {
+        match self.surface_type {
+            SurfaceType::HemisphericalDome{radius} => camera_to_scene_dome(point, self.image_width, self.image_height, radius),
+            SurfaceType::Wall => camera_to_scene_wall(self, point),
+            SurfaceType::Plane {normal, offset} => camera_to_scene_plane(self, point, vec3(normal[0], normal[1], normal[2]), offset)
+        }
     }
 }
 
@@ -65,26 +142,217 @@ fn camera_to_scene_dome(corner_pt: glm::Vec2, image_width: i32, image_height: i3
 }
 
 // wall surface specific. wall is assumed to be at z = 0
-fn camera_to_scene_wall(camera: &PhysicalCamera, pt: glm::Vec2, image_width: i32, image_height: i32) -> Result<glm::Vec3, &'static str> {
+fn camera_to_scene_wall(mapper: &SceneMapper, pt: glm::Vec2) -> Result<glm::Vec3, &'static str> {
     // unproject camera point into camera based scene
-    // FIXME shouldn't be rebuilding these for each point
-    // consider simpifying and inline this where it's needed
-    // actually, maybe it's fine as this is only used for mapping the projected
-    // points
-    let model = look_at(camera.position, camera.look_at + camera.position, camera.up_dir);
-    let proj = perspective(radians(camera.calibration.fov), (image_width as f32) / (image_height as f32), 0.1_f32, 1000_f32);
-    
+    let image_width = mapper.image_width;
+    let image_height = mapper.image_height;
+    let camera_position = mapper.camera_position;
+
     // un_project could have bugs
     let scene_pt = un_project(vec3(pt.x, image_height as f32 - pt.y, 1.), // 1 means at the back of the depth range
-                        &model,
-                        &proj,
+                        &mapper.model,
+                        &mapper.proj,
                         vec4(0., 0., image_width as f32, image_height as f32))?;
     
     // now intersect with the XY plain
-    let zmag = (scene_pt.z - camera.position.z).abs();
-    let scene_pt2 = ((scene_pt - camera.position) / zmag) * (0. /*wall z*/ - camera.position.z).abs() + camera.position;
+    let zmag = (scene_pt.z - camera_position.z).abs();
+    let scene_pt2 = ((scene_pt - camera_position) / zmag) * (0. /*wall z*/ - camera_position.z).abs() + camera_position;
     
     debug!("scene 3d point for {},{} is {},{},{} (was {},{},{})", pt.x, pt.y, scene_pt2.x, scene_pt2.y, scene_pt2.z, scene_pt.x, scene_pt.y, scene_pt.z);
 
     Ok(scene_pt2)
 }
+
+// like camera_to_scene_wall but for any plane
+fn camera_to_scene_plane(mapper: &SceneMapper, pt: glm::Vec2, normal: glm::Vec3, offset: f32) -> Result<glm::Vec3, &'static str> {
+    let scene_pt = un_project(vec3(pt.x, mapper.image_height as f32 - pt.y, 1.),
+                        &mapper.model,
+                        &mapper.proj,
+                        vec4(0., 0., mapper.image_width as f32, mapper.image_height as f32))?;
+    let dir = scene_pt - mapper.camera_position;
+    let denom = dot(normal, dir);
+    if denom.abs() < 1e-9 {
+        return Err("camera ray is parallel to the plane");
+    }
+    let t = (offset - dot(normal, mapper.camera_position)) / denom;
+    if t <= 0. {
+        return Err("plane is behind the camera");
+    }
+    Ok(mapper.camera_position + dir * t)
+}
+
+/// convert a point on the projection surface in scene space to a point in camera photo space,
+/// the inverse of camera_to_scene
+pub fn scene_to_camera(surface_type: &SurfaceType, camera: &CameraModel, point: glm::Vec3) -> Option<glm::Vec2> {
+    let intrinsics = &camera.intrinsics;
+    match surface_type {
+        SurfaceType::HemisphericalDome{radius} => scene_to_camera_dome(point, intrinsics.image_width, intrinsics.image_height, *radius),
+        SurfaceType::Wall | SurfaceType::Plane {..} => scene_to_camera_wall(camera, point)
+    }
+}
+
+/// intersect a ray with the projection surface, returning the nearest hit in front of the origin
+pub fn intersect_ray(surface_type: &SurfaceType, origin: glm::Vec3, direction: glm::Vec3) -> Option<glm::Vec3> {
+    let dir = normalize(direction);
+    match surface_type {
+        SurfaceType::HemisphericalDome{radius} => {
+            // sphere at the origin, |o + td|^2 = r^2
+            let b = dot(origin, dir);
+            let c = dot(origin, origin) - radius * radius;
+            let disc = b * b - c;
+            if disc < 0. {
+                return None;
+            }
+            let t1 = -b - disc.sqrt();
+            let t2 = -b + disc.sqrt();
+            let t = if t1 > 1e-6 { t1 } else { t2 };
+            let hit = origin + dir * t;
+            // only the upper half of the sphere exists
+            if t > 1e-6 && hit.y >= 0. { Some(hit) } else { None }
+        },
+        SurfaceType::Wall => {
+            if dir.z.abs() < 1e-9 {
+                return None;
+            }
+            let t = -origin.z / dir.z;
+            if t > 1e-6 { Some(origin + dir * t) } else { None }
+        },
+        SurfaceType::Plane {normal, offset} => {
+            let normal = vec3(normal[0], normal[1], normal[2]);
+            let denom = dot(normal, dir);
+            if denom.abs() < 1e-9 {
+                return None;
+            }
+            let t = (offset - dot(normal, origin)) / denom;
+            if t > 1e-6 { Some(origin + dir * t) } else { None }
+        }
+    }
+}
+
+fn scene_to_camera_dome(scene_pt: glm::Vec3, image_width: i32, image_height: i32, dome_radius: f32) -> Option<glm::Vec2> {
+    // reverse the steps in camera_to_scene_dome
+    let h = image_height as f32;
+    let w = image_width as f32;
+    let n = scene_pt / dome_radius;
+    if n.y < 0. {
+        return None;
+    }
+
+    let angle1 = n.y.min(1.).asin();
+    let v = 1. - angle1 / (consts::PI/2.);
+    let angle2 = n.z.atan2(n.x);
+
+    Some(vec2(v * angle2.sin() * w * 0.5 + w * 0.5, v * angle2.cos() * h * 0.5 + h * 0.5))
+}
+
+fn scene_to_camera_wall(camera: &CameraModel, scene_pt: glm::Vec3) -> Option<glm::Vec2> {
+    // the same camera model camera_to_scene_wall unprojects with
+    let (model, proj) = camera.view_and_projection();
+    let (image_width, image_height) = (camera.intrinsics.image_width, camera.intrinsics.image_height);
+    let window_pt = project(scene_pt, &model, &proj, vec4(0., 0., image_width as f32, image_height as f32));
+
+    // behind the camera
+    if window_pt.z > 1. || window_pt.z < 0. {
+        return None;
+    }
+    Some(vec2(window_pt.x, image_height as f32 - window_pt.y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: glm::Vec3, b: glm::Vec3) -> bool {
+        length(a - b) < 1e-3
+    }
+
+    /// A camera 5 in front of the wall looking at it
+    fn wall_camera() -> CameraModel {
+        CameraModel {
+            position: vec3(0., 1., 5.),
+            look_at: vec3(0., 0., -1.),
+            up_dir: vec3(0., 1., 0.),
+            intrinsics: CameraIntrinsics {fov: 60., image_width: 1600, image_height: 1200},
+        }
+    }
+
+    #[test]
+    fn dome_intersection() {
+        let dome = SurfaceType::HemisphericalDome {radius: 5.};
+        let origin = vec3(0., 0., 0.);
+        assert!(close(intersect_ray(&dome, origin, vec3(0., 1., 0.)).unwrap(), vec3(0., 5., 0.)));
+        let hit = intersect_ray(&dome, origin, vec3(1., 1., -1.)).unwrap();
+        assert!((length(hit) - 5.).abs() < 1e-4);
+        assert!(close(normalize(hit), normalize(vec3(1., 1., -1.))));
+        // from off center the far side is hit, not the one behind the origin
+        let hit = intersect_ray(&dome, vec3(0., 1., 3.), vec3(0., 0., -1.)).unwrap();
+        assert!(close(hit, vec3(0., 1., -(24_f32).sqrt())));
+        // only the upper half exists
+        assert!(intersect_ray(&dome, origin, vec3(0., -1., 0.)).is_none());
+        assert!(intersect_ray(&dome, vec3(0., 10., 0.), vec3(1., 0., 0.)).is_none());
+    }
+
+    #[test]
+    fn wall_intersection() {
+        assert!(close(intersect_ray(&SurfaceType::Wall, vec3(1., 2., 5.), vec3(0., 0., -1.)).unwrap(), vec3(1., 2., 0.)));
+        assert!(close(intersect_ray(&SurfaceType::Wall, vec3(0., 0., 4.), vec3(1., 0., -1.)).unwrap(), vec3(4., 0., 0.)));
+        // parallel, and facing away
+        assert!(intersect_ray(&SurfaceType::Wall, vec3(0., 0., 4.), vec3(1., 0., 0.)).is_none());
+        assert!(intersect_ray(&SurfaceType::Wall, vec3(0., 0., 4.), vec3(0., 0., 1.)).is_none());
+    }
+
+    #[test]
+    fn plane_intersection() {
+        // the floor a meter down
+        let floor = SurfaceType::Plane {normal: [0., 1., 0.], offset: -1.};
+        assert!(close(intersect_ray(&floor, vec3(0., 0., 0.), vec3(0., -1., -1.)).unwrap(), vec3(0., -1., -1.)));
+        assert!(intersect_ray(&floor, vec3(0., 0., 0.), vec3(0., 1., 0.)).is_none());
+        assert!(intersect_ray(&floor, vec3(0., 0., 0.), vec3(1., 0., 0.)).is_none());
+
+        // a wall as a plane is the same as the wall
+        let wall = SurfaceType::Plane {normal: [0., 0., 1.], offset: 0.};
+        let (origin, dir) = (vec3(0.5, 2., 3.), vec3(0.2, -0.1, -1.));
+        assert!(close(intersect_ray(&wall, origin, dir).unwrap(), intersect_ray(&SurfaceType::Wall, origin, dir).unwrap()));
+    }
+
+    #[test]
+    fn camera_and_scene_mappings_are_inverses() {
+        let dome_camera = CameraModel {
+            position: vec3(0., 0., 0.),
+            look_at: vec3(0., 1., 0.),
+            up_dir: vec3(0., 0., -1.),
+            intrinsics: CameraIntrinsics {fov: 180., image_width: 2000, image_height: 2000},
+        };
+        let cases = [
+            (SurfaceType::HemisphericalDome {radius: 5.}, dome_camera),
+            (SurfaceType::Wall, wall_camera()),
+            (SurfaceType::Plane {normal: [0., 0., 1.], offset: -0.5}, wall_camera()),
+        ];
+        for (surface, camera) in cases.iter() {
+            for pixel in [vec2(800., 600.), vec2(1200., 900.), vec2(950., 1300.), vec2(300., 700.)].iter() {
+                let scene = camera_to_scene(surface, camera, *pixel).unwrap();
+                let back = scene_to_camera(surface, camera, scene).unwrap();
+                assert!(length(back - *pixel) < 0.05, "{:?}: {:?} came back as {:?}", surface, pixel, back);
+            }
+        }
+        // on the flat surfaces the point is where the camera ray hits them
+        let camera = wall_camera();
+        let scene = camera_to_scene(&SurfaceType::Wall, &camera, vec2(800., 600.)).unwrap();
+        assert!(close(scene, vec3(0., 1., 0.)));
+        let scene = camera_to_scene(&SurfaceType::Plane {normal: [0., 0., 1.], offset: -0.5}, &camera, vec2(800., 600.)).unwrap();
+        assert!(close(scene, vec3(0., 1., -0.5)));
+    }
+
+    #[test]
+    fn outside_the_fisheye_isnt_on_the_dome() {
+        let camera = CameraModel {
+            position: vec3(0., 0., 0.),
+            look_at: vec3(0., 1., 0.),
+            up_dir: vec3(0., 0., -1.),
+            intrinsics: CameraIntrinsics {fov: 180., image_width: 2000, image_height: 2000},
+        };
+        let dome = SurfaceType::HemisphericalDome {radius: 5.};
+        assert!(camera_to_scene(&dome, &camera, vec2(10., 10.)).is_err());
+        assert!(scene_to_camera(&dome, &camera, vec3(0., -1., 4.)).is_none());
+    }
+}
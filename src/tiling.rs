@@ -0,0 +1,264 @@
+//! Capturing a chessboard too dense to detect in one go a tile at a time. The grid's inner
+//! corners are split into overlapping blocks and each is shown on its own, drawn where its
+//! squares lie on the full board (see `PatternPlacement`), so every tile's corners sit on the
+//! full grid and its local indices map straight onto the global ones. The per-tile corners
+//! are stitched into one grid that the later stages use like a single capture's.
+
+#[cfg(feature = "opencv")]
+use opencv::prelude::*;
+#[cfg(feature = "opencv")]
+use glm::*;
+#[cfg(feature = "opencv")]
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use super::GridSpec;
+#[cfg(feature = "opencv")]
+use super::{PhysicalCamera, Resolution, Error, PatternDisplay, coverage, images, photo};
+#[cfg(feature = "opencv")]
+use super::{images::GridRegion, pipeline::{self, Capture, ImagePointGrid}, progress::ProgressSink, timings::Timings};
+#[cfg(feature = "opencv")]
+use super::{projector::{ProjectorOrientation, PatternPlacement}, detection::DetectionOptions};
+
+/// How the grid is split into tiles
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Tiling {
+    /// tiles across and down
+    pub cols: i32,
+    pub rows: i32,
+    /// inner corners neighbouring tiles share at least, so the borders are seen twice
+    pub overlap: i32,
+}
+
+impl Tiling {
+    /// "COLSxROWS" tiles
+    pub fn parse(input: &str, overlap: i32) -> Result<Tiling, &'static str> {
+        let grid = GridSpec::parse_sized(input).map_err(|_| "tiles must be in the form \"COLSxROWS\"")?;
+        Ok(Tiling {cols: grid.cols, rows: grid.rows, overlap: overlap})
+    }
+
+    /// The inner corners of grid in each tile, row of tiles by row. Each is odd x even like
+    /// the full board so it can be drawn as a chessboard of its own.
+    #[cfg(feature = "opencv")]
+    pub fn regions(&self, grid: GridSpec) -> Result<Vec<GridRegion>, Error> {
+        let cols = tile_spans(self.cols, grid.cols, self.overlap, 1, 3)
+            .ok_or(Error::Config(format!("{} can't be split into {} tiles across with {} corners overlap", grid, self.cols, self.overlap)))?;
+        let rows = tile_spans(self.rows, grid.rows, self.overlap, 0, 2)
+            .ok_or(Error::Config(format!("{} can't be split into {} tiles down with {} corners overlap", grid, self.rows, self.overlap)))?;
+        let mut regions = vec![];
+        for (row, tile_rows) in rows.iter() {
+            for (col, tile_cols) in cols.iter() {
+                regions.push(GridRegion {col: *col, row: *row, cols: *tile_cols, rows: *tile_rows});
+            }
+        }
+        Ok(regions)
+    }
+}
+
+/// (start, length) of each of n tiles along an axis of total corners, with lengths of the
+/// given parity (1 odd, 0 even) and at least min_length. None when they don't fit.
+#[cfg(feature = "opencv")]
+fn tile_spans(n: i32, total: i32, overlap: i32, parity: i32, min_length: i32) -> Option<Vec<(i32, i32)>> {
+    if n < 1 || overlap < 0 {
+        return None;
+    }
+    if n == 1 {
+        return Some(vec![(0, total)]);
+    }
+    let mut length = ((total + (n - 1) * overlap) as f32 / n as f32).ceil() as i32;
+    if length % 2 != parity {
+        length += 1;
+    }
+    let length = length.max(min_length);
+    if length > total {
+        return None;
+    }
+    Some((0..n).map(|t| (((t * (total - length)) as f32 / (n - 1) as f32).round() as i32, length)).collect())
+}
+
+/// Where a tile's squares lie on the board drawn in placement (the whole raster when None)
+#[cfg(feature = "opencv")]
+pub fn tile_placement(grid: GridSpec, region: GridRegion, placement: Option<&PatternPlacement>) -> PatternPlacement {
+    let (w, h) = ((grid.cols + 1) as f32, (grid.rows + 1) as f32);
+    let tile = PatternPlacement {
+        x: region.col as f32 / w,
+        y: region.row as f32 / h,
+        width: (region.cols + 1) as f32 / w,
+        height: (region.rows + 1) as f32 / h,
+    };
+    match placement {
+        Some(outer) => PatternPlacement {
+            x: outer.x + tile.x * outer.width,
+            y: outer.y + tile.y * outer.height,
+            width: tile.width * outer.width,
+            height: tile.height * outer.height,
+        },
+        None => tile
+    }
+}
+
+/// Merge each tile's corners into one grid. Where tiles overlap the corner from the tile it's
+/// nearest the middle of is used, those near a tile's border are the least reliable.
+#[cfg(feature = "opencv")]
+pub fn stitch(grid: GridSpec, tiles: &[(GridRegion, ImagePointGrid)]) -> ImagePointGrid {
+    let mut points = vec![vec2(0., 0.); grid.len()];
+    let mut valid = vec![false; grid.len()];
+    let mut confidence = vec![0.; grid.len()];
+    let mut best = vec![std::f32::INFINITY; grid.len()];
+    for (region, corners) in tiles {
+        let center = vec2((region.cols - 1) as f32 / 2., (region.rows - 1) as f32 / 2.);
+        for row in 0..region.rows {
+            for col in 0..region.cols {
+                let point = match corners.get(col, row) {
+                    Some(point) => point,
+                    None => continue
+                };
+                // relative to the tile's size, so a long thin tile's ends don't always lose
+                let offset = vec2(col as f32, row as f32) - center;
+                let distance = (offset.x / (center.x + 1.)).abs().max((offset.y / (center.y + 1.)).abs());
+                let i = ((region.row + row) * grid.cols + region.col + col) as usize;
+                if distance < best[i] {
+                    best[i] = distance;
+                    points[i] = point;
+                    valid[i] = true;
+                    confidence[i] = corners.confidence[(row * region.cols + col) as usize];
+                }
+            }
+        }
+    }
+    ImagePointGrid {cols: grid.cols, rows: grid.rows, points: points, valid: valid, confidence: confidence}
+}
+
+/// Show each tile of the grid chessboard in turn, detect its corners and stitch them. The
+/// returned capture's photos are the first tile's, its coverage is of the stitched grid. A
+/// tile only covers part of the board so coverage is checked, and warned about, once for the
+/// stitched grid rather than for each tile.
+#[cfg(feature = "opencv")]
+pub fn detect_tiled(physical_camera: &PhysicalCamera, display: &PatternDisplay, camera_type: photo::CameraType, grid: GridSpec, tiling: &Tiling, projector_res: Resolution, orientation: ProjectorOrientation, placement: Option<&PatternPlacement>, detection: &DetectionOptions, debug_encoding: images::ImageEncoding, progress: &mut dyn ProgressSink, timings: &mut Option<Timings>) -> Result<Capture, Error> {
+    let regions = tiling.regions(grid)?;
+    let mut tiles = vec![];
+    let mut first: Option<Capture> = None;
+    let mut flipped = false;
+    let tile_detection = DetectionOptions {min_camera_coverage: None, coverage_warnings: false, ..detection.clone()};
+    for (i, region) in regions.iter().enumerate() {
+        let tile_grid = GridSpec {cols: region.cols, rows: region.rows};
+        let pattern = images::Pattern::Chessboard {grid: tile_grid}.placed(Some(&tile_placement(grid, *region, placement)));
+        let capture = pipeline::detect_pattern_corners(physical_camera, display, camera_type.clone(), &pattern, tile_grid, projector_res, orientation, &tile_detection, debug_encoding, progress, timings)
+            .map_err(|err| {
+                warn!("tile {} of {} (corners {},{} to {},{}) failed", i + 1, regions.len(), region.col, region.row, region.col + region.cols - 1, region.row + region.rows - 1);
+                err
+            })?;
+        info!("tile {} of {} detected {} corners", i + 1, regions.len(), capture.image_points.len());
+        flipped |= capture.flipped;
+        tiles.push((*region, capture.image_points.clone()));
+        if first.is_none() {
+            first = Some(capture);
+        }
+    }
+    let mut capture = first.ok_or(Error::Config("the tiling has no tiles".to_string()))?;
+    let image_points = stitch(grid, &tiles);
+    if !image_points.is_complete() {
+        return Err(Error::Detection(format!("the tiles only covered {} of {} chessboard corners", image_points.valid_points().count(), grid.len())));
+    }
+    let full_board = images::Pattern::Chessboard {grid: grid}.placed(placement);
+    let camera_points: Vec<glm::Vec2> = image_points.valid_points().cloned().collect();
+    capture.coverage = coverage::measure(&camera_points, capture.undistorted.cols(), capture.undistorted.rows(), full_board.corner_positions().as_deref());
+    pipeline::report_coverage(&capture.coverage, detection)?;
+    capture.image_points = image_points;
+    capture.flipped = flipped;
+    Ok(capture)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiling_spellings() {
+        assert_eq!(Tiling::parse("3x2", 2), Ok(Tiling {cols: 3, rows: 2, overlap: 2}));
+        assert_eq!(Tiling::parse("1x1", 0), Ok(Tiling {cols: 1, rows: 1, overlap: 0}));
+        for input in ["3", "0x2", "3x-1", "axb", ""].iter() {
+            assert!(Tiling::parse(input, 2).is_err(), "{} was accepted", input);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "opencv"))]
+mod opencv_tests {
+    use super::*;
+
+    #[test]
+    fn spans_overlap_and_keep_their_parity() {
+        assert_eq!(tile_spans(1, 9, 0, 1, 3), Some(vec![(0, 9)]));
+        assert_eq!(tile_spans(2, 17, 3, 1, 3), Some(vec![(0, 11), (6, 11)]));
+        assert_eq!(tile_spans(3, 12, 2, 0, 2), Some(vec![(0, 6), (3, 6), (6, 6)]));
+        assert_eq!(tile_spans(0, 9, 0, 1, 3), None);
+        assert_eq!(tile_spans(2, 9, -1, 1, 3), None);
+        // the overlap makes every tile longer than the axis
+        assert_eq!(tile_spans(2, 5, 6, 1, 3), None);
+    }
+
+    #[test]
+    fn regions_cover_every_corner() {
+        for (grid, tiling) in [
+            (GridSpec {cols: 17, rows: 12}, Tiling {cols: 2, rows: 2, overlap: 2}),
+            (GridSpec {cols: 25, rows: 16}, Tiling {cols: 3, rows: 2, overlap: 3}),
+            (GridSpec {cols: 9, rows: 6}, Tiling {cols: 1, rows: 1, overlap: 0}),
+        ].iter() {
+            let regions = tiling.regions(*grid).unwrap();
+            assert_eq!(regions.len() as i32, tiling.cols * tiling.rows);
+            let mut seen = vec![0; grid.len()];
+            for region in regions.iter() {
+                assert!(region.cols % 2 == 1 && region.rows % 2 == 0, "{:?} isn't odd x even", region);
+                assert!(region.col >= 0 && region.row >= 0 && region.col + region.cols <= grid.cols && region.row + region.rows <= grid.rows, "{:?} is off {}", region, grid);
+                for row in region.row..region.row + region.rows {
+                    for col in region.col..region.col + region.cols {
+                        seen[(row * grid.cols + col) as usize] += 1;
+                    }
+                }
+            }
+            assert!(seen.iter().all(|count| *count > 0), "{} in {:?} leaves corners out", grid, tiling);
+            // neighbours share at least the overlap
+            for pair in regions.windows(2).filter(|pair| pair[0].row == pair[1].row) {
+                assert!(pair[0].col + pair[0].cols - pair[1].col >= tiling.overlap, "{:?}", pair);
+            }
+        }
+        assert!(Tiling {cols: 4, rows: 1, overlap: 12}.regions(GridSpec {cols: 9, rows: 6}).is_err());
+    }
+
+    #[test]
+    fn tiles_are_placed_where_their_squares_are() {
+        let grid = GridSpec {cols: 9, rows: 6};
+        let region = GridRegion {col: 2, row: 0, cols: 7, rows: 6};
+        let tile = tile_placement(grid, region, None);
+        assert_eq!((tile.x, tile.y, tile.width, tile.height), (0.2, 0., 0.8, 1.));
+        let outer = PatternPlacement {x: 0.5, y: 0.25, width: 0.5, height: 0.5};
+        let placed = tile_placement(grid, region, Some(&outer));
+        assert!((placed.x - 0.6).abs() < 1e-6 && (placed.y - 0.25).abs() < 1e-6);
+        assert!((placed.width - 0.4).abs() < 1e-6 && (placed.height - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn stitching_prefers_the_corner_nearer_its_tile_middle() {
+        let grid = GridSpec {cols: 9, rows: 6};
+        let regions = Tiling {cols: 2, rows: 1, overlap: 3}.regions(grid).unwrap();
+        assert_eq!((regions[0].col, regions[1].col, regions[0].cols), (0, 2, 7));
+        // each tile's corners at their place on the board, the second tile's marked by a
+        // half pixel shift
+        let tiles: Vec<(GridRegion, ImagePointGrid)> = regions.iter().enumerate().map(|(t, region)| {
+            let mut points = vec![];
+            for row in 0..region.rows {
+                for col in 0..region.cols {
+                    points.push(vec2(((region.col + col) * 10) as f32, ((region.row + row) * 10) as f32 + t as f32 * 0.5));
+                }
+            }
+            (*region, ImagePointGrid::new(region.cols, region.rows, points))
+        }).collect();
+        let stitched = stitch(grid, &tiles);
+        assert!(stitched.is_complete());
+        assert_eq!(stitched.get(3, 2), Some(vec2(30., 20.)));
+        assert_eq!(stitched.get(5, 2), Some(vec2(50., 20.5)));
+        assert_eq!(stitched.get(0, 0), Some(vec2(0., 0.)));
+        assert_eq!(stitched.get(8, 5), Some(vec2(80., 50.5)));
+    }
+}
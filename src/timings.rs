@@ -0,0 +1,78 @@
+//! Wall clock time spent in each stage of a calibration run, for finding out where the time
+//! goes on slow hardware. Nothing is timed unless `CalibrationOptions::timings` is set.
+
+use serde::{Serialize, Deserialize};
+use std::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    /// showing the pattern, including sending it to the control server
+    Display,
+    Capture,
+    Undistort,
+    /// finding the chessboard, over every preprocessing variant tried
+    Detection,
+    Subpixel,
+    /// mapping corners onto the surface, look_at and resampling to the warp grid
+    Scene,
+    /// fov and UV warp
+    Uv,
+    Serialization,
+}
+
+/// Seconds spent in each stage. Stages that ran more than once (retried detection, several
+/// cameras or eyes) are summed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Timings {
+    pub display: f64,
+    pub capture: f64,
+    pub undistort: f64,
+    pub detection: f64,
+    pub subpixel: f64,
+    pub scene: f64,
+    pub uv: f64,
+    /// not known yet when the JSON is written, so only set in the returned result
+    pub serialization: f64,
+}
+
+impl Timings {
+    pub fn add(&mut self, stage: Stage, seconds: f64) {
+        let total = match stage {
+            Stage::Display => &mut self.display,
+            Stage::Capture => &mut self.capture,
+            Stage::Undistort => &mut self.undistort,
+            Stage::Detection => &mut self.detection,
+            Stage::Subpixel => &mut self.subpixel,
+            Stage::Scene => &mut self.scene,
+            Stage::Uv => &mut self.uv,
+            Stage::Serialization => &mut self.serialization,
+        };
+        *total += seconds;
+    }
+
+    pub fn total(&self) -> f64 {
+        self.display + self.capture + self.undistort + self.detection + self.subpixel + self.scene + self.uv + self.serialization
+    }
+
+    /// One line for the log
+    pub fn summary(&self) -> String {
+        format!(
+            "display {:.2}s, capture {:.2}s, undistort {:.2}s, detection {:.2}s, subpixel {:.2}s, scene {:.2}s, uv {:.2}s, serialization {:.2}s, total {:.2}s",
+            self.display, self.capture, self.undistort, self.detection, self.subpixel, self.scene, self.uv, self.serialization, self.total()
+        )
+    }
+}
+
+/// Run f, adding the time it took to stage when timings are being recorded
+pub fn timed<T>(timings: &mut Option<Timings>, stage: Stage, f: impl FnOnce() -> T) -> T {
+    match timings {
+        Some(timings) => {
+            let started = Instant::now();
+            let result = f();
+            timings.add(stage, started.elapsed().as_secs_f64());
+            result
+        },
+        None => f()
+    }
+}
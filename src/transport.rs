@@ -0,0 +1,368 @@
+//! How requests to the control server are carried. By default they go over HTTP with the
+//! `NetworkConfig`'s client, a `Transport` set on the config carries them instead: a
+//! `RecordingTransport` appends every exchange to a transcript file, a `ReplayTransport`
+//! answers from one without any server and fails on requests that differ from the recorded
+//! ones, and tests can implement the trait for a server of their own.
+//!
+//! Transcripts are JSON lines, one `Exchange` each. Header names are recorded but their
+//! values are written as `REDACTED` unless the recording is told to keep them, binary
+//! bodies (patterns) aren't recorded either and are kept as a hash.
+
+use serde::{Serialize, Deserialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use log::debug;
+use super::network::{self, NetworkConfig, NetworkError};
+use super::output::fnv1a_hex;
+
+/// Written in place of a header value that isn't kept, replay only checks the name
+pub const REDACTED: &str = "<redacted>";
+
+/// A POST to the control server
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub url: String,
+    pub content_type: String,
+    /// the config's extra headers, sent over the client's defaults
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// What came back, whatever the status
+#[derive(Clone, Debug)]
+pub struct HttpReply {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: String,
+}
+
+/// Carries requests to the control server
+pub trait Transport {
+    fn send(&self, config: &NetworkConfig, request: &HttpRequest) -> Result<HttpReply, NetworkError>;
+}
+
+/// A transport shared by every clone of a `NetworkConfig`
+#[derive(Clone)]
+pub struct SharedTransport(pub Arc<dyn Transport + Send + Sync>);
+
+impl SharedTransport {
+    pub fn new<T: Transport + Send + Sync + 'static>(transport: T) -> SharedTransport {
+        SharedTransport(Arc::new(transport))
+    }
+}
+
+impl fmt::Debug for SharedTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedTransport")
+    }
+}
+
+/// The real thing, over HTTP(S) with the config's client. The request's own headers are set
+/// too, so a request built with other headers than the config's still carries them.
+pub struct HttpTransport;
+
+impl Transport for HttpTransport {
+    fn send(&self, config: &NetworkConfig, request: &HttpRequest) -> Result<HttpReply, NetworkError> {
+        let builder = config.client()?.post(&request.url);
+        let res = request.headers.iter()
+            .fold(builder, |builder, (name, value)| builder.header(name.as_str(), value.as_str()))
+            .header("Content-Type", &request.content_type)
+            .body(request.body.clone())
+            .send()?;
+        let status = res.status().as_u16();
+        let content_type = res.headers().get(reqwest::header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(String::from);
+        Ok(HttpReply {status: status, content_type: content_type, body: res.text()?})
+    }
+}
+
+/// One request and what came back, as a transcript line
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Exchange {
+    pub url: String,
+    pub content_type: String,
+    /// the request's extra headers, values `REDACTED` unless they were kept
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<(String, String)>,
+    /// the request body when it's text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    pub body_length: usize,
+    /// fnv1a of the request body, see `output::fnv1a_hex`
+    pub body_hash: String,
+    /// None when the request failed before a reply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_content_type: Option<String>,
+    #[serde(default)]
+    pub reply_body: String,
+    /// why the request failed, when it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub elapsed_ms: u64,
+}
+
+impl Exchange {
+    fn new(request: &HttpRequest, result: &Result<HttpReply, NetworkError>, elapsed_ms: u64, kept_headers: &[String]) -> Exchange {
+        let (status, reply_content_type, reply_body, error) = match result {
+            Ok(reply) => (Some(reply.status), reply.content_type.clone(), reply.body.clone(), None),
+            Err(err) => (None, None, String::new(), Some(err.to_string())),
+        };
+        Exchange {
+            url: request.url.clone(),
+            content_type: request.content_type.clone(),
+            headers: request.headers.iter()
+                .map(|(name, value)| {
+                    let kept = kept_headers.iter().any(|kept| kept.eq_ignore_ascii_case(name));
+                    (name.clone(), if kept { value.clone() } else { REDACTED.to_string() })
+                })
+                .collect(),
+            body: if request.content_type.starts_with("image/") { None } else { String::from_utf8(request.body.clone()).ok() },
+            body_length: request.body.len(),
+            body_hash: fnv1a_hex(&request.body),
+            status: status,
+            reply_content_type: reply_content_type,
+            reply_body: reply_body,
+            error: error,
+            elapsed_ms: elapsed_ms,
+        }
+    }
+}
+
+/// Passes requests on to inner and appends each exchange to a transcript
+pub struct RecordingTransport {
+    inner: SharedTransport,
+    file: Mutex<File>,
+    kept_headers: Vec<String>,
+}
+
+impl RecordingTransport {
+    /// Record to path, appending when it exists, with requests going over HTTP
+    pub fn new(path: &str) -> Result<RecordingTransport, NetworkError> {
+        RecordingTransport::wrapping(path, SharedTransport::new(HttpTransport))
+    }
+
+    pub fn wrapping(path: &str, inner: SharedTransport) -> Result<RecordingTransport, NetworkError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|err| NetworkError::Transcript(format!("can't open {} to record to: {}", path, err)))?;
+        Ok(RecordingTransport {inner: inner, file: Mutex::new(file), kept_headers: vec![]})
+    }
+
+    /// Record the values of these headers (any case) as they're sent, e.g. ones a replay
+    /// should check. Every other header's value is `REDACTED`.
+    pub fn keeping_header_values(mut self, names: &[&str]) -> RecordingTransport {
+        self.kept_headers = names.iter().map(|name| name.to_string()).collect();
+        self
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn send(&self, config: &NetworkConfig, request: &HttpRequest) -> Result<HttpReply, NetworkError> {
+        let start = Instant::now();
+        let result = self.inner.0.send(config, request);
+        let exchange = Exchange::new(request, &result, start.elapsed().as_millis() as u64, &self.kept_headers);
+        let line = serde_json::to_string(&exchange).map_err(|err| NetworkError::Transcript(err.to_string()))?;
+        writeln!(self.file.lock().unwrap(), "{}", line).map_err(|err| NetworkError::Transcript(format!("can't record {}: {}", request.url, err)))?;
+        result
+    }
+}
+
+/// How closely a replayed request's body must match the recorded one's
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyMatching {
+    /// byte for byte where the transcript has the body, by hash where it doesn't
+    Exact,
+    Hash,
+    /// only the URL, content type and headers are compared
+    Ignore,
+}
+
+impl BodyMatching {
+    pub fn parse(input: &str) -> Result<BodyMatching, &'static str> {
+        match input {
+            "exact" => Ok(BodyMatching::Exact),
+            "hash" => Ok(BodyMatching::Hash),
+            "ignore" => Ok(BodyMatching::Ignore),
+            _ => Err("body matching must be exact, hash or ignore")
+        }
+    }
+}
+
+/// Answers requests from a transcript, in order, without any server. A request that isn't
+/// the next recorded one is an error, including one with other headers. Redacted header
+/// values are only checked by name.
+pub struct ReplayTransport {
+    exchanges: Mutex<VecDeque<Exchange>>,
+    matching: BodyMatching,
+}
+
+impl ReplayTransport {
+    pub fn new(exchanges: Vec<Exchange>, matching: BodyMatching) -> ReplayTransport {
+        ReplayTransport {exchanges: Mutex::new(exchanges.into()), matching: matching}
+    }
+
+    /// Replay the transcript recorded at path
+    pub fn load(path: &str, matching: BodyMatching) -> Result<ReplayTransport, NetworkError> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| NetworkError::Transcript(format!("can't read transcript {}: {}", path, err)))?;
+        let exchanges = text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| serde_json::from_str(line).map_err(|err| NetworkError::Transcript(format!("{} line {}: {}", path, i + 1, err))))
+            .collect::<Result<Vec<Exchange>, NetworkError>>()?;
+        Ok(ReplayTransport::new(exchanges, matching))
+    }
+
+    /// Exchanges that haven't been replayed yet, a complete run leaves none
+    pub fn remaining(&self) -> usize {
+        self.exchanges.lock().unwrap().len()
+    }
+
+    fn mismatch(&self, request: &HttpRequest, recorded: &Exchange) -> Option<String> {
+        if request.url != recorded.url {
+            return Some(format!("expected a request to {}", recorded.url));
+        }
+        if request.content_type != recorded.content_type {
+            return Some(format!("expected content type {} but it's {}", recorded.content_type, request.content_type));
+        }
+        let names = |headers: &[(String, String)]| headers.iter().map(|(name, _)| name.to_lowercase()).collect::<Vec<String>>();
+        if names(&request.headers) != names(&recorded.headers) {
+            return Some(format!("expected headers [{}] but they're [{}]", names(&recorded.headers).join(", "), names(&request.headers).join(", ")));
+        }
+        let changed = request.headers.iter().zip(recorded.headers.iter())
+            .find(|((_, value), (_, recorded))| recorded != REDACTED && value != recorded);
+        if let Some(((name, _), _)) = changed {
+            return Some(format!("header {} differs from the recorded value", name));
+        }
+        let same_body = match (self.matching, &recorded.body) {
+            (BodyMatching::Ignore, _) => true,
+            (BodyMatching::Exact, Some(body)) => body.as_bytes() == request.body.as_slice(),
+            _ => recorded.body_length == request.body.len() && recorded.body_hash == fnv1a_hex(&request.body),
+        };
+        if !same_body {
+            return Some(format!("the body differs from the recorded {} bytes", recorded.body_length));
+        }
+        None
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send(&self, _config: &NetworkConfig, request: &HttpRequest) -> Result<HttpReply, NetworkError> {
+        let recorded = self.exchanges.lock().unwrap().pop_front()
+            .ok_or_else(|| NetworkError::Transcript(format!("{} was requested after the end of the transcript", request.url)))?;
+        if let Some(reason) = self.mismatch(request, &recorded) {
+            return Err(NetworkError::Transcript(format!("replayed request to {} doesn't match the transcript, {}", request.url, reason)));
+        }
+        debug!("replaying the recorded reply to {}", request.url);
+        match (recorded.status, recorded.error) {
+            (Some(status), _) => Ok(HttpReply {status: status, content_type: recorded.reply_content_type, body: recorded.reply_body}),
+            (None, error) => Err(NetworkError::Transcript(format!("recorded failure: {}", error.unwrap_or_default()))),
+        }
+    }
+}
+
+/// Send request with the config's transport, or over HTTP without one
+pub fn send(config: &NetworkConfig, request: &HttpRequest) -> Result<HttpReply, NetworkError> {
+    match &config.transport {
+        Some(transport) => transport.0.send(config, request),
+        None => HttpTransport.send(config, request),
+    }
+}
+
+/// Send request and check the reply's status, see `network::command_response`
+pub fn send_command_request(config: &NetworkConfig, request: &HttpRequest) -> Result<network::CommandResponse, NetworkError> {
+    network::reply_response(send(config, request)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    /// Replies 200 with an empty JSON object to everything
+    struct Server;
+
+    impl Transport for Server {
+        fn send(&self, _config: &NetworkConfig, _request: &HttpRequest) -> Result<HttpReply, NetworkError> {
+            Ok(HttpReply {status: 200, content_type: Some("application/json".to_string()), body: "{}".to_string()})
+        }
+    }
+
+    fn request(url: &str, headers: &[(&str, &str)], body: &str) -> HttpRequest {
+        HttpRequest {
+            url: url.to_string(),
+            content_type: "application/json".to_string(),
+            headers: headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn record(transport: RecordingTransport, requests: &[HttpRequest], path: &str) -> Vec<Exchange> {
+        for request in requests {
+            transport.send(&NetworkConfig::default(), request).unwrap();
+        }
+        fs::read_to_string(path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn header_values_are_redacted_unless_kept() {
+        let headers = [("Authorization", "Bearer secret"), ("X-Rig", "dome")];
+        let file = NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        let recording = RecordingTransport::wrapping(path, SharedTransport::new(Server)).unwrap();
+        let exchanges = record(recording, &[request("http://rig/state", &headers, "{}")], path);
+        assert_eq!(exchanges[0].headers, vec![("Authorization".to_string(), REDACTED.to_string()), ("X-Rig".to_string(), REDACTED.to_string())]);
+
+        let file = NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        let recording = RecordingTransport::wrapping(path, SharedTransport::new(Server)).unwrap().keeping_header_values(&["x-rig"]);
+        let exchanges = record(recording, &[request("http://rig/state", &headers, "{}")], path);
+        assert_eq!(exchanges[0].headers[0].1, REDACTED);
+        assert_eq!(exchanges[0].headers[1].1, "dome");
+        assert_eq!(exchanges[0].body.as_deref(), Some("{}"));
+    }
+
+    #[test]
+    fn replay_checks_headers() {
+        let file = NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        let recording = RecordingTransport::wrapping(path, SharedTransport::new(Server)).unwrap().keeping_header_values(&["X-Rig"]);
+        let exchanges = record(recording, &[request("http://rig/state", &[("Authorization", "Bearer secret"), ("X-Rig", "dome")], "{}")], path);
+        let replay = |headers: &[(&str, &str)]| {
+            ReplayTransport::new(exchanges.clone(), BodyMatching::Exact).send(&NetworkConfig::default(), &request("http://rig/state", headers, "{}"))
+        };
+
+        // a redacted value can be anything, a kept one has to match
+        assert_eq!(replay(&[("authorization", "Bearer other"), ("X-Rig", "dome")]).unwrap().body, "{}");
+        assert!(replay(&[("Authorization", "Bearer secret"), ("X-Rig", "flat")]).is_err());
+        assert!(replay(&[("Authorization", "Bearer secret")]).is_err());
+        assert!(replay(&[("Authorization", "Bearer secret"), ("X-Rig", "dome"), ("X-Extra", "1")]).is_err());
+    }
+
+    #[test]
+    fn replay_checks_the_url_and_body() {
+        let file = NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        let recording = RecordingTransport::wrapping(path, SharedTransport::new(Server)).unwrap();
+        let exchanges = record(recording, &[request("http://rig/state", &[], "{}"), request("http://rig/set_calibration", &[], "{\"fov\":40}")], path);
+        let config = NetworkConfig::default();
+
+        let replay = ReplayTransport::new(exchanges.clone(), BodyMatching::Exact);
+        assert!(replay.send(&config, &request("http://rig/state", &[], "{}")).is_ok());
+        assert!(replay.send(&config, &request("http://rig/set_calibration", &[], "{\"fov\":41}")).is_err());
+        assert_eq!(replay.remaining(), 0);
+        assert!(replay.send(&config, &request("http://rig/state", &[], "{}")).is_err(), "requests after the transcript ends are refused");
+
+        let replay = ReplayTransport::new(exchanges.clone(), BodyMatching::Ignore);
+        assert!(replay.send(&config, &request("http://rig/blank", &[], "{}")).is_err());
+
+        let replay = ReplayTransport::new(exchanges, BodyMatching::Ignore);
+        assert!(replay.send(&config, &request("http://rig/state", &[], "anything")).is_ok());
+        assert!(replay.send(&config, &request("http://rig/set_calibration", &[], "{\"fov\":41}")).is_ok());
+    }
+}
@@ -0,0 +1,178 @@
+//! Checking an existing calibration against a fresh capture of the same rig.
+
+use glm::*;
+use serde::{Serialize, Deserialize};
+use super::{PhysicalCamera, GridSpec, Error, CalibrationResult};
+use super::camera_calibration;
+use super::output::{self, WarpOrder, OutputConventions};
+use super::pipeline::{self, VirtualCamera, LookAtMethod};
+
+/// How far the surface points of a fresh capture are from a stored calibration's
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VerificationReport {
+    /// the maximum scene displacement allowed, in scene units
+    pub tolerance: f32,
+    pub passed: bool,
+    pub warp_res_x: i32,
+    pub warp_res_y: i32,
+    /// corners compared, those the stored calibration filled in from neighbours are skipped
+    pub compared_corners: usize,
+    pub scene_rms: f32,
+    pub scene_max: f32,
+    /// displacement of the projected points seen from the stored virtual camera, 0-1 across
+    /// the upright image
+    pub uv_rms: f32,
+    pub uv_max: f32,
+    /// per corner, row by row. None for corners that weren't compared.
+    pub scene_displacement: Vec<Option<f32>>,
+    pub uv_displacement: Vec<Option<f32>>,
+    /// ways the camera used now differs from the one the calibration was made with
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub camera_mismatches: Vec<String>,
+}
+
+impl VerificationReport {
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// The stored calibration's output grid, checking its arrays are the size it claims
+pub fn stored_grid(stored: &CalibrationResult) -> Result<GridSpec, Error> {
+    let grid = GridSpec {cols: stored.warp_res_x, rows: stored.warp_res_y};
+    if stored.scene.len() != grid.len() || stored.warp.len() != grid.len() {
+        return Err(Error::Config(format!(
+            "stored calibration claims a {} grid but has {} scene and {} warp points",
+            grid, stored.scene.len(), stored.warp.len()
+        )));
+    }
+    Ok(grid)
+}
+
+/// The stored scene points row by row in the native system, whatever conventions they were
+/// written with
+fn stored_scene_row_major(stored: &CalibrationResult, grid: GridSpec) -> (Vec<glm::Vec3>, Vec<bool>) {
+    let valid = stored.valid.clone().unwrap_or(vec![true; grid.len()]);
+    let conventions = stored_conventions(stored);
+    let scene: Vec<glm::Vec3> = stored.scene.iter().map(|p| conventions.transform.inverse_point(*p)).collect();
+    if conventions.order != WarpOrder::ColumnMajor {
+        return (scene, valid);
+    }
+    let (cols, rows) = (grid.cols as usize, grid.rows as usize);
+    let stored_index = |i: usize| (i % cols) * rows + i / cols;
+    (
+        (0..grid.len()).map(|i| scene[stored_index(i)]).collect(),
+        (0..grid.len()).map(|i| valid[stored_index(i)]).collect()
+    )
+}
+
+/// Differences between the camera pose and intrinsics recorded in meta and the ones in use
+pub fn camera_mismatches(meta: &output::Meta, physical_camera: &PhysicalCamera, calibration_meta: &output::CalibrationFileMeta) -> Vec<String> {
+    let mut mismatches = vec![];
+    let pose = &meta.physical_camera;
+    let moved = |name: &str, stored: [f32; 3], current: glm::Vec3| {
+        if length(vec3(stored[0], stored[1], stored[2]) - current) > 1e-4 {
+            Some(format!("camera {} was {:?}, now {:?}", name, stored, current.as_array()))
+        } else {
+            None
+        }
+    };
+    mismatches.extend(moved("position", pose.position, physical_camera.position));
+    mismatches.extend(moved("direction", pose.look_at, physical_camera.look_at));
+    mismatches.extend(moved("up", pose.up, physical_camera.up_dir));
+
+    if let Some(stored) = &meta.camera_calibration {
+        if stored.hash != calibration_meta.hash {
+            mismatches.push(format!("camera calibration file {} has changed since {} was used", calibration_meta.path, stored.path));
+        }
+    }
+    if let Some(stored) = &meta.camera_intrinsics {
+        let current = camera_calibration::intrinsics_meta(&physical_camera.calibration);
+        if stored.image_width != current.image_width || stored.image_height != current.image_height {
+            mismatches.push(format!(
+                "camera image was {}x{}, now {}x{}",
+                stored.image_width, stored.image_height, current.image_width, current.image_height
+            ));
+        }
+        let relative = |a: f64, b: f64| (a - b).abs() / a.abs().max(1e-9);
+        if relative(stored.focal_length[0], current.focal_length[0]) > 1e-3 || relative(stored.focal_length[1], current.focal_length[1]) > 1e-3 {
+            mismatches.push(format!("camera focal length was {:?}, now {:?}", stored.focal_length, current.focal_length));
+        }
+        if stored.distortion_coefficients != current.distortion_coefficients {
+            mismatches.push("camera distortion coefficients have changed".to_string());
+        }
+    }
+    mismatches
+}
+
+fn stored_conventions(stored: &CalibrationResult) -> OutputConventions {
+    stored.meta.as_ref().map(|meta| meta.output_conventions).unwrap_or_default()
+}
+
+/// Compare freshly located scene points, already resampled to the stored grid, against the
+/// stored calibration
+pub fn compare(stored: &CalibrationResult, fresh_scene: &Vec<glm::Vec3>, aspect_ratio: f32, tolerance: f32) -> Result<VerificationReport, Error> {
+    let grid = stored_grid(stored)?;
+    if fresh_scene.len() != grid.len() {
+        return Err(Error::Config(format!(
+            "fresh capture has {} scene points but the stored calibration's grid is {}",
+            fresh_scene.len(), grid
+        )));
+    }
+    let (stored_scene, valid) = stored_scene_row_major(stored, grid);
+
+    // both sets of points seen through the stored virtual camera, so the uv displacement
+    // doesn't depend on how the stored warp was written
+    let transform = stored_conventions(stored).transform;
+    let virtual_camera = VirtualCamera {
+        position: transform.inverse_point(stored.eye),
+        up_dir: transform.inverse_direction(stored.up),
+        look_at: Some(transform.inverse_point(stored.look_at)),
+        fov: Some(stored.fov),
+        optics: stored.projector_optics,
+        clip_planes: stored.meta.as_ref().and_then(|meta| meta.clip_planes).map(|[near, far]| (near, far)),
+        look_at_method: stored.meta.as_ref().and_then(|meta| meta.look_at_method).unwrap_or(LookAtMethod::ImageCenter),
+    };
+    let (model, proj) = pipeline::view_and_projection(&virtual_camera, aspect_ratio);
+
+    let mut scene_displacement = vec![];
+    let mut uv_displacement = vec![];
+    let (mut scene_sum_sq, mut uv_sum_sq) = (0_f32, 0_f32);
+    let (mut scene_max, mut uv_max) = (0_f32, 0_f32);
+    let mut compared = 0;
+    for i in 0..grid.len() {
+        if !valid[i] {
+            scene_displacement.push(None);
+            uv_displacement.push(None);
+            continue;
+        }
+        let d = length(fresh_scene[i] - stored_scene[i]);
+        let uv_d = length(pipeline::project_with(fresh_scene[i], &model, &proj) - pipeline::project_with(stored_scene[i], &model, &proj));
+        scene_sum_sq += d * d;
+        uv_sum_sq += uv_d * uv_d;
+        scene_max = scene_max.max(d);
+        uv_max = uv_max.max(uv_d);
+        compared += 1;
+        scene_displacement.push(Some(d));
+        uv_displacement.push(Some(uv_d));
+    }
+    if compared == 0 {
+        return Err(Error::Config("the stored calibration has no valid corners to compare".to_string()));
+    }
+
+    Ok(VerificationReport {
+        tolerance: tolerance,
+        passed: scene_max <= tolerance,
+        warp_res_x: grid.cols,
+        warp_res_y: grid.rows,
+        compared_corners: compared,
+        scene_rms: (scene_sum_sq / compared as f32).sqrt(),
+        scene_max: scene_max,
+        uv_rms: (uv_sum_sq / compared as f32).sqrt(),
+        uv_max: uv_max,
+        scene_displacement: scene_displacement,
+        uv_displacement: uv_displacement,
+        camera_mismatches: vec![],
+    })
+}